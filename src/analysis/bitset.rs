@@ -0,0 +1,114 @@
+//! A small, growable bit set used to represent dataflow facts.
+
+/// Dense set of `usize` elements backed by 64-bit words.
+#[derive(Clone, Debug, Default)]
+pub struct BitSet {
+    words: Vec<u64>,
+}
+
+impl BitSet {
+    /// Creates an empty set that can hold elements in `0..capacity` without reallocating.
+    pub fn with_capacity(capacity: usize) -> Self {
+        BitSet {
+            words: vec![0; capacity.div_ceil(64)],
+        }
+    }
+
+    /// Adds an element to the set. Returns whether the element was newly inserted.
+    pub fn insert(&mut self, elem: usize) -> bool {
+        let (word, bit) = (elem / 64, elem % 64);
+        if word >= self.words.len() {
+            self.words.resize(word + 1, 0);
+        }
+        let was_set = self.words[word] & (1 << bit) != 0;
+        self.words[word] |= 1 << bit;
+        !was_set
+    }
+
+    /// Removes an element from the set. Returns whether the element was present.
+    pub fn remove(&mut self, elem: usize) -> bool {
+        let (word, bit) = (elem / 64, elem % 64);
+        if word >= self.words.len() {
+            return false;
+        }
+        let was_set = self.words[word] & (1 << bit) != 0;
+        self.words[word] &= !(1 << bit);
+        was_set
+    }
+
+    /// Checks whether the element is in the set.
+    pub fn contains(&self, elem: usize) -> bool {
+        let (word, bit) = (elem / 64, elem % 64);
+        word < self.words.len() && self.words[word] & (1 << bit) != 0
+    }
+
+    /// Adds all elements of `other` to this set. Returns whether this set changed.
+    pub fn union_with(&mut self, other: &BitSet) -> bool {
+        if other.words.len() > self.words.len() {
+            self.words.resize(other.words.len(), 0);
+        }
+        let mut changed = false;
+        for (word, other_word) in self.words.iter_mut().zip(other.words.iter()) {
+            let new = *word | *other_word;
+            changed |= new != *word;
+            *word = new;
+        }
+        changed
+    }
+
+    /// Keeps only the elements that are also in `other`. Returns whether this set changed.
+    pub fn intersect_with(&mut self, other: &BitSet) -> bool {
+        let mut changed = false;
+        for (idx, word) in self.words.iter_mut().enumerate() {
+            let other_word = other.words.get(idx).copied().unwrap_or(0);
+            let new = *word & other_word;
+            changed |= new != *word;
+            *word = new;
+        }
+        changed
+    }
+
+    /// Checks whether the set has no elements.
+    pub fn is_empty(&self) -> bool {
+        self.words.iter().all(|word| *word == 0)
+    }
+
+    /// Number of elements in the set.
+    pub fn len(&self) -> usize {
+        self.words
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    /// Iterates over the elements of the set in increasing order.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words.iter().enumerate().flat_map(|(idx, word)| {
+            (0..64)
+                .filter(move |bit| word & (1 << bit) != 0)
+                .map(move |bit| idx * 64 + bit)
+        })
+    }
+}
+
+impl PartialEq for BitSet {
+    fn eq(&self, other: &Self) -> bool {
+        // sets may have grown to different lengths, missing words are all zeros
+        let len = self.words.len().max(other.words.len());
+        (0..len).all(|idx| {
+            self.words.get(idx).copied().unwrap_or(0) == other.words.get(idx).copied().unwrap_or(0)
+        })
+    }
+}
+
+impl Eq for BitSet {}
+
+impl FromIterator<usize> for BitSet {
+    fn from_iter<T: IntoIterator<Item = usize>>(iter: T) -> Self {
+        let mut set = BitSet::default();
+        for elem in iter {
+            set.insert(elem);
+        }
+        set
+    }
+}
@@ -0,0 +1,494 @@
+//! Control flow graph over the instructions of a function body.
+//!
+//! Basic blocks are contiguous instruction ranges of a [`Body`]. The graph follows wasm's structured
+//! control flow: branches to a `loop` go to its header, branches to any other block go to its `end`.
+//! A synthetic exit block (with an empty instruction range) collects every way out of the function
+//! (falling off the end, `return`, tail calls and traps).
+//!
+//! Exceptional edges are modelled for `throw`-like instructions and for calls that are lexically
+//! inside a `try_table` (or legacy `try`), they flow to the handlers of the enclosing try blocks.
+
+use crate::ir::types::Body;
+use wasmparser::{Catch, Operator};
+
+/// Index of a basic block in a [`ControlFlowGraph`].
+pub type BlockIdx = usize;
+
+/// A maximal sequence of instructions with a single entry point.
+#[derive(Clone, Debug, Default)]
+pub struct BasicBlock {
+    /// Index of the first instruction of the block.
+    pub start: usize,
+    /// Index one past the last instruction of the block.
+    pub end: usize,
+    /// Blocks that control can flow to once this block finishes.
+    pub succs: Vec<BlockIdx>,
+    /// Blocks that can flow into this block.
+    pub preds: Vec<BlockIdx>,
+}
+
+impl BasicBlock {
+    /// The range of instruction indices covered by this block.
+    pub fn instrs(&self) -> std::ops::Range<usize> {
+        self.start..self.end
+    }
+
+    /// Index of the last instruction of the block, `None` for the (empty) exit block.
+    pub fn last_instr(&self) -> Option<usize> {
+        if self.end > self.start {
+            Some(self.end - 1)
+        } else {
+            None
+        }
+    }
+}
+
+/// Control flow graph of a single function.
+#[derive(Clone, Debug)]
+pub struct ControlFlowGraph {
+    /// The basic blocks, in program order. The last block is the synthetic exit block.
+    pub blocks: Vec<BasicBlock>,
+    /// Maps an instruction index to the basic block that contains it.
+    instr_to_block: Vec<BlockIdx>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum FrameKind {
+    Func,
+    Block,
+    Loop,
+    If,
+    TryTable,
+    Try,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Frame {
+    kind: FrameKind,
+    /// Instruction index of the `block`/`loop`/`if`/`try*` opening this frame
+    start: usize,
+}
+
+/// Matching information for structured control instructions
+struct Structure {
+    /// For each block-opening instruction, the index of its `end` (or `delegate`)
+    end_of: Vec<Option<usize>>,
+    /// For each `try`, the indices of its `catch` and `catch_all` clauses
+    catches_of: Vec<Vec<usize>>,
+}
+
+impl Structure {
+    fn new(ops: &[&Operator]) -> Self {
+        let len = ops.len();
+        let mut end_of = vec![None; len];
+        let mut catches_of = vec![vec![]; len];
+        let mut stack: Vec<usize> = vec![];
+        for (idx, op) in ops.iter().enumerate() {
+            match op {
+                Operator::Block { .. }
+                | Operator::Loop { .. }
+                | Operator::If { .. }
+                | Operator::TryTable { .. }
+                | Operator::Try { .. } => stack.push(idx),
+                Operator::Catch { .. } | Operator::CatchAll => {
+                    if let Some(start) = stack.last() {
+                        catches_of[*start].push(idx);
+                    }
+                }
+                Operator::End | Operator::Delegate { .. } => {
+                    if let Some(start) = stack.pop() {
+                        end_of[start] = Some(idx);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Structure { end_of, catches_of }
+    }
+}
+
+impl ControlFlowGraph {
+    /// Builds the control flow graph of a function body.
+    pub fn new(body: &Body) -> Self {
+        let ops: Vec<&Operator> = body.instructions.iter().map(|instr| &instr.op).collect();
+        Self::from_ops(&ops)
+    }
+
+    pub(crate) fn from_ops(ops: &[&Operator]) -> Self {
+        let len = ops.len();
+        let structure = Structure::new(ops);
+
+        // ==== find the leaders ====
+        let mut is_leader = vec![false; len + 1];
+        is_leader[0] = true;
+        let mut stack = vec![Frame {
+            kind: FrameKind::Func,
+            start: len,
+        }];
+        for (idx, op) in ops.iter().enumerate() {
+            update_stack(&mut stack, op, idx);
+            if starts_block(op) {
+                is_leader[idx] = true;
+            }
+            if ends_block(op) || (may_throw(op) && in_try(&stack)) {
+                is_leader[idx + 1] = true;
+            }
+        }
+
+        let mut blocks = vec![];
+        let mut instr_to_block = vec![0; len];
+        for idx in 0..len {
+            if is_leader[idx] {
+                if let Some(prev) = blocks.last_mut() {
+                    let prev: &mut BasicBlock = prev;
+                    prev.end = idx;
+                }
+                blocks.push(BasicBlock {
+                    start: idx,
+                    end: len,
+                    ..Default::default()
+                });
+            }
+            instr_to_block[idx] = blocks.len() - 1;
+        }
+        // the synthetic exit block
+        blocks.push(BasicBlock {
+            start: len,
+            end: len,
+            ..Default::default()
+        });
+        let exit = blocks.len() - 1;
+
+        let mut cfg = ControlFlowGraph {
+            blocks,
+            instr_to_block,
+        };
+
+        // ==== connect the blocks ====
+        let mut stack = vec![Frame {
+            kind: FrameKind::Func,
+            start: len,
+        }];
+        for (idx, op) in ops.iter().enumerate() {
+            // the branch targets of an `end` are resolved against the enclosing frame
+            let frames_before = stack.clone();
+            update_stack(&mut stack, op, idx);
+
+            let block = cfg.instr_to_block[idx];
+            if cfg.blocks[block].end != idx + 1 {
+                // not the last instruction of the block
+                continue;
+            }
+
+            let mut succs = vec![];
+            let fallthrough = |succs: &mut Vec<BlockIdx>| {
+                if idx + 1 >= len {
+                    succs.push(exit);
+                    return;
+                }
+                match ops[idx + 1] {
+                    // the end of the then (or try) arm skips the other arms
+                    Operator::Else | Operator::Catch { .. } | Operator::CatchAll => {
+                        let top = stack.last().unwrap();
+                        succs.push(cfg.label_target(&structure, top, len));
+                    }
+                    _ => succs.push(cfg.instr_to_block[idx + 1]),
+                }
+            };
+            match op {
+                Operator::Br { relative_depth } => {
+                    succs.push(cfg.branch_target(&structure, &stack, *relative_depth, len));
+                }
+                Operator::BrIf { relative_depth }
+                | Operator::BrOnNull { relative_depth }
+                | Operator::BrOnNonNull { relative_depth }
+                | Operator::BrOnCast { relative_depth, .. }
+                | Operator::BrOnCastFail { relative_depth, .. } => {
+                    succs.push(cfg.branch_target(&structure, &stack, *relative_depth, len));
+                    fallthrough(&mut succs);
+                }
+                Operator::BrTable { targets } => {
+                    for target in targets.targets().flatten() {
+                        succs.push(cfg.branch_target(&structure, &stack, target, len));
+                    }
+                    succs.push(cfg.branch_target(&structure, &stack, targets.default(), len));
+                }
+                Operator::If { .. } => {
+                    // then arm
+                    fallthrough(&mut succs);
+                    // else arm (or straight to the end)
+                    let else_or_end = ops[idx + 1..structure.end_of[idx].unwrap_or(len - 1)]
+                        .iter()
+                        .enumerate()
+                        .scan(0u32, |depth, (offset, op)| {
+                            let found = *depth == 0 && matches!(op, Operator::Else);
+                            match op {
+                                Operator::Block { .. }
+                                | Operator::Loop { .. }
+                                | Operator::If { .. }
+                                | Operator::TryTable { .. }
+                                | Operator::Try { .. } => *depth += 1,
+                                Operator::End | Operator::Delegate { .. } => {
+                                    *depth = depth.saturating_sub(1)
+                                }
+                                _ => {}
+                            }
+                            Some((found, idx + 1 + offset))
+                        })
+                        .find(|(found, _)| *found)
+                        .map(|(_, else_idx)| else_idx)
+                        .or(structure.end_of[idx]);
+                    match else_or_end {
+                        Some(target) => succs.push(cfg.instr_to_block[target]),
+                        None => succs.push(exit),
+                    }
+                }
+                Operator::Return
+                | Operator::ReturnCall { .. }
+                | Operator::ReturnCallIndirect { .. }
+                | Operator::ReturnCallRef { .. }
+                | Operator::Unreachable => succs.push(exit),
+                Operator::Throw { .. } | Operator::Rethrow { .. } | Operator::ThrowRef => {
+                    cfg.handler_targets(&structure, &stack, ops, &mut succs);
+                    succs.push(exit);
+                }
+                Operator::End | Operator::Delegate { .. } => {
+                    if frames_before.len() <= 1 {
+                        // the end of the function
+                        succs.push(exit);
+                    } else {
+                        fallthrough(&mut succs);
+                    }
+                }
+                _ => {
+                    if may_throw(op) && in_try(&stack) {
+                        cfg.handler_targets(&structure, &stack, ops, &mut succs);
+                    }
+                    fallthrough(&mut succs);
+                }
+            }
+
+            succs.sort_unstable();
+            succs.dedup();
+            for succ in succs.iter() {
+                cfg.blocks[*succ].preds.push(block);
+            }
+            cfg.blocks[block].succs = succs;
+        }
+        cfg
+    }
+
+    /// The block where execution of the function begins.
+    pub fn entry(&self) -> BlockIdx {
+        0
+    }
+
+    /// The synthetic block that every exit from the function flows into.
+    pub fn exit(&self) -> BlockIdx {
+        self.blocks.len() - 1
+    }
+
+    /// Number of blocks, including the synthetic exit block.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Checks if the graph has no blocks. A graph always has at least the exit block.
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Get a block by its index.
+    pub fn block(&self, idx: BlockIdx) -> &BasicBlock {
+        &self.blocks[idx]
+    }
+
+    /// The block containing the instruction at `instr_idx`.
+    pub fn block_of(&self, instr_idx: usize) -> BlockIdx {
+        self.instr_to_block[instr_idx]
+    }
+
+    /// The blocks in reverse post-order, starting from the entry block.
+    /// Unreachable blocks are not included.
+    pub fn reverse_post_order(&self) -> Vec<BlockIdx> {
+        self.post_order_from(self.entry(), |block| &block.succs)
+            .into_iter()
+            .rev()
+            .collect()
+    }
+
+    /// The blocks in reverse post-order of the reversed graph, starting from the exit block.
+    /// Blocks that cannot reach the exit are not included.
+    pub fn reverse_post_order_backward(&self) -> Vec<BlockIdx> {
+        self.post_order_from(self.exit(), |block| &block.preds)
+            .into_iter()
+            .rev()
+            .collect()
+    }
+
+    fn post_order_from<F>(&self, root: BlockIdx, edges: F) -> Vec<BlockIdx>
+    where
+        F: Fn(&BasicBlock) -> &Vec<BlockIdx>,
+    {
+        let mut visited = vec![false; self.blocks.len()];
+        let mut order = Vec::with_capacity(self.blocks.len());
+        // (block, index of the next edge to visit)
+        let mut stack = vec![(root, 0)];
+        visited[root] = true;
+        while let Some((block, next)) = stack.last_mut() {
+            let edges = edges(&self.blocks[*block]);
+            if *next < edges.len() {
+                let succ = edges[*next];
+                *next += 1;
+                if !visited[succ] {
+                    visited[succ] = true;
+                    stack.push((succ, 0));
+                }
+            } else {
+                order.push(*block);
+                stack.pop();
+            }
+        }
+        order
+    }
+
+    /// The block a branch to `frame` lands on.
+    fn label_target(&self, structure: &Structure, frame: &Frame, len: usize) -> BlockIdx {
+        match frame.kind {
+            FrameKind::Loop => self.instr_to_block[frame.start],
+            FrameKind::Func => self.instr_to_block[len - 1],
+            _ => match structure.end_of[frame.start] {
+                Some(end) => self.instr_to_block[end],
+                None => self.exit(),
+            },
+        }
+    }
+
+    fn branch_target(
+        &self,
+        structure: &Structure,
+        stack: &[Frame],
+        relative_depth: u32,
+        len: usize,
+    ) -> BlockIdx {
+        match stack.len().checked_sub(1 + relative_depth as usize) {
+            Some(frame) => self.label_target(structure, &stack[frame], len),
+            None => self.exit(),
+        }
+    }
+
+    /// Adds the handlers that an exception thrown with `stack` as the control stack may reach.
+    fn handler_targets(
+        &self,
+        structure: &Structure,
+        stack: &[Frame],
+        ops: &[&Operator],
+        succs: &mut Vec<BlockIdx>,
+    ) {
+        for (depth, frame) in stack.iter().enumerate().rev() {
+            match frame.kind {
+                FrameKind::TryTable => {
+                    if let Operator::TryTable { try_table } = ops[frame.start] {
+                        for catch in try_table.catches.iter() {
+                            let label = match catch {
+                                Catch::One { label, .. }
+                                | Catch::OneRef { label, .. }
+                                | Catch::All { label }
+                                | Catch::AllRef { label } => *label,
+                            };
+                            // catch labels are relative to the block enclosing the try_table
+                            succs.push(self.branch_target(
+                                structure,
+                                &stack[..depth],
+                                label,
+                                ops.len(),
+                            ));
+                        }
+                    }
+                }
+                FrameKind::Try => {
+                    for catch in structure.catches_of[frame.start].iter() {
+                        succs.push(self.instr_to_block[*catch]);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn update_stack(stack: &mut Vec<Frame>, op: &Operator, idx: usize) {
+    let kind = match op {
+        Operator::Block { .. } => FrameKind::Block,
+        Operator::Loop { .. } => FrameKind::Loop,
+        Operator::If { .. } => FrameKind::If,
+        Operator::TryTable { .. } => FrameKind::TryTable,
+        Operator::Try { .. } => FrameKind::Try,
+        Operator::End | Operator::Delegate { .. } => {
+            stack.pop();
+            return;
+        }
+        _ => return,
+    };
+    stack.push(Frame { kind, start: idx });
+}
+
+/// Instructions that control can arrive at from somewhere other than the previous instruction.
+fn starts_block(op: &Operator) -> bool {
+    matches!(
+        op,
+        Operator::Loop { .. }
+            | Operator::Else
+            | Operator::Catch { .. }
+            | Operator::CatchAll
+            | Operator::End
+            | Operator::Delegate { .. }
+    )
+}
+
+/// Instructions after which control may not continue with the next instruction.
+fn ends_block(op: &Operator) -> bool {
+    matches!(
+        op,
+        Operator::If { .. }
+            | Operator::Br { .. }
+            | Operator::BrIf { .. }
+            | Operator::BrTable { .. }
+            | Operator::BrOnNull { .. }
+            | Operator::BrOnNonNull { .. }
+            | Operator::BrOnCast { .. }
+            | Operator::BrOnCastFail { .. }
+            | Operator::Return
+            | Operator::ReturnCall { .. }
+            | Operator::ReturnCallIndirect { .. }
+            | Operator::ReturnCallRef { .. }
+            | Operator::Unreachable
+            | Operator::Throw { .. }
+            | Operator::Rethrow { .. }
+            | Operator::ThrowRef
+    )
+}
+
+/// Instructions that may implicitly transfer control to an exception handler.
+fn may_throw(op: &Operator) -> bool {
+    matches!(
+        op,
+        Operator::Call { .. } | Operator::CallIndirect { .. } | Operator::CallRef { .. }
+    )
+}
+
+fn in_try(stack: &[Frame]) -> bool {
+    stack
+        .iter()
+        .any(|frame| matches!(frame.kind, FrameKind::TryTable | FrameKind::Try))
+}
+
+/// Instructions that open, split or leave a structured block.
+pub(crate) fn is_control_op(op: &Operator) -> bool {
+    starts_block(op)
+        || ends_block(op)
+        || matches!(
+            op,
+            Operator::Block { .. } | Operator::TryTable { .. } | Operator::Try { .. }
+        )
+}
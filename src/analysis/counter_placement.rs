@@ -0,0 +1,327 @@
+//! Minimal placement of coverage probes over the basic blocks of a function.
+//!
+//! Injecting a probe at the entry of every block is wasteful: the number of times control flows
+//! into a block is also the number of times it flows out of it, so most counts follow from the
+//! others. Following Knuth ("Optimal measurement points for program frequency counts") and Ball and
+//! Larus ("Optimally profiling and tracing programs"), every block `b` is split into an edge
+//! `b.in -> b.out` whose flow is the number of executions of `b`, the control flow edges go from
+//! `b.out` to the `in` of their target, and an edge from the exit back to the entry closes the
+//! flow. The blocks whose split edge is not in a spanning tree of this graph get a probe. The flow
+//! of every edge of the tree, thus the count of every other block, is then solved from the probes
+//! by flow conservation. The blocks of deeper loops are added to the tree first so that the probes
+//! end up on the blocks that run the least.
+//!
+//! A probe counts the arrivals in its block. For the final `end` of the function, only falling
+//! through to it counts: branches out of the function through its outermost label are considered
+//! to flow straight to the exit.
+//!
+//! The inference assumes that every invocation of the function ran to its end or returned, a trap,
+//! an uncaught exception or a function still running when the probes are read breaks the
+//! conservation of the flow. The inferred counts are clamped to 0.
+
+use crate::analysis::bitset::BitSet;
+use crate::analysis::cfg::{BlockIdx, ControlFlowGraph};
+use crate::analysis::dominators::DominatorTree;
+use crate::analysis::loops::LoopNest;
+use crate::ir::types::Body;
+use wasmparser::Operator;
+
+/// A count as a weighted sum of the probes: (index in [`CounterPlacement::probes`], coefficient).
+pub type ProbeSum = Vec<(usize, i64)>;
+
+/// The blocks of a function to place coverage probes on.
+#[derive(Clone, Debug)]
+pub struct CounterPlacement {
+    /// The blocks that need a probe, in program order.
+    pub probes: Vec<BlockIdx>,
+    /// For every block, its count as a sum of the probes.
+    counts: Vec<ProbeSum>,
+}
+
+/// The graph whose flow is solved: blocks are split in an `in` and an `out` node.
+struct FlowGraph {
+    /// (from, to) nodes of every edge.
+    edges: Vec<(usize, usize)>,
+    /// The edge between the `in` and `out` nodes of every block, `None` for unreachable blocks and
+    /// the exit (which is a single node).
+    split: Vec<Option<usize>>,
+    /// The edge from the exit back to the entry.
+    closing: usize,
+}
+
+impl FlowGraph {
+    fn new(body: &Body, cfg: &ControlFlowGraph) -> Self {
+        let num_blocks = cfg.len();
+        let (node_in, node_out) = (|block: BlockIdx| 2 * block, |block: BlockIdx| 2 * block + 1);
+        let exit = cfg.exit();
+        let reachable = DominatorTree::dominators(cfg);
+        let reachable = |block: BlockIdx| block != exit && reachable.contains(block);
+
+        // the block of the final `end`, where a branch to the outermost label does not arrive
+        let len = body.instructions.len();
+        let final_end = (len > 0 && cfg.block(cfg.block_of(len - 1)).start == len - 1)
+            .then(|| cfg.block_of(len - 1));
+
+        let mut edges = vec![];
+        for block in (0..num_blocks).filter(|block| reachable(*block)) {
+            let mut succs = vec![];
+            for succ in cfg.block(block).succs.iter() {
+                if Some(*succ) != final_end {
+                    succs.push(*succ);
+                    continue;
+                }
+                let last = &body.instructions[cfg.block(block).end - 1].op;
+                let adjacent = cfg.block(block).end == len - 1;
+                if adjacent && falls_through(last) {
+                    succs.push(*succ);
+                }
+                if !adjacent || is_branch(last) {
+                    succs.push(exit);
+                }
+            }
+            succs.sort_unstable();
+            succs.dedup();
+            for succ in succs {
+                edges.push((node_out(block), node_in(succ)));
+            }
+        }
+        let closing = edges.len();
+        edges.push((node_in(exit), node_in(cfg.entry())));
+
+        let mut split = vec![None; num_blocks];
+        for block in (0..num_blocks).filter(|block| reachable(*block)) {
+            split[block] = Some(edges.len());
+            edges.push((node_in(block), node_out(block)));
+        }
+        FlowGraph {
+            edges,
+            split,
+            closing,
+        }
+    }
+
+    fn num_nodes(&self) -> usize {
+        2 * self.split.len()
+    }
+
+    /// Solves the flow of the edges from the flow of the split edges of the probed blocks, by
+    /// repeatedly solving the nodes where a single edge is unknown.
+    fn solve(&self, probes: &[BlockIdx]) -> Vec<Option<ProbeSum>> {
+        let mut flow: Vec<Option<ProbeSum>> = vec![None; self.edges.len()];
+        for (idx, probe) in probes.iter().enumerate() {
+            flow[self.split[*probe].unwrap()] = Some(vec![(idx, 1)]);
+        }
+
+        // (edge, +1 if it flows into the node, -1 if it flows out)
+        let mut node_edges: Vec<Vec<(usize, i64)>> = vec![vec![]; self.num_nodes()];
+        for (edge, (from, to)) in self.edges.iter().enumerate() {
+            node_edges[*from].push((edge, -1));
+            node_edges[*to].push((edge, 1));
+        }
+        let mut unknown: Vec<usize> = node_edges
+            .iter()
+            .map(|edges| {
+                edges
+                    .iter()
+                    .filter(|(edge, _)| flow[*edge].is_none())
+                    .count()
+            })
+            .collect();
+        let mut worklist: Vec<usize> = (0..self.num_nodes())
+            .filter(|node| unknown[*node] == 1)
+            .collect();
+        while let Some(node) = worklist.pop() {
+            if unknown[node] != 1 {
+                continue;
+            }
+            let (edge, sign) = *node_edges[node]
+                .iter()
+                .find(|(edge, _)| flow[*edge].is_none())
+                .unwrap();
+            // the flows into and out of the node balance
+            let mut sum = ProbeSum::new();
+            for (other, other_sign) in node_edges[node].iter().filter(|(other, _)| *other != edge) {
+                for (probe, coefficient) in flow[*other].as_ref().unwrap() {
+                    add_term(&mut sum, *probe, -sign * other_sign * coefficient);
+                }
+            }
+            flow[edge] = Some(sum);
+            let (from, to) = self.edges[edge];
+            for end in [from, to] {
+                unknown[end] -= 1;
+                if unknown[end] == 1 {
+                    worklist.push(end);
+                }
+            }
+        }
+        flow
+    }
+}
+
+fn add_term(sum: &mut ProbeSum, probe: usize, coefficient: i64) {
+    match sum.binary_search_by_key(&probe, |(probe, _)| *probe) {
+        Ok(pos) => {
+            sum[pos].1 += coefficient;
+            if sum[pos].1 == 0 {
+                sum.remove(pos);
+            }
+        }
+        Err(pos) => {
+            if coefficient != 0 {
+                sum.insert(pos, (probe, coefficient));
+            }
+        }
+    }
+}
+
+/// Whether control may continue with the instruction following `op`.
+fn falls_through(op: &Operator) -> bool {
+    !matches!(
+        op,
+        Operator::Br { .. }
+            | Operator::BrTable { .. }
+            | Operator::Return
+            | Operator::ReturnCall { .. }
+            | Operator::ReturnCallIndirect { .. }
+            | Operator::ReturnCallRef { .. }
+            | Operator::Unreachable
+            | Operator::Throw { .. }
+            | Operator::Rethrow { .. }
+            | Operator::ThrowRef
+    )
+}
+
+/// Whether `op` branches to a label.
+fn is_branch(op: &Operator) -> bool {
+    matches!(
+        op,
+        Operator::Br { .. }
+            | Operator::BrIf { .. }
+            | Operator::BrTable { .. }
+            | Operator::BrOnNull { .. }
+            | Operator::BrOnNonNull { .. }
+            | Operator::BrOnCast { .. }
+            | Operator::BrOnCastFail { .. }
+    )
+}
+
+/// Union-find over the nodes of the flow graph.
+struct Components(Vec<usize>);
+
+impl Components {
+    fn find(&mut self, node: usize) -> usize {
+        let mut root = node;
+        while self.0[root] != root {
+            root = self.0[root];
+        }
+        let mut node = node;
+        while self.0[node] != root {
+            node = std::mem::replace(&mut self.0[node], root);
+        }
+        root
+    }
+
+    /// Merges the components of `a` and `b`, false if they already were the same.
+    fn union(&mut self, a: usize, b: usize) -> bool {
+        let (a, b) = (self.find(a), self.find(b));
+        self.0[a] = b;
+        a != b
+    }
+}
+
+impl CounterPlacement {
+    /// Computes the placement of probes for the blocks of `body`.
+    pub fn new(body: &Body) -> Self {
+        Self::with_cfg(body, &ControlFlowGraph::new(body))
+    }
+
+    /// Computes the placement of probes for the blocks of an already built graph of `body`.
+    /// The synthetic exit block and the unreachable blocks never get a probe.
+    pub fn with_cfg(body: &Body, cfg: &ControlFlowGraph) -> Self {
+        let flow = FlowGraph::new(body, cfg);
+        let loops = LoopNest::new(body);
+
+        // the control flow edges first, then the blocks of the deepest loops: the split edges
+        // that do not fit in the spanning tree need a probe
+        let mut components = Components((0..flow.num_nodes()).collect());
+        for (from, to) in flow.edges[..=flow.closing].iter() {
+            components.union(*from, *to);
+        }
+        let mut blocks: Vec<BlockIdx> = (0..cfg.len())
+            .filter(|block| flow.split[*block].is_some())
+            .collect();
+        blocks.sort_by_key(|block| std::cmp::Reverse(loops.depth(cfg.block(*block).start)));
+        let mut probes: Vec<BlockIdx> = blocks
+            .into_iter()
+            .filter(|block| !components.union(2 * block, 2 * block + 1))
+            .collect();
+        probes.sort_unstable();
+
+        // control flow edges closing a cycle on their own are not measured by any probe, probe
+        // the blocks that are left unsolved until all of them are
+        let solved = loop {
+            let solved = flow.solve(&probes);
+            let unsolved = (0..cfg.len())
+                .find(|block| flow.split[*block].is_some_and(|edge| solved[edge].is_none()));
+            match unsolved {
+                Some(block) => {
+                    probes.push(block);
+                    probes.sort_unstable();
+                }
+                None => break solved,
+            }
+        };
+
+        let counts = (0..cfg.len())
+            .map(|block| {
+                let edge = if block == cfg.exit() {
+                    Some(flow.closing)
+                } else {
+                    flow.split[block]
+                };
+                edge.and_then(|edge| solved[edge].clone())
+                    .unwrap_or_default()
+            })
+            .collect();
+        CounterPlacement { probes, counts }
+    }
+
+    /// Whether `block` holds a probe.
+    pub fn is_probe(&self, block: BlockIdx) -> bool {
+        self.probes.contains(&block)
+    }
+
+    /// The number of executions of `block` as a sum of the probes. For the exit block, the number
+    /// of invocations of the function. Empty for the unreachable blocks.
+    pub fn count_of(&self, block: BlockIdx) -> &[(usize, i64)] {
+        &self.counts[block]
+    }
+
+    /// Infers the number of executions of every block from the values of the probes.
+    /// `counts[i]` is the value of the probe on `self.probes[i]`.
+    pub fn infer_counts(&self, counts: &[u64]) -> Vec<u64> {
+        self.counts
+            .iter()
+            .map(|sum| evaluate(sum, |probe| counts.get(probe).copied().unwrap_or(0)))
+            .collect()
+    }
+
+    /// Infers the blocks that ran from the values of the probes, see [`Self::infer_counts`].
+    pub fn infer_coverage(&self, counts: &[u64]) -> BitSet {
+        self.infer_counts(counts)
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(block, _)| block)
+            .collect()
+    }
+}
+
+/// The value of a sum of probes (or counters), clamped to the range of a count.
+pub(crate) fn evaluate<F: Fn(usize) -> u64>(sum: &[(usize, i64)], value_of: F) -> u64 {
+    let total: i128 = sum
+        .iter()
+        .map(|(probe, coefficient)| *coefficient as i128 * value_of(*probe) as i128)
+        .sum();
+    total.clamp(0, u64::MAX as i128) as u64
+}
@@ -0,0 +1,257 @@
+//! A generic worklist solver for forward and backward dataflow analyses over a function [`Body`].
+//!
+//! Facts are computed per basic block of the [`ControlFlowGraph`] and can then be queried for the
+//! program points right before and right after any instruction. The transfer function is handed
+//! the whole [`Instruction`], so analyses can take injected instrumentation into account.
+
+use crate::analysis::cfg::{is_control_op, BlockIdx, ControlFlowGraph};
+use crate::ir::types::{Body, Instruction};
+use std::collections::VecDeque;
+
+/// The direction facts flow in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    /// Facts flow from the function entry towards its exit (e.g. reaching definitions).
+    Forward,
+    /// Facts flow from the function exit towards its entry (e.g. liveness).
+    Backward,
+}
+
+/// A dataflow analysis, defined by its lattice of facts and its transfer function.
+pub trait DataflowAnalysis {
+    /// The dataflow fact tracked at every program point.
+    type Fact: Clone + PartialEq;
+
+    /// The direction this analysis runs in.
+    fn direction(&self) -> Direction;
+
+    /// The initial fact of every program point, the identity of [`DataflowAnalysis::join`].
+    fn bottom(&self) -> Self::Fact;
+
+    /// The fact at the function entry (forward) or the function exit (backward).
+    fn boundary(&self) -> Self::Fact;
+
+    /// Merges `other` into `fact` where control flow meets.
+    fn join(&self, fact: &mut Self::Fact, other: &Self::Fact);
+
+    /// Applies the effect of the instruction at `idx` to `fact`.
+    /// For a backward analysis `fact` is the fact after the instruction and becomes the fact before it.
+    fn transfer(&self, idx: usize, instr: &Instruction, fact: &mut Self::Fact);
+}
+
+/// The solution of a [`DataflowAnalysis`] for one function body.
+#[derive(Clone, Debug)]
+pub struct DataflowResults<F> {
+    /// The control flow graph the analysis was solved over.
+    pub cfg: ControlFlowGraph,
+    direction: Direction,
+    /// Fact at the start of every block
+    block_entry: Vec<F>,
+    /// Fact at the end of every block
+    block_exit: Vec<F>,
+}
+
+/// Solves `analysis` over `body` until a fixed point is reached.
+pub fn solve<A: DataflowAnalysis>(analysis: &A, body: &Body) -> DataflowResults<A::Fact> {
+    let cfg = ControlFlowGraph::new(body);
+    solve_with_cfg(analysis, body, cfg)
+}
+
+/// Solves `analysis` over `body` with a control flow graph that has already been built.
+pub fn solve_with_cfg<A: DataflowAnalysis>(
+    analysis: &A,
+    body: &Body,
+    cfg: ControlFlowGraph,
+) -> DataflowResults<A::Fact> {
+    let direction = analysis.direction();
+    let num_blocks = cfg.len();
+    let mut block_entry = vec![analysis.bottom(); num_blocks];
+    let mut block_exit = vec![analysis.bottom(); num_blocks];
+
+    // visit blocks in an order where most inputs are known before they are needed
+    let mut order = match direction {
+        Direction::Forward => cfg.reverse_post_order(),
+        Direction::Backward => cfg.reverse_post_order_backward(),
+    };
+    let mut queued = vec![false; num_blocks];
+    for block in order.iter() {
+        queued[*block] = true;
+    }
+    // blocks that are unreachable (or cannot reach the exit) are still solved
+    order.extend((0..num_blocks).filter(|block| !queued[*block]));
+    queued.iter_mut().for_each(|q| *q = true);
+    let mut worklist: VecDeque<BlockIdx> = order.into();
+
+    while let Some(block) = worklist.pop_front() {
+        queued[block] = false;
+        let bb = cfg.block(block);
+        match direction {
+            Direction::Forward => {
+                let mut fact = if block == cfg.entry() {
+                    analysis.boundary()
+                } else {
+                    analysis.bottom()
+                };
+                for pred in bb.preds.iter() {
+                    analysis.join(&mut fact, &block_exit[*pred]);
+                }
+                block_entry[block] = fact.clone();
+                for idx in bb.instrs() {
+                    analysis.transfer(idx, &body.instructions[idx], &mut fact);
+                }
+                if fact != block_exit[block] {
+                    block_exit[block] = fact;
+                    for succ in bb.succs.iter() {
+                        if !queued[*succ] {
+                            queued[*succ] = true;
+                            worklist.push_back(*succ);
+                        }
+                    }
+                }
+            }
+            Direction::Backward => {
+                let mut fact = if block == cfg.exit() {
+                    analysis.boundary()
+                } else {
+                    analysis.bottom()
+                };
+                for succ in bb.succs.iter() {
+                    analysis.join(&mut fact, &block_entry[*succ]);
+                }
+                block_exit[block] = fact.clone();
+                for idx in bb.instrs().rev() {
+                    analysis.transfer(idx, &body.instructions[idx], &mut fact);
+                }
+                if fact != block_entry[block] {
+                    block_entry[block] = fact;
+                    for pred in bb.preds.iter() {
+                        if !queued[*pred] {
+                            queued[*pred] = true;
+                            worklist.push_back(*pred);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    DataflowResults {
+        cfg,
+        direction,
+        block_entry,
+        block_exit,
+    }
+}
+
+impl<F: Clone> DataflowResults<F> {
+    /// The fact at the start of a basic block.
+    pub fn block_entry(&self, block: BlockIdx) -> &F {
+        &self.block_entry[block]
+    }
+
+    /// The fact at the end of a basic block.
+    pub fn block_exit(&self, block: BlockIdx) -> &F {
+        &self.block_exit[block]
+    }
+
+    /// The fact right before the instruction at `idx` (including any instrumentation injected there).
+    pub fn before<A>(&self, analysis: &A, body: &Body, idx: usize) -> F
+    where
+        A: DataflowAnalysis<Fact = F>,
+    {
+        self.at(analysis, body, idx, true)
+    }
+
+    /// The fact right after the instruction at `idx` (including any instrumentation injected there).
+    pub fn after<A>(&self, analysis: &A, body: &Body, idx: usize) -> F
+    where
+        A: DataflowAnalysis<Fact = F>,
+    {
+        self.at(analysis, body, idx, false)
+    }
+
+    /// Computes the facts before and after every instruction of `body`, indexed by instruction.
+    pub fn per_instruction<A>(&self, analysis: &A, body: &Body) -> (Vec<F>, Vec<F>)
+    where
+        A: DataflowAnalysis<Fact = F>,
+    {
+        let len = body.instructions.len();
+        let mut before: Vec<Option<F>> = vec![None; len];
+        let mut after: Vec<Option<F>> = vec![None; len];
+        for (block, bb) in self.cfg.blocks.iter().enumerate() {
+            match self.direction {
+                Direction::Forward => {
+                    let mut fact = self.block_entry[block].clone();
+                    for idx in bb.instrs() {
+                        before[idx] = Some(fact.clone());
+                        analysis.transfer(idx, &body.instructions[idx], &mut fact);
+                        after[idx] = Some(fact.clone());
+                    }
+                }
+                Direction::Backward => {
+                    let mut fact = self.block_exit[block].clone();
+                    for idx in bb.instrs().rev() {
+                        after[idx] = Some(fact.clone());
+                        analysis.transfer(idx, &body.instructions[idx], &mut fact);
+                        before[idx] = Some(fact.clone());
+                    }
+                }
+            }
+        }
+        (
+            before.into_iter().map(|fact| fact.unwrap()).collect(),
+            after.into_iter().map(|fact| fact.unwrap()).collect(),
+        )
+    }
+
+    fn at<A>(&self, analysis: &A, body: &Body, idx: usize, before: bool) -> F
+    where
+        A: DataflowAnalysis<Fact = F>,
+    {
+        let bb = self.cfg.block(self.cfg.block_of(idx));
+        match self.direction {
+            Direction::Forward => {
+                let mut fact = self.block_entry[self.cfg.block_of(idx)].clone();
+                let last = if before { idx } else { idx + 1 };
+                for i in bb.start..last {
+                    analysis.transfer(i, &body.instructions[i], &mut fact);
+                }
+                fact
+            }
+            Direction::Backward => {
+                let mut fact = self.block_exit[self.cfg.block_of(idx)].clone();
+                let first = if before { idx } else { idx + 1 };
+                for i in (first..bb.end).rev() {
+                    analysis.transfer(i, &body.instructions[i], &mut fact);
+                }
+                fact
+            }
+        }
+    }
+}
+
+/// The operators executed for an instruction once its instrumentation is encoded, in order.
+/// Returns `None` if one of the injected bodies contains control flow, in which case the injected
+/// code cannot be treated as straight-line code.
+pub(crate) fn straight_line_ops<'a, 'b>(
+    instr: &'b Instruction<'a>,
+) -> Option<Vec<&'b wasmparser::Operator<'a>>> {
+    let flag = &instr.instr_flag;
+    let mut ops: Vec<&wasmparser::Operator> = flag.before.iter().collect();
+    match &flag.alternate {
+        Some(alt) => ops.extend(alt.iter()),
+        None => ops.push(&instr.op),
+    }
+    ops.extend(flag.after.iter());
+    let injected = flag
+        .before
+        .iter()
+        .chain(flag.alternate.iter().flatten())
+        .chain(flag.after.iter());
+    for op in injected {
+        if is_control_op(op) {
+            return None;
+        }
+    }
+    Some(ops)
+}
@@ -0,0 +1,162 @@
+//! Dominator and post-dominator trees over the basic blocks of a [`ControlFlowGraph`].
+//!
+//! A block `a` dominates a block `b` if every path from the entry of the function to `b` goes through
+//! `a`. It post-dominates `b` if every path from `b` to the exit of the function goes through `a`.
+//! The trees are computed with the iterative algorithm of Cooper, Harvey and Kennedy
+//! ("A Simple, Fast Dominance Algorithm").
+
+use crate::analysis::cfg::{BlockIdx, ControlFlowGraph};
+
+/// A dominator (or post-dominator) tree of a control flow graph.
+#[derive(Clone, Debug)]
+pub struct DominatorTree {
+    root: BlockIdx,
+    /// The immediate dominator of every block, `None` for the root and for the blocks that are
+    /// not reachable from the root.
+    idom: Vec<Option<BlockIdx>>,
+    children: Vec<Vec<BlockIdx>>,
+    /// Pre-order and post-order numbers of the blocks in the tree, to answer dominance queries
+    pre: Vec<usize>,
+    post: Vec<usize>,
+}
+
+impl DominatorTree {
+    /// Computes the dominator tree of `cfg`, rooted at its entry block.
+    /// Blocks that are unreachable from the entry are not part of the tree.
+    pub fn dominators(cfg: &ControlFlowGraph) -> Self {
+        Self::compute(cfg.len(), cfg.reverse_post_order(), |block| {
+            &cfg.block(block).preds
+        })
+    }
+
+    /// Computes the post-dominator tree of `cfg`, rooted at its exit block.
+    /// Blocks that cannot reach the exit (e.g. infinite loops) are not part of the tree.
+    pub fn post_dominators(cfg: &ControlFlowGraph) -> Self {
+        Self::compute(cfg.len(), cfg.reverse_post_order_backward(), |block| {
+            &cfg.block(block).succs
+        })
+    }
+
+    /// `order` is a reverse post-order of the graph starting from the root, `preds` gives the
+    /// predecessors of a block in the direction of the analysis.
+    fn compute<'a, F>(num_blocks: usize, order: Vec<BlockIdx>, preds: F) -> Self
+    where
+        F: Fn(BlockIdx) -> &'a Vec<BlockIdx>,
+    {
+        let root = order[0];
+        let mut order_num = vec![usize::MAX; num_blocks];
+        for (num, block) in order.iter().enumerate() {
+            order_num[*block] = num;
+        }
+        let mut idom: Vec<Option<BlockIdx>> = vec![None; num_blocks];
+        idom[root] = Some(root);
+
+        let intersect = |idom: &Vec<Option<BlockIdx>>, mut a: BlockIdx, mut b: BlockIdx| {
+            while a != b {
+                while order_num[a] > order_num[b] {
+                    a = idom[a].unwrap();
+                }
+                while order_num[b] > order_num[a] {
+                    b = idom[b].unwrap();
+                }
+            }
+            a
+        };
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in order.iter().skip(1) {
+                let mut new_idom = None;
+                for pred in preds(*block).iter() {
+                    if idom[*pred].is_none() {
+                        // not processed yet
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        None => *pred,
+                        Some(other) => intersect(&idom, *pred, other),
+                    });
+                }
+                if new_idom.is_some() && idom[*block] != new_idom {
+                    idom[*block] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+        idom[root] = None;
+
+        let mut children = vec![vec![]; num_blocks];
+        for (block, parent) in idom.iter().enumerate() {
+            if let Some(parent) = parent {
+                children[*parent].push(block);
+            }
+        }
+        let mut tree = DominatorTree {
+            root,
+            idom,
+            children,
+            pre: vec![usize::MAX; num_blocks],
+            post: vec![usize::MAX; num_blocks],
+        };
+        tree.number();
+        tree
+    }
+
+    fn number(&mut self) {
+        let (mut pre, mut post) = (0, 0);
+        // (block, index of the next child to visit)
+        let mut stack = vec![(self.root, 0)];
+        self.pre[self.root] = pre;
+        pre += 1;
+        while let Some((block, next)) = stack.last_mut() {
+            if let Some(child) = self.children[*block].get(*next).copied() {
+                *next += 1;
+                self.pre[child] = pre;
+                pre += 1;
+                stack.push((child, 0));
+            } else {
+                self.post[*block] = post;
+                post += 1;
+                stack.pop();
+            }
+        }
+    }
+
+    /// The root of the tree: the entry block for dominators, the exit block for post-dominators.
+    pub fn root(&self) -> BlockIdx {
+        self.root
+    }
+
+    /// Whether `block` is part of the tree.
+    pub fn contains(&self, block: BlockIdx) -> bool {
+        self.pre[block] != usize::MAX
+    }
+
+    /// The immediate dominator of `block`, `None` for the root and the blocks outside of the tree.
+    pub fn idom(&self, block: BlockIdx) -> Option<BlockIdx> {
+        self.idom[block]
+    }
+
+    /// The blocks immediately dominated by `block`, in program order.
+    pub fn children(&self, block: BlockIdx) -> &[BlockIdx] {
+        &self.children[block]
+    }
+
+    /// Whether `a` dominates `b`. Every block of the tree dominates itself.
+    pub fn dominates(&self, a: BlockIdx, b: BlockIdx) -> bool {
+        self.contains(a)
+            && self.contains(b)
+            && self.pre[a] <= self.pre[b]
+            && self.post[b] <= self.post[a]
+    }
+
+    /// Whether `a` dominates `b` and is not `b`.
+    pub fn strictly_dominates(&self, a: BlockIdx, b: BlockIdx) -> bool {
+        a != b && self.dominates(a, b)
+    }
+
+    /// The dominators of `block`, from its immediate dominator up to the root.
+    pub fn dominators_of(&self, block: BlockIdx) -> impl Iterator<Item = BlockIdx> + '_ {
+        std::iter::successors(self.idom[block], |block| self.idom[*block])
+    }
+}
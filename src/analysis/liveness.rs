@@ -0,0 +1,188 @@
+//! Live locals analysis.
+//!
+//! A local is live at a program point if its current value may be read before it is overwritten.
+//! Injected `before`/`after`/`alternate` instrumentation is taken into account: straight-line injected
+//! code is analysed precisely, injected code that contains control flow only contributes its reads.
+//! Locals touched by instrumentation whose position is only decided on encode (semantic after, block
+//! entry/exit/alt and function entry/exit) are treated as live everywhere.
+
+use crate::analysis::bitset::BitSet;
+use crate::analysis::dataflow::{solve, straight_line_ops, DataflowAnalysis, Direction};
+use crate::analysis::{all_ops, declared_local_types, local_access, special_ops, LocalAccess};
+use crate::ir::id::LocalID;
+use crate::ir::module::module_functions::LocalFunction;
+use crate::ir::types::{Body, DataType, FuncInstrFlag, Instruction};
+use wasmparser::Operator;
+
+/// The live locals dataflow problem, a backward analysis over sets of local indices.
+#[derive(Clone, Debug, Default)]
+pub struct LiveLocals {
+    num_locals: usize,
+}
+
+impl LiveLocals {
+    /// Creates the analysis for a function with `num_locals` locals, parameters included.
+    pub fn new(num_locals: usize) -> Self {
+        LiveLocals { num_locals }
+    }
+}
+
+fn apply(op: &Operator, live: &mut BitSet) {
+    match local_access(op) {
+        Some((local, LocalAccess::Get)) => {
+            live.insert(local as usize);
+        }
+        Some((local, LocalAccess::Set | LocalAccess::Tee)) => {
+            live.remove(local as usize);
+        }
+        None => {}
+    }
+}
+
+impl DataflowAnalysis for LiveLocals {
+    type Fact = BitSet;
+
+    fn direction(&self) -> Direction {
+        Direction::Backward
+    }
+
+    fn bottom(&self) -> BitSet {
+        BitSet::with_capacity(self.num_locals)
+    }
+
+    fn boundary(&self) -> BitSet {
+        // nothing is read once the function returns
+        BitSet::with_capacity(self.num_locals)
+    }
+
+    fn join(&self, fact: &mut BitSet, other: &BitSet) {
+        fact.union_with(other);
+    }
+
+    fn transfer(&self, _idx: usize, instr: &Instruction, live: &mut BitSet) {
+        match straight_line_ops(instr) {
+            Some(ops) => {
+                for op in ops.iter().rev() {
+                    apply(op, live);
+                }
+            }
+            None => {
+                // the injected control flow may skip any of the writes, only keep the reads
+                for op in all_ops(instr) {
+                    if let Some((local, LocalAccess::Get)) = local_access(op) {
+                        live.insert(local as usize);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The live locals of a function, computed for every instruction.
+#[derive(Clone, Debug)]
+pub struct Liveness {
+    num_params: usize,
+    local_types: Vec<DataType>,
+    /// Locals that must be considered live at every program point
+    pinned: BitSet,
+    live_before: Vec<BitSet>,
+    live_after: Vec<BitSet>,
+}
+
+impl Liveness {
+    /// Computes the live locals of a function body with `num_params` parameters.
+    pub fn new(body: &Body, num_params: usize) -> Self {
+        let local_types = declared_local_types(body);
+        let analysis = LiveLocals::new(num_params + local_types.len());
+        let results = solve(&analysis, body);
+        let (live_before, live_after) = results.per_instruction(&analysis, body);
+
+        let mut pinned = BitSet::with_capacity(num_params + local_types.len());
+        for instr in body.instructions.iter() {
+            for op in special_ops(instr) {
+                if let Some((local, _)) = local_access(op) {
+                    pinned.insert(local as usize);
+                }
+            }
+        }
+        Liveness {
+            num_params,
+            local_types,
+            pinned,
+            live_before,
+            live_after,
+        }
+    }
+
+    /// Computes the live locals of a local function, including its function entry/exit instrumentation.
+    pub fn for_function(func: &LocalFunction) -> Self {
+        Self::with_func_instr(&func.body, func.args.len(), &func.instr_flag)
+    }
+
+    /// Computes the live locals of `body`, with the locals used by the function entry/exit
+    /// instrumentation `instr_flag` live everywhere.
+    pub fn with_func_instr(body: &Body, num_params: usize, instr_flag: &FuncInstrFlag) -> Self {
+        let mut liveness = Self::new(body, num_params);
+        for op in instr_flag.entry.iter().chain(instr_flag.exit.iter()) {
+            if let Some((local, _)) = local_access(op) {
+                liveness.pin(LocalID(local));
+            }
+        }
+        for local in instr_flag.exit_results.iter() {
+            liveness.pin(*local);
+        }
+        liveness
+    }
+
+    /// Treats `local` as live everywhere, e.g. because code that is not part of the body reads it.
+    pub fn pin(&mut self, local: LocalID) {
+        self.pinned.insert(*local as usize);
+    }
+
+    /// The locals that are live right before the instruction at `idx` (and its injected code).
+    pub fn live_before(&self, idx: usize) -> BitSet {
+        let mut live = self.live_before[idx].clone();
+        live.union_with(&self.pinned);
+        live
+    }
+
+    /// The locals that are live right after the instruction at `idx` (and its injected code).
+    pub fn live_after(&self, idx: usize) -> BitSet {
+        let mut live = self.live_after[idx].clone();
+        live.union_with(&self.pinned);
+        live
+    }
+
+    /// Checks if `local` may be read after the program point right before the instruction at `idx`.
+    pub fn is_live_before(&self, idx: usize, local: LocalID) -> bool {
+        self.pinned.contains(*local as usize) || self.live_before[idx].contains(*local as usize)
+    }
+
+    /// Checks if `local` may be read after the program point right after the instruction at `idx`.
+    pub fn is_live_after(&self, idx: usize, local: LocalID) -> bool {
+        self.pinned.contains(*local as usize) || self.live_after[idx].contains(*local as usize)
+    }
+
+    /// The declared locals (parameters excluded) of type `ty` that are dead across the instruction at
+    /// `idx`: their value is not needed before nor after it, and neither the instruction nor its
+    /// injected code refers to them. Code injected at `idx` may freely use them as temporaries.
+    pub fn dead_locals_at(&self, idx: usize, ty: DataType, body: &Body) -> Vec<LocalID> {
+        let mut referenced = BitSet::default();
+        for op in all_ops(&body.instructions[idx]) {
+            if let Some((local, _)) = local_access(op) {
+                referenced.insert(local as usize);
+            }
+        }
+        self.local_types
+            .iter()
+            .enumerate()
+            .filter(|(_, local_ty)| **local_ty == ty)
+            .map(|(offset, _)| LocalID((self.num_params + offset) as u32))
+            .filter(|local| {
+                !referenced.contains(**local as usize)
+                    && !self.is_live_before(idx, *local)
+                    && !self.is_live_after(idx, *local)
+            })
+            .collect()
+    }
+}
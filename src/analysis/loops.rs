@@ -0,0 +1,224 @@
+//! Loop-nest tree of a function body.
+//!
+//! Wasm control flow is structured, so every loop is a `loop` ... `end` range of instructions and the
+//! loops of a function form a tree that follows their lexical nesting. For every loop this module
+//! records its header, the branches that jump back to it (back-edges) and the instructions that leave
+//! it (exits). Only the original instructions of the body are considered, injected instrumentation is
+//! ignored. Traps and exceptions are not counted as exits.
+
+use crate::ir::types::Body;
+use wasmparser::{Catch, Operator};
+
+/// Index of a loop in a [`LoopNest`].
+pub type LoopIdx = usize;
+
+/// A single `loop` of a function body.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Loop {
+    /// Index of the `loop` instruction.
+    pub header: usize,
+    /// Index of the `end` closing the loop.
+    pub end: usize,
+    /// The innermost loop containing this one, `None` for an outermost loop.
+    pub parent: Option<LoopIdx>,
+    /// The loops directly nested in this one.
+    pub children: Vec<LoopIdx>,
+    /// Nesting depth of the loop, outermost loops have depth 1.
+    pub depth: u32,
+    /// Instructions branching back to the header of the loop (`br`, `br_if`, `br_table`, ...).
+    pub back_edges: Vec<usize>,
+    /// Instructions transferring control out of the loop: branches to an enclosing label,
+    /// `return`s and the `end` of the loop itself (falling through).
+    pub exits: Vec<usize>,
+}
+
+impl Loop {
+    /// The range of instruction indices covered by the loop, from its header to its `end`.
+    pub fn instrs(&self) -> std::ops::RangeInclusive<usize> {
+        self.header..=self.end
+    }
+
+    /// Whether the instruction at `instr_idx` is part of the loop (including nested loops).
+    pub fn contains(&self, instr_idx: usize) -> bool {
+        self.instrs().contains(&instr_idx)
+    }
+}
+
+/// The loop-nest tree of a single function.
+#[derive(Clone, Debug, Default)]
+pub struct LoopNest {
+    /// The loops, in the order of their headers.
+    pub loops: Vec<Loop>,
+    /// Maps an instruction index to the innermost loop containing it.
+    instr_to_loop: Vec<Option<LoopIdx>>,
+}
+
+/// An open block while walking the body: the loop it is, if any
+type Frame = Option<LoopIdx>;
+
+impl LoopNest {
+    /// Builds the loop-nest tree of `body`.
+    pub fn new(body: &Body) -> Self {
+        let mut nest = LoopNest {
+            loops: vec![],
+            instr_to_loop: Vec::with_capacity(body.instructions.len()),
+        };
+        // the function itself is the outermost frame
+        let mut stack: Vec<Frame> = vec![None];
+        for (idx, instr) in body.instructions.iter().enumerate() {
+            match &instr.op {
+                Operator::Loop { .. } => {
+                    let parent = stack.iter().rev().find_map(|frame| *frame);
+                    let loop_idx = nest.loops.len();
+                    nest.loops.push(Loop {
+                        header: idx,
+                        end: idx,
+                        parent,
+                        children: vec![],
+                        depth: parent.map_or(1, |parent| nest.loops[parent].depth + 1),
+                        back_edges: vec![],
+                        exits: vec![],
+                    });
+                    if let Some(parent) = parent {
+                        nest.loops[parent].children.push(loop_idx);
+                    }
+                    stack.push(Some(loop_idx));
+                }
+                Operator::Block { .. } | Operator::If { .. } | Operator::Try { .. } => {
+                    stack.push(None);
+                }
+                Operator::TryTable { try_table } => {
+                    // the labels of the catch clauses are resolved outside of the `try_table`
+                    for catch in try_table.catches.iter() {
+                        match catch {
+                            Catch::One { label, .. }
+                            | Catch::OneRef { label, .. }
+                            | Catch::All { label }
+                            | Catch::AllRef { label } => {
+                                nest.branch(&stack, *label, idx);
+                            }
+                        }
+                    }
+                    stack.push(None);
+                }
+                Operator::Br { relative_depth }
+                | Operator::BrIf { relative_depth }
+                | Operator::BrOnNull { relative_depth }
+                | Operator::BrOnNonNull { relative_depth }
+                | Operator::BrOnCast { relative_depth, .. }
+                | Operator::BrOnCastFail { relative_depth, .. } => {
+                    nest.branch(&stack, *relative_depth, idx);
+                }
+                Operator::BrTable { targets } => {
+                    for target in targets.targets().flatten().chain([targets.default()]) {
+                        nest.branch(&stack, target, idx);
+                    }
+                }
+                Operator::Return
+                | Operator::ReturnCall { .. }
+                | Operator::ReturnCallIndirect { .. }
+                | Operator::ReturnCallRef { .. } => {
+                    nest.leave(&stack[1..], idx);
+                }
+                _ => {}
+            }
+            nest.instr_to_loop
+                .push(stack.iter().rev().find_map(|frame| *frame));
+            match &instr.op {
+                Operator::End | Operator::Delegate { .. } => {
+                    if let Some(Some(loop_idx)) = stack.pop() {
+                        nest.loops[loop_idx].end = idx;
+                        add_once(&mut nest.loops[loop_idx].exits, idx);
+                    }
+                }
+                _ => {}
+            }
+        }
+        nest
+    }
+
+    /// Records a branch from `idx` to the label `relative_depth`.
+    fn branch(&mut self, stack: &[Frame], relative_depth: u32, idx: usize) {
+        let Some(target) = stack.len().checked_sub(relative_depth as usize + 1) else {
+            return;
+        };
+        if let Some(loop_idx) = stack[target] {
+            add_once(&mut self.loops[loop_idx].back_edges, idx);
+        }
+        // every loop nested in the target is left
+        self.leave(&stack[target + 1..], idx);
+    }
+
+    /// Records `idx` as an exit of every loop in `frames`.
+    fn leave(&mut self, frames: &[Frame], idx: usize) {
+        for loop_idx in frames.iter().flatten() {
+            add_once(&mut self.loops[*loop_idx].exits, idx);
+        }
+    }
+
+    /// The number of loops in the function.
+    pub fn len(&self) -> usize {
+        self.loops.len()
+    }
+
+    /// Whether the function has no loops.
+    pub fn is_empty(&self) -> bool {
+        self.loops.is_empty()
+    }
+
+    /// Returns the loop at `loop_idx`.
+    pub fn get(&self, loop_idx: LoopIdx) -> &Loop {
+        &self.loops[loop_idx]
+    }
+
+    /// The outermost loops of the function.
+    pub fn roots(&self) -> impl Iterator<Item = LoopIdx> + '_ {
+        self.loops
+            .iter()
+            .enumerate()
+            .filter(|(_, l)| l.parent.is_none())
+            .map(|(idx, _)| idx)
+    }
+
+    /// The innermost loop containing the instruction at `instr_idx`, if any.
+    pub fn innermost_loop(&self, instr_idx: usize) -> Option<LoopIdx> {
+        self.instr_to_loop.get(instr_idx).copied().flatten()
+    }
+
+    /// The loop whose header is the instruction at `instr_idx`, if any.
+    pub fn loop_with_header(&self, instr_idx: usize) -> Option<LoopIdx> {
+        self.innermost_loop(instr_idx)
+            .filter(|loop_idx| self.loops[*loop_idx].header == instr_idx)
+    }
+
+    /// The number of loops containing the instruction at `instr_idx`.
+    pub fn depth(&self, instr_idx: usize) -> u32 {
+        self.innermost_loop(instr_idx)
+            .map_or(0, |loop_idx| self.loops[loop_idx].depth)
+    }
+
+    /// Whether the instruction at `instr_idx` may branch back to the header of a loop.
+    pub fn is_back_edge(&self, instr_idx: usize) -> bool {
+        self.enclosing_loops(instr_idx)
+            .any(|loop_idx| self.loops[loop_idx].back_edges.contains(&instr_idx))
+    }
+
+    /// Whether the instruction at `instr_idx` may leave a loop.
+    pub fn is_exit(&self, instr_idx: usize) -> bool {
+        self.enclosing_loops(instr_idx)
+            .any(|loop_idx| self.loops[loop_idx].exits.contains(&instr_idx))
+    }
+
+    /// The loops containing the instruction at `instr_idx`, from the innermost to the outermost.
+    pub fn enclosing_loops(&self, instr_idx: usize) -> impl Iterator<Item = LoopIdx> + '_ {
+        std::iter::successors(self.innermost_loop(instr_idx), |loop_idx| {
+            self.loops[*loop_idx].parent
+        })
+    }
+}
+
+fn add_once(instrs: &mut Vec<usize>, idx: usize) {
+    if instrs.last() != Some(&idx) {
+        instrs.push(idx);
+    }
+}
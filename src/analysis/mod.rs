@@ -0,0 +1,81 @@
+//! Static analyses over the instructions of a function body.
+//!
+//! The [`dataflow`] module provides a generic forward/backward solver over the [`cfg::ControlFlowGraph`]
+//! of a [`Body`]. [`liveness`] and [`reaching_definitions`] are built on top of it and take the
+//! instrumentation that has been injected so far into account. [`loops`] builds the loop-nest tree of
+//! a body from its structured control flow. [`dominators`] computes the dominator and post-dominator
+//! trees of the graph. [`counter_placement`] places as few coverage probes as possible, solving the
+//! count of the other blocks by flow conservation. [`usage`] summarizes the globals and memories
+//! every function of a module accesses.
+
+use crate::ir::types::{Body, Instruction};
+use wasmparser::Operator;
+
+pub mod bitset;
+pub mod cfg;
+pub mod counter_placement;
+pub mod dataflow;
+pub mod dominators;
+pub mod liveness;
+pub mod loops;
+pub mod reaching_definitions;
+pub mod usage;
+
+/// How an instruction accesses a local.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum LocalAccess {
+    Get,
+    Set,
+    Tee,
+}
+
+pub(crate) fn local_access(op: &Operator) -> Option<(u32, LocalAccess)> {
+    match op {
+        Operator::LocalGet { local_index } => Some((*local_index, LocalAccess::Get)),
+        Operator::LocalSet { local_index } => Some((*local_index, LocalAccess::Set)),
+        Operator::LocalTee { local_index } => Some((*local_index, LocalAccess::Tee)),
+        _ => None,
+    }
+}
+
+/// Every operator attached to an instruction: the original one and all injected instrumentation,
+/// including the special modes that are only resolved on encode.
+pub(crate) fn all_ops<'a, 'b>(
+    instr: &'b Instruction<'a>,
+) -> impl Iterator<Item = &'b Operator<'a>> {
+    std::iter::once(&instr.op)
+        .chain(injected_ops(instr))
+        .chain(special_ops(instr))
+}
+
+/// The operators injected `before`, `after` and as `alternate` of an instruction.
+pub(crate) fn injected_ops<'a, 'b>(
+    instr: &'b Instruction<'a>,
+) -> impl Iterator<Item = &'b Operator<'a>> {
+    let flag = &instr.instr_flag;
+    flag.before
+        .iter()
+        .chain(flag.after.iter())
+        .chain(flag.alternate.iter().flatten())
+}
+
+/// The operators injected with a special instrumentation mode (semantic after, block entry/exit/alt).
+/// Their final position in the function is only known once they are resolved on encode.
+pub(crate) fn special_ops<'a, 'b>(
+    instr: &'b Instruction<'a>,
+) -> impl Iterator<Item = &'b Operator<'a>> {
+    let flag = &instr.instr_flag;
+    flag.semantic_after
+        .iter()
+        .chain(flag.block_entry.iter())
+        .chain(flag.block_exit.iter())
+        .chain(flag.block_alt.iter().flatten())
+}
+
+/// The type of every local of `body` (parameters excluded), indexed by `local_index - num_params`.
+pub(crate) fn declared_local_types(body: &Body) -> Vec<crate::ir::types::DataType> {
+    body.locals
+        .iter()
+        .flat_map(|(count, ty)| std::iter::repeat_n(*ty, *count as usize))
+        .collect()
+}
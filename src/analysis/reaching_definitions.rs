@@ -0,0 +1,203 @@
+//! Reaching definitions of locals.
+//!
+//! A definition of a local is a `local.set`/`local.tee`, or the value a local has on function entry
+//! (the argument for parameters, the default value for declared locals). A definition reaches a
+//! program point if there is a path from it to that point that does not overwrite the local.
+//!
+//! Writes in straight-line injected `before`/`after`/`alternate` code are analysed precisely. Writes
+//! in injected code that contains control flow, or in instrumentation that is only placed on encode
+//! (semantic after, block entry/exit/alt), are "may" definitions: they never kill other definitions.
+
+use crate::analysis::bitset::BitSet;
+use crate::analysis::dataflow::{
+    solve, straight_line_ops, DataflowAnalysis, DataflowResults, Direction,
+};
+use crate::analysis::{declared_local_types, injected_ops, local_access, special_ops, LocalAccess};
+use crate::ir::id::LocalID;
+use crate::ir::types::{Body, Instruction};
+
+/// Where a definition happens.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum DefinitionSite {
+    /// The value of the local on function entry.
+    Entry,
+    /// The instruction at this index of the body writes the local.
+    Instr(usize),
+    /// Instrumentation injected at the instruction at this index writes the local.
+    Injected(usize),
+}
+
+/// A single definition of a local.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Definition {
+    pub local: LocalID,
+    pub site: DefinitionSite,
+}
+
+/// The reaching definitions dataflow problem, a forward analysis over sets of definition ids.
+#[derive(Clone, Debug)]
+pub struct ReachingDefinitionsAnalysis {
+    defs: Vec<Definition>,
+    /// For each instruction, the definitions it makes in execution order and whether they kill
+    defs_at: Vec<Vec<(usize, bool)>>,
+    /// For each local, all of its definitions
+    defs_of_local: Vec<BitSet>,
+}
+
+impl ReachingDefinitionsAnalysis {
+    /// Collects the definitions of a function body with `num_params` parameters.
+    pub fn new(body: &Body, num_params: usize) -> Self {
+        let num_locals = num_params + declared_local_types(body).len();
+        let mut analysis = ReachingDefinitionsAnalysis {
+            defs: Vec::with_capacity(num_locals),
+            defs_at: Vec::with_capacity(body.instructions.len()),
+            defs_of_local: vec![BitSet::default(); num_locals],
+        };
+        for local in 0..num_locals {
+            analysis.add_def(LocalID(local as u32), DefinitionSite::Entry);
+        }
+        for (idx, instr) in body.instructions.iter().enumerate() {
+            let mut defs = vec![];
+            match straight_line_ops(instr) {
+                Some(ops) => {
+                    for op in ops {
+                        if let Some((local, LocalAccess::Set | LocalAccess::Tee)) = local_access(op)
+                        {
+                            let site = if std::ptr::eq(op, &instr.op) {
+                                DefinitionSite::Instr(idx)
+                            } else {
+                                DefinitionSite::Injected(idx)
+                            };
+                            defs.push((analysis.add_def(LocalID(local), site), true));
+                        }
+                    }
+                }
+                None => {
+                    for op in std::iter::once(&instr.op).chain(injected_ops(instr)) {
+                        if let Some((local, LocalAccess::Set | LocalAccess::Tee)) = local_access(op)
+                        {
+                            let site = if std::ptr::eq(op, &instr.op) {
+                                DefinitionSite::Instr(idx)
+                            } else {
+                                DefinitionSite::Injected(idx)
+                            };
+                            defs.push((analysis.add_def(LocalID(local), site), false));
+                        }
+                    }
+                }
+            }
+            for op in special_ops(instr) {
+                if let Some((local, LocalAccess::Set | LocalAccess::Tee)) = local_access(op) {
+                    let def = analysis.add_def(LocalID(local), DefinitionSite::Injected(idx));
+                    defs.push((def, false));
+                }
+            }
+            analysis.defs_at.push(defs);
+        }
+        analysis
+    }
+
+    fn add_def(&mut self, local: LocalID, site: DefinitionSite) -> usize {
+        let id = self.defs.len();
+        self.defs.push(Definition { local, site });
+        if *local as usize >= self.defs_of_local.len() {
+            // the body refers to a local that is not declared, still track it
+            self.defs_of_local
+                .resize(*local as usize + 1, BitSet::default());
+        }
+        self.defs_of_local[*local as usize].insert(id);
+        id
+    }
+}
+
+impl DataflowAnalysis for ReachingDefinitionsAnalysis {
+    type Fact = BitSet;
+
+    fn direction(&self) -> Direction {
+        Direction::Forward
+    }
+
+    fn bottom(&self) -> BitSet {
+        BitSet::with_capacity(self.defs.len())
+    }
+
+    fn boundary(&self) -> BitSet {
+        self.defs
+            .iter()
+            .enumerate()
+            .filter(|(_, def)| def.site == DefinitionSite::Entry)
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    fn join(&self, fact: &mut BitSet, other: &BitSet) {
+        fact.union_with(other);
+    }
+
+    fn transfer(&self, idx: usize, _instr: &Instruction, reaching: &mut BitSet) {
+        for (def, kills) in self.defs_at[idx].iter() {
+            if *kills {
+                for other in self.defs_of_local[*self.defs[*def].local as usize].iter() {
+                    reaching.remove(other);
+                }
+            }
+            reaching.insert(*def);
+        }
+    }
+}
+
+/// The reaching definitions of a function.
+#[derive(Clone, Debug)]
+pub struct ReachingDefinitions {
+    analysis: ReachingDefinitionsAnalysis,
+    results: DataflowResults<BitSet>,
+}
+
+impl ReachingDefinitions {
+    /// Computes the reaching definitions of a function body with `num_params` parameters.
+    pub fn new(body: &Body, num_params: usize) -> Self {
+        let analysis = ReachingDefinitionsAnalysis::new(body, num_params);
+        let results = solve(&analysis, body);
+        ReachingDefinitions { analysis, results }
+    }
+
+    /// All definitions of the function, indexed by definition id.
+    pub fn definitions(&self) -> &[Definition] {
+        &self.analysis.defs
+    }
+
+    /// The definitions of `local` that reach the program point right before the instruction at `idx`
+    /// (and its injected code). `body` must be the body the analysis was computed for.
+    pub fn reaching_before(&self, body: &Body, idx: usize, local: LocalID) -> Vec<Definition> {
+        let fact = self.results.before(&self.analysis, body, idx);
+        self.of_local(&fact, local)
+    }
+
+    /// The definitions of `local` that reach the program point right after the instruction at `idx`
+    /// (and its injected code). `body` must be the body the analysis was computed for.
+    pub fn reaching_after(&self, body: &Body, idx: usize, local: LocalID) -> Vec<Definition> {
+        let fact = self.results.after(&self.analysis, body, idx);
+        self.of_local(&fact, local)
+    }
+
+    /// The definition a `local.get` of `local` right before the instruction at `idx` is guaranteed
+    /// to observe, if exactly one definition reaches it.
+    pub fn unique_reaching_before(
+        &self,
+        body: &Body,
+        idx: usize,
+        local: LocalID,
+    ) -> Option<Definition> {
+        match self.reaching_before(body, idx, local).as_slice() {
+            [def] => Some(*def),
+            _ => None,
+        }
+    }
+
+    fn of_local(&self, fact: &BitSet, local: LocalID) -> Vec<Definition> {
+        fact.iter()
+            .map(|id| self.analysis.defs[id])
+            .filter(|def| def.local == local)
+            .collect()
+    }
+}
@@ -0,0 +1,318 @@
+//! Summary of the module state (globals and memories) that every function touches.
+//!
+//! For each local function, the globals it reads and writes, the memories it loads from, stores to
+//! and grows, and whether it calls imported functions. The original code of the function and the
+//! instrumentation injected into it are summarized separately, which allows checking that probes
+//! do not touch the state of the application.
+
+use crate::analysis::{injected_ops, special_ops};
+use crate::ir::id::{FunctionID, GlobalID, MemoryID};
+use crate::ir::module::module_functions::{FuncKind, Functions, LocalFunction};
+use crate::ir::module::Module;
+use crate::ir::wrappers::{refers_to_global, refers_to_memory, visit_memory_indices};
+use std::collections::{BTreeSet, HashMap};
+use wasmparser::Operator;
+
+/// The globals and memories accessed by a sequence of instructions.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct StateUsage {
+    /// Globals read with `global.get` (or an atomic read).
+    pub globals_read: BTreeSet<GlobalID>,
+    /// Globals written with `global.set` (or an atomic write).
+    pub globals_written: BTreeSet<GlobalID>,
+    /// Memories loaded from (including `memory.size` and the source of `memory.copy`).
+    pub memories_loaded: BTreeSet<MemoryID>,
+    /// Memories stored to (including `memory.fill`, `memory.init` and atomic read-modify-writes).
+    pub memories_stored: BTreeSet<MemoryID>,
+    /// Memories grown with `memory.grow`.
+    pub memories_grown: BTreeSet<MemoryID>,
+    /// Whether there is a direct call to an imported function.
+    pub calls_imports: bool,
+    /// Whether there is a `call_indirect` or `call_ref`, whose callee is unknown.
+    pub calls_indirect: bool,
+}
+
+impl StateUsage {
+    /// Whether no state is accessed and no unknown code is called.
+    pub fn is_empty(&self) -> bool {
+        *self == StateUsage::default()
+    }
+
+    /// Whether the code may change the state of the module or of the host: it writes a global,
+    /// stores to or grows a memory, or calls an import or an unknown function.
+    pub fn has_side_effects(&self) -> bool {
+        !self.globals_written.is_empty()
+            || !self.memories_stored.is_empty()
+            || !self.memories_grown.is_empty()
+            || self.calls_imports
+            || self.calls_indirect
+    }
+
+    /// Adds the accesses of `other` to this summary.
+    pub fn union_with(&mut self, other: &StateUsage) {
+        self.globals_read.extend(other.globals_read.iter());
+        self.globals_written.extend(other.globals_written.iter());
+        self.memories_loaded.extend(other.memories_loaded.iter());
+        self.memories_stored.extend(other.memories_stored.iter());
+        self.memories_grown.extend(other.memories_grown.iter());
+        self.calls_imports |= other.calls_imports;
+        self.calls_indirect |= other.calls_indirect;
+    }
+
+    fn record(&mut self, op: &Operator, functions: &Functions) {
+        if refers_to_global(op) {
+            self.record_global(op);
+        }
+        if refers_to_memory(op) {
+            self.record_memory(op);
+        }
+        match op {
+            Operator::Call { function_index } | Operator::ReturnCall { function_index }
+                if functions.is_import(FunctionID(*function_index)) =>
+            {
+                self.calls_imports = true;
+            }
+            Operator::CallIndirect { .. }
+            | Operator::ReturnCallIndirect { .. }
+            | Operator::CallRef { .. }
+            | Operator::ReturnCallRef { .. } => self.calls_indirect = true,
+            _ => {}
+        }
+    }
+
+    fn record_global(&mut self, op: &Operator) {
+        match op {
+            Operator::GlobalGet { global_index }
+            | Operator::GlobalAtomicGet { global_index, .. } => {
+                self.globals_read.insert(GlobalID(*global_index));
+            }
+            Operator::GlobalSet { global_index }
+            | Operator::GlobalAtomicSet { global_index, .. } => {
+                self.globals_written.insert(GlobalID(*global_index));
+            }
+            Operator::GlobalAtomicRmwAdd { global_index, .. }
+            | Operator::GlobalAtomicRmwSub { global_index, .. }
+            | Operator::GlobalAtomicRmwAnd { global_index, .. }
+            | Operator::GlobalAtomicRmwOr { global_index, .. }
+            | Operator::GlobalAtomicRmwXor { global_index, .. }
+            | Operator::GlobalAtomicRmwXchg { global_index, .. }
+            | Operator::GlobalAtomicRmwCmpxchg { global_index, .. } => {
+                self.globals_read.insert(GlobalID(*global_index));
+                self.globals_written.insert(GlobalID(*global_index));
+            }
+            _ => {}
+        }
+    }
+
+    fn record_memory(&mut self, op: &Operator) {
+        let mut indices = vec![];
+        visit_memory_indices(&mut op.clone(), |mem| indices.push(MemoryID(*mem)));
+        match op {
+            Operator::MemoryGrow { .. } => {
+                self.memories_grown.extend(indices);
+            }
+            Operator::MemoryCopy { .. } => {
+                // source, then destination
+                self.memories_loaded.insert(indices[0]);
+                self.memories_stored.insert(indices[1]);
+            }
+            _ if is_read_modify_write(op) => {
+                self.memories_loaded.extend(indices.iter());
+                self.memories_stored.extend(indices);
+            }
+            _ if is_memory_write(op) => {
+                self.memories_stored.extend(indices);
+            }
+            _ => {
+                self.memories_loaded.extend(indices);
+            }
+        }
+    }
+}
+
+/// The state accessed by a local function.
+#[derive(Clone, Debug, Default)]
+pub struct FunctionUsage {
+    /// Accesses of the original code of the function.
+    pub original: StateUsage,
+    /// Accesses of the instrumentation injected into the function.
+    pub injected: StateUsage,
+    /// Functions called directly by the original code.
+    pub callees: BTreeSet<FunctionID>,
+}
+
+impl FunctionUsage {
+    /// Summarizes the accesses of `func`. `functions` tells which callees are imported.
+    pub fn new(func: &LocalFunction, functions: &Functions) -> Self {
+        let mut usage = FunctionUsage::default();
+        for instr in func.body.instructions.iter() {
+            usage.original.record(&instr.op, functions);
+            if let Operator::Call { function_index } | Operator::ReturnCall { function_index } =
+                instr.op
+            {
+                usage.callees.insert(FunctionID(function_index));
+            }
+            for op in injected_ops(instr).chain(special_ops(instr)) {
+                usage.injected.record(op, functions);
+            }
+        }
+        for op in func
+            .instr_flag
+            .entry
+            .iter()
+            .chain(func.instr_flag.exit.iter())
+        {
+            usage.injected.record(op, functions);
+        }
+        usage
+    }
+
+    /// Accesses of the function once instrumented.
+    pub fn combined(&self) -> StateUsage {
+        let mut usage = self.original.clone();
+        usage.union_with(&self.injected);
+        usage
+    }
+}
+
+/// The state accessed by every local function of a module.
+#[derive(Clone, Debug, Default)]
+pub struct ModuleUsage {
+    functions: HashMap<FunctionID, FunctionUsage>,
+}
+
+impl ModuleUsage {
+    /// Summarizes every local function of `module`.
+    pub fn new(module: &Module) -> Self {
+        let mut functions = HashMap::new();
+        for func in module.functions.iter().filter(|func| !func.deleted) {
+            if let FuncKind::Local(local) = &func.kind {
+                functions.insert(local.func_id, FunctionUsage::new(local, &module.functions));
+            }
+        }
+        ModuleUsage { functions }
+    }
+
+    /// The summary of the local function `func`, `None` if it is imported.
+    pub fn get(&self, func: FunctionID) -> Option<&FunctionUsage> {
+        self.functions.get(&func)
+    }
+
+    /// Iterates over the summaries of the local functions.
+    pub fn iter(&self) -> impl Iterator<Item = (&FunctionID, &FunctionUsage)> {
+        self.functions.iter()
+    }
+
+    /// Accesses of the original code of `func` and of every function it (transitively) calls
+    /// directly. Indirect calls are only reported through [`StateUsage::calls_indirect`].
+    pub fn transitive(&self, func: FunctionID) -> StateUsage {
+        let mut usage = StateUsage::default();
+        for func in self.reachable(func) {
+            if let Some(summary) = self.functions.get(&func) {
+                usage.union_with(&summary.original);
+            }
+        }
+        usage
+    }
+
+    /// `func` and every function it (transitively) calls directly from its original code.
+    pub fn reachable(&self, func: FunctionID) -> BTreeSet<FunctionID> {
+        let mut visited = BTreeSet::from([func]);
+        let mut worklist = vec![func];
+        while let Some(func) = worklist.pop() {
+            if let Some(summary) = self.functions.get(&func) {
+                for callee in summary.callees.iter() {
+                    if visited.insert(*callee) {
+                        worklist.push(*callee);
+                    }
+                }
+            }
+        }
+        visited
+    }
+}
+
+fn is_memory_write(op: &Operator) -> bool {
+    matches!(
+        op,
+        Operator::I32Store { .. }
+            | Operator::I32Store8 { .. }
+            | Operator::I32Store16 { .. }
+            | Operator::I64Store { .. }
+            | Operator::I64Store8 { .. }
+            | Operator::I64Store16 { .. }
+            | Operator::I64Store32 { .. }
+            | Operator::F32Store { .. }
+            | Operator::F64Store { .. }
+            | Operator::I32AtomicStore { .. }
+            | Operator::I32AtomicStore8 { .. }
+            | Operator::I32AtomicStore16 { .. }
+            | Operator::I64AtomicStore { .. }
+            | Operator::I64AtomicStore8 { .. }
+            | Operator::I64AtomicStore16 { .. }
+            | Operator::I64AtomicStore32 { .. }
+            | Operator::V128Store { .. }
+            | Operator::V128Store8Lane { .. }
+            | Operator::V128Store16Lane { .. }
+            | Operator::V128Store32Lane { .. }
+            | Operator::V128Store64Lane { .. }
+            | Operator::MemoryFill { .. }
+            | Operator::MemoryInit { .. }
+            | Operator::MemoryDiscard { .. }
+    )
+}
+
+fn is_read_modify_write(op: &Operator) -> bool {
+    matches!(
+        op,
+        Operator::I32AtomicRmwAdd { .. }
+            | Operator::I64AtomicRmwAdd { .. }
+            | Operator::I32AtomicRmw8AddU { .. }
+            | Operator::I32AtomicRmw16AddU { .. }
+            | Operator::I64AtomicRmw8AddU { .. }
+            | Operator::I64AtomicRmw16AddU { .. }
+            | Operator::I64AtomicRmw32AddU { .. }
+            | Operator::I32AtomicRmwSub { .. }
+            | Operator::I64AtomicRmwSub { .. }
+            | Operator::I32AtomicRmw8SubU { .. }
+            | Operator::I32AtomicRmw16SubU { .. }
+            | Operator::I64AtomicRmw8SubU { .. }
+            | Operator::I64AtomicRmw16SubU { .. }
+            | Operator::I64AtomicRmw32SubU { .. }
+            | Operator::I32AtomicRmwAnd { .. }
+            | Operator::I64AtomicRmwAnd { .. }
+            | Operator::I32AtomicRmw8AndU { .. }
+            | Operator::I32AtomicRmw16AndU { .. }
+            | Operator::I64AtomicRmw8AndU { .. }
+            | Operator::I64AtomicRmw16AndU { .. }
+            | Operator::I64AtomicRmw32AndU { .. }
+            | Operator::I32AtomicRmwOr { .. }
+            | Operator::I64AtomicRmwOr { .. }
+            | Operator::I32AtomicRmw8OrU { .. }
+            | Operator::I32AtomicRmw16OrU { .. }
+            | Operator::I64AtomicRmw8OrU { .. }
+            | Operator::I64AtomicRmw16OrU { .. }
+            | Operator::I64AtomicRmw32OrU { .. }
+            | Operator::I32AtomicRmwXor { .. }
+            | Operator::I64AtomicRmwXor { .. }
+            | Operator::I32AtomicRmw8XorU { .. }
+            | Operator::I32AtomicRmw16XorU { .. }
+            | Operator::I64AtomicRmw8XorU { .. }
+            | Operator::I64AtomicRmw16XorU { .. }
+            | Operator::I64AtomicRmw32XorU { .. }
+            | Operator::I32AtomicRmwXchg { .. }
+            | Operator::I64AtomicRmwXchg { .. }
+            | Operator::I32AtomicRmw8XchgU { .. }
+            | Operator::I32AtomicRmw16XchgU { .. }
+            | Operator::I64AtomicRmw8XchgU { .. }
+            | Operator::I64AtomicRmw16XchgU { .. }
+            | Operator::I64AtomicRmw32XchgU { .. }
+            | Operator::I32AtomicRmwCmpxchg { .. }
+            | Operator::I64AtomicRmwCmpxchg { .. }
+            | Operator::I32AtomicRmw8CmpxchgU { .. }
+            | Operator::I32AtomicRmw16CmpxchgU { .. }
+            | Operator::I64AtomicRmw8CmpxchgU { .. }
+            | Operator::I64AtomicRmw16CmpxchgU { .. }
+            | Operator::I64AtomicRmw32CmpxchgU { .. }
+    )
+}
@@ -3,7 +3,6 @@ use wasmparser::BinaryReaderError;
 
 #[derive(Debug, Clone)]
 #[allow(clippy::enum_variant_names)]
-
 /// Error for parsing
 pub enum Error {
     BinaryReaderError(BinaryReaderError),
@@ -33,6 +32,40 @@ pub enum Error {
     InvalidMemoryReservedByte {
         func_range: Range<usize>,
     },
+    /// The instruction range passed to `Module::outline` cannot be moved into its own function.
+    InvalidOutlineRange {
+        range: Range<usize>,
+        reason: String,
+    },
+    /// The instrumentation of the instruction at `instr_idx` cannot be flattened into plain
+    /// instructions by `transform::flatten_instrumentation`.
+    InvalidFlatten {
+        instr_idx: usize,
+        reason: String,
+    },
+    /// The code injected under the tag passed to `Module::strip_instrumentation` cannot be removed.
+    InvalidStrip {
+        tag: String,
+        reason: String,
+    },
+    /// The function passed to `Module::wrap_import` cannot be wrapped.
+    InvalidImportWrap {
+        func_id: u32,
+        reason: String,
+    },
+    /// A pass of `orca_wasm::passes` cannot instrument the module.
+    InvalidPass {
+        pass: String,
+        reason: String,
+    },
+    /// The DWARF debug information cannot be rewritten for the encoded module.
+    InvalidDebugInfo {
+        reason: String,
+    },
+    /// The text format of a module, component or instructions cannot be printed or parsed.
+    InvalidWat {
+        reason: String,
+    },
 }
 
 impl From<BinaryReaderError> for Error {
@@ -94,6 +127,39 @@ impl std::fmt::Display for Error {
             Error::InvalidMemoryReservedByte { func_range } => {
                 write!(f, "Found a `memory.*` instruction with an invalid reserved byte in function at {:?}", func_range)
             }
+            Error::InvalidOutlineRange { range, reason } => {
+                write!(
+                    f,
+                    "Cannot outline instructions {} - {}: {}",
+                    range.start, range.end, reason
+                )
+            }
+            Error::InvalidFlatten { instr_idx, reason } => {
+                write!(
+                    f,
+                    "Cannot flatten the instrumentation of instruction {}: {}",
+                    instr_idx, reason
+                )
+            }
+            Error::InvalidStrip { tag, reason } => {
+                write!(
+                    f,
+                    "Cannot strip the instrumentation tagged {}: {}",
+                    tag, reason
+                )
+            }
+            Error::InvalidImportWrap { func_id, reason } => {
+                write!(f, "Cannot wrap the function {}: {}", func_id, reason)
+            }
+            Error::InvalidPass { pass, reason } => {
+                write!(f, "Cannot run the {} pass: {}", pass, reason)
+            }
+            Error::InvalidDebugInfo { reason } => {
+                write!(f, "Cannot rewrite the DWARF debug information: {}", reason)
+            }
+            Error::InvalidWat { reason } => {
+                write!(f, "Invalid text format: {}", reason)
+            }
         }
     }
 }
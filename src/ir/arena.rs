@@ -0,0 +1,72 @@
+//! Storage for the binaries parsed from the text format.
+
+use std::cell::{Cell, OnceCell};
+
+/// The number of chunks of an arena: chunk `i` holds `2^i` binaries.
+const NUM_CHUNKS: usize = usize::BITS as usize;
+
+/// A chunk of binaries, filled in order.
+type Chunk = Box<[OnceCell<Box<[u8]>>]>;
+
+/// Owns the binaries parsed from the text format by [`Module::parse_wat_instrs`], which the parsed
+/// instructions borrow, as [`Module::parse`] borrows the buffer of its caller. The arena must
+/// outlive them; the binaries are freed along with it.
+///
+/// The modules and components parsed by [`Module::from_wat`] and [`Component::from_wat`] own an
+/// arena, see [`OwnedModule::with_module`].
+///
+/// # Example
+///
+/// ```
+/// use orca_wasm::ir::arena::WatArena;
+/// use orca_wasm::ir::id::FunctionID;
+/// use orca_wasm::Module;
+///
+/// let wasm = wat::parse_str("(module (func $main (local $x i32)))").unwrap();
+/// let module = Module::parse(&wasm, false).unwrap();
+/// let arena = WatArena::new();
+/// let ops = module
+///     .parse_wat_instrs(FunctionID(0), "local.get $x drop", &arena)
+///     .unwrap();
+/// assert_eq!(ops.len(), 2);
+/// ```
+///
+/// [`Module::from_wat`]: crate::Module::from_wat
+/// [`Component::from_wat`]: crate::Component::from_wat
+/// [`Module::parse_wat_instrs`]: crate::Module::parse_wat_instrs
+/// [`Module::parse`]: crate::Module::parse
+/// [`OwnedModule::with_module`]: crate::ir::module::OwnedModule::with_module
+#[derive(Debug)]
+pub struct WatArena {
+    /// The binaries, in chunks that are never moved nor freed before the arena
+    chunks: [OnceCell<Chunk>; NUM_CHUNKS],
+    /// The number of binaries
+    len: Cell<usize>,
+}
+
+impl Default for WatArena {
+    fn default() -> Self {
+        WatArena {
+            chunks: std::array::from_fn(|_| OnceCell::new()),
+            len: Cell::new(0),
+        }
+    }
+}
+
+impl WatArena {
+    /// Creates an empty arena.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Moves `wasm` into the arena.
+    pub(crate) fn alloc(&self, wasm: Vec<u8>) -> &[u8] {
+        let idx = self.len.get();
+        self.len.set(idx + 1);
+        // chunk `i` holds the binaries `2^i - 1..2^(i + 1) - 1`
+        let chunk = (usize::BITS - 1 - (idx + 1).leading_zeros()) as usize;
+        let slots = self.chunks[chunk]
+            .get_or_init(|| (0..1usize << chunk).map(|_| OnceCell::new()).collect());
+        slots[idx + 1 - (1 << chunk)].get_or_init(|| wasm.into_boxed_slice())
+    }
+}
@@ -2,12 +2,10 @@
 //! Intermediate Representation of a wasm component.
 
 use crate::error::Error;
-use crate::ir::helpers::{
-    print_alias, print_component_export, print_component_import, print_component_type,
-    print_core_type,
-};
+use crate::ir::arena::WatArena;
 use crate::ir::id::{CustomSectionID, FunctionID, GlobalID, ModuleID};
 use crate::ir::module::Module;
+use crate::ir::printer::component_to_wat;
 use crate::ir::section::ComponentSection;
 use crate::ir::wrappers::{
     add_to_namemap, convert_component_type, convert_instance_type, convert_module_type_declaration,
@@ -16,7 +14,8 @@ use crate::ir::wrappers::{
 
 use crate::ir::module::module_functions::FuncKind;
 use crate::ir::module::module_globals::Global;
-use crate::ir::types::CustomSections;
+use crate::ir::types::{CustomSections, Location};
+use std::fmt;
 use wasm_encoder::reencode::{Reencode, ReencodeComponent};
 use wasm_encoder::{ComponentAliasSection, ModuleArg, ModuleSection, NestedComponentSection};
 use wasmparser::{
@@ -25,7 +24,7 @@ use wasmparser::{
     Parser, Payload,
 };
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 /// Intermediate Representation of a wasm component.
 pub struct Component<'a> {
     /// Modules
@@ -80,6 +79,48 @@ impl Default for Component<'_> {
     }
 }
 
+/// A [`Component`] that owns the binary it is parsed from, see [`Component::from_wat`] and
+/// [`OwnedModule`].
+///
+/// [`OwnedModule`]: crate::ir::module::OwnedModule
+#[derive(Debug)]
+pub struct OwnedComponent {
+    // dropped before the arena it borrows
+    component: Component<'static>,
+    arena: WatArena,
+}
+
+impl OwnedComponent {
+    /// Parses a `Component` from a wasm binary, which it keeps. See [`Component::parse`].
+    pub fn parse(wasm: Vec<u8>, enable_multi_memory: bool) -> Result<Self, Error> {
+        let arena = WatArena::new();
+        // SAFETY: see `OwnedModule::parse`
+        let wasm: &'static [u8] = unsafe { &*(arena.alloc(wasm) as *const [u8]) };
+        let component = Component::parse(wasm, enable_multi_memory)?;
+        Ok(OwnedComponent { component, arena })
+    }
+
+    /// The component.
+    pub fn component(&self) -> &Component<'_> {
+        &self.component
+    }
+
+    /// Runs `f` over the component and the arena it borrows from, which keeps the instructions
+    /// parsed by [`ComponentIterator::inject_wat`].
+    ///
+    /// [`ComponentIterator::inject_wat`]: crate::iterator::component_iterator::ComponentIterator::inject_wat
+    pub fn with_component<R>(
+        &mut self,
+        f: impl for<'c> FnOnce(&mut Component<'c>, &'c WatArena) -> R,
+    ) -> R {
+        // SAFETY: see `OwnedModule::with_module`
+        let component = unsafe {
+            &mut *(&mut self.component as *mut Component<'static>).cast::<Component<'_>>()
+        };
+        f(component, &self.arena)
+    }
+}
+
 impl<'a> Component<'a> {
     /// Creates a new Empty Component
     pub fn new() -> Self {
@@ -165,6 +206,24 @@ impl<'a> Component<'a> {
         Component::parse_comp(wasm, enable_multi_memory, parser, 0, &mut vec![])
     }
 
+    /// Parses a `Component` from the text format, with multi-memory enabled. As for
+    /// [`Module::from_wat`], the component owns its binary.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use orca_wasm::Component;
+    ///
+    /// let comp = Component::from_wat("(component (core module (func)))").unwrap();
+    /// assert_eq!(comp.component().modules.len(), 1);
+    /// ```
+    pub fn from_wat(wat: &str) -> Result<OwnedComponent, Error> {
+        let wasm = wat::parse_str(wat).map_err(|err| Error::InvalidWat {
+            reason: err.to_string(),
+        })?;
+        OwnedComponent::parse(wasm, true)
+    }
+
     fn parse_comp(
         wasm: &'a [u8],
         enable_multi_memory: bool,
@@ -579,10 +638,10 @@ impl<'a> Component<'a> {
                                             reencode.component_val_type(*val_type)
                                         })),
                                     wasmparser::ComponentDefinedType::Flags(flags) => {
-                                        enc.flags(flags.clone().into_vec().into_iter())
+                                        enc.flags(flags.clone().into_vec())
                                     }
                                     wasmparser::ComponentDefinedType::Enum(en) => {
-                                        enc.enum_type(en.clone().into_vec().into_iter())
+                                        enc.enum_type(en.clone().into_vec())
                                     }
                                     wasmparser::ComponentDefinedType::Option(opt) => {
                                         enc.option(reencode.component_val_type(*opt))
@@ -999,52 +1058,27 @@ impl<'a> Component<'a> {
         component
     }
 
-    /// Print a rudimentary textual representation of a `Component`
-    pub fn print(&self) {
-        // Print Alias
-        if !self.alias.is_empty() {
-            eprintln!("Alias Section:");
-            for alias in self.alias.iter() {
-                print_alias(alias);
-            }
-            eprintln!();
-        }
-
-        // Print CoreType
-        if !self.core_types.is_empty() {
-            eprintln!("Core Type Section:");
-            for cty in self.core_types.iter() {
-                print_core_type(cty);
-            }
-            eprintln!();
-        }
-
-        // Print ComponentType
-        if !self.component_types.is_empty() {
-            eprintln!("Component Type Section:");
-            for cty in self.component_types.iter() {
-                print_component_type(cty);
-            }
-            eprintln!();
-        }
-
-        // Print Imports
-        if !self.imports.is_empty() {
-            eprintln!("Imports Section:");
-            for imp in self.imports.iter() {
-                print_component_import(imp);
-            }
-            eprintln!();
-        }
+    /// The text format of the component, as it would be encoded, with the instrumentation that is
+    /// still pending on its modules marked inline, see [`Module::to_wat`].
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use orca_wasm::Component;
+    ///
+    /// let file = "path_to_file";
+    /// let buff = wat::parse_file(file).expect("couldn't convert the input wat to Wasm");
+    /// let comp = Component::parse(&buff, false).unwrap();
+    /// println!("{}", comp.to_wat().unwrap());
+    /// ```
+    pub fn to_wat(&self) -> Result<String, Error> {
+        component_to_wat(self)
+    }
 
-        // Print Exports
-        if !self.imports.is_empty() {
-            eprintln!("Exports Section:");
-            for exp in self.exports.iter() {
-                print_component_export(exp);
-            }
-            eprintln!();
-        }
+    /// Print the text format of the `Component` to stderr, see [`Component::to_wat`].
+    #[deprecated(note = "use the `Display` implementation or `Component::to_wat` instead")]
+    pub fn print(&self) {
+        eprintln!("{}", self);
     }
 
     /// Emit the Component into a wasm binary file.
@@ -1055,6 +1089,47 @@ impl<'a> Component<'a> {
         Ok(())
     }
 
+    /// The source location of the instruction at `loc` in one of the modules, see
+    /// [`Module::source_location`].
+    pub fn source_location(&self, loc: Location) -> Option<(&str, u32, u32)> {
+        let Location::Component {
+            mod_idx,
+            func_idx,
+            instr_idx,
+        } = loc
+        else {
+            return None;
+        };
+        self.modules
+            .get(*mod_idx as usize)?
+            .source_location(Location::Module {
+                func_idx,
+                instr_idx,
+            })
+    }
+
+    /// The locations of the parsed instructions of the modules on `line` of the source `file`, see
+    /// [`Module::locations_of`].
+    pub fn locations_of(&self, file: &str, line: u32) -> Vec<Location> {
+        let mut locations = vec![];
+        for (mod_idx, module) in self.modules.iter().enumerate() {
+            for loc in module.locations_of(file, line) {
+                if let Location::Module {
+                    func_idx,
+                    instr_idx,
+                } = loc
+                {
+                    locations.push(Location::Component {
+                        mod_idx: ModuleID(mod_idx as u32),
+                        func_idx,
+                        instr_idx,
+                    });
+                }
+            }
+        }
+        locations
+    }
+
     /// Get Local Function ID by name
     // Note: returned absolute id here
     pub fn get_fid_by_name(&self, name: &str, module_idx: ModuleID) -> Option<FunctionID> {
@@ -1064,16 +1139,18 @@ impl<'a> Component<'a> {
             .enumerate()
         {
             if let FuncKind::Local(l) = &func.kind {
-                match &l.body.name {
-                    Some(n) => {
-                        if n == name {
-                            return Some(FunctionID(idx as u32));
-                        }
-                    }
-                    None => {}
+                if l.body.name.as_deref() == Some(name) {
+                    return Some(FunctionID(idx as u32));
                 }
             }
         }
         None
     }
 }
+
+impl fmt::Display for Component<'_> {
+    /// See [`Component::to_wat`].
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_wat().map_err(|_| fmt::Error)?)
+    }
+}
@@ -1,8 +1,598 @@
-use gimli::read::{Dwarf};
+//! The DWARF debug information of modules.
+//!
+//! The `.debug_*` custom sections of a parsed binary are loaded into a [`ModuleDebugData`]. DWARF
+//! refers to code with offsets in the code section, which instrumentation moves: when the module
+//! is encoded, the code section records an [`AddressMap`] from the offsets of the parsed binary to
+//! those of the encoded one, and the debug information is rewritten with it.
+//!
+//! The line programs are also read into a [`LineTable`], which maps the instructions of the parsed
+//! binary to their source lines (see [`Module::source_location`]).
+//!
+//! [`Module::source_location`]: crate::Module::source_location
 
-/// The DWARF debug section in input WebAssembly binary.
+use crate::error::Error;
+use gimli::read::{self, EndianSlice};
+use gimli::write::{self, Address};
+use gimli::{LittleEndian, SectionId};
+use log::warn;
+use std::ops::Range;
+
+/// The address given to the code of the parsed binary that is not part of the encoded binary,
+/// as done by `wasm-ld` (-1 starts a base address selection in DWARF 4 range lists).
+pub const TOMBSTONE: u64 = 0xffff_fffe;
+
+/// The sections whose content is regenerated from the units when the debug information is
+/// rewritten. The indexes (`.debug_aranges`, `.debug_pubnames` and `.debug_pubtypes`) are dropped
+/// instead, as they refer to the old `.debug_info`.
+const REWRITTEN_SECTIONS: [&str; 14] = [
+    ".debug_abbrev",
+    ".debug_addr",
+    ".debug_aranges",
+    ".debug_info",
+    ".debug_line",
+    ".debug_line_str",
+    ".debug_loc",
+    ".debug_loclists",
+    ".debug_pubnames",
+    ".debug_pubtypes",
+    ".debug_ranges",
+    ".debug_rnglists",
+    ".debug_str",
+    ".debug_str_offsets",
+];
+
+type Reader<'a> = EndianSlice<'a, LittleEndian>;
+
+/// A function body in a code section: (size field, body).
+type CodeBody = (u64, Range<u64>);
+
+/// The DWARF debug sections in input WebAssembly binary.
 #[derive(Debug, Default)]
-pub struct ModuleDebugData {
+pub struct ModuleDebugData<'a> {
     /// DWARF debug data
-    pub dwarf: Dwarf<Vec<u8>>
-}
\ No newline at end of file
+    pub dwarf: read::Dwarf<Reader<'a>>,
+    /// The source lines of the code, read from `.debug_line`
+    pub lines: LineTable,
+    /// The `.debug_*` custom sections: (name, data).
+    pub(crate) sections: Vec<(&'a str, &'a [u8])>,
+    /// The function bodies of the parsed binary, as offsets in its code section: (size field,
+    /// body). The body starts with the declaration of the locals.
+    pub(crate) bodies: Vec<(usize, Range<usize>)>,
+    /// The offset of the code section in the parsed binary.
+    pub(crate) code_start: usize,
+}
+
+impl Clone for ModuleDebugData<'_> {
+    /// The DWARF of gimli cannot be cloned: it is loaded again from the sections.
+    fn clone(&self) -> Self {
+        ModuleDebugData::new(self.sections.clone(), self.bodies.clone(), self.code_start)
+    }
+}
+
+impl<'a> ModuleDebugData<'a> {
+    /// Loads the DWARF of the `.debug_*` custom `sections` of a binary whose function bodies
+    /// are `bodies`, in a code section starting at `code_start`.
+    pub(crate) fn new(
+        sections: Vec<(&'a str, &'a [u8])>,
+        bodies: Vec<(usize, Range<usize>)>,
+        code_start: usize,
+    ) -> Self {
+        let dwarf = read::Dwarf::load(|id: SectionId| -> Result<Reader<'a>, gimli::Error> {
+            let data = sections
+                .iter()
+                .find(|(name, _)| *name == id.name())
+                .map_or(&[][..], |(_, data)| *data);
+            Ok(EndianSlice::new(data, LittleEndian))
+        })
+        .unwrap_or_default();
+        ModuleDebugData {
+            lines: LineTable::new(&dwarf),
+            dwarf,
+            sections,
+            bodies,
+            code_start,
+        }
+    }
+
+    /// Whether the binary has no debug section.
+    pub fn is_empty(&self) -> bool {
+        self.sections.is_empty()
+    }
+
+    /// The `.debug_*` custom sections of the parsed binary: (name, data).
+    pub fn sections(&self) -> &[(&'a str, &'a [u8])] {
+        &self.sections
+    }
+
+    /// Encodes the debug sections for the code moved as described by `addresses`: (name, data).
+    ///
+    /// The line programs, the addresses, the ranges and the location lists of the compilation units
+    /// are rewritten, the code that was removed getting the [`TOMBSTONE`] address or being left out
+    /// of the lists. Fails if gimli cannot convert the units, e.g. for type units.
+    pub(crate) fn encode(&self, addresses: &AddressMap) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        let invalid = |reason: String| Error::InvalidDebugInfo { reason };
+        let translate = |address: u64| {
+            Some(Address::Constant(
+                addresses.translate(address).unwrap_or(TOMBSTONE),
+            ))
+        };
+        let mut dwarf =
+            write::Dwarf::from(&self.dwarf, &translate).map_err(|err| invalid(err.to_string()))?;
+
+        // gimli keeps the offsets relative to the start of the lists and sequences, and the length
+        // of the code of the entries: convert them from the input again
+        let mut headers = self.dwarf.units();
+        let mut idx = 0;
+        while let Some(header) = headers.next().map_err(|err| invalid(err.to_string()))? {
+            let unit = self
+                .dwarf
+                .unit(header)
+                .map_err(|err| invalid(err.to_string()))?;
+            let id = dwarf.units.id(idx);
+            idx += 1;
+            let line_program = match unit.line_program.clone() {
+                Some(program) => convert_line_program(
+                    program,
+                    &self.dwarf,
+                    &mut dwarf.line_strings,
+                    &mut dwarf.strings,
+                    addresses,
+                )
+                .map_err(|err| invalid(err.to_string()))?,
+                None => write::LineProgram::none(),
+            };
+            let to = dwarf.units.get_mut(id);
+            to.line_program = line_program;
+            to.ranges = write::RangeListTable::default();
+            to.locations = write::LocationListTable::default();
+            let root = to.root();
+            let mut tree = unit
+                .entries_tree(None)
+                .map_err(|err| invalid(err.to_string()))?;
+            let from = tree.root().map_err(|err| invalid(err.to_string()))?;
+            convert_entry(from, root, &self.dwarf, &unit, to, addresses)
+                .map_err(|err| invalid(err.to_string()))?;
+        }
+
+        let mut sections = write::Sections::new(write::EndianVec::new(LittleEndian));
+        dwarf
+            .write(&mut sections)
+            .map_err(|err| invalid(err.to_string()))?;
+        let mut encoded = vec![];
+        sections
+            .for_each(|id, data| {
+                if !data.slice().is_empty() {
+                    encoded.push((id.name().to_string(), data.slice().to_vec()));
+                }
+                Ok::<(), write::Error>(())
+            })
+            .map_err(|err| invalid(err.to_string()))?;
+        // keep the sections that do not refer to code, e.g. `.debug_macinfo`
+        for (name, data) in self.sections.iter() {
+            if !REWRITTEN_SECTIONS.contains(name) {
+                encoded.push((name.to_string(), data.to_vec()));
+            }
+        }
+        Ok(encoded)
+    }
+}
+
+/// A position in a source file: (file index, line, column), the column being 0 when unknown.
+pub type SourcePosition = (u32, u32, u32);
+
+/// The source locations of the code of a binary, read from the line programs of its DWARF.
+#[derive(Clone, Debug, Default)]
+pub struct LineTable {
+    /// The paths of the source files, joined to their directory.
+    pub files: Vec<String>,
+    /// The rows of the line programs sorted by address: (address, (file, line, column)), `None`
+    /// at the end of a sequence.
+    rows: Vec<(u64, Option<SourcePosition>)>,
+}
+
+impl LineTable {
+    /// Reads the line programs of `dwarf`, up to the first invalid one.
+    pub(crate) fn new(dwarf: &read::Dwarf<Reader>) -> Self {
+        let mut table = LineTable::default();
+        if let Err(e) = table.read(dwarf) {
+            warn!("Cannot read the DWARF line programs: {}", e);
+        }
+        // the end of a sequence comes before the start of the next one at the same address
+        table
+            .rows
+            .sort_by_key(|(address, row)| (*address, row.is_some()));
+        table
+    }
+
+    fn read(&mut self, dwarf: &read::Dwarf<Reader>) -> read::Result<()> {
+        let mut units = dwarf.units();
+        while let Some(header) = units.next()? {
+            let unit = dwarf.unit(header)?;
+            let Some(program) = unit.line_program.clone() else {
+                continue;
+            };
+            let mut rows = program.rows();
+            while let Some((header, row)) = rows.next_row()? {
+                if row.end_sequence() {
+                    self.rows.push((row.address(), None));
+                    continue;
+                }
+                let (Some(line), Some(file)) = (row.line(), row.file(header)) else {
+                    continue;
+                };
+                let mut path = dwarf
+                    .attr_string(&unit, file.path_name())?
+                    .to_string_lossy()
+                    .into_owned();
+                if let Some(dir) = file.directory(header) {
+                    let dir = dwarf.attr_string(&unit, dir)?.to_string_lossy();
+                    if !dir.is_empty() && !path.starts_with('/') {
+                        path = format!("{}/{}", dir.trim_end_matches('/'), path);
+                    }
+                }
+                let file = match self.files.iter().position(|known| *known == path) {
+                    Some(idx) => idx,
+                    None => {
+                        self.files.push(path);
+                        self.files.len() - 1
+                    }
+                };
+                let column = match row.column() {
+                    read::ColumnType::LeftEdge => 0,
+                    read::ColumnType::Column(column) => column.get() as u32,
+                };
+                self.rows.push((
+                    row.address(),
+                    Some((file as u32, line.get() as u32, column)),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the binary has no line information.
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// The source location of the code at `address`: (index in the files, line, column), the
+    /// column being 0 when it is not known.
+    pub fn lookup(&self, address: u64) -> Option<SourcePosition> {
+        let idx = self.rows.partition_point(|(row, _)| *row <= address);
+        self.rows[idx.checked_sub(1)?].1
+    }
+
+    /// The indexes of the files whose path is `file`, or ends with `/` followed by `file`.
+    pub fn find_files(&self, file: &str) -> Vec<u32> {
+        (0..self.files.len() as u32)
+            .filter(|idx| {
+                let path = &self.files[*idx as usize];
+                path == file
+                    || path
+                        .strip_suffix(file)
+                        .is_some_and(|dir| dir.ends_with('/'))
+            })
+            .collect()
+    }
+}
+
+/// Rewrites the attributes of the entry `to` converted by gimli from `from` that hold lengths of
+/// code or lists of addresses, and those of its children.
+fn convert_entry(
+    from: read::EntriesTreeNode<Reader>,
+    to: write::UnitEntryId,
+    dwarf: &read::Dwarf<Reader>,
+    unit: &read::Unit<Reader>,
+    to_unit: &mut write::Unit,
+    addresses: &AddressMap,
+) -> read::Result<()> {
+    let entry = from.entry();
+    let mut attrs = entry.attrs();
+    while let Some(attr) = attrs.next()? {
+        if attr.name() == gimli::DW_AT_high_pc {
+            let (Some(len), Some(low)) =
+                (attr.udata_value(), entry.attr_value(gimli::DW_AT_low_pc)?)
+            else {
+                continue;
+            };
+            let Some(low) = dwarf.attr_address(unit, low)? else {
+                continue;
+            };
+            let len = match (addresses.translate(low), addresses.translate_end(low + len)) {
+                (Some(low), Some(high)) => high.saturating_sub(low),
+                _ => 0,
+            };
+            to_unit
+                .get_mut(to)
+                .set(gimli::DW_AT_high_pc, write::AttributeValue::Udata(len));
+        } else if let Some(offset) = dwarf.attr_ranges_offset(unit, attr.value())? {
+            // the ranges are absolute, from a base address of 0
+            let mut list = vec![write::Range::BaseAddress {
+                address: Address::Constant(0),
+            }];
+            let mut ranges = dwarf.ranges(unit, offset)?;
+            while let Some(range) = ranges.next()? {
+                if let Some((begin, end)) = addresses.translate_range(range.begin, range.end) {
+                    list.push(write::Range::StartEnd {
+                        begin: Address::Constant(begin),
+                        end: Address::Constant(end),
+                    });
+                }
+            }
+            let id = to_unit.ranges.add(write::RangeList(list));
+            to_unit
+                .get_mut(to)
+                .set(attr.name(), write::AttributeValue::RangeListRef(id));
+        } else if let Some(offset) = dwarf.attr_locations_offset(unit, attr.value())? {
+            let mut list = vec![write::Location::BaseAddress {
+                address: Address::Constant(0),
+            }];
+            let mut locations = dwarf.locations(unit, offset)?;
+            while let Some(location) = locations.next()? {
+                let range = location.range;
+                if let Some((begin, end)) = addresses.translate_range(range.begin, range.end) {
+                    list.push(write::Location::StartEnd {
+                        begin: Address::Constant(begin),
+                        end: Address::Constant(end),
+                        data: write::Expression::raw(location.data.0.to_vec()),
+                    });
+                }
+            }
+            let id = to_unit.locations.add(write::LocationList(list));
+            to_unit
+                .get_mut(to)
+                .set(attr.name(), write::AttributeValue::LocationListRef(id));
+        }
+    }
+
+    // gimli converts the children in order
+    let children: Vec<write::UnitEntryId> = to_unit.get(to).children().copied().collect();
+    let mut from_children = from.children();
+    let mut idx = 0;
+    while let Some(child) = from_children.next()? {
+        if let Some(to_child) = children.get(idx) {
+            convert_entry(child, *to_child, dwarf, unit, to_unit, addresses)?;
+        }
+        idx += 1;
+    }
+    Ok(())
+}
+
+/// Converts the line program `from`, translating the address of each row. The header is converted
+/// as gimli does, so that the files referred to by the entries are the same.
+fn convert_line_program(
+    from: read::IncompleteLineProgram<Reader>,
+    dwarf: &read::Dwarf<Reader>,
+    line_strings: &mut write::LineStringTable,
+    strings: &mut write::StringTable,
+    addresses: &AddressMap,
+) -> write::ConvertResult<write::LineProgram> {
+    let mut line_string =
+        |attr: read::AttributeValue<Reader>| -> write::ConvertResult<write::LineString> {
+            Ok(match attr {
+                read::AttributeValue::String(string) => write::LineString::String(string.to_vec()),
+                read::AttributeValue::DebugStrRef(offset) => {
+                    let string = dwarf.debug_str.get_str(offset)?;
+                    write::LineString::StringRef(strings.add(string.slice()))
+                }
+                read::AttributeValue::DebugLineStrRef(offset) => {
+                    let string = dwarf.debug_line_str.get_str(offset)?;
+                    write::LineString::LineStringRef(line_strings.add(string.slice()))
+                }
+                _ => return Err(write::ConvertError::UnsupportedLineStringForm),
+            })
+        };
+
+    let header = from.header();
+    let encoding = header.encoding();
+    let comp_dir = match header.directory(0) {
+        Some(dir) => line_string(dir)?,
+        None => write::LineString::String(vec![]),
+    };
+    let comp_name = match header.file(0) {
+        Some(file) => line_string(file.path_name())?,
+        None => write::LineString::String(vec![]),
+    };
+    let mut program =
+        write::LineProgram::new(encoding, header.line_encoding(), comp_dir, comp_name, None);
+    let mut dirs = vec![];
+    let mut files = vec![];
+    if header.version() <= 4 {
+        dirs.push(program.default_directory());
+        files.push(None);
+    }
+    for dir in header.include_directories() {
+        dirs.push(program.add_directory(line_string(*dir)?));
+    }
+    program.file_has_timestamp = header.file_has_timestamp();
+    program.file_has_size = header.file_has_size();
+    program.file_has_md5 = header.file_has_md5();
+    program.file_has_source = header.file_has_source();
+    for file in header.file_names().iter() {
+        let name = line_string(file.path_name())?;
+        let dir = *dirs
+            .get(file.directory_index() as usize)
+            .ok_or(write::ConvertError::InvalidDirectoryIndex)?;
+        let info = write::FileInfo {
+            timestamp: file.timestamp(),
+            size: file.size(),
+            md5: *file.md5(),
+            source: file.source().map(&mut line_string).transpose()?,
+        };
+        files.push(Some(program.add_file(name, dir, Some(info))));
+    }
+
+    // the rows of a sequence, dropped if the code of its start was removed
+    let mut sequence: Option<(u64, u64)> = None;
+    let mut rows = from.rows();
+    while let Some((_, row)) = rows.next_row()? {
+        if row.end_sequence() {
+            if let Some((start, last)) = sequence.take() {
+                let end = addresses.translate_end(row.address()).unwrap_or(last);
+                program.end_sequence(end.max(last) - start);
+            }
+            continue;
+        }
+        let Some(address) = addresses.translate(row.address()) else {
+            continue;
+        };
+        let start = match sequence {
+            Some((start, last)) if address >= last => start,
+            // the code of the row moved before the previous one
+            Some(_) => continue,
+            None => {
+                program.begin_sequence(Some(Address::Constant(address)));
+                address
+            }
+        };
+        sequence = Some((start, address));
+        let file = files
+            .get(row.file_index() as usize)
+            .copied()
+            .flatten()
+            .ok_or(write::ConvertError::InvalidFileIndex)?;
+        let to = program.row();
+        to.address_offset = address - start;
+        to.op_index = row.op_index();
+        to.file = file;
+        to.line = row.line().map_or(0, |line| line.get());
+        to.column = match row.column() {
+            read::ColumnType::LeftEdge => 0,
+            read::ColumnType::Column(column) => column.get(),
+        };
+        to.discriminator = row.discriminator();
+        to.is_statement = row.is_stmt();
+        to.basic_block = row.basic_block();
+        to.prologue_end = row.prologue_end();
+        to.epilogue_begin = row.epilogue_begin();
+        to.isa = row.isa();
+        program.generate_row();
+    }
+    Ok(program)
+}
+
+/// Where the code of a parsed binary is in the binary it was encoded to: maps the offsets in the
+/// code section of the parsed binary, as used by DWARF, to the offsets in the code section of the
+/// encoded binary.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AddressMap {
+    /// The function bodies that were encoded, sorted: in the parsed binary, then in the encoded
+    /// binary.
+    bodies: Vec<(CodeBody, CodeBody)>,
+    /// The instructions of the parsed binary that were encoded in their function, sorted: (offset
+    /// in the parsed binary, offset in the encoded binary).
+    instrs: Vec<(u64, u64)>,
+}
+
+impl AddressMap {
+    /// Records that the body at `from` (with its size field) was encoded at `to`.
+    pub(crate) fn add_body(&mut self, from: (usize, Range<usize>), to: (usize, Range<usize>)) {
+        let range = |range: Range<usize>| range.start as u64..range.end as u64;
+        self.bodies
+            .push(((from.0 as u64, range(from.1)), (to.0 as u64, range(to.1))));
+    }
+
+    /// Records that the instruction at `from` was encoded at `to`.
+    pub(crate) fn add_instr(&mut self, from: usize, to: usize) {
+        self.instrs.push((from as u64, to as u64));
+    }
+
+    /// Shifts all the offsets of the encoded binary by `delta`, once the start of the code section
+    /// is known.
+    pub(crate) fn shift(&mut self, delta: usize) {
+        let delta = delta as u64;
+        for (_, (size, body)) in self.bodies.iter_mut() {
+            *size += delta;
+            *body = body.start + delta..body.end + delta;
+        }
+        for (_, to) in self.instrs.iter_mut() {
+            *to += delta;
+        }
+    }
+
+    /// Sorts the recorded offsets by their offset in the parsed binary.
+    pub(crate) fn finish(&mut self) {
+        self.bodies.sort_by_key(|((size, _), _)| *size);
+        self.instrs.sort();
+    }
+
+    /// Whether no code of the parsed binary was encoded.
+    pub fn is_empty(&self) -> bool {
+        self.bodies.is_empty()
+    }
+
+    /// The offset in the encoded binary of the code at `address` in the parsed binary, `None` if
+    /// the function of the code was not encoded. The address 0 is kept, as it is the base address
+    /// of compilation units covering several functions.
+    ///
+    /// An address between two encoded instructions keeps its distance to the previous one, up to
+    /// the next one: the code injected before an instruction is part of the code of the previous
+    /// instruction.
+    pub fn translate(&self, address: u64) -> Option<u64> {
+        if address == 0 {
+            return Some(0);
+        }
+        let idx = self
+            .bodies
+            .partition_point(|((size, _), _)| *size <= address)
+            .checked_sub(1)?;
+        let ((from_size, from), (to_size, to)) = &self.bodies[idx];
+        if address > from.end {
+            return None;
+        }
+        if address == *from_size {
+            return Some(*to_size);
+        }
+        if address < from.start {
+            return Some(to.start);
+        }
+        if address == from.end {
+            return Some(to.end);
+        }
+        // the instructions of the body around the address
+        let next = self.instrs.partition_point(|(instr, _)| *instr <= address);
+        let limit = match self.instrs.get(next) {
+            Some((instr, to_instr)) if *instr < from.end => *to_instr,
+            _ => to.end,
+        };
+        let (base, to_base) = match next.checked_sub(1).map(|prev| self.instrs[prev]) {
+            Some((instr, to_instr)) if instr >= from.start => (instr, to_instr),
+            _ => (from.start, to.start),
+        };
+        Some((to_base + (address - base)).min(limit))
+    }
+
+    /// Like [`translate`], for the end of some code: the end of a body is not the start of the next
+    /// one.
+    ///
+    /// [`translate`]: AddressMap::translate
+    pub fn translate_end(&self, address: u64) -> Option<u64> {
+        let idx = self
+            .bodies
+            .partition_point(|((size, _), _)| *size < address)
+            .checked_sub(1)?;
+        let ((_, from), (_, to)) = &self.bodies[idx];
+        if address == from.end {
+            return Some(to.end);
+        }
+        self.translate(address)
+    }
+
+    /// The range in the encoded binary of the code in `begin..end` in the parsed binary, `None` if
+    /// it was not encoded or is empty.
+    pub fn translate_range(&self, begin: u64, end: u64) -> Option<(u64, u64)> {
+        let begin = self.translate(begin)?;
+        let end = self.translate_end(end)?;
+        (begin < end).then_some((begin, end))
+    }
+}
+
+/// The number of bytes of the unsigned LEB128 encoding of `value`.
+pub(crate) fn leb128_len(mut value: usize) -> usize {
+    let mut len = 1;
+    while value >= 0x80 {
+        value >>= 7;
+        len += 1;
+    }
+    len
+}
@@ -1,5 +1,6 @@
 //! Function Builder
 
+use crate::analysis::liveness::Liveness;
 use crate::ir::id::{FunctionID, ImportsID, LocalID, ModuleID, TypeID};
 use crate::ir::module::module_functions::{add_local, add_locals, LocalFunction};
 use crate::ir::module::{Module, ReIndexable};
@@ -172,6 +173,33 @@ impl<'a, 'b> FunctionModifier<'a, 'b> {
             &mut self.body.locals,
         );
     }
+
+    /// Get a local of type `ty` that instrumentation injected at `instr_idx` can use as a temporary.
+    /// A declared local that is dead across the instruction is reused when there is one, otherwise a
+    /// new local is added.
+    pub fn add_temp_local(&mut self, ty: DataType, instr_idx: usize) -> LocalID {
+        self.add_temp_locals(&[ty], instr_idx)[0]
+    }
+
+    /// Get distinct locals of the given types that instrumentation injected at `instr_idx` can use as
+    /// temporaries, reusing declared locals that are dead across the instruction where possible.
+    pub fn add_temp_locals(&mut self, types: &[DataType], instr_idx: usize) -> Vec<LocalID> {
+        let liveness = Liveness::with_func_instr(self.body, self.args.len(), self.instr_flag);
+
+        let mut taken = vec![];
+        for ty in types.iter() {
+            let reusable = liveness
+                .dead_locals_at(instr_idx, *ty, self.body)
+                .into_iter()
+                .find(|local| !taken.contains(local));
+            let local = match reusable {
+                Some(local) => local,
+                None => self.add_local(*ty),
+            };
+            taken.push(local);
+        }
+        taken
+    }
 }
 impl AddLocal for FunctionModifier<'_, '_> {
     /// add a local and return local index
@@ -194,7 +222,8 @@ impl<'a, 'b> Inject<'b> for FunctionModifier<'a, 'b> {
         } else {
             // inject at instruction level
             if let Some(idx) = self.instr_idx {
-                let is_special = self.body.instructions[idx].add_instr(instr);
+                let is_special = self.body.instructions[idx]
+                    .add_instr_tagged(instr, self.instr_flag.current_tag);
                 // remember if we injected a special instrumentation (to be resolved before encoding)
                 self.instr_flag.has_special_instr |= is_special;
             } else {
@@ -283,7 +312,7 @@ impl<'a, 'b> Instrumenter<'b> for FunctionModifier<'a, 'b> {
         self
     }
 
-    fn get_injected_val(&self, idx: usize) -> &Operator {
+    fn get_injected_val(&self, idx: usize) -> &Operator<'_> {
         self.body.instructions[idx].instr_flag.get_instr(idx)
     }
 }
@@ -1,12 +1,15 @@
 //! The Intermediate Representation for components and modules.
 
+pub mod arena;
 pub mod component;
+pub mod dwarf;
 pub mod function;
-mod helpers;
 pub mod id;
 #[cfg(test)]
 pub mod instr_tests;
 pub mod module;
+mod printer;
 pub mod section;
+pub mod source_map;
 pub mod types;
 pub(crate) mod wrappers;
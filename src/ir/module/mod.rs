@@ -2,8 +2,12 @@
 
 use super::types::{DataType, InitExpr, Instruction, InstrumentationMode};
 use crate::error::Error;
+use crate::ir::arena::WatArena;
+use crate::ir::dwarf::{leb128_len, AddressMap, ModuleDebugData};
 use crate::ir::function::FunctionModifier;
-use crate::ir::id::{DataSegmentID, FunctionID, GlobalID, ImportsID, LocalID, MemoryID, TypeID};
+use crate::ir::id::{
+    DataSegmentID, ElementID, FunctionID, GlobalID, ImportsID, LocalID, MemoryID, TableID, TypeID,
+};
 use crate::ir::module::module_exports::{Export, ModuleExports};
 use crate::ir::module::module_functions::{
     add_local, FuncKind, Function, Functions, ImportedFunction, LocalFunction,
@@ -12,43 +16,53 @@ use crate::ir::module::module_globals::{
     Global, GlobalKind, ImportedGlobal, LocalGlobal, ModuleGlobals,
 };
 use crate::ir::module::module_imports::{Import, ModuleImports};
+use crate::ir::module::module_injected::InjectedRanges;
 use crate::ir::module::module_memories::{ImportedMemory, LocalMemory, MemKind, Memories, Memory};
 use crate::ir::module::module_tables::ModuleTables;
 use crate::ir::module::module_types::{ModuleTypes, Types};
+use crate::ir::printer::module_to_wat;
+use crate::ir::source_map::{CodeOffsets, SourceMap, SOURCE_MAPPING_URL};
 use crate::ir::types::InstrumentationMode::{BlockAlt, BlockEntry, BlockExit, SemanticAfter};
 use crate::ir::types::{
-    BlockType, Body, CustomSections, DataSegment, DataSegmentKind, ElementItems, ElementKind,
-    InstrumentationFlag,
+    BlockType, Body, BodyTags, CustomSections, DataSegment, DataSegmentKind, ElementItems,
+    ElementKind, InstrumentationFlag, Origin, INJECTED_SECTION,
 };
 use crate::ir::wrappers::{
-    indirect_namemap_parser2encoder, namemap_parser2encoder, refers_to_func, refers_to_global,
-    refers_to_memory, update_fn_instr, update_global_instr, update_memory_instr,
+    introduces_label, namemap_parser2encoder, refers_to_func, refers_to_global, refers_to_memory,
+    update_fn_instr, update_global_instr, update_memory_instr,
 };
 use crate::opcode::{Inject, Instrumenter};
+use crate::transform::local_compaction::compact_locals;
+use crate::transform::outline::outline_range;
+use crate::transform::peephole::optimize;
+use crate::transform::wrap::wrap_import;
 use crate::{Location, Opcode};
 use log::{error, warn};
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::Infallible;
+use std::fmt;
 use std::vec::IntoIter;
 use wasm_encoder::reencode::{Reencode, RoundtripReencoder};
-use wasm_encoder::TagSection;
+use wasm_encoder::{Encode, TagSection};
 use wasmparser::Operator::Block;
 use wasmparser::{
-    CompositeInnerType, ExternalKind, GlobalType, MemoryType, Operator, Parser, Payload, TagType,
-    TypeRef,
+    BinaryReader, CompositeInnerType, ExternalKind, FunctionBody, GlobalType, MemoryType, Operator,
+    Parser, Payload, TableType, TagType, TypeRef,
 };
 
 pub mod module_exports;
 pub mod module_functions;
 pub mod module_globals;
 pub mod module_imports;
+mod module_injected;
 pub mod module_memories;
 pub mod module_tables;
 pub mod module_types;
 #[cfg(test)]
 mod test;
 
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default)]
 /// Intermediate Representation of a wasm module. See the [WASM Spec] for different sections.
 ///
 /// [WASM Spec]: https://webassembly.github.io/spec/core/binary/modules.html
@@ -81,6 +95,8 @@ pub struct Module<'a> {
     pub tags: Vec<TagType>,
     /// Custom Sections
     pub custom_sections: CustomSections<'a>,
+    /// DWARF debug information, read from the `.debug_*` custom sections
+    pub debug: ModuleDebugData<'a>,
     /// Number of local functions (not counting imported functions)
     pub(crate) num_local_functions: u32,
     /// Number of local globals (not counting imported globals)
@@ -93,16 +109,82 @@ pub struct Module<'a> {
     pub(crate) num_local_memories: u32,
 
     // just a placeholder for round-trip
-    pub(crate) local_names: wasm_encoder::IndirectNameMap,
-    pub(crate) label_names: wasm_encoder::IndirectNameMap,
-    pub(crate) type_names: wasm_encoder::NameMap,
     pub(crate) table_names: wasm_encoder::NameMap,
-    pub(crate) memory_names: wasm_encoder::NameMap,
-    pub(crate) global_names: wasm_encoder::NameMap,
     pub(crate) elem_names: wasm_encoder::NameMap,
     pub(crate) data_names: wasm_encoder::NameMap,
-    pub(crate) field_names: wasm_encoder::IndirectNameMap,
     pub(crate) tag_names: wasm_encoder::NameMap,
+
+    /// Whether to merge locals with non-overlapping live ranges on encode
+    pub(crate) compact_locals: bool,
+    /// Whether to run the peephole optimizer over instrumented functions on encode
+    pub(crate) optimize_instrumentation: bool,
+    /// Whether function exits also run when an exception propagates out of the function
+    pub(crate) exit_on_exception: bool,
+    /// The tag recorded for the instrumentation injected from now on
+    pub(crate) instrumentation_tag: Option<&'a str>,
+    /// The order of the instrumentation layers, outermost first
+    pub(crate) layer_order: Vec<&'a str>,
+}
+
+/// A [`Module`] that owns the binary it is parsed from, see [`Module::from_wat`].
+///
+/// The module borrows the binary from an arena it owns, so it is only lent for as long as the
+/// `OwnedModule` is borrowed, see [`OwnedModule::with_module`].
+#[derive(Debug)]
+pub struct OwnedModule {
+    // dropped before the arena it borrows
+    module: Module<'static>,
+    arena: WatArena,
+}
+
+impl OwnedModule {
+    /// Parses a `Module` from a wasm binary, which it keeps. See [`Module::parse`].
+    pub fn parse(wasm: Vec<u8>, enable_multi_memory: bool) -> Result<Self, Error> {
+        let arena = WatArena::new();
+        // SAFETY: the binaries of the arena are neither moved nor freed before the arena is, which
+        // is dropped after the module. The module is only lent for as long as `self` is borrowed,
+        // so nothing borrowed from the binary outlives the arena.
+        let wasm: &'static [u8] = unsafe { &*(arena.alloc(wasm) as *const [u8]) };
+        let module = Module::parse(wasm, enable_multi_memory)?;
+        Ok(OwnedModule { module, arena })
+    }
+
+    /// The module.
+    pub fn module(&self) -> &Module<'_> {
+        &self.module
+    }
+
+    /// Runs `f` over the module and the arena it borrows from, which keeps the instructions parsed
+    /// by [`Module::parse_wat_instrs`] and [`ModuleIterator::inject_wat`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use orca_wasm::ir::id::FunctionID;
+    /// use orca_wasm::Module;
+    ///
+    /// let mut module = Module::from_wat("(module (func $main (local $x i32)))").unwrap();
+    /// let wasm = module.with_module(|module, arena| {
+    ///     let ops = module
+    ///         .parse_wat_instrs(FunctionID(0), "local.get $x drop", arena)
+    ///         .unwrap();
+    ///     assert_eq!(ops.len(), 2);
+    ///     module.encode()
+    /// });
+    /// assert!(wasmparser::validate(&wasm).is_ok());
+    /// ```
+    ///
+    /// [`ModuleIterator::inject_wat`]: crate::iterator::module_iterator::ModuleIterator::inject_wat
+    pub fn with_module<R>(
+        &mut self,
+        f: impl for<'m> FnOnce(&mut Module<'m>, &'m WatArena) -> R,
+    ) -> R {
+        // SAFETY: as `f` is generic over the lifetime of the module, it can only store in it what
+        // lives as long as the arena: `'static` data or the binaries of the arena.
+        let module =
+            unsafe { &mut *(&mut self.module as *mut Module<'static>).cast::<Module<'_>>() };
+        f(module, &self.arena)
+    }
 }
 
 impl<'a> Module<'a> {
@@ -122,6 +204,25 @@ impl<'a> Module<'a> {
         Module::parse_internal(wasm, enable_multi_memory, parser)
     }
 
+    /// Parses a `Module` from the text format, with multi-memory enabled. The module owns its
+    /// binary.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use orca_wasm::Module;
+    ///
+    /// let module = Module::from_wat("(module (func $main))").unwrap();
+    /// let main = module.module().functions.get_local_fid_by_name("main");
+    /// assert_eq!(main.map(|id| *id), Some(0));
+    /// ```
+    pub fn from_wat(wat: &str) -> Result<OwnedModule, Error> {
+        let wasm = wat::parse_str(wat).map_err(|err| Error::InvalidWat {
+            reason: err.to_string(),
+        })?;
+        OwnedModule::parse(wasm, true)
+    }
+
     pub(crate) fn parse_internal(
         wasm: &'a [u8],
         enable_multi_memory: bool,
@@ -135,26 +236,30 @@ impl<'a> Module<'a> {
         let mut functions = vec![];
         let mut elements = vec![];
         let mut code_section_count = 0;
+        let mut code_section_start = 0;
         let mut code_sections = vec![];
         let mut globals = vec![];
         let mut exports = vec![];
         let mut start = None;
         let mut data_section_count = None;
         let mut custom_sections = vec![];
+        let mut debug_sections = vec![];
+        // the function bodies in the code section, for DWARF: (size field, body)
+        let mut bodies: Vec<(usize, std::ops::Range<usize>)> = vec![];
         let mut tags: Vec<TagType> = vec![];
+        let mut injected = None;
 
         let mut module_name: Option<String> = None;
         // for the other names, we directly encode it without passing them into the IR
-        let mut local_names = wasm_encoder::IndirectNameMap::new();
-        let mut label_names = wasm_encoder::IndirectNameMap::new();
-        let mut type_names = wasm_encoder::NameMap::new();
         let mut table_names = wasm_encoder::NameMap::new();
-        let mut memory_names = wasm_encoder::NameMap::new();
-        let mut global_names = wasm_encoder::NameMap::new();
         let mut elem_names = wasm_encoder::NameMap::new();
         let mut data_names = wasm_encoder::NameMap::new();
-        let mut field_names = wasm_encoder::IndirectNameMap::new();
         let mut tag_names = wasm_encoder::NameMap::new();
+        // the names of the entities created once all the sections are parsed: (index, name)
+        let mut type_names = vec![];
+        let mut field_names = vec![];
+        let mut memory_names = vec![];
+        let mut global_names = vec![];
         let mut recgroup_map = HashMap::new();
 
         for payload in parser.parse_all(wasm) {
@@ -314,12 +419,20 @@ impl<'a> Module<'a> {
                 }
                 Payload::CodeSectionStart {
                     count,
-                    range: _,
+                    range,
                     size: _,
                 } => {
                     code_section_count = count as usize;
+                    code_section_start = range.start;
                 }
                 Payload::CodeSectionEntry(body) => {
+                    let range = body.range().start - code_section_start
+                        ..body.range().end - code_section_start;
+                    let size_start = match bodies.last() {
+                        Some((_, prev)) => prev.end,
+                        None => range.start - leb128_len(range.len()),
+                    };
+                    bodies.push((size_start, range));
                     let locals_reader = body.get_locals_reader()?;
                     let locals = locals_reader.into_iter().collect::<Result<Vec<_>, _>>()?;
                     let mut num_locals = 0;
@@ -331,10 +444,12 @@ impl<'a> Module<'a> {
                         })
                         .collect();
 
-                    let instructions = body
+                    let (instructions, offsets): (Vec<_>, Vec<_>) = body
                         .get_operators_reader()?
+                        .into_iter_with_offsets()
+                        .collect::<Result<Vec<_>, _>>()?
                         .into_iter()
-                        .collect::<Result<Vec<_>, _>>()?;
+                        .unzip();
                     if let Some(last) = instructions.last() {
                         if let Operator::End = last {
                         } else {
@@ -355,14 +470,21 @@ impl<'a> Module<'a> {
                             func_range: body.range(),
                         });
                     }
-                    let instructions_bool: Vec<_> =
-                        instructions.into_iter().map(Instruction::new).collect();
+                    let instructions_bool: Vec<_> = instructions
+                        .into_iter()
+                        .zip(offsets)
+                        .map(|(op, offset)| Instruction {
+                            offset: Some(offset - code_section_start),
+                            ..Instruction::new(op)
+                        })
+                        .collect();
                     code_sections.push(Body {
                         locals,
                         num_locals,
                         instructions: instructions_bool.clone(),
                         num_instructions: instructions_bool.len(),
                         name: None,
+                        local_names: BTreeMap::new(),
                     });
                 }
                 Payload::TagSection(tag_section_reader) => {
@@ -408,22 +530,74 @@ impl<'a> Module<'a> {
                                         module_name = Some(name.to_string());
                                     }
                                     wasmparser::Name::Local(names) => {
-                                        local_names = indirect_namemap_parser2encoder(names);
+                                        for naming in names {
+                                            let naming = naming?;
+                                            let Some(body) = naming
+                                                .index
+                                                .checked_sub(imports.num_funcs)
+                                                .and_then(|idx| {
+                                                    code_sections.get_mut(idx as usize)
+                                                })
+                                            else {
+                                                continue;
+                                            };
+                                            for name in naming.names {
+                                                let name = name?;
+                                                body.local_names.insert(
+                                                    LocalID(name.index),
+                                                    name.name.to_string(),
+                                                );
+                                            }
+                                        }
                                     }
                                     wasmparser::Name::Label(names) => {
-                                        label_names = indirect_namemap_parser2encoder(names);
+                                        for naming in names {
+                                            let naming = naming?;
+                                            let Some(body) = naming
+                                                .index
+                                                .checked_sub(imports.num_funcs)
+                                                .and_then(|idx| {
+                                                    code_sections.get_mut(idx as usize)
+                                                })
+                                            else {
+                                                continue;
+                                            };
+                                            // the labels are numbered in the order of the body
+                                            let mut labels: Vec<&mut Instruction> = body
+                                                .instructions
+                                                .iter_mut()
+                                                .filter(|instr| introduces_label(&instr.op))
+                                                .collect();
+                                            for name in naming.names {
+                                                let name = name?;
+                                                if let Some(instr) =
+                                                    labels.get_mut(name.index as usize)
+                                                {
+                                                    instr.label = Some(name.name.to_string());
+                                                }
+                                            }
+                                        }
                                     }
                                     wasmparser::Name::Type(names) => {
-                                        type_names = namemap_parser2encoder(names);
+                                        for name in names {
+                                            let name = name?;
+                                            type_names.push((name.index, name.name.to_string()));
+                                        }
                                     }
                                     wasmparser::Name::Table(names) => {
                                         table_names = namemap_parser2encoder(names);
                                     }
                                     wasmparser::Name::Memory(names) => {
-                                        memory_names = namemap_parser2encoder(names);
+                                        for name in names {
+                                            let name = name?;
+                                            memory_names.push((name.index, name.name.to_string()));
+                                        }
                                     }
                                     wasmparser::Name::Global(names) => {
-                                        global_names = namemap_parser2encoder(names);
+                                        for name in names {
+                                            let name = name?;
+                                            global_names.push((name.index, name.name.to_string()));
+                                        }
                                     }
                                     wasmparser::Name::Element(names) => {
                                         elem_names = namemap_parser2encoder(names);
@@ -432,7 +606,17 @@ impl<'a> Module<'a> {
                                         data_names = namemap_parser2encoder(names);
                                     }
                                     wasmparser::Name::Field(names) => {
-                                        field_names = indirect_namemap_parser2encoder(names);
+                                        for naming in names {
+                                            let naming = naming?;
+                                            for name in naming.names {
+                                                let name = name?;
+                                                field_names.push((
+                                                    naming.index,
+                                                    name.index,
+                                                    name.name.to_string(),
+                                                ));
+                                            }
+                                        }
                                     }
                                     wasmparser::Name::Tag(names) => {
                                         tag_names = namemap_parser2encoder(names);
@@ -455,6 +639,13 @@ impl<'a> Module<'a> {
                             custom_sections
                                 .push((custom_section_reader.name(), custom_section_reader.data()));
                         }
+                        _ if custom_section_reader.name() == INJECTED_SECTION => {
+                            injected = Some(InjectedRanges::parse(custom_section_reader.data())?);
+                        }
+                        _ if custom_section_reader.name().starts_with(".debug_") => {
+                            debug_sections
+                                .push((custom_section_reader.name(), custom_section_reader.data()));
+                        }
                         _ => {
                             custom_sections
                                 .push((custom_section_reader.name(), custom_section_reader.data()));
@@ -541,6 +732,24 @@ impl<'a> Module<'a> {
             ));
         }
 
+        // Record the pass that injected the instructions of a previous encoding
+        for (func_idx, instr_idx, origin) in injected.iter().flat_map(InjectedRanges::origins) {
+            let instr = match final_funcs
+                .get_mut(func_idx as usize)
+                .map(|func| &mut func.kind)
+            {
+                Some(FuncKind::Local(func)) => func.body.instructions.get_mut(instr_idx as usize),
+                _ => None,
+            };
+            match instr {
+                Some(instr) => instr.instr_flag.origin = Some(origin),
+                None => warn!(
+                    "The {} section refers to a missing instruction {} of function {}",
+                    INJECTED_SECTION, instr_idx, func_idx
+                ),
+            }
+        }
+
         // Process the imported memories
         let mut final_mems = vec![];
         let mut imp_mem_id = 0;
@@ -569,13 +778,31 @@ impl<'a> Module<'a> {
         let num_globals = globals.len() as u32;
         let num_memories = memories.len() as u32;
         let num_tables = tables.len() as u32;
-        let module_globals = ModuleGlobals::new(&imports, globals);
+        let mut module_globals = ModuleGlobals::new(&imports, globals);
+        let mut module_types = ModuleTypes::new(types, recgroup_map);
+        let mut module_memories = Memories::new(final_mems);
+        for (idx, name) in global_names {
+            if (idx as usize) < module_globals.len() {
+                module_globals.set_name(GlobalID(idx), name);
+            }
+        }
+        for (idx, name) in type_names {
+            module_types.set_name(TypeID(idx), name);
+        }
+        for (idx, field, name) in field_names {
+            module_types.set_field_name(TypeID(idx), field, name);
+        }
+        for (idx, name) in memory_names {
+            if module_memories.get_mem_by_id(MemoryID(idx)).is_some() {
+                module_memories.set_name(MemoryID(idx), name);
+            }
+        }
         Ok(Module {
-            types: ModuleTypes::new(types, recgroup_map),
+            types: module_types,
             imports,
             functions: Functions::new(final_funcs),
             tables: ModuleTables::new(tables),
-            memories: Memories::new(final_mems),
+            memories: module_memories,
             globals: module_globals,
             exports: ModuleExports::new(exports),
             start,
@@ -585,21 +812,21 @@ impl<'a> Module<'a> {
             data,
             tags,
             custom_sections: CustomSections::new(custom_sections),
+            debug: ModuleDebugData::new(debug_sections, bodies, code_section_start),
             num_local_functions: code_sections.len() as u32,
             num_local_globals: num_globals,
             num_local_tables: num_tables,
             num_local_memories: num_memories,
             module_name,
-            local_names,
-            type_names,
             table_names,
             elem_names,
-            memory_names,
-            global_names,
             data_names,
-            field_names,
             tag_names,
-            label_names,
+            compact_locals: false,
+            optimize_instrumentation: false,
+            exit_on_exception: false,
+            instrumentation_tag: None,
+            layer_order: vec![],
         })
     }
 
@@ -617,6 +844,62 @@ impl<'a> Module<'a> {
         metadata
     }
 
+    /// The source location of the instruction at `loc`, read from the DWARF line information:
+    /// (file, line, column), the column being 0 when it is not known. `None` for the instructions
+    /// that were not parsed, e.g. injected ones, and for those without line information.
+    pub fn source_location(&self, loc: Location) -> Option<(&str, u32, u32)> {
+        let Location::Module {
+            func_idx,
+            instr_idx,
+        } = loc
+        else {
+            return None;
+        };
+        if *func_idx as usize >= self.functions.len() {
+            return None;
+        }
+        let FuncKind::Local(func) = self.functions.get_kind(func_idx) else {
+            return None;
+        };
+        let offset = func.body.instructions.get(instr_idx)?.offset?;
+        let (file, line, column) = self.debug.lines.lookup(offset as u64)?;
+        Some((&self.debug.lines.files[file as usize], line, column))
+    }
+
+    /// The locations of the parsed instructions on `line` of the source `file`, in order. The file
+    /// is given by its path, or by the end of its path after a `/` (e.g. `foo.rs` for
+    /// `/src/foo.rs`).
+    pub fn locations_of(&self, file: &str, line: u32) -> Vec<Location> {
+        let files = self.debug.lines.find_files(file);
+        if files.is_empty() {
+            return vec![];
+        }
+        let mut locations = vec![];
+        for (func_idx, func) in self.functions.iter().enumerate() {
+            let FuncKind::Local(func) = &func.kind else {
+                continue;
+            };
+            if self.functions.is_deleted(FunctionID(func_idx as u32)) {
+                continue;
+            }
+            for (instr_idx, instr) in func.body.instructions.iter().enumerate() {
+                let Some((file, instr_line, _)) = instr
+                    .offset
+                    .and_then(|offset| self.debug.lines.lookup(offset as u64))
+                else {
+                    continue;
+                };
+                if instr_line == line && files.contains(&file) {
+                    locations.push(Location::Module {
+                        func_idx: FunctionID(func_idx as u32),
+                        instr_idx,
+                    });
+                }
+            }
+        }
+        locations
+    }
+
     /// Emit the module into a wasm binary file.
     pub fn emit_wasm(&mut self, file_name: &str) -> Result<(), std::io::Error> {
         let module = self.encode_internal();
@@ -625,6 +908,29 @@ impl<'a> Module<'a> {
         Ok(())
     }
 
+    /// Encode the module into a wasm binary along with a source map of its code (see
+    /// [`source_map`]), mapping it to the source lines of the DWARF of the parsed binary, or to the
+    /// code of the parsed binary, named `input` in the map, if it has no line information.
+    ///
+    /// The binary gets a `sourceMappingURL` section with `url`, replacing the one of the parsed
+    /// binary.
+    ///
+    /// [`source_map`]: crate::ir::source_map
+    pub fn encode_with_source_map(&mut self, url: &str, input: &str) -> (Vec<u8>, SourceMap) {
+        if let Some(id) = self.custom_sections.get_id(SOURCE_MAPPING_URL.to_string()) {
+            self.custom_sections.delete(id);
+        }
+        let (mut module, offsets) = self.encode_with_offsets();
+        let map = SourceMap::new(&offsets, &self.debug.lines, input, self.debug.code_start);
+        let mut data = vec![];
+        url.encode(&mut data);
+        module.section(&wasm_encoder::CustomSection {
+            name: Cow::Borrowed(SOURCE_MAPPING_URL),
+            data: Cow::Owned(data),
+        });
+        (module.finish(), map)
+    }
+
     /// Encode the module into a wasm binary.
     ///
     /// # Example
@@ -641,6 +947,471 @@ impl<'a> Module<'a> {
         self.encode_internal().finish()
     }
 
+    /// The text format of the module, as it would be encoded, with the instrumentation that is
+    /// still pending marked inline: each injected instruction is commented out as `;; << instr ;;
+    /// mode`, where `mode` is the mode of its body (`before`, `after`, `alternate`,
+    /// `semantic_after`, `block_entry`, `block_exit`, `block_alt`, `func_entry` or `func_exit`), and
+    /// an instruction replaced by an `alternate` body is followed by `;; rm`.
+    ///
+    /// The bodies are printed next to the instruction they are attached to, before the special
+    /// modes are resolved. The module itself is left as it is.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use orca_wasm::Module;
+    ///
+    /// let file = "path_to_file";
+    /// let buff = wat::parse_file(file).expect("couldn't convert the input wat to Wasm");
+    /// let module = Module::parse(&buff, false).unwrap();
+    /// println!("{}", module.to_wat().unwrap());
+    /// ```
+    pub fn to_wat(&self) -> Result<String, Error> {
+        module_to_wat(self)
+    }
+
+    /// Parses the instructions of the text format `wat` to inject them into the function
+    /// `func_idx`, e.g. `"i32.const 1 call $log"`. The names of the types, functions, tables,
+    /// memories, globals, tags, data and element segments of the module, of the locals of the
+    /// function and of the labels defined by the instructions are resolved. The instructions
+    /// borrow their binary, which `arena` keeps.
+    ///
+    /// The instructions are parsed along with the declarations of the module in the text format.
+    /// Only the functions and globals named by the instructions are declared, so the cost of a
+    /// call does not grow with the number of functions and globals of the module.
+    ///
+    /// Fails if the function is not a local function, or if the instructions need a type the
+    /// module does not have, e.g. for a block with parameters.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use orca_wasm::ir::arena::WatArena;
+    /// use orca_wasm::ir::id::FunctionID;
+    /// use orca_wasm::Module;
+    /// use wasmparser::Operator;
+    ///
+    /// let wasm =
+    ///     wat::parse_str(r#"(module (import "env" "log" (func $log (param i32))) (func $main))"#)
+    ///         .unwrap();
+    /// let module = Module::parse(&wasm, false).unwrap();
+    /// let arena = WatArena::new();
+    /// let ops = module
+    ///     .parse_wat_instrs(FunctionID(1), "i32.const 1 call $log", &arena)
+    ///     .unwrap();
+    /// assert_eq!(ops[1], Operator::Call { function_index: 0 });
+    /// ```
+    pub fn parse_wat_instrs(
+        &self,
+        func_idx: FunctionID,
+        wat: &str,
+        arena: &'a WatArena,
+    ) -> Result<Vec<Operator<'a>>, Error> {
+        let invalid = |reason: String| Error::InvalidWat { reason };
+        let Some(Function {
+            kind: FuncKind::Local(func),
+            ..
+        }) = self.functions.get_fn_by_id(func_idx)
+        else {
+            return Err(invalid(format!(
+                "function {} is not a local function",
+                *func_idx
+            )));
+        };
+
+        // the functions and globals that may be named by `wat`
+        let names = wat_identifiers(wat);
+        let named =
+            |name: &Option<String>| name.as_deref().is_some_and(|name| names.contains(name));
+        let funcs: Vec<u32> = (0..self.functions.len() as u32)
+            .filter(|idx| named(self.functions.get_name(FunctionID(*idx))))
+            .collect();
+        let globals: Vec<u32> = (0..self.globals.len() as u32)
+            .filter(|idx| named(self.globals.get_name(GlobalID(*idx))))
+            .collect();
+
+        // `wat` is parsed a second time with the declared functions and globals shifted by one: the
+        // indices that moved refer to them, the others are written as indices in `wat`
+        let body = self.parse_declared_wat(func, &funcs, &globals, false, wat)?;
+        let shifted = self.parse_declared_wat(func, &funcs, &globals, true, wat)?;
+        let mut log = IndexLog::default();
+        for op in holder_ops(&shifted)? {
+            log.instruction(op)
+                .map_err(|err| invalid(err.to_string()))?;
+        }
+        let mut resolve = WatIndices {
+            funcs: &funcs,
+            globals: &globals,
+            shifted_funcs: log.funcs.into_iter(),
+            shifted_globals: log.globals.into_iter(),
+        };
+        let mut bytes = vec![];
+        for op in holder_ops(&body)? {
+            resolve
+                .instruction(op)
+                .map_err(|err| invalid(err.to_string()))?
+                .encode(&mut bytes);
+        }
+
+        let mut reader = BinaryReader::new(arena.alloc(bytes), 0);
+        let mut ops = vec![];
+        while !reader.eof() {
+            ops.push(reader.read_operator()?);
+        }
+        Ok(ops)
+    }
+
+    /// Parses `wat` as the body of a function with the type and the locals of `func`, in a module
+    /// with the declarations of [`Self::wat_declarations`]. Returns the body of the function.
+    fn parse_declared_wat(
+        &self,
+        func: &LocalFunction,
+        funcs: &[u32],
+        globals: &[u32],
+        shifted: bool,
+        wat: &str,
+    ) -> Result<Vec<u8>, Error> {
+        let invalid = |reason: String| Error::InvalidWat { reason };
+        let binary = self.wat_declarations(func, funcs, globals, shifted)?;
+        let text = wasmprinter::print_bytes(&binary).map_err(|err| invalid(err.to_string()))?;
+        // the `nop` of the holder is the only instruction: `wat` takes its place
+        let mut start = 0;
+        let nop = text
+            .split_inclusive('\n')
+            .find_map(|line| {
+                let range = start..start + line.len();
+                start = range.end;
+                (line.trim() == "nop").then_some(range)
+            })
+            .ok_or_else(|| invalid("unexpected declarations".to_string()))?;
+        let wasm = wat::parse_str(format!("{}{wat}\n{}", &text[..nop.start], &text[nop.end..]))
+            .map_err(|err| invalid(err.to_string()))?;
+
+        for payload in Parser::new(0).parse_all(&wasm) {
+            match payload? {
+                Payload::TypeSection(reader) if reader.count() as usize > self.types.len() => {
+                    return Err(invalid(
+                        "the instructions need a type the module does not have".to_string(),
+                    ));
+                }
+                Payload::CodeSectionEntry(body) => return Ok(body.as_bytes().to_vec()),
+                _ => {}
+            }
+        }
+        Err(invalid("unexpected declarations".to_string()))
+    }
+
+    /// A module declaring the types, tables, memories, tags and segments of this one, in the order
+    /// of their IDs and with their names, the functions `funcs` and the globals `globals`, with
+    /// their names, and a function with the type and the locals of `func` holding a `nop`. The
+    /// types and the segments are defined (the segments are empty), the other entities are
+    /// imported. If `shifted`, an unnamed function and global are declared before `funcs` and
+    /// `globals`.
+    fn wat_declarations(
+        &self,
+        func: &LocalFunction,
+        funcs: &[u32],
+        globals: &[u32],
+        shifted: bool,
+    ) -> Result<Vec<u8>, Error> {
+        let invalid = |err: wasm_encoder::reencode::Error| Error::InvalidWat {
+            reason: err.to_string(),
+        };
+        let mut reencode = RoundtripReencoder;
+        let mut types = wasm_encoder::TypeSection::new();
+        let mut type_names = wasm_encoder::NameMap::new();
+        for (idx, ty) in self.types.iter().enumerate() {
+            types.ty().subtype(&self.encode_type(ty));
+            if let Some(name) = self.types.get_name(TypeID(idx as u32)) {
+                type_names.append(idx as u32, name);
+            }
+        }
+        let mut imports = wasm_encoder::ImportSection::new();
+        let mut function_names = wasm_encoder::NameMap::new();
+        if shifted {
+            imports.import("", "", wasm_encoder::EntityType::Function(*func.ty_id));
+        }
+        for (pos, idx) in (shifted as u32..).zip(funcs) {
+            let function = self.functions.get(FunctionID(*idx));
+            imports.import(
+                "",
+                "",
+                wasm_encoder::EntityType::Function(*function.get_type_id()),
+            );
+            if let Some(name) = self.functions.get_name(FunctionID(*idx)) {
+                function_names.append(pos, name);
+            }
+        }
+        let mut global_names = wasm_encoder::NameMap::new();
+        if shifted {
+            imports.import(
+                "",
+                "",
+                wasm_encoder::GlobalType {
+                    val_type: wasm_encoder::ValType::I32,
+                    mutable: false,
+                    shared: false,
+                },
+            );
+        }
+        for (pos, idx) in (shifted as u32..).zip(globals) {
+            let ty = match self.globals.get_kind(GlobalID(*idx)) {
+                GlobalKind::Local(LocalGlobal { ty, .. }) => ty,
+                GlobalKind::Import(ImportedGlobal { ty, .. }) => ty,
+            };
+            imports.import(
+                "",
+                "",
+                wasm_encoder::GlobalType {
+                    val_type: reencode.val_type(ty.content_type).map_err(invalid)?,
+                    mutable: ty.mutable,
+                    shared: ty.shared,
+                },
+            );
+            if let Some(name) = self.globals.get_name(GlobalID(*idx)) {
+                global_names.append(pos, name);
+            }
+        }
+        let mut memory_names = wasm_encoder::NameMap::new();
+        for (idx, memory) in self.memories.iter().enumerate() {
+            imports.import("", "", wasm_encoder::MemoryType::from(memory.ty));
+            if let Some(name) = &memory.name {
+                memory_names.append(idx as u32, name);
+            }
+        }
+        // the imported tables and tags come first
+        let imported_tables = self.imports.iter().filter_map(|import| match import.ty {
+            TypeRef::Table(ty) => Some(ty),
+            _ => None,
+        });
+        for ty in imported_tables.chain(self.tables.iter().map(|(ty, _)| *ty)) {
+            imports.import("", "", reencode.table_type(ty).map_err(invalid)?);
+        }
+        let imported_tags = self.imports.iter().filter_map(|import| match import.ty {
+            TypeRef::Tag(ty) => Some(ty),
+            _ => None,
+        });
+        for ty in imported_tags.chain(self.tags.iter().copied()) {
+            imports.import("", "", reencode.tag_type(ty));
+        }
+
+        let mut functions = wasm_encoder::FunctionSection::new();
+        functions.function(*func.ty_id);
+        let mut elements = wasm_encoder::ElementSection::new();
+        for (_, items) in self.elements.iter() {
+            let ty = match items {
+                ElementItems::Functions(_) => wasm_encoder::RefType::FUNCREF,
+                ElementItems::ConstExprs { ty, .. } => reencode.ref_type(*ty).map_err(invalid)?,
+            };
+            elements.passive(wasm_encoder::Elements::Expressions(ty, Cow::Borrowed(&[])));
+        }
+        let mut code = wasm_encoder::CodeSection::new();
+        let mut holder = wasm_encoder::Function::new(
+            func.body
+                .locals
+                .iter()
+                .map(|(count, ty)| (*count, wasm_encoder::ValType::from(ty))),
+        );
+        holder
+            .instruction(&wasm_encoder::Instruction::Nop)
+            .instruction(&wasm_encoder::Instruction::End);
+        code.function(&holder);
+        let mut data = wasm_encoder::DataSection::new();
+        for _ in self.data.iter() {
+            data.passive([]);
+        }
+        let mut local_names = wasm_encoder::NameMap::new();
+        for (local, name) in func.body.local_names.iter() {
+            local_names.append(**local, name);
+        }
+        let mut locals = wasm_encoder::IndirectNameMap::new();
+        locals.append(funcs.len() as u32 + shifted as u32, &local_names);
+        let mut names = wasm_encoder::NameSection::new();
+        names.functions(&function_names);
+        names.locals(&locals);
+        names.types(&type_names);
+        names.tables(&self.table_names);
+        names.memories(&memory_names);
+        names.globals(&global_names);
+        names.elements(&self.elem_names);
+        names.data(&self.data_names);
+        names.tags(&self.tag_names);
+
+        let mut decls = wasm_encoder::Module::new();
+        decls
+            .section(&types)
+            .section(&imports)
+            .section(&functions)
+            .section(&elements)
+            .section(&code)
+            .section(&data)
+            .section(&names);
+        Ok(decls.finish())
+    }
+
+    /// Enable or disable merging locals of the same type whose live ranges do not overlap when the
+    /// module is encoded (see [`compact_locals`]). Disabled by default.
+    ///
+    /// A merged local keeps the name of the first named local merged into it.
+    ///
+    /// [`compact_locals`]: crate::transform::local_compaction::compact_locals
+    pub fn set_compact_locals(&mut self, enable: bool) {
+        self.compact_locals = enable;
+    }
+
+    /// Enable or disable the peephole and constant-folding optimizer (see [`optimize`]) for functions
+    /// that carry instrumentation, once their instrumentation is resolved on encode. Disabled by
+    /// default. Only the injected code is rewritten: functions without instrumentation, and the
+    /// original instructions of the others, are encoded as they are.
+    ///
+    /// Note that the optimized functions are flattened: after encoding, their injected code is part of
+    /// the plain instructions of the body.
+    ///
+    /// [`optimize`]: crate::transform::peephole::optimize
+    pub fn set_optimize_instrumentation(&mut self, enable: bool) {
+        self.optimize_instrumentation = enable;
+    }
+
+    /// Enable or disable running the function `exit` instrumentation when an exception propagates out
+    /// of the function, including exceptions thrown by its callees. Disabled by default.
+    ///
+    /// When enabled, the body of every function with `exit` instrumentation is wrapped in a `try_table`
+    /// with a `catch_all_ref` that runs the `exit` body and rethrows the exception with `throw_ref`.
+    /// The `exit` body is then no longer injected before `throw`, `rethrow`, `throw_ref` and
+    /// `resume_throw`, which are caught by the wrapper. The instrumented module requires the
+    /// exception-handling proposal.
+    pub fn set_exit_on_exception(&mut self, enable: bool) {
+        self.exit_on_exception = enable;
+    }
+
+    /// Set the tag recorded for the instrumentation injected from now on, usually the name of the
+    /// instrumentation pass. `None` (the default) stops recording.
+    ///
+    /// When the module is encoded, the ranges of instructions injected under a tag are described in
+    /// the [`INJECTED_SECTION`] custom section, which is read back when the module is parsed again.
+    /// The code a pass injected can then be removed with [`Module::strip_instrumentation`].
+    ///
+    /// Only the injected operators are recorded: the code orca generates to resolve the special
+    /// modes (e.g. the `block` wrapping a function with `exit` instrumentation, or the flags of
+    /// `block_exit`) and the functions, globals and locals added by a pass are not.
+    pub fn set_instrumentation_tag(&mut self, tag: Option<&'a str>) {
+        self.instrumentation_tag = tag;
+        for func_idx in 0..self.functions.len() {
+            if let FuncKind::Local(func) =
+                &mut self.functions.get_mut(FunctionID(func_idx as u32)).kind
+            {
+                func.instr_flag.current_tag = tag;
+            }
+        }
+    }
+
+    /// Set the order of the instrumentation layers, outermost first. A layer is the code injected
+    /// under a tag (see [`Module::set_instrumentation_tag`]), usually by one pass.
+    ///
+    /// When the module is encoded, the code that runs before an instruction (`before`,
+    /// `block_entry` and function `entry`) runs the outermost layer first, and the code that runs
+    /// after it (`after`, `semantic_after`, `block_exit` and function `exit`) the innermost layer
+    /// first, so that the outer layers wrap the inner ones whatever the order the passes ran in.
+    /// Untagged code and the layers missing from `layers` are the innermost, and the code of a layer
+    /// keeps the order it was injected in. Without an order (the default), the code runs in the
+    /// order it was injected in.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use orca_wasm::Module;
+    /// # let buff = vec![];
+    /// let mut module = Module::parse(&buff, false).unwrap();
+    /// // the timing probes of the profiler wrap the logging probes of the tracer
+    /// module.set_layer_order(&["profiler", "tracer"]);
+    /// ```
+    pub fn set_layer_order(&mut self, layers: &[&'a str]) {
+        self.layer_order = layers.to_vec();
+    }
+
+    /// Orders the pending instrumentation by layer (see [`Module::set_layer_order`]).
+    pub(crate) fn order_layers(&mut self) {
+        let layers = &self.layer_order;
+        for func_idx in 0..self.functions.len() {
+            if let FuncKind::Local(func) =
+                &mut self.functions.get_mut(FunctionID(func_idx as u32)).kind
+            {
+                func.instr_flag.order_layers(layers);
+                for instr in func.body.instructions.iter_mut() {
+                    instr.instr_flag.order_layers(layers);
+                }
+            }
+        }
+    }
+
+    /// Removes the code injected under `tag` (see [`Module::set_instrumentation_tag`]), both the
+    /// instructions recorded in the [`INJECTED_SECTION`] of a parsed module and the pending
+    /// instrumentation. Returns the number of removed operators.
+    ///
+    /// The code orca generated around the injected code is kept: once the injected operators are
+    /// gone it does not change the semantics of the function. The module is left unchanged and an
+    /// error is returned if the tagged code replaced original instructions (`alternate` and
+    /// `block_alt` instrumentation), as they cannot be restored, or if other instrumentation is
+    /// pending on a tagged instruction.
+    pub fn strip_instrumentation(&mut self, tag: &str) -> Result<usize, Error> {
+        for func in self.functions.iter() {
+            if let FuncKind::Local(func) = &func.kind {
+                if let Some(idx) = func
+                    .body
+                    .instructions
+                    .iter()
+                    .position(|instr| instr.instr_flag.replaced_by(tag))
+                {
+                    return Err(Error::InvalidStrip {
+                        tag: tag.to_string(),
+                        reason: format!(
+                            "it replaced the instruction {} of function {}",
+                            idx, *func.func_id
+                        ),
+                    });
+                }
+                if let Some(idx) = func.body.instructions.iter().position(|instr| {
+                    instr.instr_flag.has_instr()
+                        && instr
+                            .instr_flag
+                            .origin
+                            .is_some_and(|origin| origin.tag == tag)
+                }) {
+                    return Err(Error::InvalidStrip {
+                        tag: tag.to_string(),
+                        reason: format!(
+                            "the instruction {} of function {} carries pending instrumentation",
+                            idx, *func.func_id
+                        ),
+                    });
+                }
+            }
+        }
+
+        let mut removed = 0;
+        for func_idx in 0..self.functions.len() {
+            if let FuncKind::Local(func) =
+                &mut self.functions.get_mut(FunctionID(func_idx as u32)).kind
+            {
+                removed += func.instr_flag.strip_tag(tag);
+                let instructions = &mut func.body.instructions;
+                let len = instructions.len();
+                instructions.retain(|instr| {
+                    instr
+                        .instr_flag
+                        .origin
+                        .is_none_or(|origin| origin.tag != tag)
+                });
+                removed += len - instructions.len();
+                for instr in instructions.iter_mut() {
+                    removed += instr.instr_flag.strip_tag(tag);
+                }
+                func.body.num_instructions = instructions.len();
+            }
+        }
+        Ok(removed)
+    }
+
     /// Visits the Orca Module and resolves the special instrumentation by
     /// translating them into the straightforward before/after/alt modes.
     fn resolve_special_instrumentation(&mut self) {
@@ -656,6 +1427,7 @@ impl<'a> Module<'a> {
 
                 let mut instr_func_on_entry = None;
                 let mut instr_func_on_exit = None;
+                let mut exit_results = vec![];
                 if let FuncKind::Local(LocalFunction { instr_flag, .. }) =
                     self.functions.get_kind_mut(func_idx)
                 {
@@ -666,17 +1438,26 @@ impl<'a> Module<'a> {
 
                     // save off the function entry/exit special mode bodies
                     if !instr_flag.entry.is_empty() {
-                        instr_func_on_entry = Some(instr_flag.entry.to_owned());
-                        instr_flag.entry = vec![];
+                        instr_func_on_entry = Some(InstrBody::new(
+                            std::mem::take(&mut instr_flag.entry),
+                            std::mem::take(&mut instr_flag.entry_tags),
+                        ));
                     }
                     if !instr_flag.exit.is_empty() {
-                        instr_func_on_exit = Some(instr_flag.exit.to_owned());
-                        instr_flag.exit = vec![];
+                        instr_func_on_exit = Some(InstrBody::new(
+                            std::mem::take(&mut instr_flag.exit),
+                            std::mem::take(&mut instr_flag.exit_tags),
+                        ));
+                        exit_results = std::mem::take(&mut instr_flag.exit_results);
                     }
                 }
 
                 // initialize with 0 to store the func block!
                 let mut block_stack: Vec<BlockID> = vec![0];
+                // the result types of the blocks on the stack, to type the flag checks
+                let func_ty = self.functions.get_type_id(func_idx);
+                let mut block_results: Vec<Vec<DataType>> =
+                    vec![self.types.get(func_ty).unwrap().results()];
                 let mut delete_block: Option<BlockID> = None;
                 let mut retain_end = true;
                 let mut resolve_on_else_or_end: HashMap<InstrumentationMode, InstrToInject> =
@@ -690,7 +1471,7 @@ impl<'a> Module<'a> {
                         let on_entry = if let Some(on_entry) = &mut instr_func_on_entry {
                             on_entry
                         } else {
-                            let on_entry = vec![];
+                            let on_entry = InstrBody::default();
                             instr_func_on_entry = Some(on_entry);
                             if let Some(ref mut on_entry) = instr_func_on_entry {
                                 on_entry
@@ -702,10 +1483,17 @@ impl<'a> Module<'a> {
                         let func_ty = self.functions.get_type_id(func_idx);
                         let func_results = self.types.get(func_ty).unwrap().results();
                         let block_ty = self.types.add_func_type(&[], &func_results);
-                        resolve_function_exit_with_block_wrapper(on_entry, block_ty);
+                        resolve_function_exit_with_block_wrapper(
+                            on_entry,
+                            block_ty,
+                            self.exit_on_exception,
+                        );
                     }
                 }
                 let mut builder = self.functions.get_fn_modifier(func_idx).unwrap();
+                // the code generated to resolve the special modes has no tag,
+                // the resolved bodies keep the tags of their operators
+                let saved_tag = builder.instr_flag.current_tag.take();
 
                 // Must make copy to be able to iterate over body while calling builder.* methods that mutate the instrumentation flag!
                 let readable_copy_of_body = builder.body.instructions.clone();
@@ -714,6 +1502,7 @@ impl<'a> Module<'a> {
                     Instruction {
                         op,
                         instr_flag: instrumentation,
+                        ..
                     },
                 ) in readable_copy_of_body.iter().enumerate()
                 {
@@ -725,22 +1514,32 @@ impl<'a> Module<'a> {
                     }
                     if let Some(on_exit) = &mut instr_func_on_exit {
                         if !on_exit.is_empty() {
-                            resolve_function_exit(on_exit, &mut builder, op, idx);
+                            resolve_function_exit(
+                                on_exit,
+                                &exit_results,
+                                self.exit_on_exception,
+                                &mut builder,
+                                op,
+                                idx,
+                            );
                         }
                     }
 
                     // resolve instruction-level instrumentation
                     match op {
-                        Operator::Block { .. } | Operator::Loop { .. } | Operator::If { .. } => {
+                        Operator::Block { blockty }
+                        | Operator::Loop { blockty }
+                        | Operator::If { blockty } => {
                             // The block ID will just be the curr len of the stack!
                             block_stack.push(block_stack.len() as u32);
+                            block_results.push(results_of_block(&self.types, blockty));
 
                             // Handle block alt
                             if let Some(block_alt) = &instrumentation.block_alt {
                                 // only plan to handle if we're not already removing the block this instr is in
                                 if delete_block.is_none()
                                     && plan_resolution_block_alt(
-                                        block_alt,
+                                        &InstrBody::of(instrumentation, block_alt, BlockAlt),
                                         &mut builder,
                                         &mut retain_end,
                                         op,
@@ -773,7 +1572,14 @@ impl<'a> Module<'a> {
                             // necessary for if statements with block_exit instrumentation
                             for (mode, instr_to_inject) in resolve_on_else_or_end.iter() {
                                 // resolve bodies at the else
-                                resolve_bodies(&mut builder, mode, instr_to_inject, idx);
+                                resolve_bodies(
+                                    &mut builder,
+                                    &mut self.types,
+                                    mode,
+                                    instr_to_inject,
+                                    block_results.last().unwrap(),
+                                    idx,
+                                );
                             }
                             resolve_on_else_or_end.clear();
 
@@ -782,7 +1588,7 @@ impl<'a> Module<'a> {
                                 // only plan to handle if we're not already removing the block this instr is in
                                 if delete_block.is_none()
                                     && plan_resolution_block_alt(
-                                        block_alt,
+                                        &InstrBody::of(instrumentation, block_alt, BlockAlt),
                                         &mut builder,
                                         &mut retain_end,
                                         op,
@@ -814,6 +1620,7 @@ impl<'a> Module<'a> {
                         Operator::End => {
                             // Pop the stack and check to see if we have instrumentation to inject!
                             if let Some(block_id) = block_stack.pop() {
+                                let results = block_results.pop().unwrap_or_default();
                                 if let Some(delete_block_id) = delete_block.as_mut() {
                                     // Delete the block, but don't remove the end if we say not to
                                     // should still process instrumentation on the end though...
@@ -845,7 +1652,14 @@ impl<'a> Module<'a> {
                                 // we've reached an end, make sure resolve_on_else is cleared!
                                 // resolve bodies for else OR end
                                 for (mode, instr_to_inject) in resolve_on_else_or_end.iter() {
-                                    resolve_bodies(&mut builder, mode, instr_to_inject, idx);
+                                    resolve_bodies(
+                                        &mut builder,
+                                        &mut self.types,
+                                        mode,
+                                        instr_to_inject,
+                                        &results,
+                                        idx,
+                                    );
                                 }
                                 resolve_on_else_or_end.clear();
 
@@ -854,7 +1668,14 @@ impl<'a> Module<'a> {
                                 if let Some(to_resolve) = resolve_on_end.remove(&block_id) {
                                     for (mode, instr_to_inject) in to_resolve.iter() {
                                         // resolve bodies at the end
-                                        resolve_bodies(&mut builder, mode, instr_to_inject, idx);
+                                        resolve_bodies(
+                                            &mut builder,
+                                            &mut self.types,
+                                            mode,
+                                            instr_to_inject,
+                                            &results,
+                                            idx,
+                                        );
                                     }
                                 }
                             }
@@ -885,8 +1706,14 @@ impl<'a> Module<'a> {
                             after: _,
                             alternate: _,
                             current_mode: _,
+                            tags: _,
+                            origin: _,
                             // exhaustive to help identify where to add code to handle other special modes.
                         } = instrumentation;
+                        let block_entry = &InstrBody::of(instrumentation, block_entry, BlockEntry);
+                        let block_exit = &InstrBody::of(instrumentation, block_exit, BlockExit);
+                        let semantic_after =
+                            &InstrBody::of(instrumentation, semantic_after, SemanticAfter);
 
                         // Handle block entry
                         if !block_entry.is_empty() {
@@ -938,6 +1765,7 @@ impl<'a> Module<'a> {
                         }
                     }
                 }
+                builder.instr_flag.current_tag = saved_tag;
             }
         }
     }
@@ -1101,6 +1929,17 @@ impl<'a> Module<'a> {
     /// Encodes an Orca Module to a wasm_encoder Module.
     /// This requires a mutable reference to self due to the special instrumentation resolution step.
     pub(crate) fn encode_internal(&mut self) -> wasm_encoder::Module {
+        self.encode_with_offsets().0
+    }
+
+    /// Like [`encode_internal`], also returning where the code of the encoded module comes from.
+    ///
+    /// [`encode_internal`]: Module::encode_internal
+    fn encode_with_offsets(&mut self) -> (wasm_encoder::Module, CodeOffsets) {
+        // The layers are ordered in the bodies of every mode, before they are merged when resolved
+        if !self.layer_order.is_empty() {
+            self.order_layers();
+        }
         // First resolve any instrumentation that needs to be translated to before/after/alt
         self.resolve_special_instrumentation();
 
@@ -1391,6 +2230,12 @@ impl<'a> Module<'a> {
             module.section(&tags);
         }
 
+        let mut injected = InjectedRanges::default();
+        // where the code of the parsed binary is encoded, to rewrite the debug information
+        let mut addresses = AddressMap::default();
+        let mut offsets = CodeOffsets::default();
+        let mut local_names = wasm_encoder::IndirectNameMap::new();
+        let mut label_names = wasm_encoder::IndirectNameMap::new();
         if !self.num_local_functions > 0 {
             let mut code = wasm_encoder::CodeSection::new();
             for rel_func_idx in 0..self.functions.len() {
@@ -1407,23 +2252,57 @@ impl<'a> Module<'a> {
                     .functions
                     .get_mut(FunctionID(rel_func_idx as u32))
                     .unwrap_local_mut();
+                if self.optimize_instrumentation
+                    && func
+                        .body
+                        .instructions
+                        .iter()
+                        .any(|instr| instr.instr_flag.has_instr())
+                {
+                    optimize(&mut func.body);
+                }
+                if self.compact_locals {
+                    compact_locals(func);
+                }
                 let Body {
                     instructions,
                     locals,
                     name,
+                    local_names: func_local_names,
                     ..
                 } = &mut func.body;
                 let mut converted_locals = Vec::with_capacity(locals.len());
                 for (c, ty) in locals {
                     converted_locals.push((*c, wasm_encoder::ValType::from(&*ty)));
                 }
+                // the body of the parsed binary, found with the final `end` (the instructions
+                // outlined from a body keep their offsets), and the encoded offsets of the runs of
+                // code of each instruction: (encoded, parsed)
+                let from_body =
+                    instructions
+                        .last()
+                        .and_then(|instr| instr.offset)
+                        .and_then(|offset| {
+                            let bodies = &self.debug.bodies;
+                            let idx = bodies.partition_point(|(_, body)| body.start <= offset);
+                            bodies[..idx].last().filter(|(_, body)| offset < body.end)
+                        });
+                let mut runs = vec![];
                 let mut function = wasm_encoder::Function::new(converted_locals);
                 let instr_len = instructions.len() - 1;
+                let final_func_idx = rel_func_idx as u32;
+                // the number of instructions encoded so far, to record the injected ranges
+                let mut encoded = 0;
+                // the names of the labels, numbered in the order they are encoded
+                let mut labels = wasm_encoder::NameMap::new();
+                let mut num_labels = 0;
                 for (
                     idx,
                     Instruction {
                         op,
                         instr_flag: instrument,
+                        offset,
+                        label,
                     },
                 ) in instructions.iter_mut().enumerate()
                 {
@@ -1437,7 +2316,13 @@ impl<'a> Module<'a> {
                         update_memory_instr(op, &memory_mapping);
                     }
                     if !instrument.has_instr() {
+                        if let Some(origin) = instrument.origin {
+                            injected.record(final_func_idx, encoded, origin);
+                        }
+                        runs.push((function.byte_len(), *offset));
+                        name_label(op, label, &mut labels, &mut num_labels);
                         encode(&op.clone(), &mut function, &mut reencode);
+                        encoded += 1;
                     } else {
                         // this instruction has instrumentation, handle it!
                         let InstrumentationFlag {
@@ -1449,6 +2334,8 @@ impl<'a> Module<'a> {
                             block_entry,
                             block_exit,
                             block_alt,
+                            tags,
+                            origin,
                         } = instrument;
 
                         // Check if special instrumentation modes have been resolved!
@@ -1468,6 +2355,16 @@ impl<'a> Module<'a> {
                         let at_end = idx >= instr_len;
 
                         // First encode before instructions
+                        record_injected(
+                            &mut injected,
+                            final_func_idx,
+                            &mut encoded,
+                            tags,
+                            InstrumentationMode::Before,
+                            before.len(),
+                        );
+                        runs.push((function.byte_len(), None));
+                        num_labels += count_labels(before);
                         update_ids_and_encode(
                             before,
                             &func_mapping,
@@ -1480,6 +2377,16 @@ impl<'a> Module<'a> {
                         // If there are any alternate, encode the alternate
                         if !at_end && !alternate.is_none() {
                             if let Some(alt) = alternate {
+                                record_injected(
+                                    &mut injected,
+                                    final_func_idx,
+                                    &mut encoded,
+                                    tags,
+                                    InstrumentationMode::Alternate,
+                                    alt.len(),
+                                );
+                                runs.push((function.byte_len(), None));
+                                num_labels += count_labels(alt);
                                 update_ids_and_encode(
                                     alt,
                                     &func_mapping,
@@ -1490,11 +2397,27 @@ impl<'a> Module<'a> {
                                 );
                             }
                         } else {
+                            if let Some(origin) = origin {
+                                injected.record(final_func_idx, encoded, *origin);
+                            }
+                            runs.push((function.byte_len(), *offset));
+                            name_label(op, label, &mut labels, &mut num_labels);
                             encode(&op.clone(), &mut function, &mut reencode);
+                            encoded += 1;
                         }
 
                         // Now encode the after instructions
                         if !at_end {
+                            record_injected(
+                                &mut injected,
+                                final_func_idx,
+                                &mut encoded,
+                                tags,
+                                InstrumentationMode::After,
+                                after.len(),
+                            );
+                            runs.push((function.byte_len(), None));
+                            num_labels += count_labels(after);
                             update_ids_and_encode(
                                 after,
                                 &func_mapping,
@@ -1506,6 +2429,47 @@ impl<'a> Module<'a> {
                         }
                     }
 
+                    /// Records the tagged instructions of the `len` instructions of the body of
+                    /// `mode` that are encoded next.
+                    fn record_injected<'a>(
+                        injected: &mut InjectedRanges<'a>,
+                        func_idx: u32,
+                        encoded: &mut u32,
+                        tags: &[(InstrumentationMode, BodyTags<'a>)],
+                        mode: InstrumentationMode,
+                        len: usize,
+                    ) {
+                        let body_tags = tags.iter().find(|(body_mode, _)| *body_mode == mode);
+                        for idx in 0..len {
+                            if let Some(tag) = body_tags.and_then(|(_, tags)| tags.tag_of(idx)) {
+                                let origin = Origin {
+                                    tag,
+                                    replaces_original: mode == InstrumentationMode::Alternate,
+                                };
+                                injected.record(func_idx, *encoded, origin);
+                            }
+                            *encoded += 1;
+                        }
+                    }
+                    /// Names the label introduced by the instruction `op` of the body, if any,
+                    /// counting the labels encoded so far in `num_labels`.
+                    fn name_label(
+                        op: &Operator,
+                        label: &Option<String>,
+                        labels: &mut wasm_encoder::NameMap,
+                        num_labels: &mut u32,
+                    ) {
+                        if introduces_label(op) {
+                            if let Some(label) = label {
+                                labels.append(*num_labels, label);
+                            }
+                            *num_labels += 1;
+                        }
+                    }
+                    /// The number of labels introduced by the injected `instrs`.
+                    fn count_labels(instrs: &[Operator]) -> u32 {
+                        instrs.iter().filter(|op| introduces_label(op)).count() as u32
+                    }
                     fn update_ids_and_encode(
                         instrs: &mut Vec<Operator>,
                         func_mapping: &HashMap<u32, u32>,
@@ -1542,8 +2506,39 @@ impl<'a> Module<'a> {
                 if let Some(name) = name {
                     function_names.append(rel_func_idx as u32, name.as_str());
                 }
+                if !func_local_names.is_empty() {
+                    let mut names = wasm_encoder::NameMap::new();
+                    for (local, name) in func_local_names.iter() {
+                        names.append(**local, name);
+                    }
+                    local_names.append(rel_func_idx as u32, &names);
+                }
+                if !labels.is_empty() {
+                    label_names.append(rel_func_idx as u32, &labels);
+                }
+                let entry = code.byte_len();
                 code.function(&function);
+                let start = code.byte_len() - function.byte_len();
+                if let Some((size, body)) = from_body {
+                    addresses.add_body((*size, body.clone()), (entry, start..code.byte_len()));
+                    // the instructions outlined from another function are not part of the body
+                    for (to, from) in runs.iter() {
+                        if let Some(from) = from.filter(|from| body.contains(from)) {
+                            addresses.add_instr(from, start + to);
+                        }
+                    }
+                }
+                for (to, from) in runs {
+                    offsets.add(start + to, from);
+                }
             }
+            let count_len = leb128_len(code.len() as usize);
+            addresses.shift(count_len);
+            addresses.finish();
+            offsets.shift(count_len);
+            // after the id and the size of the section
+            offsets.code_start =
+                module.as_slice().len() + 1 + leb128_len(count_len + code.byte_len());
             module.section(&code);
         }
 
@@ -1577,20 +2572,53 @@ impl<'a> Module<'a> {
         if let Some(module_name) = &self.module_name {
             names.module(module_name);
         }
+        // the names kept on the entities are encoded with their final indices
+        let mut type_names = wasm_encoder::NameMap::new();
+        for (ty, name) in self.types.names.iter() {
+            type_names.append(**ty, name);
+        }
+        let mut field_names = wasm_encoder::IndirectNameMap::new();
+        for (ty, fields) in self.types.field_names.iter() {
+            let mut names = wasm_encoder::NameMap::new();
+            for (field, name) in fields.iter() {
+                names.append(*field, name);
+            }
+            field_names.append(**ty, &names);
+        }
+        let mut memory_names = wasm_encoder::NameMap::new();
+        for (idx, memory) in self.memories.iter().enumerate() {
+            if let Some(name) = &memory.name {
+                memory_names.append(idx as u32, name);
+            }
+        }
+        let mut global_names = wasm_encoder::NameMap::new();
+        for (idx, global) in self.globals.iter().enumerate() {
+            if let Some(name) = &global.name {
+                global_names.append(idx as u32, name);
+            }
+        }
+
         names.functions(&function_names);
-        names.locals(&self.local_names);
-        names.labels(&self.label_names);
-        names.types(&self.type_names);
+        names.locals(&local_names);
+        names.labels(&label_names);
+        names.types(&type_names);
         names.tables(&self.table_names);
-        names.memories(&self.memory_names);
-        names.globals(&self.global_names);
+        names.memories(&memory_names);
+        names.globals(&global_names);
         names.elements(&self.elem_names);
         names.data(&self.data_names);
-        names.fields(&self.field_names);
+        names.fields(&field_names);
         names.tag(&self.tag_names);
 
         module.section(&names);
 
+        if !injected.is_empty() {
+            module.section(&wasm_encoder::CustomSection {
+                name: Cow::Borrowed(INJECTED_SECTION),
+                data: Cow::Owned(injected.encode()),
+            });
+        }
+
         // encode the rest of custom sections
         for section in self.custom_sections.iter() {
             module.section(&wasm_encoder::CustomSection {
@@ -1599,7 +2627,30 @@ impl<'a> Module<'a> {
             });
         }
 
-        module
+        // the debug information refers to the moved code
+        if !self.debug.is_empty() {
+            match self.debug.encode(&addresses) {
+                Ok(sections) => {
+                    for (name, data) in sections {
+                        module.section(&wasm_encoder::CustomSection {
+                            name: Cow::Owned(name),
+                            data: Cow::Owned(data),
+                        });
+                    }
+                }
+                Err(e) => {
+                    warn!("{}, the debug sections are encoded unchanged", e);
+                    for (name, data) in self.debug.sections() {
+                        module.section(&wasm_encoder::CustomSection {
+                            name: Cow::Borrowed(name),
+                            data: Cow::Borrowed(data),
+                        });
+                    }
+                }
+            }
+        }
+
+        (module, offsets)
     }
 
     /// Add a new Data Segment to the module.
@@ -1610,6 +2661,14 @@ impl<'a> Module<'a> {
         DataSegmentID(index as u32)
     }
 
+    /// Add a new Element Segment to the module.
+    /// Returns the index of the new Element Segment in the Element Section.
+    pub fn add_element(&mut self, kind: ElementKind<'a>, items: ElementItems<'a>) -> ElementID {
+        let index = self.elements.len();
+        self.elements.push((kind, items));
+        ElementID(index as u32)
+    }
+
     /// Get the memory ID of a module. Does not support multiple memories
     pub fn get_memory_id(&self) -> Option<MemoryID> {
         if self.memories.len() > 1 {
@@ -1656,6 +2715,18 @@ impl<'a> Module<'a> {
         (id, self.imports.add(import))
     }
 
+    // ==========================
+    // ==== Table Management ====
+    // ==========================
+
+    /// Add a new locally-defined table of type `ty`, whose elements are initialized to null.
+    /// Returns its ID, which follows the imported tables in the table index space.
+    pub fn add_local_table(&mut self, ty: TableType) -> TableID {
+        self.tables.add(ty, None);
+        self.num_local_tables += 1;
+        TableID(self.imports.num_tables + self.num_local_tables - 1)
+    }
+
     // ===========================
     // ==== Memory Management ====
     // ===========================
@@ -1708,17 +2779,64 @@ impl<'a> Module<'a> {
         body: Body<'a>,
     ) -> FunctionID {
         let ty = self.types.add_func_type(params, results);
-        let local_func = LocalFunction::new(
+        let mut local_func = LocalFunction::new(
             ty,
             FunctionID(0), // will be fixed
             body,
             params.len(),
         );
+        local_func.instr_flag.current_tag = self.instrumentation_tag;
 
         self.num_local_functions += 1;
         self.functions.add_local_func(local_func, name.clone())
     }
 
+    /// Mark the local function `func_id` to inject at its exit, like [`Instrumenter::func_exit`], and
+    /// get the locals holding its return values (in the order of the results of its type) while the
+    /// injected body runs. Returns `None` if the function is imported.
+    ///
+    /// At a `return` and at the end of the function, the results are spilled into the locals before the
+    /// `exit` body and pushed back after it. The `exit` body must leave the operand stack as it found
+    /// it. The locals are not set when the function leaves through a tail call, a trap or an exception.
+    ///
+    /// The mode is reset by [`Functions::get_fn_modifier`]: a function modifier obtained afterwards
+    /// injects at the exit after calling [`Instrumenter::func_exit`].
+    pub fn func_exit_with_results(&mut self, func_id: FunctionID) -> Option<Vec<LocalID>> {
+        let ty_id = self.functions.get_type_id(func_id);
+        let results = self.types.get(ty_id)?.results();
+        match &mut self.functions.get_mut(func_id).kind {
+            FuncKind::Local(func) => Some(func.func_exit_with_results(&results)),
+            FuncKind::Import(_) => None,
+        }
+    }
+
+    /// Move the single-entry single-exit instruction `range` of the local function `func_id` into a
+    /// new function and replace it with a `call`. Returns the ID of the new function.
+    /// See [`outline_range`] for the requirements on the range.
+    ///
+    /// [`outline_range`]: crate::transform::outline::outline_range
+    pub fn outline(
+        &mut self,
+        func_id: FunctionID,
+        range: std::ops::Range<usize>,
+        name: Option<String>,
+    ) -> Result<FunctionID, Error> {
+        outline_range(self, func_id, range, name)
+    }
+
+    /// Redirect the calls to the imported function `import` through a new wrapper function, whose
+    /// `entry` and `exit` instrumentation hook the calls. Returns the ID of the wrapper.
+    /// See [`wrap_import`] for the calls that are redirected.
+    ///
+    /// [`wrap_import`]: crate::transform::wrap::wrap_import
+    pub fn wrap_import(
+        &mut self,
+        import: FunctionID,
+        name: Option<String>,
+    ) -> Result<FunctionID, Error> {
+        wrap_import(self, import, name)
+    }
+
     /// Add a new function to the module, returns:
     ///
     /// - FunctionID: The ID that indexes into the function ID space. To be used when referring to the function, like in `call`.
@@ -1854,6 +2972,7 @@ impl<'a> Module<'a> {
                 init_expr,
             }),
             deleted: false,
+            name: None,
         })
     }
 
@@ -1909,6 +3028,87 @@ impl<'a> Module<'a> {
     }
 }
 
+/// The identifiers of the text format `wat`, without their `$`.
+fn wat_identifiers(wat: &str) -> HashSet<&str> {
+    let is_idchar = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-./:<=>?@\\^_`|~".contains(c);
+    wat.match_indices('$')
+        .filter_map(|(pos, _)| {
+            let id = &wat[pos + 1..];
+            let len = id.find(|c| !is_idchar(c)).unwrap_or(id.len());
+            (len > 0).then(|| &id[..len])
+        })
+        .collect()
+}
+
+/// The instructions of the body of a function, without its final `end`.
+fn holder_ops(body: &[u8]) -> Result<Vec<Operator<'_>>, Error> {
+    let mut reader = FunctionBody::new(BinaryReader::new(body, 0)).get_operators_reader()?;
+    let mut ops = vec![];
+    while !reader.eof() {
+        ops.push(reader.read()?);
+    }
+    ops.pop();
+    Ok(ops)
+}
+
+/// Records the function and global indices of the instructions it reencodes.
+#[derive(Default)]
+struct IndexLog {
+    funcs: Vec<u32>,
+    globals: Vec<u32>,
+}
+
+impl Reencode for IndexLog {
+    type Error = Infallible;
+
+    fn function_index(&mut self, func: u32) -> u32 {
+        self.funcs.push(func);
+        func
+    }
+
+    fn global_index(&mut self, global: u32) -> u32 {
+        self.globals.push(global);
+        global
+    }
+}
+
+/// Maps the indices of the functions and globals declared by [`Module::wat_declarations`] to the
+/// ones of the module, given the indices of the same instructions parsed with them shifted.
+struct WatIndices<'d> {
+    funcs: &'d [u32],
+    globals: &'d [u32],
+    shifted_funcs: IntoIter<u32>,
+    shifted_globals: IntoIter<u32>,
+}
+
+impl WatIndices<'_> {
+    fn resolve(idx: u32, shifted: Option<u32>, declared: &[u32]) -> u32 {
+        match shifted {
+            Some(shifted) if shifted != idx => declared[idx as usize],
+            _ => idx,
+        }
+    }
+}
+
+impl Reencode for WatIndices<'_> {
+    type Error = Infallible;
+
+    fn function_index(&mut self, func: u32) -> u32 {
+        Self::resolve(func, self.shifted_funcs.next(), self.funcs)
+    }
+
+    fn global_index(&mut self, global: u32) -> u32 {
+        Self::resolve(global, self.shifted_globals.next(), self.globals)
+    }
+}
+
+impl fmt::Display for Module<'_> {
+    /// See [`Module::to_wat`].
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_wat().map_err(|_| fmt::Error)?)
+    }
+}
+
 pub trait GetID {
     fn get_id(&self) -> u32;
 }
@@ -1944,7 +3144,50 @@ pub trait LocalOrImport {
 // ================================
 
 type BlockID = u32;
-type InstrBody<'a> = Vec<Operator<'a>>;
+/// A body of injected operators with their tags
+#[derive(Clone, Default)]
+struct InstrBody<'a> {
+    ops: Vec<Operator<'a>>,
+    tags: BodyTags<'a>,
+}
+impl<'a> InstrBody<'a> {
+    fn new(ops: Vec<Operator<'a>>, tags: BodyTags<'a>) -> Self {
+        InstrBody { ops, tags }
+    }
+    /// The body of `mode` of an instruction
+    fn of(flag: &InstrumentationFlag<'a>, ops: &[Operator<'a>], mode: InstrumentationMode) -> Self {
+        InstrBody {
+            ops: ops.to_vec(),
+            tags: flag.tags_of(mode).cloned().unwrap_or_default(),
+        }
+    }
+    fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+    fn clear(&mut self) {
+        self.ops.clear();
+        self.tags.clear();
+    }
+    /// Appends an operator without a tag
+    fn push(&mut self, op: Operator<'a>) {
+        self.tags.record(self.ops.len(), None);
+        self.ops.push(op);
+    }
+}
+
+/// Injects `body` at the current location of `builder`, keeping the tags of its operators.
+fn inject_body<'a, 'b, 'c>(builder: &mut FunctionModifier<'a, 'b>, body: &InstrBody<'c>)
+where
+    'c: 'b,
+{
+    let tag = builder.instr_flag.current_tag;
+    for (idx, op) in body.ops.iter().enumerate() {
+        builder.instr_flag.current_tag = body.tags.tag_of(idx);
+        builder.inject(op.to_owned());
+    }
+    builder.instr_flag.current_tag = tag;
+}
+
 struct InstrBodyFlagged<'a> {
     body: InstrBody<'a>,
     bool_flag: LocalID,
@@ -1967,7 +3210,7 @@ fn resolve_function_entry<'a, 'b, 'c>(
             func_idx: FunctionID(0), // not used
             instr_idx: idx,
         });
-        builder.inject_all(instr_func_on_entry);
+        inject_body(builder, instr_func_on_entry);
 
         // remove the contents of the body now that it's been resolved
         instr_func_on_entry.clear();
@@ -1977,6 +3220,7 @@ fn resolve_function_entry<'a, 'b, 'c>(
 fn resolve_function_exit_with_block_wrapper<'a, 'b, 'c>(
     instr_func_on_entry: &mut InstrBody<'c>,
     block_ty: TypeID,
+    catch_exceptions: bool,
 ) where
     'c: 'b,
 {
@@ -1990,9 +3234,28 @@ fn resolve_function_exit_with_block_wrapper<'a, 'b, 'c>(
     instr_func_on_entry.push(Block {
         blockty: wasmparser::BlockType::from(BlockType::FuncType(block_ty)),
     });
+
+    // To handle exceptions:
+    // Wrap the body in a `try_table` whose `catch_all_ref` branches to
+    // a block ending with a copy of `exit` and a `throw_ref`.
+    // Branches to the function label now target the `try_table`,
+    // which is followed by a `br` to the outer wrapper block.
+    if catch_exceptions {
+        instr_func_on_entry.push(Block {
+            blockty: wasmparser::BlockType::Type(wasmparser::ValType::EXNREF),
+        });
+        instr_func_on_entry.push(Operator::TryTable {
+            try_table: wasmparser::TryTable {
+                ty: wasmparser::BlockType::from(BlockType::FuncType(block_ty)),
+                catches: vec![wasmparser::Catch::AllRef { label: 0 }],
+            },
+        });
+    }
 }
 fn resolve_function_exit<'a, 'b, 'c>(
     instr_func_on_exit: &mut InstrBody<'c>,
+    exit_results: &[LocalID],
+    catch_exceptions: bool,
     builder: &mut FunctionModifier<'a, 'b>,
     op: &Operator,
     idx: usize,
@@ -2012,8 +3275,27 @@ fn resolve_function_exit<'a, 'b, 'c>(
     // Place a copy of `exit` BEFORE the `throw_ref`
     // Place a copy of `exit` BEFORE the `resume_throw`
 
+    // If the results are observed, they are spilled into `exit_results` around the copy of `exit`
+    // placed at a `return` and at the end of the function (where the results are on the stack).
+
+    // If exceptions are caught by the wrapper, a thrown exception runs
+    // the copy of `exit` in the handler instead.
+
     // convert instr to simple before/after/alt
     match op {
+        Operator::Throw { .. }
+        | Operator::Rethrow { .. }
+        | Operator::ThrowRef
+        | Operator::ResumeThrow { .. }
+            if catch_exceptions => {}
+        Operator::Return if !exit_results.is_empty() => {
+            builder.before_at(Location::Module {
+                func_idx: FunctionID(0), // not used
+                instr_idx: idx,
+            });
+            inject_observing_results(builder, instr_func_on_exit, exit_results);
+            return;
+        }
         // handle returns
         Operator::Return { .. } |
             Operator::ReturnCall {..} |
@@ -2031,7 +3313,7 @@ fn resolve_function_exit<'a, 'b, 'c>(
                 func_idx: FunctionID(0), // not used
                 instr_idx: idx,
             });
-            builder.inject_all(instr_func_on_exit);
+            inject_body(builder, instr_func_on_exit);
 
             // no need to do next part if we've injected!
             return
@@ -2047,14 +3329,39 @@ fn resolve_function_exit<'a, 'b, 'c>(
             func_idx: FunctionID(0), // not used
             instr_idx: idx,
         });
+        if catch_exceptions {
+            builder.end(); // end the added try_table
+            builder.br(1); // skip the handler
+            builder.end(); // end the handler block, the caught exnref is on the stack
+            inject_body(builder, instr_func_on_exit);
+            builder.inject(Operator::ThrowRef);
+        }
         builder.end(); // end the added wrapper block!
-        builder.inject_all(instr_func_on_exit);
+        inject_observing_results(builder, instr_func_on_exit, exit_results);
 
         // remove the contents of the body now that it's been resolved
         instr_func_on_exit.clear();
     }
 }
 
+/// Injects the `exit` body between spilling the results of the function into `exit_results` and
+/// pushing them back.
+fn inject_observing_results<'a, 'b, 'c>(
+    builder: &mut FunctionModifier<'a, 'b>,
+    instr_func_on_exit: &InstrBody<'c>,
+    exit_results: &[LocalID],
+) where
+    'c: 'b,
+{
+    for local in exit_results.iter().rev() {
+        builder.local_set(*local);
+    }
+    inject_body(builder, instr_func_on_exit);
+    for local in exit_results.iter() {
+        builder.local_get(*local);
+    }
+}
+
 fn resolve_block_entry<'a, 'b, 'c>(
     block_entry: &InstrBody<'c>,
     builder: &mut FunctionModifier<'a, 'b>,
@@ -2074,7 +3381,7 @@ fn resolve_block_entry<'a, 'b, 'c>(
                 func_idx: FunctionID(0), // not used
                 instr_idx: idx,
             });
-            builder.inject_all(block_entry);
+            inject_body(builder, block_entry);
 
             // no need to remove the contents of block_entry since we're actually
             // using a read-only copy!
@@ -2142,7 +3449,7 @@ where
             if !block_alt.is_empty() {
                 // just inject immediately after the start of the block
                 builder.alternate_at(loc);
-                builder.inject_all(block_alt);
+                inject_body(builder, block_alt);
             } else {
                 // remove the instruction!
                 builder.empty_alternate_at(loc);
@@ -2236,7 +3543,7 @@ fn create_bool_flag<'a, 'b, 'c>(
     builder: &mut FunctionModifier<'a, 'b>,
     idx: usize,
     op: &Operator,
-    semantic_after: &Vec<Operator<'c>>,
+    semantic_after: &InstrBody<'c>,
 ) -> LocalID
 where
     'c: 'b,
@@ -2278,7 +3585,7 @@ where
         | Operator::BrOnCastFail { .. }
         | Operator::BrOnNonNull { .. }
         | Operator::BrOnNull { .. } => {
-            builder.inject_all(semantic_after.as_slice());
+            inject_body(builder, semantic_after);
         }
         _ => {}
     }
@@ -2288,7 +3595,7 @@ where
 fn save_not_flagged_body_to_resolve<'a>(
     resolve_on_end: &mut HashMap<BlockID, HashMap<InstrumentationMode, InstrToInject<'a>>>,
     mode: InstrumentationMode,
-    body: &Vec<Operator<'a>>,
+    body: &InstrBody<'a>,
     block_id: BlockID,
 ) {
     resolve_on_end
@@ -2308,7 +3615,7 @@ fn save_not_flagged_body_to_resolve<'a>(
 fn save_not_flagged_body_to_resolve_inner<'a>(
     inner: &mut HashMap<InstrumentationMode, InstrToInject<'a>>,
     mode: InstrumentationMode,
-    body: &Vec<Operator<'a>>,
+    body: &InstrBody<'a>,
 ) {
     inner
         .entry(mode)
@@ -2324,7 +3631,7 @@ fn save_not_flagged_body_to_resolve_inner<'a>(
 fn save_flagged_body_to_resolve<'a>(
     to_resolve: &mut HashMap<BlockID, HashMap<InstrumentationMode, InstrToInject<'a>>>,
     mode: InstrumentationMode,
-    body: &Vec<Operator<'a>>,
+    body: &InstrBody<'a>,
     bool_flag_id: LocalID,
     relative_depth: u32,
     curr_block: BlockID,
@@ -2361,10 +3668,29 @@ fn save_flagged_body_to_resolve<'a>(
         )]));
 }
 
+/// The result types of a block (or loop, or if) with the type `blockty`.
+fn results_of_block(types: &ModuleTypes, blockty: &wasmparser::BlockType) -> Vec<DataType> {
+    match blockty {
+        wasmparser::BlockType::Empty => vec![],
+        wasmparser::BlockType::Type(ty) => vec![DataType::from(*ty)],
+        wasmparser::BlockType::FuncType(ty) => types
+            .get(TypeID(*ty))
+            .map(|ty| ty.results())
+            .unwrap_or_default(),
+    }
+}
+
+/// Injects the bodies planned for the `end` (or `else`) at `idx` of a block with the given `results`.
+///
+/// The results of the block are on the stack at that point. The flagged bodies are each wrapped in an
+/// `if` checking their flag, which takes the results as parameters and gives them back: a flagged body
+/// starts with the results on the stack and must leave values of the same types there.
 fn resolve_bodies<'a, 'b, 'c>(
     builder: &mut FunctionModifier<'a, 'b>,
+    types: &mut ModuleTypes,
     mode: &InstrumentationMode,
     instr_to_inject: &InstrToInject<'c>,
+    results: &[DataType],
     idx: usize,
 ) where
     'c: 'b,
@@ -2374,6 +3700,12 @@ fn resolve_bodies<'a, 'b, 'c>(
         not_flagged,
     } = instr_to_inject;
 
+    let flag_check_ty = if flagged.is_empty() || results.is_empty() {
+        BlockType::Empty
+    } else {
+        BlockType::FuncType(types.add_func_type(results, results))
+    };
+
     let mut is_first = true;
     // inject the bodies predicated with the flag
     for InstrBodyFlagged { body, bool_flag } in flagged.iter() {
@@ -2391,25 +3723,21 @@ fn resolve_bodies<'a, 'b, 'c>(
         if is_first {
             // inject flag check
             builder.local_get(*bool_flag);
-            builder.if_stmt(BlockType::Empty); // TODO -- This will break for instrumentation that returns stuff...
+            builder.if_stmt(flag_check_ty);
         } else {
             // injecting multiple, already have an if statement
             builder.else_stmt();
             // inject flag check
             builder.local_get(*bool_flag);
-            builder.if_stmt(BlockType::Empty); // nested if for the if/else flow
+            builder.if_stmt(flag_check_ty); // nested if for the if/else flow
         }
 
         // inject body
-        builder.inject_all(body);
-        if !is_first {
-            // need to inject end of nested if!
-            builder.end();
-        }
+        inject_body(builder, body);
         is_first = false;
     }
-    if !flagged.is_empty() {
-        // inject end of flag check (the outer if)
+    for _ in flagged.iter() {
+        // inject end of the flag checks (the nested ifs, then the outer if)
         builder.end();
     }
 
@@ -2426,6 +3754,6 @@ fn resolve_bodies<'a, 'b, 'c>(
     };
     for body in not_flagged.iter() {
         // inject body
-        builder.inject_all(body);
+        inject_body(builder, body);
     }
 }
@@ -59,6 +59,28 @@ impl ModuleExports {
         self.exports.push(export);
     }
 
+    /// Add an exported global
+    pub fn add_export_global(&mut self, name: String, exp_id: u32) {
+        let export = Export {
+            name,
+            kind: ExternalKind::Global,
+            index: exp_id,
+            deleted: false,
+        };
+        self.exports.push(export);
+    }
+
+    /// Add an exported memory
+    pub fn add_export_memory(&mut self, name: String, exp_id: u32) {
+        let export = Export {
+            name,
+            kind: ExternalKind::Memory,
+            index: exp_id,
+            deleted: false,
+        };
+        self.exports.push(export);
+    }
+
     /// Get export by name and return if present
     pub fn get_by_name(&self, name: String) -> Option<Export> {
         for exp in self.exports.iter() {
@@ -108,6 +130,15 @@ impl ModuleExports {
         None
     }
 
+    /// Make the exports of the function `from` export the function `to` instead.
+    pub fn redirect_func(&mut self, from: FunctionID, to: FunctionID) {
+        for exp in self.exports.iter_mut() {
+            if matches!(exp.kind, ExternalKind::Func) && exp.index == *from {
+                exp.index = *to;
+            }
+        }
+    }
+
     /// Delete an export by its exports ID
     pub fn delete(&mut self, id: ExportsID) {
         // Must just mark for deletion as or else will result in indicies getting messed up
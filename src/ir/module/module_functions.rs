@@ -3,7 +3,7 @@
 use crate::ir::function::FunctionModifier;
 use crate::ir::id::{FunctionID, ImportsID, LocalID, TypeID};
 use crate::ir::module::{GetID, Iter, LocalOrImport, ReIndexable};
-use crate::ir::types::{Body, FuncInstrFlag, InstrumentationMode};
+use crate::ir::types::{Body, FuncInstrFlag, FuncInstrMode, InstrumentationMode};
 use crate::DataType;
 use log::warn;
 use std::vec::IntoIter;
@@ -88,6 +88,7 @@ impl<'a> Function<'a> {
 
 /// Represents whether a function is a Local Function or an Imported Function
 #[derive(Clone, Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum FuncKind<'a> {
     Local(LocalFunction<'a>),
     Import(ImportedFunction),
@@ -164,13 +165,29 @@ impl<'a> LocalFunction<'a> {
         )
     }
 
+    /// Mark the function to inject at its exit and get the locals holding its return values, of
+    /// types `results`, while the injected body runs. See [`Module::func_exit_with_results`].
+    ///
+    /// [`Module::func_exit_with_results`]: crate::Module::func_exit_with_results
+    pub(crate) fn func_exit_with_results(&mut self, results: &[DataType]) -> Vec<LocalID> {
+        if self.instr_flag.exit_results.is_empty() {
+            for ty in results.iter() {
+                let local = self.add_local(*ty);
+                self.instr_flag.exit_results.push(local);
+            }
+        }
+        self.instr_flag.current_mode = Some(FuncInstrMode::Exit);
+        self.instr_flag.exit_results.clone()
+    }
+
     pub fn add_instr(&mut self, instr: Operator<'a>, instr_idx: usize) {
         if self.instr_flag.current_mode.is_some() {
             // inject at function level
             self.instr_flag.add_instr(instr);
         } else {
             // inject at instruction level
-            let is_special = self.body.instructions[instr_idx].add_instr(instr);
+            let is_special = self.body.instructions[instr_idx]
+                .add_instr_tagged(instr, self.instr_flag.current_tag);
             // remember if we injected a special instrumentation (to be resolved before encoding)
             self.instr_flag.has_special_instr |= is_special;
         }
@@ -369,13 +386,8 @@ impl<'a> Functions<'a> {
     pub fn get_local_fid_by_name(&self, name: &str) -> Option<FunctionID> {
         for (idx, func) in self.functions.iter().enumerate() {
             if let FuncKind::Local(l) = &func.kind {
-                match &l.body.name {
-                    Some(n) => {
-                        if n == name {
-                            return Some(FunctionID(idx as u32));
-                        }
-                    }
-                    None => {}
+                if l.body.name.as_deref() == Some(name) {
+                    return Some(FunctionID(idx as u32));
                 }
             }
         }
@@ -392,7 +404,7 @@ impl<'a> Functions<'a> {
         func_id: FunctionID,
     ) -> Option<FunctionModifier<'b, 'a>> {
         // grab type and section and code section
-        return match &mut self.functions.get_mut(*func_id as usize)?.kind {
+        match &mut self.functions.get_mut(*func_id as usize)?.kind {
             FuncKind::Local(ref mut l) => {
                 // the instrflag should be reset!
                 l.instr_flag.finish_instr();
@@ -403,7 +415,7 @@ impl<'a> Functions<'a> {
                 ))
             }
             _ => None,
-        };
+        }
     }
 
     /// Delete a function
@@ -50,6 +50,8 @@ pub struct Global {
     pub(crate) kind: GlobalKind,
     /// Whether this global was deleted.
     pub(crate) deleted: bool,
+    /// The name of the global in the name section.
+    pub(crate) name: Option<String>,
 }
 
 impl GetID for Global {
@@ -87,6 +89,7 @@ impl Global {
         Self {
             kind,
             deleted: false,
+            name: None,
         }
     }
 
@@ -102,6 +105,7 @@ impl Global {
                 init_expr,
             }),
             deleted: false,
+            name: None,
         })
     }
 
@@ -176,6 +180,7 @@ impl ModuleGlobals {
                         ty,
                     }),
                     deleted: false,
+                    name: None,
                 });
             };
         }
@@ -197,6 +202,16 @@ impl ModuleGlobals {
         &self.globals[*global_id as usize].kind
     }
 
+    /// Get the name of a global
+    pub fn get_name(&self, global_id: GlobalID) -> &Option<String> {
+        &self.globals[*global_id as usize].name
+    }
+
+    /// Set the name of a global
+    pub fn set_name(&mut self, global_id: GlobalID, name: String) {
+        self.globals[*global_id as usize].name = Some(name);
+    }
+
     /// Create an iterable over the global section
     pub fn iter(&self) -> std::slice::Iter<'_, Global> {
         self.globals.iter()
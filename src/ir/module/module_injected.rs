@@ -0,0 +1,113 @@
+//! The ranges of code injected by tagged instrumentation passes (see [`INJECTED_SECTION`]).
+
+use crate::error::Error;
+use crate::ir::types::{Origin, INJECTED_SECTION};
+use wasm_encoder::Encode;
+use wasmparser::BinaryReader;
+
+/// A range of injected instructions: (start, end, tag index, replaces original)
+type Range = (u32, u32, u32, bool);
+
+/// The ranges of injected code of every function of a module.
+#[derive(Debug, Default)]
+pub(crate) struct InjectedRanges<'a> {
+    tags: Vec<&'a str>,
+    /// (function index, ranges of the function in order)
+    funcs: Vec<(u32, Vec<Range>)>,
+}
+
+impl<'a> InjectedRanges<'a> {
+    /// Records that the `instr_idx`th instruction of the function `func_idx` was injected by `origin`,
+    /// merging it with the previous range when they are contiguous.
+    pub(crate) fn record(&mut self, func_idx: u32, instr_idx: u32, origin: Origin<'a>) {
+        let tag_idx = match self.tags.iter().position(|tag| *tag == origin.tag) {
+            Some(pos) => pos as u32,
+            None => {
+                self.tags.push(origin.tag);
+                self.tags.len() as u32 - 1
+            }
+        };
+        if self.funcs.last().is_none_or(|(idx, _)| *idx != func_idx) {
+            self.funcs.push((func_idx, vec![]));
+        }
+        let ranges = &mut self.funcs.last_mut().unwrap().1;
+        if let Some((_, end, tag, replaces)) = ranges.last_mut() {
+            if *end == instr_idx && *tag == tag_idx && *replaces == origin.replaces_original {
+                *end += 1;
+                return;
+            }
+        }
+        ranges.push((instr_idx, instr_idx + 1, tag_idx, origin.replaces_original));
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.funcs.is_empty()
+    }
+
+    /// The origin of every injected instruction: (function index, instruction index, origin)
+    pub(crate) fn origins(&self) -> impl Iterator<Item = (u32, u32, Origin<'a>)> + '_ {
+        self.funcs.iter().flat_map(move |(func_idx, ranges)| {
+            ranges.iter().flat_map(move |(start, end, tag, replaces)| {
+                (*start..*end).map(move |instr_idx| {
+                    (
+                        *func_idx,
+                        instr_idx,
+                        Origin {
+                            tag: self.tags[*tag as usize],
+                            replaces_original: *replaces,
+                        },
+                    )
+                })
+            })
+        })
+    }
+
+    /// Reads the content of the [`INJECTED_SECTION`] custom section.
+    pub(crate) fn parse(data: &'a [u8]) -> Result<Self, Error> {
+        let mut reader = BinaryReader::new(data, 0);
+        let mut ranges = InjectedRanges::default();
+        for _ in 0..reader.read_var_u32()? {
+            ranges.tags.push(reader.read_string()?);
+        }
+        for _ in 0..reader.read_var_u32()? {
+            let func_idx = reader.read_var_u32()?;
+            let mut func_ranges = vec![];
+            for _ in 0..reader.read_var_u32()? {
+                let start = reader.read_var_u32()?;
+                let end = reader.read_var_u32()?;
+                let tag = reader.read_var_u32()?;
+                let replaces = reader.read_u8()? != 0;
+                if tag as usize >= ranges.tags.len() {
+                    return Err(Error::ConversionError(format!(
+                        "Unknown tag {} in the {} section",
+                        tag, INJECTED_SECTION
+                    )));
+                }
+                func_ranges.push((start, end, tag, replaces));
+            }
+            ranges.funcs.push((func_idx, func_ranges));
+        }
+        Ok(ranges)
+    }
+
+    /// Encodes the content of the [`INJECTED_SECTION`] custom section.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut data = vec![];
+        self.tags.len().encode(&mut data);
+        for tag in self.tags.iter() {
+            tag.encode(&mut data);
+        }
+        self.funcs.len().encode(&mut data);
+        for (func_idx, ranges) in self.funcs.iter() {
+            func_idx.encode(&mut data);
+            ranges.len().encode(&mut data);
+            for (start, end, tag, replaces) in ranges.iter() {
+                start.encode(&mut data);
+                end.encode(&mut data);
+                tag.encode(&mut data);
+                data.push(*replaces as u8);
+            }
+        }
+        data
+    }
+}
@@ -78,6 +78,16 @@ impl Memories {
         None
     }
 
+    /// Get the name of a memory
+    pub fn get_name(&self, mem_id: MemoryID) -> &Option<String> {
+        &self.memories[*mem_id as usize].name
+    }
+
+    /// Set the name of a memory
+    pub fn set_name(&mut self, mem_id: MemoryID, name: String) {
+        self.memories[*mem_id as usize].name = Some(name);
+    }
+
     /// Check if a memory is a local
     pub fn is_local(&self, mem_id: MemoryID) -> bool {
         self.memories[*mem_id as usize].is_local()
@@ -119,6 +129,7 @@ impl Memories {
             ty,
             kind: MemKind::Local(local_mem),
             deleted: false,
+            name: None,
         });
         id
     }
@@ -139,6 +150,7 @@ impl Memories {
                 import_mem_id: MemoryID(imp_mem_id),
             }),
             deleted: false,
+            name: None,
         });
     }
 }
@@ -149,6 +161,8 @@ pub struct Memory {
     pub ty: MemoryType,
     pub(crate) kind: MemKind,
     pub(crate) deleted: bool,
+    /// The name of the memory in the name section.
+    pub(crate) name: Option<String>,
 }
 impl GetID for Memory {
     /// Get the ID of the function
@@ -183,6 +197,7 @@ impl Memory {
             ty,
             kind,
             deleted: false,
+            name: None,
         }
     }
 
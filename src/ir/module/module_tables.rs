@@ -15,6 +15,12 @@ impl<'a> ModuleTables<'a> {
         ModuleTables { tables }
     }
 
+    /// Add a new table, returns its position in the table section
+    pub(crate) fn add(&mut self, ty: TableType, init: Option<wasmparser::ConstExpr<'a>>) -> usize {
+        self.tables.push((ty, init));
+        self.tables.len() - 1
+    }
+
     /// Check if there are any tables
     pub fn is_empty(&self) -> bool {
         self.tables.is_empty()
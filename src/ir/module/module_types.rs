@@ -2,7 +2,7 @@
 
 use crate::ir::id::TypeID;
 use crate::DataType;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::hash::Hash;
 use wasmparser::{PackedIndex, UnpackedIndex};
 
@@ -66,6 +66,10 @@ pub struct ModuleTypes {
     pub types_map: HashMap<Types, TypeID>,
     // Mapping between recursive group and TypeID
     pub(crate) recgroup_map: HashMap<u32, u32>,
+    /// The names of the types in the name section
+    pub(crate) names: BTreeMap<TypeID, String>,
+    /// The names of the fields of the struct types in the name section, by field index
+    pub(crate) field_names: BTreeMap<TypeID, BTreeMap<u32, String>>,
 }
 
 impl ModuleTypes {
@@ -79,6 +83,8 @@ impl ModuleTypes {
             types,
             types_map,
             recgroup_map,
+            names: BTreeMap::new(),
+            field_names: BTreeMap::new(),
         }
     }
 
@@ -255,6 +261,32 @@ impl ModuleTypes {
     pub fn get(&self, index: TypeID) -> Option<&Types> {
         self.types.get(*index as usize)
     }
+
+    /// Get the name of a type
+    pub fn get_name(&self, index: TypeID) -> Option<&str> {
+        self.names.get(&index).map(String::as_str)
+    }
+
+    /// Set the name of a type
+    pub fn set_name(&mut self, index: TypeID, name: String) {
+        self.names.insert(index, name);
+    }
+
+    /// Get the name of the field `field` of a struct type
+    pub fn get_field_name(&self, index: TypeID, field: u32) -> Option<&str> {
+        self.field_names
+            .get(&index)
+            .and_then(|fields| fields.get(&field))
+            .map(String::as_str)
+    }
+
+    /// Set the name of the field `field` of a struct type
+    pub fn set_field_name(&mut self, index: TypeID, field: u32, name: String) {
+        self.field_names
+            .entry(index)
+            .or_default()
+            .insert(field, name);
+    }
 }
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
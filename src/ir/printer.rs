@@ -0,0 +1,249 @@
+//! The text format of modules and components, with their pending instrumentation.
+//!
+//! A module is printed from a copy in which each body of instrumentation is turned into plain
+//! instructions next to the instruction it is attached to, as in the annotated `.wat` files of the
+//! tests: an injected line is commented out with `;; << ` and followed by the mode of its body, and
+//! an instruction replaced by an `alternate` body is followed by `;; rm`.
+
+use crate::error::Error;
+use crate::ir::component::Component;
+use crate::ir::id::FunctionID;
+use crate::ir::module::module_functions::FuncKind;
+use crate::ir::module::{Module, ReIndexable};
+use crate::ir::section::ComponentSection;
+use crate::ir::types::{Instruction, InstrumentationFlag};
+use std::collections::HashMap;
+use wasmparser::{Encoding, Operator, Parser, Payload};
+
+/// How a printed instruction differs from the encoded module.
+#[derive(Clone, Copy)]
+enum Mark {
+    /// Injected by a pending body of the mode.
+    Injected(&'static str),
+    /// Replaced by a pending `alternate` body.
+    Removed,
+}
+
+/// The marks of the flattened instructions of a module, by function.
+type Marks = HashMap<FunctionID, Vec<Option<Mark>>>;
+
+/// The text format of `module`.
+pub(crate) fn module_to_wat(module: &Module) -> Result<String, Error> {
+    let mut copy = module.clone();
+    let marks = flatten(&mut copy);
+    let bytes = copy.encode();
+    to_wat(&bytes, vec![(local_functions(&copy), marks)])
+}
+
+/// The text format of `component`.
+pub(crate) fn component_to_wat(component: &Component) -> Result<String, Error> {
+    let mut copy = component.clone();
+    let mut marks = vec![];
+    for_each_module(&mut copy, &mut |module| marks.push(flatten(module)));
+    let bytes = copy.encode();
+    let mut functions = vec![];
+    for_each_module(&mut copy, &mut |module| {
+        functions.push(local_functions(module))
+    });
+    to_wat(&bytes, functions.into_iter().zip(marks).collect())
+}
+
+/// Calls `f` on the modules of `component` and of its nested components, in the order they are
+/// encoded.
+fn for_each_module<'a>(component: &mut Component<'a>, f: &mut impl FnMut(&mut Module<'a>)) {
+    let mut num_modules = 0;
+    let mut num_components = 0;
+    for (num, section) in component.sections.iter() {
+        let num = *num as usize;
+        match section {
+            ComponentSection::Module => {
+                for module in component.modules[num_modules..num_modules + num].iter_mut() {
+                    f(module);
+                }
+                num_modules += num;
+            }
+            ComponentSection::Component => {
+                for nested in component.components[num_components..num_components + num].iter_mut()
+                {
+                    for_each_module(nested, f);
+                }
+                num_components += num;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The local functions of an encoded module, in the order of the code section.
+fn local_functions(module: &Module) -> Vec<FunctionID> {
+    module
+        .functions
+        .iter()
+        .filter(|func| !func.deleted)
+        .filter_map(|func| match func.kind() {
+            FuncKind::Local(local) => Some(local.func_id),
+            FuncKind::Import(_) => None,
+        })
+        .collect()
+}
+
+/// Turns the pending instrumentation of the functions of `module` into plain instructions, in the
+/// order: function entry, then for each instruction its `before` body, the instruction, its
+/// `alternate`, `after`, `semantic_after`, `block_entry`, `block_exit` and `block_alt` bodies, and
+/// function exit before the final `end`.
+fn flatten(module: &mut Module) -> Marks {
+    // the bodies are printed in the order they are encoded, and the instructions as they are
+    if !module.layer_order.is_empty() {
+        module.order_layers();
+        module.layer_order.clear();
+    }
+    module.compact_locals = false;
+    module.optimize_instrumentation = false;
+
+    let mut marks = Marks::new();
+    for idx in 0..module.functions.len() {
+        let func = module.functions.get_mut(FunctionID(idx as u32));
+        if func.deleted {
+            continue;
+        }
+        let FuncKind::Local(func) = &mut func.kind else {
+            continue;
+        };
+        let instr_flag = std::mem::take(&mut func.instr_flag);
+        let mut instructions = std::mem::take(&mut func.body.instructions);
+        let end = instructions.pop();
+
+        let mut flat = Vec::with_capacity(instructions.len() + 1);
+        inject(&mut flat, instr_flag.entry, "func_entry");
+        for instr in instructions {
+            let (instr, flag) = strip(instr);
+            let InstrumentationFlag {
+                before,
+                after,
+                alternate,
+                semantic_after,
+                block_entry,
+                block_exit,
+                block_alt,
+                ..
+            } = flag;
+            inject(&mut flat, before, "before");
+            flat.push((instr, alternate.as_ref().map(|_| Mark::Removed)));
+            inject(&mut flat, alternate.unwrap_or_default(), "alternate");
+            inject(&mut flat, after, "after");
+            inject(&mut flat, semantic_after, "semantic_after");
+            inject(&mut flat, block_entry, "block_entry");
+            inject(&mut flat, block_exit, "block_exit");
+            inject(&mut flat, block_alt.unwrap_or_default(), "block_alt");
+        }
+        if let Some(end) = end {
+            // only the `before` body of the final `end` is encoded
+            let (end, flag) = strip(end);
+            inject(&mut flat, flag.before, "before");
+            inject(&mut flat, instr_flag.exit, "func_exit");
+            flat.push((end, None));
+        }
+
+        let (instructions, func_marks): (Vec<_>, Vec<_>) = flat.into_iter().unzip();
+        func.body.num_instructions = instructions.len();
+        func.body.instructions = instructions;
+        marks.insert(func.func_id, func_marks);
+    }
+    marks
+}
+
+/// Splits the instrumentation off `instr`, which keeps its origin.
+fn strip(instr: Instruction) -> (Instruction, InstrumentationFlag) {
+    let Instruction {
+        op,
+        mut instr_flag,
+        offset,
+        label,
+    } = instr;
+    let stripped = Instruction {
+        op,
+        instr_flag: InstrumentationFlag {
+            origin: instr_flag.origin.take(),
+            ..Default::default()
+        },
+        offset,
+        label,
+    };
+    (stripped, instr_flag)
+}
+
+/// Appends the `ops` of a body in `mode` to the flattened instructions.
+fn inject<'a>(
+    flat: &mut Vec<(Instruction<'a>, Option<Mark>)>,
+    ops: Vec<Operator<'a>>,
+    mode: &'static str,
+) {
+    flat.extend(
+        ops.into_iter()
+            .map(|op| (Instruction::new(op), Some(Mark::Injected(mode)))),
+    );
+}
+
+/// Prints `bytes`, marking the instructions of its modules, given in the order they are encoded
+/// with their local functions.
+fn to_wat(bytes: &[u8], modules: Vec<(Vec<FunctionID>, Marks)>) -> Result<String, Error> {
+    // the offsets of the marked instructions in the binary
+    let mut marked = HashMap::new();
+    let mut modules = modules.into_iter();
+    let mut module = None;
+    let mut num_bodies = 0;
+    for payload in Parser::new(0).parse_all(bytes) {
+        match payload? {
+            Payload::Version {
+                encoding: Encoding::Module,
+                ..
+            } => {
+                module = modules.next();
+                num_bodies = 0;
+            }
+            Payload::CodeSectionEntry(body) => {
+                let func_marks = module.as_ref().and_then(|(functions, marks)| {
+                    functions
+                        .get(num_bodies)
+                        .and_then(|func_id| marks.get(func_id))
+                });
+                num_bodies += 1;
+                let Some(func_marks) = func_marks else {
+                    continue;
+                };
+                let mut reader = body.get_operators_reader()?;
+                let mut idx = 0;
+                while !reader.eof() {
+                    let offset = reader.original_position();
+                    reader.read()?;
+                    if let Some(Some(mark)) = func_marks.get(idx) {
+                        marked.insert(offset, *mark);
+                    }
+                    idx += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut storage = String::new();
+    let lines = wasmprinter::Config::new()
+        .offsets_and_lines(bytes, &mut storage)
+        .map_err(|err| Error::InvalidWat {
+            reason: err.to_string(),
+        })?;
+    let mut wat = String::new();
+    for (offset, line) in lines {
+        let (code, newline) = line.split_at(line.trim_end().len());
+        let text = code.trim_start();
+        let indent = &code[..code.len() - text.len()];
+        match offset.and_then(|offset| marked.get(&offset)) {
+            Some(Mark::Injected(mode)) => {
+                wat.push_str(&format!("{indent};; << {text} ;; {mode}{newline}"))
+            }
+            Some(Mark::Removed) => wat.push_str(&format!("{code} ;; rm{newline}")),
+            None => wat.push_str(line),
+        }
+    }
+    Ok(wat)
+}
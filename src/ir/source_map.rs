@@ -0,0 +1,179 @@
+//! Source maps of encoded modules.
+//!
+//! When a module is encoded with [`Module::encode_with_source_map`], the code section records where
+//! the code of each instruction was encoded, and a [v3 source map] maps the encoded code back to
+//! the source lines of the DWARF of the parsed binary, or, without line information, to the code
+//! of the parsed binary itself. The encoded binary gets a `sourceMappingURL` custom section
+//! pointing to the map, as used by browser devtools.
+//!
+//! [`Module::encode_with_source_map`]: crate::Module::encode_with_source_map
+//! [v3 source map]: https://sourcemaps.info/spec.html
+
+use crate::ir::dwarf::LineTable;
+use serde_json::json;
+
+/// The name of the custom section holding the URL of the source map of a binary.
+pub const SOURCE_MAPPING_URL: &str = "sourceMappingURL";
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Where the code of an encoded binary comes from.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct CodeOffsets {
+    /// The offset of the code section in the encoded binary.
+    pub(crate) code_start: usize,
+    /// The start of each run of code encoded for the same instruction, in order: (offset in the
+    /// code section of the encoded binary, offset in the code section of the parsed binary), `None`
+    /// for the code that was not parsed, e.g. injected code.
+    runs: Vec<(usize, Option<usize>)>,
+}
+
+impl CodeOffsets {
+    /// Records that the code at `to` comes from the instruction at `from`.
+    pub(crate) fn add(&mut self, to: usize, from: Option<usize>) {
+        match self.runs.last_mut() {
+            // the previous run is empty
+            Some(last) if last.0 == to => *last = (to, from),
+            // consecutive code without origin is a single run
+            Some((_, None)) if from.is_none() => {}
+            _ => self.runs.push((to, from)),
+        }
+    }
+
+    /// Shifts all the offsets of the encoded binary by `delta`, once the start of the code section
+    /// is known.
+    pub(crate) fn shift(&mut self, delta: usize) {
+        for (to, _) in self.runs.iter_mut() {
+            *to += delta;
+        }
+    }
+}
+
+/// A [v3 source map] of the code of an encoded binary. As for any WebAssembly binary, the code is
+/// on the line 0 of the generated file, each column being a byte offset in the binary.
+///
+/// [v3 source map]: https://sourcemaps.info/spec.html
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SourceMap {
+    /// The sources of the code: the source files of the DWARF line table, or the parsed binary.
+    pub sources: Vec<String>,
+    /// The mappings, sorted by the offset of their code in the encoded binary.
+    pub mappings: Vec<Mapping>,
+}
+
+/// The origin of the code at an offset of an encoded binary, up to the next mapping.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Mapping {
+    /// The offset of the code in the encoded binary.
+    pub offset: u32,
+    /// Where the code comes from: (index in the sources, line, column), from 0, `None` for the code
+    /// that was not parsed, e.g. injected code. In the parsed binary, the line is 0 and the column
+    /// is the offset of the instruction in the binary.
+    pub original: Option<(u32, u32, u32)>,
+}
+
+impl SourceMap {
+    /// The source map of the code encoded as described by `offsets`, from a binary `input` whose
+    /// code section starts at `code_start` and has the source lines `lines`.
+    pub(crate) fn new(
+        offsets: &CodeOffsets,
+        lines: &LineTable,
+        input: &str,
+        code_start: usize,
+    ) -> Self {
+        let mut map = SourceMap::default();
+        let source_of = |sources: &mut Vec<String>, source: &str| match sources
+            .iter()
+            .position(|known| known == source)
+        {
+            Some(idx) => idx as u32,
+            None => {
+                sources.push(source.to_string());
+                sources.len() as u32 - 1
+            }
+        };
+        for (to, from) in offsets.runs.iter() {
+            let original = from.and_then(|from| {
+                if lines.is_empty() {
+                    let source = source_of(&mut map.sources, input);
+                    return Some((source, 0, (code_start + from) as u32));
+                }
+                let (file, line, column) = lines.lookup(from as u64)?;
+                let source = source_of(&mut map.sources, &lines.files[file as usize]);
+                Some((source, line.saturating_sub(1), column.saturating_sub(1)))
+            });
+            let mapping = Mapping {
+                offset: (offsets.code_start + to) as u32,
+                original,
+            };
+            match map.mappings.last() {
+                Some(last) if last.original.is_none() && original.is_none() => {}
+                _ => map.mappings.push(mapping),
+            }
+        }
+        map
+    }
+
+    /// The origin of the code at `offset` in the encoded binary: (source, line, column), from 0.
+    pub fn lookup(&self, offset: u32) -> Option<(&str, u32, u32)> {
+        let idx = self
+            .mappings
+            .partition_point(|mapping| mapping.offset <= offset)
+            .checked_sub(1)?;
+        let (source, line, column) = self.mappings[idx].original?;
+        Some((&self.sources[source as usize], line, column))
+    }
+
+    /// The `mappings` field of the JSON source map: the segments of the line 0, with the fields
+    /// relative to the previous segment and encoded as base64 VLQs.
+    pub fn encode_mappings(&self) -> String {
+        let mut encoded = String::new();
+        let mut prev_offset = 0;
+        let mut prev = (0, 0, 0);
+        for (idx, mapping) in self.mappings.iter().enumerate() {
+            if idx > 0 {
+                encoded.push(',');
+            }
+            vlq(mapping.offset as i64 - prev_offset as i64, &mut encoded);
+            prev_offset = mapping.offset;
+            if let Some((source, line, column)) = mapping.original {
+                vlq(source as i64 - prev.0 as i64, &mut encoded);
+                vlq(line as i64 - prev.1 as i64, &mut encoded);
+                vlq(column as i64 - prev.2 as i64, &mut encoded);
+                prev = (source, line, column);
+            }
+        }
+        encoded
+    }
+
+    /// The JSON of the source map.
+    pub fn to_json(&self) -> String {
+        json!({
+            "version": 3,
+            "sources": self.sources,
+            "names": [],
+            "mappings": self.encode_mappings(),
+        })
+        .to_string()
+    }
+}
+
+/// Appends `value` to `out` as a base64 VLQ.
+fn vlq(value: i64, out: &mut String) {
+    let mut value = if value < 0 {
+        ((-value as u64) << 1) | 1
+    } else {
+        (value as u64) << 1
+    };
+    loop {
+        let mut digit = (value & 0x1f) as usize;
+        value >>= 5;
+        if value > 0 {
+            digit |= 0x20;
+        }
+        out.push(BASE64[digit] as char);
+        if value == 0 {
+            return;
+        }
+    }
+}
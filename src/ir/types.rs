@@ -1,8 +1,9 @@
 //! Intermediate representation of sections in a wasm module.
 
 use crate::error::Error;
-use crate::ir::id::{CustomSectionID, FunctionID, GlobalID, ModuleID, TypeID};
+use crate::ir::id::{CustomSectionID, FunctionID, GlobalID, LocalID, ModuleID, TypeID};
 use std::cmp::PartialEq;
+use std::collections::BTreeMap;
 use std::fmt::Formatter;
 use std::fmt::{self};
 use std::mem::discriminant;
@@ -707,6 +708,140 @@ impl ElementItems<'_> {
     }
 }
 
+/// The name of the custom section describing the code injected by tagged instrumentation passes.
+///
+/// It is written when a module with tagged instrumentation is encoded, and read back when the
+/// module is parsed again (see [`Module::strip_instrumentation`]). Its content is:
+///
+/// ```text
+/// tags:      vec(name: string)
+/// functions: vec(func_idx: u32, ranges: vec(start: u32, end: u32, tag_idx: u32, replaces: u8))
+/// ```
+///
+/// where `start..end` is a range of instruction indices in the body of the function, and `replaces`
+/// is 1 if the range replaced an original instruction (`alternate` instrumentation).
+///
+/// [`Module::strip_instrumentation`]: crate::Module::strip_instrumentation
+pub const INJECTED_SECTION: &str = "orca.injected";
+
+/// The pass that injected an instruction of a body.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Origin<'a> {
+    /// The tag of the pass.
+    pub tag: &'a str,
+    /// Whether the instruction is part of code that replaced an original instruction.
+    pub replaces_original: bool,
+}
+
+/// The tags of the operators of an injected body, stored as runs of consecutive operators injected
+/// under the same tag. Operators before the first run have no tag.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct BodyTags<'a> {
+    /// (index of the first operator of the run, tag of the run)
+    runs: Vec<(usize, Option<&'a str>)>,
+}
+
+impl<'a> BodyTags<'a> {
+    /// Records that the operators from `idx` on are injected under `tag`.
+    pub fn record(&mut self, idx: usize, tag: Option<&'a str>) {
+        if self.runs.last().and_then(|(_, last)| *last) == tag {
+            return;
+        }
+        self.runs.retain(|(start, _)| *start < idx);
+        self.runs.push((idx, tag));
+    }
+
+    /// The tag of the `idx`th operator.
+    pub fn tag_of(&self, idx: usize) -> Option<&'a str> {
+        self.runs
+            .iter()
+            .rev()
+            .find(|(start, _)| *start <= idx)
+            .and_then(|(_, tag)| *tag)
+    }
+
+    /// Whether no operator has a tag.
+    pub fn is_empty(&self) -> bool {
+        self.runs.iter().all(|(_, tag)| tag.is_none())
+    }
+
+    pub fn clear(&mut self) {
+        self.runs.clear();
+    }
+
+    /// The tag of every operator of a body of `len` operators.
+    pub fn expand(&self, len: usize) -> Vec<Option<&'a str>> {
+        (0..len).map(|idx| self.tag_of(idx)).collect()
+    }
+
+    /// Whether some operator has the tag `tag`.
+    pub fn contains(&self, tag: &str) -> bool {
+        self.runs.iter().any(|(_, run_tag)| *run_tag == Some(tag))
+    }
+
+    /// Removes the operators of `ops` (the body these tags describe) that have the tag `tag`.
+    /// Returns the number of removed operators.
+    pub fn strip(&mut self, ops: &mut Vec<Operator<'a>>, tag: &str) -> usize {
+        if !self.contains(tag) {
+            return 0;
+        }
+        let len = ops.len();
+        let mut kept_tags = vec![];
+        let mut idx = 0;
+        ops.retain(|_| {
+            let op_tag = self.tag_of(idx);
+            idx += 1;
+            if op_tag == Some(tag) {
+                return false;
+            }
+            kept_tags.push(op_tag);
+            true
+        });
+        *self = BodyTags::from_tags(&kept_tags);
+        len - ops.len()
+    }
+
+    /// Reorders the operators of `ops` (the body these tags describe) by the layer of their tag (see
+    /// [`Module::set_layer_order`]). Operators of the same layer keep their order.
+    ///
+    /// [`Module::set_layer_order`]: crate::Module::set_layer_order
+    pub fn order(&mut self, ops: &mut Vec<Operator<'a>>, layers: &[&str], outermost_first: bool) {
+        if self.is_empty() {
+            return;
+        }
+        let mut tagged: Vec<(Option<&'a str>, Operator<'a>)> = self
+            .expand(ops.len())
+            .into_iter()
+            .zip(ops.drain(..))
+            .collect();
+        tagged.sort_by_key(|(tag, _)| layer_rank(layers, *tag, outermost_first));
+        let (tags, sorted): (Vec<_>, Vec<_>) = tagged.into_iter().unzip();
+        *ops = sorted;
+        *self = BodyTags::from_tags(&tags);
+    }
+
+    /// Builds the tags of a body from the tag of every operator.
+    pub fn from_tags(tags: &[Option<&'a str>]) -> Self {
+        let mut body_tags = BodyTags::default();
+        for (idx, tag) in tags.iter().enumerate() {
+            body_tags.record(idx, *tag);
+        }
+        body_tags
+    }
+}
+
+/// The rank of the code injected under `tag` in a body ordered by `layers` (outermost first).
+/// Untagged code and the code of the layers missing from `layers` is the innermost.
+fn layer_rank(layers: &[&str], tag: Option<&str>, outermost_first: bool) -> usize {
+    let pos = tag.and_then(|tag| layers.iter().position(|layer| *layer == tag));
+    match (pos, outermost_first) {
+        (Some(pos), true) => pos,
+        (None, true) => layers.len(),
+        (Some(pos), false) => layers.len() - pos,
+        (None, false) => 0,
+    }
+}
+
 #[derive(Debug, Clone)]
 /// Mode of Function in case the function is mark as instrumented
 pub enum FuncInstrMode {
@@ -723,6 +858,16 @@ pub struct FuncInstrFlag<'a> {
     pub current_mode: Option<FuncInstrMode>,
     pub entry: Vec<Operator<'a>>,
     pub exit: Vec<Operator<'a>>,
+    /// Locals the results of the function are spilled into before running the `exit` body at a
+    /// `return` or at the end of the function (empty if the results are not observed).
+    pub exit_results: Vec<LocalID>,
+    /// The tag recorded for the instrumentation injected into the function (see
+    /// [`Module::set_instrumentation_tag`]).
+    ///
+    /// [`Module::set_instrumentation_tag`]: crate::Module::set_instrumentation_tag
+    pub current_tag: Option<&'a str>,
+    pub entry_tags: BodyTags<'a>,
+    pub exit_tags: BodyTags<'a>,
 }
 
 impl fmt::Display for FuncInstrFlag<'_> {
@@ -731,7 +876,11 @@ impl fmt::Display for FuncInstrFlag<'_> {
             has_special_instr,
             entry,
             exit,
+            exit_results,
             current_mode: _,
+            current_tag: _,
+            entry_tags: _,
+            exit_tags: _,
         } = self;
         if !self.has_instr() {
             write!(f, "Not Instrumented")?;
@@ -740,10 +889,12 @@ impl fmt::Display for FuncInstrFlag<'_> {
             f,
             "Has special instrumentation: {}\n \
              Func Entry: {:?} instructions\n \
-             Func Exit: {:?} instructions",
+             Func Exit: {:?} instructions\n \
+             Func Exit Results: {:?}",
             has_special_instr,
             entry.len(),
-            exit.len()
+            exit.len(),
+            exit_results
         )
     }
 }
@@ -755,11 +906,19 @@ impl PartialEq for FuncInstrFlag<'_> {
             has_special_instr,
             entry,
             exit,
+            exit_results,
             current_mode,
+            current_tag,
+            entry_tags,
+            exit_tags,
         } = self;
         let mut result = *has_special_instr == other.has_special_instr;
         result &= entry.eq(&other.entry);
         result &= exit.eq(&other.exit);
+        result &= exit_results.eq(&other.exit_results);
+        result &= *current_tag == other.current_tag;
+        result &= entry_tags.eq(&other.entry_tags);
+        result &= exit_tags.eq(&other.exit_tags);
         result &= discriminant(current_mode) == discriminant(&other.current_mode);
 
         result
@@ -776,6 +935,10 @@ impl<'a> FuncInstrFlag<'a> {
             exit,
             has_special_instr: _,
             current_mode: _,
+            exit_results: _,
+            current_tag: _,
+            entry_tags: _,
+            exit_tags: _,
         } = self;
         !entry.is_empty() || !exit.is_empty()
     }
@@ -791,13 +954,19 @@ impl<'a> FuncInstrFlag<'a> {
             None => {
                 panic!("Current mode is not set...cannot inject instructions!")
             }
-            Some(FuncInstrMode::Entry) => self.entry.push(val),
-            Some(FuncInstrMode::Exit) => self.exit.push(val),
+            Some(FuncInstrMode::Entry) => {
+                self.entry_tags.record(self.entry.len(), self.current_tag);
+                self.entry.push(val)
+            }
+            Some(FuncInstrMode::Exit) => {
+                self.exit_tags.record(self.exit.len(), self.current_tag);
+                self.exit.push(val)
+            }
         }
     }
 
     /// Get an instruction to the current FuncInstrMode's list
-    pub fn get_instr(&self, idx: usize) -> &Operator {
+    pub fn get_instr(&self, idx: usize) -> &Operator<'_> {
         match self.current_mode {
             None => {
                 panic!("Current mode is not set...cannot grab instruction without context!")
@@ -811,6 +980,18 @@ impl<'a> FuncInstrFlag<'a> {
     pub fn finish_instr(&mut self) {
         self.current_mode = None
     }
+
+    /// Orders the `entry` body outermost layer first and the `exit` body innermost layer first.
+    pub fn order_layers(&mut self, layers: &[&str]) {
+        self.entry_tags.order(&mut self.entry, layers, true);
+        self.exit_tags.order(&mut self.exit, layers, false);
+    }
+
+    /// Removes the operators injected under `tag` from the `entry` and `exit` bodies. Returns the
+    /// number of removed operators.
+    pub fn strip_tag(&mut self, tag: &str) -> usize {
+        self.entry_tags.strip(&mut self.entry, tag) + self.exit_tags.strip(&mut self.exit, tag)
+    }
 }
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
@@ -846,6 +1027,12 @@ pub struct InstrumentationFlag<'a> {
     /// Some(vec) means to replace with the vec of instructions
     /// Some(empty vec) means there is no alt instrumentation
     pub block_alt: Option<Vec<Operator<'a>>>,
+
+    /// The tags of the injected bodies, see [`BodyTags`]
+    pub tags: Vec<(InstrumentationMode, BodyTags<'a>)>,
+    /// Set if the instruction itself was injected by an earlier instrumentation pass: read from the
+    /// [`INJECTED_SECTION`] of a parsed module, or kept when injected code is flattened into the body.
+    pub origin: Option<Origin<'a>>,
 }
 
 impl fmt::Display for InstrumentationFlag<'_> {
@@ -859,6 +1046,8 @@ impl fmt::Display for InstrumentationFlag<'_> {
             block_exit,
             block_alt,
             current_mode: _,
+            tags: _,
+            origin: _,
         } = self;
         if !self.has_instr() {
             write!(f, "Not Instrumented")?;
@@ -895,6 +1084,8 @@ impl PartialEq for InstrumentationFlag<'_> {
             block_exit,
             block_alt,
             current_mode,
+            tags,
+            origin,
         } = self;
         let mut result = before.eq(&other.before);
         result &= after.eq(&other.after);
@@ -904,6 +1095,8 @@ impl PartialEq for InstrumentationFlag<'_> {
         result &= block_exit.eq(&other.block_exit);
         result &= block_alt.eq(&other.block_alt);
         result &= *current_mode == other.current_mode;
+        result &= tags.eq(&other.tags);
+        result &= *origin == other.origin;
 
         result
     }
@@ -923,6 +1116,8 @@ impl<'a> InstrumentationFlag<'a> {
             block_exit,
             block_alt,
             current_mode: _,
+            tags: _,
+            origin: _,
         } = self;
         !before.is_empty()
             || !after.is_empty()
@@ -1011,7 +1206,126 @@ impl<'a> InstrumentationFlag<'a> {
         }
     }
 
+    /// Add an instruction to the current InstrumentationMode's list, recording that it was injected
+    /// under `tag`. Returns whether the instrumentation was a 'special' mode
+    pub fn add_instr_tagged(
+        &mut self,
+        op: &Operator,
+        val: Operator<'a>,
+        tag: Option<&'a str>,
+    ) -> bool {
+        if let Some(mode) = self.current_mode {
+            let idx = self.body(mode).map_or(0, |body| body.len());
+            if tag.is_some() || self.tags_of(mode).is_some() {
+                self.tags_of_mut(mode).record(idx, tag);
+            }
+        }
+        self.add_instr(op, val)
+    }
+
+    /// The injected body of `mode`, `None` if there is no `alternate` or `block_alt` body.
+    pub fn body(&self, mode: InstrumentationMode) -> Option<&Vec<Operator<'a>>> {
+        match mode {
+            InstrumentationMode::Before => Some(&self.before),
+            InstrumentationMode::After => Some(&self.after),
+            InstrumentationMode::Alternate => self.alternate.as_ref(),
+            InstrumentationMode::SemanticAfter => Some(&self.semantic_after),
+            InstrumentationMode::BlockEntry => Some(&self.block_entry),
+            InstrumentationMode::BlockExit => Some(&self.block_exit),
+            InstrumentationMode::BlockAlt => self.block_alt.as_ref(),
+        }
+    }
+
+    /// The tags of the body of `mode`, `None` if nothing was injected there under a tag.
+    pub fn tags_of(&self, mode: InstrumentationMode) -> Option<&BodyTags<'a>> {
+        self.tags
+            .iter()
+            .find(|(body_mode, _)| *body_mode == mode)
+            .map(|(_, tags)| tags)
+    }
+
+    fn tags_of_mut(&mut self, mode: InstrumentationMode) -> &mut BodyTags<'a> {
+        let pos = match self
+            .tags
+            .iter()
+            .position(|(body_mode, _)| *body_mode == mode)
+        {
+            Some(pos) => pos,
+            None => {
+                self.tags.push((mode, BodyTags::default()));
+                self.tags.len() - 1
+            }
+        };
+        &mut self.tags[pos].1
+    }
+
+    /// The tag of the `idx`th operator of the body of `mode`.
+    pub fn tag_of(&self, mode: InstrumentationMode, idx: usize) -> Option<&'a str> {
+        self.tags_of(mode).and_then(|tags| tags.tag_of(idx))
+    }
+
+    fn body_mut(&mut self, mode: InstrumentationMode) -> Option<&mut Vec<Operator<'a>>> {
+        match mode {
+            InstrumentationMode::Before => Some(&mut self.before),
+            InstrumentationMode::After => Some(&mut self.after),
+            InstrumentationMode::Alternate => self.alternate.as_mut(),
+            InstrumentationMode::SemanticAfter => Some(&mut self.semantic_after),
+            InstrumentationMode::BlockEntry => Some(&mut self.block_entry),
+            InstrumentationMode::BlockExit => Some(&mut self.block_exit),
+            InstrumentationMode::BlockAlt => self.block_alt.as_mut(),
+        }
+    }
+
+    /// Whether code injected under `tag` replaces this instruction or the block it starts (see
+    /// [`Origin::replaces_original`]).
+    pub fn replaced_by(&self, tag: &str) -> bool {
+        self.origin
+            .is_some_and(|origin| origin.tag == tag && origin.replaces_original)
+            || [
+                InstrumentationMode::Alternate,
+                InstrumentationMode::BlockAlt,
+            ]
+            .iter()
+            .any(|mode| self.tags_of(*mode).is_some_and(|tags| tags.contains(tag)))
+    }
+
+    /// Orders the bodies that run before the instruction (or on entering its block) outermost layer
+    /// first, and the bodies that run after it innermost layer first. `alternate` and `block_alt`
+    /// bodies are left as they are.
+    pub fn order_layers(&mut self, layers: &[&str]) {
+        let mut tags = std::mem::take(&mut self.tags);
+        for (mode, body_tags) in tags.iter_mut() {
+            let outermost_first = match mode {
+                InstrumentationMode::Before | InstrumentationMode::BlockEntry => true,
+                InstrumentationMode::After
+                | InstrumentationMode::SemanticAfter
+                | InstrumentationMode::BlockExit => false,
+                InstrumentationMode::Alternate | InstrumentationMode::BlockAlt => continue,
+            };
+            if let Some(body) = self.body_mut(*mode) {
+                body_tags.order(body, layers, outermost_first);
+            }
+        }
+        self.tags = tags;
+    }
+
+    /// Removes the operators injected under `tag` from the bodies of every mode. Returns the number
+    /// of removed operators.
+    pub fn strip_tag(&mut self, tag: &str) -> usize {
+        let mut tags = std::mem::take(&mut self.tags);
+        let mut removed = 0;
+        for (mode, body_tags) in tags.iter_mut() {
+            if let Some(body) = self.body_mut(*mode) {
+                removed += body_tags.strip(body, tag);
+            }
+        }
+        tags.retain(|(_, body_tags)| !body_tags.is_empty());
+        self.tags = tags;
+        removed
+    }
+
     pub fn clear_instr(&mut self, mode: InstrumentationMode) {
+        self.tags.retain(|(body_mode, _)| *body_mode != mode);
         match mode {
             InstrumentationMode::Before => {
                 self.before.clear();
@@ -1053,7 +1367,7 @@ impl<'a> InstrumentationFlag<'a> {
     }
 
     /// Get an instruction to the current InstrumentationMode's list
-    pub fn get_instr(&self, idx: usize) -> &Operator {
+    pub fn get_instr(&self, idx: usize) -> &Operator<'_> {
         match self.current_mode {
             None => {
                 panic!("Current mode is not set...cannot grab instruction without context!")
@@ -1081,7 +1395,7 @@ impl<'a> InstrumentationFlag<'a> {
 }
 
 /// Used to represent a unique location in a wasm component or module.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Location {
     Component {
         mod_idx: ModuleID,
@@ -1108,6 +1422,8 @@ pub struct Body<'a> {
     pub instructions: Vec<Instruction<'a>>,
     pub num_instructions: usize,
     pub name: Option<String>,
+    /// The names of the parameters and locals of the function, by index
+    pub local_names: BTreeMap<LocalID, String>,
 }
 
 // 'b should outlive 'a
@@ -1122,12 +1438,12 @@ where
     }
 
     /// Get some operator (instruction) at the specified index of the body
-    pub fn get_op(&self, idx: usize) -> &Operator {
+    pub fn get_op(&self, idx: usize) -> &Operator<'_> {
         &self.instructions[idx].op
     }
 
     /// Get the instrumentation of some operator in the body
-    pub fn get_instr_flag(&self, idx: usize) -> &InstrumentationFlag {
+    pub fn get_instr_flag(&self, idx: usize) -> &InstrumentationFlag<'_> {
         &self.instructions[idx].instr_flag
     }
 
@@ -1146,6 +1462,12 @@ where
 pub struct Instruction<'a> {
     pub op: Operator<'a>,
     pub instr_flag: InstrumentationFlag<'a>,
+    /// The offset of the instruction from the start of the code section of the parsed binary
+    /// (the addresses used by DWARF), `None` for the instructions added since.
+    pub offset: Option<usize>,
+    /// The name of the label introduced by the instruction, for a `block`, `loop`, `if`, `try` or
+    /// `try_table`.
+    pub label: Option<String>,
 }
 impl<'a, 'b> Instruction<'a>
 where
@@ -1155,6 +1477,8 @@ where
         Self {
             op,
             instr_flag: InstrumentationFlag::default(),
+            offset: None,
+            label: None,
         }
     }
 
@@ -1162,7 +1486,11 @@ where
         self.instr_flag.add_instr(&self.op, val)
     }
 
-    pub fn extract_op(&'a self) -> Operator {
+    pub fn add_instr_tagged(&mut self, val: Operator<'a>, tag: Option<&'a str>) -> bool {
+        self.instr_flag.add_instr_tagged(&self.op, val, tag)
+    }
+
+    pub fn extract_op(&'a self) -> Operator<'a> {
         self.op.clone()
     }
 }
@@ -1219,6 +1547,11 @@ impl InitExpr {
         }
     }
 
+    /// The instructions of the expression
+    pub(crate) fn instructions(&self) -> &[Instructions] {
+        &self.exprs
+    }
+
     pub(crate) fn eval(init: &ConstExpr) -> InitExpr {
         use wasmparser::Operator::*;
         let mut reader = init.get_operators_reader();
@@ -1523,8 +1856,14 @@ impl<'a> CustomSections<'a> {
         None
     }
 
+    /// Add a new custom section, returns its ID
+    pub fn add(&mut self, name: &'a str, data: &'a [u8]) -> CustomSectionID {
+        self.custom_sections.push(CustomSection::new(name, data));
+        CustomSectionID(self.custom_sections.len() as u32 - 1)
+    }
+
     /// Get a custom section by its ID
-    pub fn get_by_id(&self, custom_section_id: CustomSectionID) -> &CustomSection {
+    pub fn get_by_id(&self, custom_section_id: CustomSectionID) -> &CustomSection<'_> {
         if *custom_section_id < self.custom_sections.len() as u32 {
             return &self.custom_sections[*custom_section_id as usize];
         }
@@ -325,17 +325,6 @@ pub fn convert_component_type(
     }
 }
 
-pub fn indirect_namemap_parser2encoder(
-    namemap: wasmparser::IndirectNameMap,
-) -> wasm_encoder::IndirectNameMap {
-    let mut names = wasm_encoder::IndirectNameMap::new();
-    for name in namemap {
-        let naming = name.unwrap();
-        names.append(naming.index, &namemap_parser2encoder(naming.names));
-    }
-    names
-}
-
 pub fn namemap_parser2encoder(namemap: wasmparser::NameMap) -> wasm_encoder::NameMap {
     let mut names = wasm_encoder::NameMap::new();
     for name in namemap {
@@ -352,8 +341,23 @@ pub fn add_to_namemap(namemap: &mut wasm_encoder::NameMap, names: wasmparser::Na
     }
 }
 
+/// Whether `op` introduces a label, numbered in order in the name section.
+pub(crate) fn introduces_label(op: &Operator) -> bool {
+    matches!(
+        op,
+        Operator::Block { .. }
+            | Operator::Loop { .. }
+            | Operator::If { .. }
+            | Operator::Try { .. }
+            | Operator::TryTable { .. }
+    )
+}
+
 pub(crate) fn refers_to_func(op: &Operator) -> bool {
-    matches!(op, Operator::Call { .. } | Operator::RefFunc { .. })
+    matches!(
+        op,
+        Operator::Call { .. } | Operator::ReturnCall { .. } | Operator::RefFunc { .. }
+    )
 }
 
 pub(crate) fn refers_to_global(op: &Operator) -> bool {
@@ -394,6 +398,7 @@ pub(crate) fn refers_to_memory(op: &Operator) -> bool {
         Operator::I32AtomicLoad { .. } |
         Operator::I32AtomicLoad8U { .. } |
         Operator::I32AtomicLoad16U { .. } |
+        Operator::I64AtomicLoad { .. } |
         Operator::I64AtomicLoad8U { .. } |
         Operator::I64AtomicLoad16U { .. } |
         Operator::I64AtomicLoad32U { .. } |
@@ -437,6 +442,57 @@ pub(crate) fn refers_to_memory(op: &Operator) -> bool {
         Operator::V128Store32Lane { .. } |
         Operator::V128Store64Lane { .. } |
 
+        // atomic read-modify-write
+        Operator::I32AtomicRmwAdd { .. } |
+        Operator::I64AtomicRmwAdd { .. } |
+        Operator::I32AtomicRmw8AddU { .. } |
+        Operator::I32AtomicRmw16AddU { .. } |
+        Operator::I64AtomicRmw8AddU { .. } |
+        Operator::I64AtomicRmw16AddU { .. } |
+        Operator::I64AtomicRmw32AddU { .. } |
+        Operator::I32AtomicRmwSub { .. } |
+        Operator::I64AtomicRmwSub { .. } |
+        Operator::I32AtomicRmw8SubU { .. } |
+        Operator::I32AtomicRmw16SubU { .. } |
+        Operator::I64AtomicRmw8SubU { .. } |
+        Operator::I64AtomicRmw16SubU { .. } |
+        Operator::I64AtomicRmw32SubU { .. } |
+        Operator::I32AtomicRmwAnd { .. } |
+        Operator::I64AtomicRmwAnd { .. } |
+        Operator::I32AtomicRmw8AndU { .. } |
+        Operator::I32AtomicRmw16AndU { .. } |
+        Operator::I64AtomicRmw8AndU { .. } |
+        Operator::I64AtomicRmw16AndU { .. } |
+        Operator::I64AtomicRmw32AndU { .. } |
+        Operator::I32AtomicRmwOr { .. } |
+        Operator::I64AtomicRmwOr { .. } |
+        Operator::I32AtomicRmw8OrU { .. } |
+        Operator::I32AtomicRmw16OrU { .. } |
+        Operator::I64AtomicRmw8OrU { .. } |
+        Operator::I64AtomicRmw16OrU { .. } |
+        Operator::I64AtomicRmw32OrU { .. } |
+        Operator::I32AtomicRmwXor { .. } |
+        Operator::I64AtomicRmwXor { .. } |
+        Operator::I32AtomicRmw8XorU { .. } |
+        Operator::I32AtomicRmw16XorU { .. } |
+        Operator::I64AtomicRmw8XorU { .. } |
+        Operator::I64AtomicRmw16XorU { .. } |
+        Operator::I64AtomicRmw32XorU { .. } |
+        Operator::I32AtomicRmwXchg { .. } |
+        Operator::I64AtomicRmwXchg { .. } |
+        Operator::I32AtomicRmw8XchgU { .. } |
+        Operator::I32AtomicRmw16XchgU { .. } |
+        Operator::I64AtomicRmw8XchgU { .. } |
+        Operator::I64AtomicRmw16XchgU { .. } |
+        Operator::I64AtomicRmw32XchgU { .. } |
+        Operator::I32AtomicRmwCmpxchg { .. } |
+        Operator::I64AtomicRmwCmpxchg { .. } |
+        Operator::I32AtomicRmw8CmpxchgU { .. } |
+        Operator::I32AtomicRmw16CmpxchgU { .. } |
+        Operator::I64AtomicRmw8CmpxchgU { .. } |
+        Operator::I64AtomicRmw16CmpxchgU { .. } |
+        Operator::I64AtomicRmw32CmpxchgU { .. } |
+
         // memory operations
         Operator::MemoryAtomicNotify { .. } |
         Operator::MemoryAtomicWait32 { .. } |
@@ -452,14 +508,14 @@ pub(crate) fn refers_to_memory(op: &Operator) -> bool {
 
 pub(crate) fn update_fn_instr(op: &mut Operator, mapping: &HashMap<u32, u32>) {
     match op {
-        Operator::Call { function_index } | Operator::RefFunc { function_index } => {
-            match mapping.get(&(*function_index)) {
-                Some(new_index) => {
-                    *function_index = *new_index;
-                }
-                None => panic!("Deleted function!"),
+        Operator::Call { function_index }
+        | Operator::ReturnCall { function_index }
+        | Operator::RefFunc { function_index } => match mapping.get(&(*function_index)) {
+            Some(new_index) => {
+                *function_index = *new_index;
             }
-        }
+            None => panic!("Deleted function!"),
+        },
         _ => panic!("Operation doesn't need to be checked for function IDs!"),
     }
 }
@@ -489,6 +545,17 @@ pub(crate) fn update_global_instr(op: &mut Operator, mapping: &HashMap<u32, u32>
 }
 
 pub(crate) fn update_memory_instr(op: &mut Operator, mapping: &HashMap<u32, u32>) {
+    visit_memory_indices(op, |mem| match mapping.get(mem) {
+        Some(new_index) => {
+            *mem = *new_index;
+        }
+        None => panic!("Attempting to reference a deleted memory, ID: {}", mem),
+    });
+}
+
+/// Calls `visit` on every memory index of an operation that refers to a memory
+/// (the source before the destination for `memory.copy`).
+pub(crate) fn visit_memory_indices(op: &mut Operator, mut visit: impl FnMut(&mut u32)) {
     match op {
         // loads
         Operator::I32Load { memarg } |
@@ -509,6 +576,7 @@ pub(crate) fn update_memory_instr(op: &mut Operator, mapping: &HashMap<u32, u32>
         Operator::I32AtomicLoad { memarg } |
         Operator::I32AtomicLoad8U { memarg } |
         Operator::I32AtomicLoad16U { memarg } |
+        Operator::I64AtomicLoad { memarg } |
         Operator::I64AtomicLoad8U { memarg } |
         Operator::I64AtomicLoad16U { memarg } |
         Operator::I64AtomicLoad32U { memarg } |
@@ -552,42 +620,69 @@ pub(crate) fn update_memory_instr(op: &mut Operator, mapping: &HashMap<u32, u32>
         Operator::V128Store32Lane {memarg, ..} |
         Operator::V128Store64Lane {memarg, ..} |
 
+        // atomic read-modify-write
+        Operator::I32AtomicRmwAdd {memarg} |
+        Operator::I64AtomicRmwAdd {memarg} |
+        Operator::I32AtomicRmw8AddU {memarg} |
+        Operator::I32AtomicRmw16AddU {memarg} |
+        Operator::I64AtomicRmw8AddU {memarg} |
+        Operator::I64AtomicRmw16AddU {memarg} |
+        Operator::I64AtomicRmw32AddU {memarg} |
+        Operator::I32AtomicRmwSub {memarg} |
+        Operator::I64AtomicRmwSub {memarg} |
+        Operator::I32AtomicRmw8SubU {memarg} |
+        Operator::I32AtomicRmw16SubU {memarg} |
+        Operator::I64AtomicRmw8SubU {memarg} |
+        Operator::I64AtomicRmw16SubU {memarg} |
+        Operator::I64AtomicRmw32SubU {memarg} |
+        Operator::I32AtomicRmwAnd {memarg} |
+        Operator::I64AtomicRmwAnd {memarg} |
+        Operator::I32AtomicRmw8AndU {memarg} |
+        Operator::I32AtomicRmw16AndU {memarg} |
+        Operator::I64AtomicRmw8AndU {memarg} |
+        Operator::I64AtomicRmw16AndU {memarg} |
+        Operator::I64AtomicRmw32AndU {memarg} |
+        Operator::I32AtomicRmwOr {memarg} |
+        Operator::I64AtomicRmwOr {memarg} |
+        Operator::I32AtomicRmw8OrU {memarg} |
+        Operator::I32AtomicRmw16OrU {memarg} |
+        Operator::I64AtomicRmw8OrU {memarg} |
+        Operator::I64AtomicRmw16OrU {memarg} |
+        Operator::I64AtomicRmw32OrU {memarg} |
+        Operator::I32AtomicRmwXor {memarg} |
+        Operator::I64AtomicRmwXor {memarg} |
+        Operator::I32AtomicRmw8XorU {memarg} |
+        Operator::I32AtomicRmw16XorU {memarg} |
+        Operator::I64AtomicRmw8XorU {memarg} |
+        Operator::I64AtomicRmw16XorU {memarg} |
+        Operator::I64AtomicRmw32XorU {memarg} |
+        Operator::I32AtomicRmwXchg {memarg} |
+        Operator::I64AtomicRmwXchg {memarg} |
+        Operator::I32AtomicRmw8XchgU {memarg} |
+        Operator::I32AtomicRmw16XchgU {memarg} |
+        Operator::I64AtomicRmw8XchgU {memarg} |
+        Operator::I64AtomicRmw16XchgU {memarg} |
+        Operator::I64AtomicRmw32XchgU {memarg} |
+        Operator::I32AtomicRmwCmpxchg {memarg} |
+        Operator::I64AtomicRmwCmpxchg {memarg} |
+        Operator::I32AtomicRmw8CmpxchgU {memarg} |
+        Operator::I32AtomicRmw16CmpxchgU {memarg} |
+        Operator::I64AtomicRmw8CmpxchgU {memarg} |
+        Operator::I64AtomicRmw16CmpxchgU {memarg} |
+        Operator::I64AtomicRmw32CmpxchgU {memarg} |
+
         // memory operations
         Operator::MemoryAtomicNotify {memarg} |
         Operator::MemoryAtomicWait32 {memarg} |
-        Operator::MemoryAtomicWait64 {memarg} => {
-            match mapping.get(&(memarg.memory)) {
-                Some(new_index) => {
-                    memarg.memory = *new_index;
-                }
-                None => panic!("Attempting to reference a deleted memory, ID: {}", memarg.memory),
-            }
-        }
+        Operator::MemoryAtomicWait64 {memarg} => visit(&mut memarg.memory),
         Operator::MemoryGrow {mem} |
         Operator::MemoryFill {mem} |
         Operator::MemoryInit {mem, ..} |
         Operator::MemorySize {mem} |
-        Operator::MemoryDiscard {mem} => {
-            match mapping.get(mem) {
-                Some(new_index) => {
-                    *mem = *new_index;
-                }
-                None => panic!("Attempting to reference a deleted memory, ID: {}", mem),
-            }
-        }
+        Operator::MemoryDiscard {mem} => visit(mem),
         Operator::MemoryCopy {src_mem, dst_mem} => {
-            match mapping.get(src_mem) {
-                Some(new_index) => {
-                    *src_mem = *new_index;
-                }
-                None => panic!("Attempting to reference a deleted memory, ID: {}", src_mem),
-            }
-            match mapping.get(dst_mem) {
-                Some(new_index) => {
-                    *dst_mem = *new_index;
-                }
-                None => panic!("Attempting to reference a deleted memory, ID: {}", dst_mem),
-            }
+            visit(src_mem);
+            visit(dst_mem);
         }
         _ => panic!("Operation doesn't need to be checked for memory IDs!"),
     }
@@ -1,5 +1,8 @@
 //! Iterator to traverse a Component
 
+use crate::analysis::loops::LoopNest;
+use crate::error::Error;
+use crate::ir::arena::WatArena;
 use crate::ir::component::Component;
 use crate::ir::id::{FunctionID, GlobalID, LocalID, ModuleID};
 use crate::ir::module::module_functions::FuncKind;
@@ -9,6 +12,7 @@ use crate::iterator::iterator_trait::{IteratingInstrumenter, Iterator};
 use crate::module_builder::AddLocal;
 use crate::opcode::{Inject, InjectAt, Instrumenter, MacroOpcode, Opcode};
 use crate::subiterator::component_subiterator::ComponentSubIterator;
+use log::trace;
 use std::collections::HashMap;
 use std::iter::Iterator as StdIter;
 use wasmparser::Operator;
@@ -19,13 +23,16 @@ pub struct ComponentIterator<'a, 'b> {
     pub comp: &'a mut Component<'b>,
     /// The SubIterator for this Component
     comp_iterator: ComponentSubIterator,
+    /// The loop-nest tree of the last function it was asked for, with the number of instructions
+    /// of the function when it was computed
+    loop_nest: Option<(ModuleID, FunctionID, usize, LoopNest)>,
 }
 
 fn print_metadata(metadata: &HashMap<ModuleID, Vec<(FunctionID, usize)>>) {
     for c in metadata.keys() {
-        println!("Module: {:?}", c);
+        trace!("Module: {:?}", c);
         for (m, i) in metadata.get(c).unwrap().iter() {
-            println!("Function: {:?} Instr: {:?}", m, i);
+            trace!("Function: {:?} Instr: {:?}", m, i);
         }
     }
 }
@@ -52,6 +59,7 @@ impl<'a, 'b> ComponentIterator<'a, 'b> {
                 metadata,
                 skip_funcs,
             ),
+            loop_nest: None,
         }
     }
 
@@ -98,6 +106,47 @@ impl<'a, 'b> ComponentIterator<'a, 'b> {
             panic!("Should have gotten Component Location!")
         }
     }
+
+    /// Mark the current function to inject at its exit and get the locals holding its return
+    /// values while the injected body runs. See [`Module::func_exit_with_results`].
+    ///
+    /// [`Module::func_exit_with_results`]: crate::Module::func_exit_with_results
+    pub fn func_exit_with_results(&mut self) -> Vec<LocalID> {
+        if let (
+            Location::Component {
+                mod_idx, func_idx, ..
+            },
+            ..,
+        ) = self.comp_iterator.curr_loc()
+        {
+            self.comp.modules[*mod_idx as usize]
+                .func_exit_with_results(func_idx)
+                .expect("Can't instrument into an imported function!")
+        } else {
+            panic!("Should have gotten component location!")
+        }
+    }
+
+    /// Injects the instructions of the text format `wat` at the current location, with the names
+    /// resolved against the current module. Their binary is kept by `arena`. See
+    /// [`Module::parse_wat_instrs`].
+    ///
+    /// [`Module::parse_wat_instrs`]: crate::Module::parse_wat_instrs
+    pub fn inject_wat(&mut self, wat: &str, arena: &'b WatArena) -> Result<&mut Self, Error> {
+        if let (
+            Location::Component {
+                mod_idx, func_idx, ..
+            },
+            ..,
+        ) = self.comp_iterator.curr_loc()
+        {
+            let ops =
+                self.comp.modules[*mod_idx as usize].parse_wat_instrs(func_idx, wat, arena)?;
+            Ok(self.inject_all(&ops))
+        } else {
+            panic!("Should have gotten component location!")
+        }
+    }
 }
 
 impl<'a, 'b> Inject<'b> for ComponentIterator<'a, 'b> {
@@ -382,7 +431,7 @@ impl<'a, 'b> Instrumenter<'b> for ComponentIterator<'a, 'b> {
     }
 
     /// Gets the injected instruction at the current location by index
-    fn get_injected_val(&self, idx: usize) -> &Operator {
+    fn get_injected_val(&self, idx: usize) -> &Operator<'_> {
         if let (
             Location::Component {
                 mod_idx,
@@ -464,7 +513,7 @@ impl<'a, 'b> Iterator for ComponentIterator<'a, 'b> {
     }
 
     /// Goes to the next instruction
-    fn next(&mut self) -> Option<&Operator> {
+    fn next(&mut self) -> Option<&Operator<'_>> {
         match self.comp_iterator.next() {
             false => None,
             true => self.curr_op(),
@@ -478,7 +527,7 @@ impl<'a, 'b> Iterator for ComponentIterator<'a, 'b> {
     }
 
     /// Returns the instruction at the current location
-    fn curr_op(&self) -> Option<&Operator> {
+    fn curr_op(&self) -> Option<&Operator<'_>> {
         if self.comp_iterator.end() {
             None
         } else if let (
@@ -503,6 +552,36 @@ impl<'a, 'b> Iterator for ComponentIterator<'a, 'b> {
             panic!("Should have gotten Component Location and not Module Location!")
         }
     }
+
+    /// Returns the loop-nest tree of the current function
+    fn curr_loop_nest(&mut self) -> Option<&LoopNest> {
+        if self.comp_iterator.end() {
+            return None;
+        }
+        let (
+            Location::Component {
+                mod_idx, func_idx, ..
+            },
+            ..,
+        ) = self.comp_iterator.curr_loc()
+        else {
+            panic!("Should have gotten Component Location and not Module Location!")
+        };
+        let FuncKind::Local(l) = &self.comp.modules[*mod_idx as usize]
+            .functions
+            .get(func_idx)
+            .kind
+        else {
+            return None;
+        };
+        // the body may have been edited since, e.g. outlined
+        let len = l.body.instructions.len();
+        if !matches!(&self.loop_nest, Some((m, f, n, _)) if *m == mod_idx && *f == func_idx && *n == len)
+        {
+            self.loop_nest = Some((mod_idx, func_idx, len, LoopNest::new(&l.body)));
+        }
+        self.loop_nest.as_ref().map(|(_, _, _, nest)| nest)
+    }
 }
 
 impl AddLocal for ComponentIterator<'_, '_> {
@@ -1,5 +1,6 @@
 //! Trait that needs to be satisfied by all iterators
 
+use crate::analysis::loops::{Loop, LoopNest};
 use crate::ir::id::GlobalID;
 use crate::ir::module::module_globals::Global;
 use crate::ir::types::{InstrumentationMode, Location};
@@ -13,14 +14,45 @@ pub trait Iterator {
     fn reset(&mut self);
 
     /// Go to the next Instruction
-    fn next(&mut self) -> Option<&Operator>;
+    fn next(&mut self) -> Option<&Operator<'_>>;
 
     /// Returns the Current Location as a Location and a bool value that
     /// says whether the location is at the end of the function.
     fn curr_loc(&self) -> (Location, bool);
 
     /// Get the current instruction
-    fn curr_op(&self) -> Option<&Operator>;
+    fn curr_op(&self) -> Option<&Operator<'_>>;
+
+    /// Returns the loop-nest tree of the function the iterator is currently in, `None` if the
+    /// iterator is done. The tree is cached, and computed again when the iterator moves to another
+    /// function or the number of instructions of the function changed.
+    ///
+    /// The default implementation returns `None`, so that iterators which do not compute loop
+    /// nests see no loops in [`Iterator::curr_loop`] and [`Iterator::curr_loop_depth`].
+    fn curr_loop_nest(&mut self) -> Option<&LoopNest> {
+        None
+    }
+
+    /// Returns the innermost loop containing the current instruction
+    fn curr_loop(&mut self) -> Option<&Loop> {
+        let instr_idx = instr_idx(self.curr_loc().0);
+        let nest = self.curr_loop_nest()?;
+        nest.innermost_loop(instr_idx)
+            .map(|loop_idx| nest.get(loop_idx))
+    }
+
+    /// Returns the number of loops containing the current instruction
+    fn curr_loop_depth(&mut self) -> u32 {
+        let instr_idx = instr_idx(self.curr_loc().0);
+        self.curr_loop_nest()
+            .map_or(0, |nest| nest.depth(instr_idx))
+    }
+}
+
+fn instr_idx(loc: Location) -> usize {
+    match loc {
+        Location::Component { instr_idx, .. } | Location::Module { instr_idx, .. } => instr_idx,
+    }
 }
 
 /// This trait coincides with the Iterator as instrumentation occurs during Wasm visitation.
@@ -1,5 +1,8 @@
 //! Iterator to traverse a Module
 
+use crate::analysis::loops::LoopNest;
+use crate::error::Error;
+use crate::ir::arena::WatArena;
 use crate::ir::id::{FunctionID, GlobalID, LocalID};
 use crate::ir::module::module_functions::FuncKind;
 use crate::ir::module::module_globals::Global;
@@ -18,6 +21,9 @@ pub struct ModuleIterator<'a, 'b> {
     pub module: &'a mut Module<'b>,
     /// The SubIterator for this Module
     mod_iterator: ModuleSubIterator,
+    /// The loop-nest tree of the last function it was asked for, with the number of instructions
+    /// of the function when it was computed
+    loop_nest: Option<(FunctionID, usize, LoopNest)>,
 }
 
 #[allow(dead_code)]
@@ -28,6 +34,7 @@ impl<'a, 'b> ModuleIterator<'a, 'b> {
         ModuleIterator {
             module,
             mod_iterator: ModuleSubIterator::new(metadata, skip_funcs.to_owned()),
+            loop_nest: None,
         }
     }
 
@@ -49,6 +56,30 @@ impl<'a, 'b> ModuleIterator<'a, 'b> {
             panic!("Should have gotten Module Location!")
         }
     }
+
+    /// Mark the current function to inject at its exit and get the locals holding its return
+    /// values while the injected body runs. See [`Module::func_exit_with_results`].
+    pub fn func_exit_with_results(&mut self) -> Vec<LocalID> {
+        if let (Location::Module { func_idx, .. }, ..) = self.mod_iterator.curr_loc() {
+            self.module
+                .func_exit_with_results(func_idx)
+                .expect("Cannot get an instruction to an imported function")
+        } else {
+            panic!("Should have gotten Module Location!")
+        }
+    }
+
+    /// Injects the instructions of the text format `wat` at the current location, with the names
+    /// resolved against the module. Their binary is kept by `arena`. See
+    /// [`Module::parse_wat_instrs`].
+    pub fn inject_wat(&mut self, wat: &str, arena: &'b WatArena) -> Result<&mut Self, Error> {
+        if let (Location::Module { func_idx, .. }, ..) = self.mod_iterator.curr_loc() {
+            let ops = self.module.parse_wat_instrs(func_idx, wat, arena)?;
+            Ok(self.inject_all(&ops))
+        } else {
+            panic!("Should have gotten Module Location!")
+        }
+    }
 }
 
 impl<'a, 'b> Inject<'b> for ModuleIterator<'a, 'b> {
@@ -286,7 +317,7 @@ impl<'a> Instrumenter<'a> for ModuleIterator<'_, 'a> {
     }
 
     /// Gets the injected instruction at the current location by index
-    fn get_injected_val(&self, idx: usize) -> &Operator {
+    fn get_injected_val(&self, idx: usize) -> &Operator<'_> {
         if let (
             Location::Module {
                 func_idx,
@@ -334,7 +365,7 @@ impl<'a> Iterator for ModuleIterator<'_, 'a> {
     }
 
     /// Goes to the next instruction and returns the instruction
-    fn next(&mut self) -> Option<&Operator> {
+    fn next(&mut self) -> Option<&Operator<'_>> {
         match self.mod_iterator.next() {
             false => None,
             true => self.curr_op(),
@@ -366,4 +397,23 @@ impl<'a> Iterator for ModuleIterator<'_, 'a> {
             panic!("Should have gotten Module Location!")
         }
     }
+
+    /// Returns the loop-nest tree of the current function
+    fn curr_loop_nest(&mut self) -> Option<&LoopNest> {
+        if self.mod_iterator.end() {
+            return None;
+        }
+        let (Location::Module { func_idx, .. }, ..) = self.mod_iterator.curr_loc() else {
+            panic!("Should have gotten Module Location!")
+        };
+        let FuncKind::Local(l) = &self.module.functions.get(func_idx).kind else {
+            return None;
+        };
+        // the body may have been edited since, e.g. outlined
+        let len = l.body.instructions.len();
+        if !matches!(&self.loop_nest, Some((f, n, _)) if *f == func_idx && *n == len) {
+            self.loop_nest = Some((func_idx, len, LoopNest::new(&l.body)));
+        }
+        self.loop_nest.as_ref().map(|(_, _, nest)| nest)
+    }
 }
@@ -4,12 +4,16 @@
 //! [Dfinity's IC]: https://github.com/dfinity/ic/tree/master/rs/wasm_transform
 //! [Walrus]: https://github.com/rustwasm/walrus/tree/main
 
+pub mod analysis;
 mod error;
 pub mod ir;
 pub mod iterator;
 pub mod module_builder;
 pub mod opcode;
+pub mod passes;
+pub mod probes;
 pub mod subiterator;
+pub mod transform;
 
 pub use crate::opcode::Opcode;
 
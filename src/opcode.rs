@@ -4,7 +4,9 @@
 // note that the location of the injection is handled specific implementation
 // for iterators, we inject at the location the iterator is pointing at (curr_loc)
 // for FunctionBuilder, we inject at the end of the function
-use crate::ir::id::{DataSegmentID, ElementID, FieldID, FunctionID, GlobalID, LocalID, TypeID};
+use crate::ir::id::{
+    DataSegmentID, ElementID, FieldID, FunctionID, GlobalID, LocalID, TableID, TypeID,
+};
 use crate::ir::module::module_types::HeapType;
 use crate::ir::types::{BlockType, FuncInstrMode, InstrumentationMode};
 use crate::Location;
@@ -70,6 +72,9 @@ pub trait Instrumenter<'a> {
     fn empty_alternate_at(&mut self, loc: Location) -> &mut Self;
 
     /// Injects a Semantic After at a given location
+    ///
+    /// When the body runs after the `end` of a block, the block's results are on the stack:
+    /// it may consume them as long as it pushes values of the same types back.
     fn semantic_after_at(&mut self, loc: Location) -> &mut Self {
         self.set_instrument_mode_at(InstrumentationMode::SemanticAfter, loc);
         self
@@ -82,6 +87,9 @@ pub trait Instrumenter<'a> {
     }
 
     /// Injects a block exit at a given location
+    ///
+    /// The block's results are on the stack when the body runs: it may consume them as long as
+    /// it pushes values of the same types back.
     fn block_exit_at(&mut self, loc: Location) -> &mut Self {
         self.set_instrument_mode_at(InstrumentationMode::BlockExit, loc);
         self
@@ -97,7 +105,7 @@ pub trait Instrumenter<'a> {
     fn empty_block_alt_at(&mut self, loc: Location) -> &mut Self;
 
     /// Get the instruction injected at index idx
-    fn get_injected_val(&self, idx: usize) -> &Operator;
+    fn get_injected_val(&self, idx: usize) -> &Operator<'_>;
 }
 
 /// Defines Injection behaviour at the current location of the Iterator
@@ -135,6 +143,16 @@ pub trait Opcode<'a>: Inject<'a> {
         self
     }
 
+    /// Inject a call_indirect instruction, calling a function of type `type_index` through the table
+    /// `table_index`
+    fn call_indirect(&mut self, type_index: TypeID, table_index: TableID) -> &mut Self {
+        self.inject(Operator::CallIndirect {
+            type_index: *type_index,
+            table_index: *table_index,
+        });
+        self
+    }
+
     /// Inject a return statement
     fn return_stmt(&mut self) -> &mut Self {
         self.inject(Operator::Return);
@@ -987,6 +1005,12 @@ pub trait Opcode<'a>: Inject<'a> {
         self
     }
 
+    /// Inject a select instruction
+    fn select(&mut self) -> &mut Self {
+        self.inject(Operator::Select);
+        self
+    }
+
     // Linear Memory Access
     // note: walrus does not specify max_align (probably it's the same as align)
 
@@ -0,0 +1,868 @@
+//! An address sanitizer: checks that the loads and stores of a module stay within the blocks
+//! allocated on its heap, and reports the invalid accesses to a host function.
+//!
+//! Wasm only checks the accesses against the bounds of the whole linear memory, so an overflow out
+//! of a heap block silently corrupts its neighbours. As in AddressSanitizer, the pass keeps a
+//! shadow byte for every 8-byte granule of memory 0, in a new memory (the instrumented module
+//! requires the multi-memory proposal):
+//! - 0 when the whole granule is addressable,
+//! - `k` in 1..=7 when only its first `k` bytes are,
+//! - negative when it is poisoned: [`HEADER_POISON`] and [`REDZONE_POISON`] around an allocated
+//!   block, [`FREED_HEADER_POISON`] and [`FREED_POISON`] for a freed block.
+//!
+//! Memory that was never allocated (the stack, the static data) is addressable. The allocation
+//! functions exported by the guest (see [`AsanOptions`]) are hooked by wrapper functions, which the
+//! exports and the direct calls are redirected to: the wrappers allocate a header before every
+//! block, which records its size, and a redzone after it, unpoison the block and poison its header
+//! and redzone, and poison exactly the bytes of the blocks that are freed. A block without a header
+//! (allocated before the module was instrumented, or through a table) is passed to the allocator
+//! as it is, and nothing is poisoned when it is freed. Every access to memory 0 is then preceded by
+//! a call to a check of the shadow of its bytes, which calls the imported handler on the first
+//! poisoned byte (see [`ReportKind`]). The shadow grows along with memory 0, whether it is grown by
+//! the module or by the host.
+//!
+//! The pass assumes that the allocator returns blocks aligned to 8 bytes, and that `cabi_realloc`
+//! reallocates a block with the alignment it was allocated with. The allocator itself (the
+//! functions reachable from the allocation functions) is neither checked nor redirected, nor are
+//! the calls through a table. An underflow is caught when it reaches the header of the block.
+
+use crate::analysis::usage::ModuleUsage;
+use crate::error::Error;
+use crate::ir::function::FunctionBuilder;
+use crate::ir::id::{FunctionID, LocalID, MemoryID};
+use crate::ir::types::BlockType;
+use crate::iterator::iterator_trait::{IteratingInstrumenter, Iterator};
+use crate::iterator::module_iterator::ModuleIterator;
+use crate::module_builder::AddLocal;
+use crate::probes::{AccessKind, AccessTemps, AccessTracer, MemoryAccess};
+use crate::transform::wrap::redirect_calls;
+use crate::{DataType, Location, Module, Opcode};
+use std::collections::{BTreeSet, HashMap};
+use wasmparser::{MemArg, MemoryType, Operator};
+
+/// The shadow byte of the granules of the redzone following an allocated block.
+pub const REDZONE_POISON: u8 = 0xFA;
+/// The shadow byte of the granules of a freed block.
+pub const FREED_POISON: u8 = 0xFD;
+/// The shadow byte of the last granule of the header before an allocated block, which holds the
+/// size of the block and of its header. The other granules of the header are [`REDZONE_POISON`].
+pub const HEADER_POISON: u8 = 0xFC;
+/// The shadow byte of the last granule of the header before a freed block.
+pub const FREED_HEADER_POISON: u8 = 0xFE;
+
+/// The size of the header allocated before a block, unless its alignment is larger.
+const HEADER: i32 = 8;
+
+/// The invalid operation reported to the handler, its third argument.
+///
+/// The handler has the type `[i64, i64, i32] -> []`: it receives the address and the length of
+/// the access (0 for a free), then the kind. The instrumented code goes on once it returns, so the
+/// handler traps to stop at the first error.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReportKind {
+    /// A load, or the source of a `memory.copy`, reads poisoned memory.
+    Read = 0,
+    /// A store, read-modify-write or `memory.fill`, or the destination of a `memory.copy`, writes
+    /// poisoned memory.
+    Write = 1,
+    /// A block that was already freed is freed (or reallocated) again.
+    InvalidFree = 2,
+}
+
+/// The configuration of the address sanitizer.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AsanOptions<'s> {
+    /// The imported handler the invalid accesses are reported to: (module, name).
+    pub handler: (&'s str, &'s str),
+    /// The number of bytes of the redzone allocated after every block, a multiple of 8.
+    pub redzone: u32,
+    /// The name of the exported `malloc`, of type `[i32] -> [i32]`.
+    pub malloc: &'s str,
+    /// The name of the exported `calloc`, of type `[i32, i32] -> [i32]`.
+    pub calloc: &'s str,
+    /// The name of the exported `realloc`, of type `[i32, i32] -> [i32]`.
+    pub realloc: &'s str,
+    /// The name of the exported `free`, of type `[i32] -> []`.
+    pub free: &'s str,
+    /// The name of the exported `cabi_realloc` of the canonical ABI, of type
+    /// `[i32, i32, i32, i32] -> [i32]`.
+    pub cabi_realloc: &'s str,
+}
+
+impl Default for AsanOptions<'_> {
+    fn default() -> Self {
+        AsanOptions {
+            handler: ("asan", "report"),
+            redzone: 16,
+            malloc: "malloc",
+            calloc: "calloc",
+            realloc: "realloc",
+            free: "free",
+            cabi_realloc: "cabi_realloc",
+        }
+    }
+}
+
+/// What the address sanitizer added to the module.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Asan {
+    /// The shadow memory.
+    pub shadow: MemoryID,
+    /// The imported handler.
+    pub handler: FunctionID,
+    /// The check called before the accesses, of type `[i64, i64, i32] -> []` like the handler.
+    pub check: FunctionID,
+    /// The allocation functions that were hooked, with their wrappers: (function, wrapper).
+    pub wrappers: Vec<(FunctionID, FunctionID)>,
+    /// The functions whose accesses are not checked: the allocator and the generated functions.
+    pub unchecked: BTreeSet<FunctionID>,
+}
+
+/// An allocation function of the guest.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Allocator {
+    Malloc,
+    Calloc,
+    Realloc,
+    Free,
+    CabiRealloc,
+}
+
+impl Allocator {
+    fn params(&self) -> &'static [DataType] {
+        use DataType::I32;
+        match self {
+            Allocator::Malloc | Allocator::Free => &[I32],
+            Allocator::Calloc | Allocator::Realloc => &[I32, I32],
+            Allocator::CabiRealloc => &[I32, I32, I32, I32],
+        }
+    }
+
+    fn results(&self) -> &'static [DataType] {
+        match self {
+            Allocator::Free => &[],
+            _ => &[DataType::I32],
+        }
+    }
+}
+
+/// The generated functions shared by the wrappers and the instrumentation.
+struct Runtime {
+    shadow: MemoryID,
+    handler: FunctionID,
+    check: FunctionID,
+    /// `[i32 address, i32 size, i32 header] -> [i32 block]`: takes the address returned by the
+    /// allocator, writes the header of the block, unpoisons the block and poisons its header and
+    /// redzone. A block without a header (0), which the allocator did not allocate for a wrapper,
+    /// is only unpoisoned.
+    alloc: FunctionID,
+    /// `[i32 block] -> [i32 address]`: poisons a freed block, or reports it if it was already freed.
+    /// Returns the address to pass to the allocator, the block itself if it has no header.
+    free: FunctionID,
+    /// `[i32] -> [i32]`: grows the shadow after memory 0 grew, returns its argument. The check and
+    /// the hooks of the allocation functions also grow the shadow when memory 0 was grown by the
+    /// host.
+    grow_shadow: FunctionID,
+}
+
+/// Instruments `module` with the address sanitizer configured by `options`.
+///
+/// Fails if memory 0 does not exist or is a 64-bit memory, if the redzone is not a multiple of 8,
+/// or if no allocation function is exported (or one has an unexpected type).
+pub fn instrument(module: &mut Module, options: &AsanOptions) -> Result<Asan, Error> {
+    let invalid = |reason: String| Error::InvalidPass {
+        pass: "address sanitizer".to_string(),
+        reason,
+    };
+    if options.redzone == 0 || !options.redzone.is_multiple_of(8) {
+        return Err(invalid(format!(
+            "the redzone must be a positive multiple of 8 bytes, not {}",
+            options.redzone
+        )));
+    }
+    let memory = match module.memories.get_mem_by_id(MemoryID(0)) {
+        Some(mem) => mem.ty,
+        None => return Err(invalid("the module has no memory".to_string())),
+    };
+    if memory.memory64 || memory.page_size_log2.is_some() {
+        return Err(invalid(
+            "memory 0 must be a 32-bit memory with the default page size".to_string(),
+        ));
+    }
+
+    let mut allocators = vec![];
+    for (allocator, name) in [
+        (Allocator::Malloc, options.malloc),
+        (Allocator::Calloc, options.calloc),
+        (Allocator::Realloc, options.realloc),
+        (Allocator::Free, options.free),
+        (Allocator::CabiRealloc, options.cabi_realloc),
+    ] {
+        if let Some(func) = module.exports.get_func_by_name(name.to_string()) {
+            let ty = module.types.get(module.functions.get_type_id(func));
+            if ty.is_none_or(|ty| {
+                ty.params() != allocator.params() || ty.results() != allocator.results()
+            }) {
+                return Err(invalid(format!(
+                    "the exported `{}` is not of type {:?} -> {:?}",
+                    name,
+                    allocator.params(),
+                    allocator.results()
+                )));
+            }
+            allocators.push((allocator, func, name));
+        }
+    }
+    if allocators.is_empty() {
+        return Err(invalid("no allocation function is exported".to_string()));
+    }
+
+    let usage = ModuleUsage::new(module);
+    let mut unchecked: BTreeSet<FunctionID> = allocators
+        .iter()
+        .flat_map(|(_, func, _)| usage.reachable(*func))
+        .collect();
+    let tracer = AccessTracer::new(module);
+
+    // a shadow byte for every 8 bytes of memory 0
+    let shadow = module.add_local_memory(MemoryType {
+        memory64: false,
+        shared: false,
+        initial: memory.initial.div_ceil(8).max(1),
+        maximum: None,
+        page_size_log2: None,
+    });
+    let handler_ty = module
+        .types
+        .add_func_type(&[DataType::I64, DataType::I64, DataType::I32], &[]);
+    let (handler, _) = module.add_import_func(
+        options.handler.0.to_string(),
+        options.handler.1.to_string(),
+        handler_ty,
+    );
+    let runtime = Runtime::new(module, shadow, handler, options.redzone);
+    let mut generated = vec![
+        runtime.check,
+        runtime.alloc,
+        runtime.free,
+        runtime.grow_shadow,
+    ];
+
+    let mut wrappers = vec![];
+    for (allocator, func, name) in allocators {
+        let wrapper = runtime.wrap(module, (allocator, func, name), options.redzone);
+        module.exports.redirect_func(func, wrapper);
+        wrappers.push((func, wrapper));
+        generated.push(wrapper);
+    }
+    let skipped: Vec<FunctionID> = unchecked.iter().chain(generated.iter()).copied().collect();
+    for (func, wrapper) in wrappers.iter() {
+        redirect_calls(module, *func, *wrapper, &skipped);
+    }
+
+    // the allocator still grows the shadow along with memory 0
+    let mut temps: HashMap<FunctionID, AccessTemps> = HashMap::new();
+    let mut mod_it = ModuleIterator::new(module, &generated);
+    loop {
+        if let (Location::Module { func_idx, .. }, ..) = mod_it.curr_loc() {
+            match mod_it.curr_op() {
+                Some(Operator::MemoryGrow { mem: 0 }) => {
+                    mod_it.after();
+                    mod_it.call(runtime.grow_shadow);
+                }
+                Some(op) if !unchecked.contains(&func_idx) => {
+                    if let Some(access) = tracer.access(op) {
+                        if access.memory == 0 || access.source_memory == Some(0) {
+                            mod_it.before();
+                            let temps = temps.entry(func_idx).or_default();
+                            check_access(&mut mod_it, &access, temps, runtime.check);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        if mod_it.next().is_none() {
+            break;
+        };
+    }
+
+    unchecked.extend(generated);
+    Ok(Asan {
+        shadow,
+        handler,
+        check: runtime.check,
+        wrappers,
+        unchecked,
+    })
+}
+
+/// Injects the checks of the bytes of memory 0 accessed by `access`.
+fn check_access<'a, T: Opcode<'a> + AddLocal + ?Sized>(
+    injector: &mut T,
+    access: &MemoryAccess,
+    temps: &mut AccessTemps,
+    check: FunctionID,
+) {
+    let locals = access.save(injector, temps);
+    if access.memory == 0 {
+        let kind = match access.kind {
+            AccessKind::Load | AccessKind::WaitNotify => ReportKind::Read,
+            AccessKind::Store
+            | AccessKind::ReadModifyWrite
+            | AccessKind::CompareExchange
+            | AccessKind::Copy
+            | AccessKind::Fill => ReportKind::Write,
+        };
+        injector
+            .local_get(locals.address)
+            .local_get(locals.width)
+            .i32_const(kind as i32)
+            .call(check);
+    }
+    if let (Some(0), Some(source)) = (access.source_memory, locals.source) {
+        injector
+            .local_get(source)
+            .local_get(locals.width)
+            .i32_const(ReportKind::Read as i32)
+            .call(check);
+    }
+}
+
+impl Runtime {
+    fn new(module: &mut Module, shadow: MemoryID, handler: FunctionID, redzone: u32) -> Self {
+        let mut runtime = Runtime {
+            shadow,
+            handler,
+            check: FunctionID(0),
+            alloc: FunctionID(0),
+            free: FunctionID(0),
+            grow_shadow: FunctionID(0),
+        };
+        runtime.check = runtime.add_check(module);
+        runtime.alloc = runtime.add_alloc(module, redzone);
+        runtime.free = runtime.add_free(module);
+        runtime.grow_shadow = runtime.add_grow_shadow(module);
+        runtime
+    }
+
+    /// Accesses a single shadow byte.
+    fn shadow_arg(&self) -> MemArg {
+        MemArg {
+            align: 0,
+            max_align: 0,
+            offset: 0,
+            memory: *self.shadow,
+        }
+    }
+
+    /// Accesses a field of the header of a block in memory 0.
+    fn memory_arg(&self) -> MemArg {
+        MemArg {
+            align: 2,
+            max_align: 2,
+            offset: 0,
+            memory: 0,
+        }
+    }
+
+    fn add_check(&self, module: &mut Module) -> FunctionID {
+        use DataType::{I32, I64};
+        let (address, len, kind) = (LocalID(0), LocalID(1), LocalID(2));
+        let mut check = FunctionBuilder::new(&[I64, I64, I32], &[]);
+        let end = check.add_local(I64);
+        let granule = check.add_local(I64);
+        let last = check.add_local(I64);
+        let poison = check.add_local(I32);
+        let missing = check.add_local(I32);
+
+        // nothing is accessed, or the access traps anyway
+        check
+            .local_get(len)
+            .i64_eqz()
+            .local_get(address)
+            .local_get(len)
+            .i64_add()
+            .memory_size(0)
+            .i64_extend_i32u()
+            .i64_const(16)
+            .i64_shl()
+            .i64_gt_unsigned()
+            .i32_or()
+            .if_stmt(BlockType::Empty)
+            .return_stmt()
+            .end();
+
+        // the last byte accessed, and the granules of the first and the last bytes
+        check
+            .local_get(address)
+            .local_get(len)
+            .i64_add()
+            .i64_const(1)
+            .i64_sub()
+            .local_tee(end)
+            .i64_const(3)
+            .i64_shr_unsigned()
+            .local_set(last)
+            .local_get(address)
+            .i64_const(3)
+            .i64_shr_unsigned()
+            .local_set(granule);
+
+        // memory 0 was grown by the host
+        check
+            .local_get(last)
+            .i64_const(16)
+            .i64_shr_unsigned()
+            .memory_size(*self.shadow)
+            .i64_extend_i32u()
+            .i64_gte_unsigned()
+            .if_stmt(BlockType::Empty);
+        self.fit_shadow(&mut check, missing);
+        check.end();
+
+        check
+            .loop_stmt(BlockType::Empty)
+            .local_get(granule)
+            .i32_wrap_i64()
+            .i32_load8_s(self.shadow_arg())
+            .local_tee(poison)
+            .if_stmt(BlockType::Empty)
+            // the granule is poisoned, or only its first bytes are addressable and the access
+            // goes past them
+            .local_get(poison)
+            .i32_const(0)
+            .i32_lt_signed()
+            .local_get(end)
+            .i32_wrap_i64()
+            .i32_const(7)
+            .i32_and()
+            .i32_const(7)
+            .local_get(granule)
+            .local_get(last)
+            .i64_eq()
+            .select()
+            .local_get(poison)
+            .i32_gte_signed()
+            .i32_or()
+            .if_stmt(BlockType::Empty)
+            .local_get(address)
+            .local_get(len)
+            .local_get(kind)
+            .call(self.handler)
+            .return_stmt()
+            .end()
+            .end()
+            .local_get(granule)
+            .local_get(last)
+            .i64_lt_unsigned()
+            .if_stmt(BlockType::Empty)
+            .local_get(granule)
+            .i64_const(1)
+            .i64_add()
+            .local_set(granule)
+            .br(1)
+            .end()
+            .end();
+
+        check.set_name("asan_check".to_string());
+        check.finish_module(module)
+    }
+
+    fn add_alloc(&self, module: &mut Module, redzone: u32) -> FunctionID {
+        use DataType::I32;
+        let (raw, size, header) = (LocalID(0), LocalID(1), LocalID(2));
+        let mut alloc = FunctionBuilder::new(&[I32, I32, I32], &[I32]);
+        let block = alloc.add_local(I32);
+        let granule = alloc.add_local(I32);
+        let missing = alloc.add_local(I32);
+
+        alloc
+            .local_get(raw)
+            .i32_eqz()
+            .if_stmt(BlockType::Empty)
+            .i32_const(0)
+            .return_stmt()
+            .end();
+        self.fit_shadow(&mut alloc, missing);
+        alloc
+            .local_get(raw)
+            .local_get(header)
+            .i32_add()
+            .local_set(block);
+
+        // the size of the block and of its header, in the last granule of the header, which is
+        // poisoned along with the rest of the header
+        alloc
+            .local_get(header)
+            .if_stmt(BlockType::Empty)
+            .local_get(block)
+            .i32_const(8)
+            .i32_sub()
+            .local_get(size)
+            .i32_store(self.memory_arg())
+            .local_get(block)
+            .i32_const(4)
+            .i32_sub()
+            .local_get(header)
+            .i32_store(self.memory_arg())
+            .local_get(raw)
+            .i32_const(3)
+            .i32_shr_unsigned()
+            .i32_const(REDZONE_POISON as i32)
+            .local_get(header)
+            .i32_const(3)
+            .i32_shr_unsigned()
+            .i32_const(1)
+            .i32_sub()
+            .memory_fill(*self.shadow)
+            .local_get(block)
+            .i32_const(3)
+            .i32_shr_unsigned()
+            .i32_const(1)
+            .i32_sub()
+            .i32_const(HEADER_POISON as i32)
+            .i32_store8(self.shadow_arg())
+            .end();
+
+        // unpoison the granules of the block
+        alloc
+            .local_get(block)
+            .i32_const(3)
+            .i32_shr_unsigned()
+            .local_tee(granule)
+            .i32_const(0)
+            .local_get(size)
+            .i32_const(3)
+            .i32_shr_unsigned()
+            .memory_fill(*self.shadow)
+            .local_get(granule)
+            .local_get(size)
+            .i32_const(3)
+            .i32_shr_unsigned()
+            .i32_add()
+            .local_set(granule);
+        alloc
+            .local_get(size)
+            .i32_const(7)
+            .i32_and()
+            .if_stmt(BlockType::Empty)
+            .local_get(granule)
+            .local_get(size)
+            .i32_const(7)
+            .i32_and()
+            .i32_store8(self.shadow_arg())
+            .local_get(granule)
+            .i32_const(1)
+            .i32_add()
+            .local_set(granule)
+            .end();
+
+        // poison the remaining granules of the redzone
+        alloc
+            .local_get(header)
+            .if_stmt(BlockType::Empty)
+            .local_get(granule)
+            .i32_const(REDZONE_POISON as i32)
+            .local_get(block)
+            .local_get(size)
+            .i32_add()
+            .i32_const(redzone as i32)
+            .i32_add()
+            .i32_const(3)
+            .i32_shr_unsigned()
+            .local_get(granule)
+            .i32_sub()
+            .memory_fill(*self.shadow)
+            .end()
+            .local_get(block);
+
+        alloc.set_name("asan_alloc".to_string());
+        alloc.finish_module(module)
+    }
+
+    fn add_free(&self, module: &mut Module) -> FunctionID {
+        use DataType::I32;
+        let block = LocalID(0);
+        let mut free = FunctionBuilder::new(&[I32], &[I32]);
+        let granule = free.add_local(I32);
+        let header = free.add_local(I32);
+        let missing = free.add_local(I32);
+
+        // a block of the wrappers is aligned and follows its header
+        free.local_get(block)
+            .i32_const(8)
+            .i32_lt_unsigned()
+            .local_get(block)
+            .i32_const(7)
+            .i32_and()
+            .i32_or()
+            .if_stmt(BlockType::Empty)
+            .local_get(block)
+            .return_stmt()
+            .end();
+        self.fit_shadow(&mut free, missing);
+        free.local_get(block)
+            .i32_const(3)
+            .i32_shr_unsigned()
+            .i32_const(1)
+            .i32_sub()
+            .local_tee(granule)
+            .i32_load8_u(self.shadow_arg())
+            .local_tee(header)
+            .i32_const(FREED_HEADER_POISON as i32)
+            .i32_eq()
+            .if_stmt(BlockType::Empty)
+            .local_get(block)
+            .i64_extend_i32u()
+            .i64_const(0)
+            .i32_const(ReportKind::InvalidFree as i32)
+            .call(self.handler)
+            .else_stmt()
+            // not allocated by the wrappers: its size is unknown, nothing is poisoned
+            .local_get(header)
+            .i32_const(HEADER_POISON as i32)
+            .i32_ne()
+            .if_stmt(BlockType::Empty)
+            .local_get(block)
+            .return_stmt()
+            .end()
+            // poison the bytes of the block
+            .local_get(granule)
+            .i32_const(FREED_HEADER_POISON as i32)
+            .i32_store8(self.shadow_arg())
+            .local_get(granule)
+            .i32_const(1)
+            .i32_add()
+            .i32_const(FREED_POISON as i32)
+            .local_get(block)
+            .i32_const(8)
+            .i32_sub()
+            .i32_load(self.memory_arg())
+            .i32_const(7)
+            .i32_add()
+            .i32_const(3)
+            .i32_shr_unsigned()
+            .memory_fill(*self.shadow)
+            .end();
+
+        // the address the allocator returned
+        free.local_get(block)
+            .local_get(block)
+            .i32_const(4)
+            .i32_sub()
+            .i32_load(self.memory_arg())
+            .i32_sub();
+
+        free.set_name("asan_free".to_string());
+        free.finish_module(module)
+    }
+
+    fn add_grow_shadow(&self, module: &mut Module) -> FunctionID {
+        use DataType::I32;
+        let result = LocalID(0);
+        let mut grow = FunctionBuilder::new(&[I32], &[I32]);
+        let missing = grow.add_local(I32);
+
+        self.fit_shadow(&mut grow, missing);
+        grow.local_get(result);
+
+        grow.set_name("asan_grow_shadow".to_string());
+        grow.finish_module(module)
+    }
+
+    /// Grows the shadow to cover memory 0, which may have grown since it was last fitted, inside
+    /// the module or from the host. `missing` is an `i32` local.
+    fn fit_shadow<'a, T: Opcode<'a> + ?Sized>(&self, injector: &mut T, missing: LocalID) {
+        // the shadow covers memory 0 with an eighth of its pages
+        injector
+            .memory_size(0)
+            .i32_const(7)
+            .i32_add()
+            .i32_const(3)
+            .i32_shr_unsigned()
+            .memory_size(*self.shadow)
+            .i32_sub()
+            .local_tee(missing)
+            .i32_const(0)
+            .i32_gt_signed()
+            .if_stmt(BlockType::Empty)
+            .local_get(missing)
+            .memory_grow(*self.shadow)
+            .drop()
+            .end();
+    }
+
+    /// Adds the wrapper of the allocation function `func`, exported as `name`, which allocates a
+    /// header before every block and `redzone` more bytes after it.
+    fn wrap(
+        &self,
+        module: &mut Module,
+        (allocator, func, name): (Allocator, FunctionID, &str),
+        redzone: u32,
+    ) -> FunctionID {
+        use DataType::I32;
+        let mut wrapper = FunctionBuilder::new(allocator.params(), allocator.results());
+        let param = LocalID;
+        let redzone = redzone as i32;
+        match allocator {
+            Allocator::Malloc => {
+                wrapper
+                    .local_get(param(0))
+                    .i32_const(HEADER + redzone)
+                    .i32_add()
+                    .call(func)
+                    .local_get(param(0))
+                    .i32_const(HEADER)
+                    .call(self.alloc);
+            }
+            Allocator::Calloc => {
+                let size = wrapper.add_local(I32);
+                wrapper
+                    .local_get(param(0))
+                    .local_get(param(1))
+                    .i32_mul()
+                    .local_tee(size)
+                    .i32_const(HEADER + redzone)
+                    .i32_add()
+                    .i32_const(1)
+                    .call(func)
+                    .local_get(size)
+                    .i32_const(HEADER)
+                    .call(self.alloc);
+            }
+            Allocator::Realloc => {
+                let old = wrapper.add_local(I32);
+                let new = wrapper.add_local(I32);
+                wrapper.local_get(param(0)).call(self.free).local_set(old);
+                // a block that was not allocated by the wrappers has no header
+                wrapper
+                    .local_get(old)
+                    .local_get(param(0))
+                    .i32_eq()
+                    .local_get(param(0))
+                    .i32_const(0)
+                    .i32_ne()
+                    .i32_and()
+                    .if_stmt(BlockType::Empty)
+                    .local_get(param(0))
+                    .local_get(param(1))
+                    .call(func)
+                    .local_get(param(1))
+                    .i32_const(0)
+                    .call(self.alloc)
+                    .return_stmt()
+                    .end();
+                wrapper
+                    .local_get(old)
+                    .local_get(param(1))
+                    .i32_const(HEADER + redzone)
+                    .i32_add()
+                    .call(func)
+                    .local_tee(new)
+                    .i32_eqz()
+                    .local_get(param(0))
+                    .i32_const(0)
+                    .i32_ne()
+                    .i32_and()
+                    // the old block is still allocated
+                    .if_stmt(BlockType::Empty)
+                    .local_get(old)
+                    .local_get(param(0))
+                    .i32_const(HEADER)
+                    .i32_sub()
+                    .i32_load(self.memory_arg())
+                    .i32_const(HEADER)
+                    .call(self.alloc)
+                    .drop()
+                    .end()
+                    .local_get(new)
+                    .local_get(param(1))
+                    .i32_const(HEADER)
+                    .call(self.alloc);
+            }
+            Allocator::Free => {
+                wrapper.local_get(param(0)).call(self.free).call(func);
+            }
+            Allocator::CabiRealloc => {
+                // (old block, old size, alignment, new size)
+                let old = wrapper.add_local(I32);
+                let header = wrapper.add_local(I32);
+                let new = wrapper.add_local(I32);
+                wrapper.local_get(param(0)).call(self.free).local_set(old);
+                wrapper
+                    .local_get(old)
+                    .local_get(param(0))
+                    .i32_eq()
+                    .local_get(param(0))
+                    .i32_const(0)
+                    .i32_ne()
+                    .i32_and()
+                    .if_stmt(BlockType::Empty)
+                    .local_get(param(0))
+                    .local_get(param(1))
+                    .local_get(param(2))
+                    .local_get(param(3))
+                    .call(func)
+                    .local_get(param(3))
+                    .i32_const(0)
+                    .call(self.alloc)
+                    .return_stmt()
+                    .end();
+                // a new block keeps its alignment with a header of the size of the alignment
+                wrapper
+                    .local_get(param(0))
+                    .local_get(old)
+                    .i32_sub()
+                    .local_tee(header)
+                    .i32_eqz()
+                    .if_stmt(BlockType::Empty)
+                    .local_get(param(2))
+                    .i32_const(HEADER)
+                    .local_get(param(2))
+                    .i32_const(HEADER)
+                    .i32_gt_unsigned()
+                    .select()
+                    .local_set(header)
+                    .end();
+                wrapper
+                    .local_get(old)
+                    .local_get(param(1))
+                    .local_get(header)
+                    .i32_add()
+                    .i32_const(redzone)
+                    .i32_add()
+                    .i32_const(0)
+                    .local_get(param(0))
+                    .select()
+                    .local_get(param(2))
+                    .local_get(param(3))
+                    .local_get(header)
+                    .i32_add()
+                    .i32_const(redzone)
+                    .i32_add()
+                    .call(func)
+                    .local_tee(new)
+                    .i32_eqz()
+                    .local_get(param(0))
+                    .i32_const(0)
+                    .i32_ne()
+                    .i32_and()
+                    // the old block is still allocated
+                    .if_stmt(BlockType::Empty)
+                    .local_get(old)
+                    .local_get(param(1))
+                    .local_get(header)
+                    .call(self.alloc)
+                    .drop()
+                    .end()
+                    .local_get(new)
+                    .local_get(param(3))
+                    .local_get(header)
+                    .call(self.alloc);
+            }
+        }
+        wrapper.set_name(format!("asan_{}", name));
+        wrapper.finish_module(module)
+    }
+}
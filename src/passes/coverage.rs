@@ -0,0 +1,562 @@
+//! Code coverage: counts the executions of the basic blocks of every function.
+//!
+//! The blocks that hold a counter are chosen with [`CounterPlacement`]: the number of executions of
+//! the other blocks is solved from the counters by flow conservation. The counters live in a new
+//! memory or in globals (see [`CounterStorage`]). The pass exports a function that dumps them, by
+//! calling an imported sink of type `[i32, i64] -> []` with the index and the value of every
+//! counter, and describes them in the [`COVERAGE_SECTION`] custom section (see [`CoverageMap`]):
+//! the function and the instructions of every block, their code offsets and, when the module has
+//! DWARF line information, their source lines. [`CoverageMap::to_lcov`] turns the dumped counters
+//! into an lcov report.
+//!
+//! The functions are identified by their index in the module given to the pass, and the
+//! instructions by their index in the function before it was instrumented. A branch out of a
+//! function through its outermost label (rather than a `return`) does not count as running the
+//! block of its final `end`.
+//!
+//! [`CounterPlacement`]: crate::analysis::counter_placement::CounterPlacement
+
+use crate::analysis::cfg::{BlockIdx, ControlFlowGraph};
+use crate::analysis::counter_placement::{evaluate, CounterPlacement};
+use crate::error::Error;
+use crate::ir::function::FunctionBuilder;
+use crate::ir::id::{FunctionID, TableID, TypeID};
+use crate::ir::module::module_functions::FuncKind;
+use crate::ir::module::ReIndexable;
+use crate::ir::types::{BlockType, ElementItems, ElementKind};
+use crate::iterator::iterator_trait::{IteratingInstrumenter, Iterator};
+use crate::iterator::module_iterator::ModuleIterator;
+use crate::module_builder::AddLocal;
+use crate::opcode::MacroOpcode;
+use crate::probes::{CounterArray, GlobalCounter};
+use crate::{DataType, Location, Module, Opcode};
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+use wasm_encoder::Encode;
+use wasmparser::{BinaryReader, ConstExpr, MemArg, Operator, RefType, TableType};
+
+/// The name of the custom section holding the [`CoverageMap`] of an instrumented module.
+pub const COVERAGE_SECTION: &str = "orca.coverage";
+
+/// Where the counters are kept.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum CounterStorage {
+    /// In a new memory (the instrumented module requires the multi-memory proposal if it already
+    /// has one), see [`CounterArray`].
+    #[default]
+    Memory,
+    /// In a new global for every counter, see [`GlobalCounter`]. The dump function reads them
+    /// through a new table of functions.
+    Globals,
+}
+
+/// The configuration of the coverage pass.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CoverageOptions<'s> {
+    /// Where the counters are kept.
+    pub storage: CounterStorage,
+    /// The imported sink the counters are dumped to: (module, name).
+    pub sink: (&'s str, &'s str),
+    /// The name of the exported function dumping the counters.
+    pub dump: &'s str,
+}
+
+impl Default for CoverageOptions<'_> {
+    fn default() -> Self {
+        CoverageOptions {
+            storage: CounterStorage::Memory,
+            sink: ("coverage", "dump"),
+            dump: "coverage_dump",
+        }
+    }
+}
+
+/// What the coverage pass added to the module.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Coverage {
+    /// The imported sink.
+    pub sink: FunctionID,
+    /// The exported function dumping the counters.
+    pub dump: FunctionID,
+    /// The blocks covered by the counters, as written to the [`COVERAGE_SECTION`].
+    pub map: CoverageMap,
+}
+
+/// The blocks covered by the counters of an instrumented module.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CoverageMap {
+    /// The source files the lines of the blocks refer to.
+    pub files: Vec<String>,
+    /// The number of counters.
+    pub num_counters: u32,
+    /// The covered functions.
+    pub functions: Vec<CoveredFunction>,
+}
+
+/// A function of a [`CoverageMap`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CoveredFunction {
+    /// The index of the function in the module given to the pass.
+    pub func: u32,
+    /// The name of the function, if it had one.
+    pub name: Option<String>,
+    /// The basic blocks of the function, in program order. The first one is its entry.
+    pub blocks: Vec<CoveredBlock>,
+}
+
+/// A basic block of a [`CoveredFunction`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CoveredBlock {
+    /// The indices of the instructions of the block.
+    pub instrs: Range<u32>,
+    /// The code section offset of the first instruction of the block in the parsed binary.
+    pub offset: Option<u32>,
+    /// The counter of the block, if it holds one: it counts the executions of the block.
+    pub counter: Option<u32>,
+    /// The number of executions of the block as a sum of counters: (counter, coefficient).
+    pub terms: Vec<(u32, i64)>,
+    /// The source lines of the instructions of the block: (index in the files, line).
+    pub lines: Vec<(u32, u32)>,
+}
+
+impl CoveredBlock {
+    /// The number of executions of the block, given the values of the counters. It is exact as
+    /// long as every invocation of the function ran to its end or returned, see
+    /// [`CounterPlacement`].
+    ///
+    /// [`CounterPlacement`]: crate::analysis::counter_placement::CounterPlacement
+    pub fn count(&self, counts: &[u64]) -> u64 {
+        let terms: Vec<(usize, i64)> = self
+            .terms
+            .iter()
+            .map(|(counter, coefficient)| (*counter as usize, *coefficient))
+            .collect();
+        evaluate(&terms, |counter| counts.get(counter).copied().unwrap_or(0))
+    }
+}
+
+impl CoverageMap {
+    /// Reads the map of the instrumented module `wasm`, `None` if it has no [`COVERAGE_SECTION`].
+    pub fn from_wasm(wasm: &[u8]) -> Result<Option<Self>, Error> {
+        for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+            if let wasmparser::Payload::CustomSection(section) = payload? {
+                if section.name() == COVERAGE_SECTION {
+                    return Self::parse(section.data()).map(Some);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Reads the content of the [`COVERAGE_SECTION`] custom section.
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        let mut reader = BinaryReader::new(data, 0);
+        let mut map = CoverageMap::default();
+        for _ in 0..reader.read_var_u32()? {
+            map.files.push(reader.read_string()?.to_string());
+        }
+        map.num_counters = reader.read_var_u32()?;
+        let optional = |value: u32| value.checked_sub(1);
+        for _ in 0..reader.read_var_u32()? {
+            let mut func = CoveredFunction {
+                func: reader.read_var_u32()?,
+                ..Default::default()
+            };
+            if reader.read_u8()? != 0 {
+                func.name = Some(reader.read_string()?.to_string());
+            }
+            for _ in 0..reader.read_var_u32()? {
+                let mut block = CoveredBlock {
+                    instrs: reader.read_var_u32()?..reader.read_var_u32()?,
+                    offset: optional(reader.read_var_u32()?),
+                    counter: optional(reader.read_var_u32()?),
+                    ..Default::default()
+                };
+                for _ in 0..reader.read_var_u32()? {
+                    block
+                        .terms
+                        .push((reader.read_var_u32()?, reader.read_var_i64()?));
+                }
+                for _ in 0..reader.read_var_u32()? {
+                    let file = reader.read_var_u32()?;
+                    if file as usize >= map.files.len() {
+                        return Err(Error::ConversionError(format!(
+                            "Unknown file {} in the {} section",
+                            file, COVERAGE_SECTION
+                        )));
+                    }
+                    block.lines.push((file, reader.read_var_u32()?));
+                }
+                func.blocks.push(block);
+            }
+            map.functions.push(func);
+        }
+        Ok(map)
+    }
+
+    /// Encodes the content of the [`COVERAGE_SECTION`] custom section.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = vec![];
+        let optional = |value: Option<u32>, data: &mut Vec<u8>| {
+            value.map_or(0, |value| value + 1).encode(data);
+        };
+        self.files.len().encode(&mut data);
+        for file in self.files.iter() {
+            file.encode(&mut data);
+        }
+        self.num_counters.encode(&mut data);
+        self.functions.len().encode(&mut data);
+        for func in self.functions.iter() {
+            func.func.encode(&mut data);
+            match &func.name {
+                Some(name) => {
+                    data.push(1);
+                    name.encode(&mut data);
+                }
+                None => data.push(0),
+            }
+            func.blocks.len().encode(&mut data);
+            for block in func.blocks.iter() {
+                block.instrs.start.encode(&mut data);
+                block.instrs.end.encode(&mut data);
+                optional(block.offset, &mut data);
+                optional(block.counter, &mut data);
+                block.terms.len().encode(&mut data);
+                for (counter, coefficient) in block.terms.iter() {
+                    counter.encode(&mut data);
+                    coefficient.encode(&mut data);
+                }
+                block.lines.len().encode(&mut data);
+                for (file, line) in block.lines.iter() {
+                    file.encode(&mut data);
+                    line.encode(&mut data);
+                }
+            }
+        }
+        data
+    }
+
+    /// Writes an lcov tracefile from the values of the counters, e.g. as dumped by the
+    /// instrumented module (`counts[i]` is the value of the counter `i`).
+    ///
+    /// The blocks are reported on their source lines. The functions without line information are
+    /// reported as a file named `wasm-function[<index>]` whose lines are the instructions of the
+    /// function, numbered from 1.
+    pub fn to_lcov(&self, counts: &[u64]) -> String {
+        /// The functions (first line, name, count) and the lines of a source file.
+        type FileReport = (Vec<(u32, String, u64)>, BTreeMap<u32, u64>);
+        let mut reports: BTreeMap<String, FileReport> = BTreeMap::new();
+        for func in self.functions.iter() {
+            let name = func
+                .name
+                .clone()
+                .unwrap_or_else(|| format!("wasm-function[{}]", func.func));
+            let mut first_line = None;
+            for block in func.blocks.iter() {
+                let count = block.count(counts);
+                let lines: Vec<(String, u32)> = if block.lines.is_empty() {
+                    vec![(
+                        format!("wasm-function[{}]", func.func),
+                        block.instrs.start + 1,
+                    )]
+                } else {
+                    block
+                        .lines
+                        .iter()
+                        .map(|(file, line)| (self.files[*file as usize].clone(), *line))
+                        .collect()
+                };
+                for (file, line) in lines {
+                    if first_line.is_none() {
+                        first_line = Some((file.clone(), line));
+                    }
+                    let hits = reports.entry(file).or_default().1.entry(line).or_default();
+                    *hits = (*hits).max(count);
+                }
+            }
+            if let (Some((file, line)), Some(entry)) = (first_line, func.blocks.first()) {
+                reports
+                    .entry(file)
+                    .or_default()
+                    .0
+                    .push((line, name, entry.count(counts)));
+            }
+        }
+
+        let mut lcov = String::new();
+        for (file, (functions, lines)) in reports {
+            lcov.push_str(&format!("TN:\nSF:{}\n", file));
+            for (line, name, _) in functions.iter() {
+                lcov.push_str(&format!("FN:{},{}\n", line, name));
+            }
+            for (_, name, count) in functions.iter() {
+                lcov.push_str(&format!("FNDA:{},{}\n", count, name));
+            }
+            lcov.push_str(&format!("FNF:{}\n", functions.len()));
+            let hit = functions.iter().filter(|(_, _, count)| *count > 0).count();
+            lcov.push_str(&format!("FNH:{}\n", hit));
+            for (line, count) in lines.iter() {
+                lcov.push_str(&format!("DA:{},{}\n", line, count));
+            }
+            lcov.push_str(&format!("LF:{}\n", lines.len()));
+            let hit = lines.values().filter(|count| **count > 0).count();
+            lcov.push_str(&format!("LH:{}\nend_of_record\n", hit));
+        }
+        lcov
+    }
+}
+
+/// The counters of the instrumented module.
+enum Counters {
+    Memory(CounterArray),
+    Globals(Vec<GlobalCounter>),
+}
+
+impl Counters {
+    fn new(module: &mut Module, storage: CounterStorage, len: u32) -> Self {
+        match storage {
+            CounterStorage::Memory => Counters::Memory(CounterArray::with_new_memory(module, len)),
+            CounterStorage::Globals => Counters::Globals(
+                (0..len)
+                    .map(|_| GlobalCounter::new(module, DataType::I64))
+                    .collect(),
+            ),
+        }
+    }
+
+    fn increment<'a, T: Opcode<'a> + ?Sized>(&self, injector: &mut T, counter: u32) {
+        match self {
+            Counters::Memory(array) => array.increment(injector, counter),
+            Counters::Globals(globals) => globals[counter as usize].increment(injector),
+        }
+    }
+
+    /// Adds the function calling `sink` with the index and the value of every counter. It loops over
+    /// the counters, reading them from the counter array or, for globals, through a table of
+    /// functions that read them.
+    fn add_dump(&self, module: &mut Module, sink: FunctionID, name: &str) -> FunctionID {
+        let len = match self {
+            Counters::Memory(array) => array.len,
+            Counters::Globals(globals) => globals.len() as u32,
+        };
+        let mut dump = FunctionBuilder::new(&[], &[]);
+        let counter = dump.add_local(DataType::I32);
+        dump.block(BlockType::Empty)
+            .loop_stmt(BlockType::Empty)
+            .local_get(counter)
+            .u32_const(len)
+            .i32_gte_unsigned()
+            .br_if(1)
+            .local_get(counter);
+        match self {
+            Counters::Memory(array) => {
+                dump.local_get(counter)
+                    .i32_const(3)
+                    .i32_shl()
+                    .i64_load(MemArg {
+                        align: 3,
+                        max_align: 3,
+                        offset: array.offset_of(0),
+                        memory: *array.memory,
+                    });
+            }
+            Counters::Globals(globals) => {
+                let (ty, table) = add_getters(module, globals, name);
+                dump.local_get(counter).call_indirect(ty, table);
+            }
+        }
+        dump.call(sink)
+            .local_get(counter)
+            .i32_const(1)
+            .i32_add()
+            .local_set(counter)
+            .br(0)
+            .end()
+            .end();
+        dump.set_name(name.to_string());
+        dump.finish_module(module)
+    }
+}
+
+/// Adds a function of type `[] -> [i64]` reading each of the `globals`, and a table holding them in
+/// order. Returns the type of the functions and the table.
+fn add_getters(module: &mut Module, globals: &[GlobalCounter], name: &str) -> (TypeID, TableID) {
+    // i32.const 0, the offset of the element segment
+    const OFFSET: &[u8] = &[0x41, 0x00, 0x0b];
+
+    let ty = module.types.add_func_type(&[], &[DataType::I64]);
+    let getters: Vec<FunctionID> = globals
+        .iter()
+        .enumerate()
+        .map(|(idx, counter)| {
+            let mut getter = FunctionBuilder::new(&[], &[DataType::I64]);
+            getter.global_get(counter.global);
+            getter.set_name(format!("{}_counter_{}", name, idx));
+            getter.finish_module(module)
+        })
+        .collect();
+    let len = getters.len() as u64;
+    let table = module.add_local_table(TableType {
+        element_type: RefType::FUNCREF,
+        table64: false,
+        initial: len,
+        maximum: Some(len),
+        shared: false,
+    });
+    module.add_element(
+        ElementKind::Active {
+            table_index: Some(*table),
+            offset_expr: ConstExpr::new(BinaryReader::new(OFFSET, 0)),
+        },
+        ElementItems::Functions(getters),
+    );
+    (ty, table)
+}
+
+/// Instruments every local function of `module` with coverage counters configured by `options`,
+/// and adds the [`COVERAGE_SECTION`] describing them.
+///
+/// Fails if the module already exports a function named like the dump function.
+pub fn instrument<'a>(
+    module: &mut Module<'a>,
+    options: &CoverageOptions,
+) -> Result<Coverage, Error> {
+    if module
+        .exports
+        .get_by_name(options.dump.to_string())
+        .is_some()
+    {
+        return Err(Error::InvalidPass {
+            pass: "coverage".to_string(),
+            reason: format!("the module already exports `{}`", options.dump),
+        });
+    }
+
+    let lines = &module.debug.lines;
+    let mut map = CoverageMap {
+        files: lines.files.clone(),
+        ..Default::default()
+    };
+    // (function, instruction) -> (counter, whether it is incremented after the instruction)
+    let mut sites: HashMap<(FunctionID, usize), (u32, bool)> = HashMap::new();
+    for func_idx in 0..module.functions.len() as u32 {
+        let func_id = FunctionID(func_idx);
+        if module.functions.is_deleted(func_id) {
+            continue;
+        }
+        let FuncKind::Local(func) = module.functions.get_kind(func_id) else {
+            continue;
+        };
+        let instrs = &func.body.instructions;
+        let cfg = ControlFlowGraph::new(&func.body);
+        let placement = CounterPlacement::with_cfg(&func.body, &cfg);
+
+        let mut counter_of: HashMap<BlockIdx, u32> = HashMap::new();
+        for probe in placement.probes.iter() {
+            let start = cfg.block(*probe).start;
+            sites.insert(
+                (func_id, start),
+                (
+                    map.num_counters,
+                    enters_after(&instrs[start].op, start, instrs.len()),
+                ),
+            );
+            counter_of.insert(*probe, map.num_counters);
+            map.num_counters += 1;
+        }
+
+        let mut blocks = vec![];
+        for (block_idx, block) in cfg.blocks.iter().enumerate() {
+            if block.instrs().is_empty() {
+                continue;
+            }
+            let terms = placement
+                .count_of(block_idx)
+                .iter()
+                .map(|(probe, coefficient)| (counter_of[&placement.probes[*probe]], *coefficient))
+                .collect();
+            let mut block_lines: Vec<(u32, u32)> = vec![];
+            for offset in instrs[block.instrs()]
+                .iter()
+                .filter_map(|instr| instr.offset)
+            {
+                if let Some((file, line, _)) = lines.lookup(offset as u64) {
+                    if !block_lines.contains(&(file, line)) {
+                        block_lines.push((file, line));
+                    }
+                }
+            }
+            blocks.push(CoveredBlock {
+                instrs: block.start as u32..block.end as u32,
+                offset: instrs[block.start].offset.map(|offset| offset as u32),
+                counter: counter_of.get(&block_idx).copied(),
+                terms,
+                lines: block_lines,
+            });
+        }
+        map.functions.push(CoveredFunction {
+            func: func_idx,
+            name: module.functions.get_name(func_id).clone(),
+            blocks,
+        });
+    }
+
+    let counters = Counters::new(module, options.storage, map.num_counters);
+    let sink_ty = module
+        .types
+        .add_func_type(&[DataType::I32, DataType::I64], &[]);
+    let (sink, _) = module.add_import_func(
+        options.sink.0.to_string(),
+        options.sink.1.to_string(),
+        sink_ty,
+    );
+
+    let mut mod_it = ModuleIterator::new(module, &vec![]);
+    loop {
+        if let (
+            Location::Module {
+                func_idx,
+                instr_idx,
+            },
+            ..,
+        ) = mod_it.curr_loc()
+        {
+            if let Some((counter, after)) = sites.get(&(func_idx, instr_idx)) {
+                if *after {
+                    mod_it.after();
+                } else {
+                    mod_it.before();
+                }
+                counters.increment(&mut mod_it, *counter);
+            }
+        }
+        if mod_it.next().is_none() {
+            break;
+        };
+    }
+
+    let dump = counters.add_dump(module, sink, options.dump);
+    module
+        .exports
+        .add_export_func(options.dump.to_string(), *dump);
+    module
+        .custom_sections
+        .add(COVERAGE_SECTION, map.encode().leak());
+
+    Ok(Coverage { sink, dump, map })
+}
+
+/// Whether the block starting with `op` (the `idx`th of `len` instructions) is entered right after
+/// it: the branches to a `loop` or an `end` run the code that follows it, and the arms of `if` and
+/// `try` start after their `else` and `catch`. The final `end` of the function is entered before.
+fn enters_after(op: &Operator, idx: usize, len: usize) -> bool {
+    match op {
+        Operator::Loop { .. }
+        | Operator::Else
+        | Operator::Catch { .. }
+        | Operator::CatchAll
+        | Operator::Delegate { .. } => true,
+        Operator::End => idx + 1 < len,
+        _ => false,
+    }
+}
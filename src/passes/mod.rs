@@ -0,0 +1,11 @@
+//! Ready-made instrumentation passes.
+//!
+//! A pass instruments a whole [`Module`] at once: it adds the state and the imports it needs,
+//! injects its probes with the iterators, and returns the IDs of what it added so that the
+//! instrumented module can be inspected or extended.
+//!
+//! [`Module`]: crate::Module
+
+pub mod asan;
+pub mod coverage;
+pub mod replay;
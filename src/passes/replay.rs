@@ -0,0 +1,575 @@
+//! Deterministic record and replay of the calls to the imported functions.
+//!
+//! Every imported function is wrapped by a function generated with the [`FunctionBuilder`], which
+//! the calls of the local functions are redirected to. When recording, the wrapper calls the
+//! import and writes an entry describing the call into a new log memory, exported so that the host
+//! can read it, then passes the entry to an imported sink of type `[i32, i32] -> []` (its address
+//! in the log memory and its length). The host concatenates the entries into the log. When
+//! replaying, the host loads the log into the exported log memory, and the wrappers read the
+//! results of the calls from it instead of calling the imports.
+//!
+//! An entry is laid out as follows, every field is little endian:
+//! - the index of the import (in the module given to the pass), a `u32`,
+//! - the arguments, then the results of the call (4 bytes for `i32` and `f32`, 8 for `i64` and
+//!   `f64`),
+//! - the number of memory regions the host wrote, a `u32`, then for each region its address and
+//!   its length (two `u32`) followed by its bytes.
+//!
+//! The pass cannot tell which memory the host writes: the regions of memory 0 written by each
+//! import are declared with [`HostWrite`]s, and copied after the call when recording, or from the
+//! log when replaying. Replaying traps when the next entry of the log is not for the called
+//! import. The imports whose type has other value types than `i32`, `i64`, `f32` and `f64` are
+//! left alone, and the calls through a table or a function reference are not redirected (see
+//! [`Replay::indirect`]). The instrumented module requires the multi-memory proposal if it already
+//! has a memory.
+//!
+//! [`FunctionBuilder`]: crate::ir::function::FunctionBuilder
+
+use crate::error::Error;
+use crate::ir::function::FunctionBuilder;
+use crate::ir::id::{FunctionID, GlobalID, ImportsID, LocalID, MemoryID, TypeID};
+use crate::ir::module::module_functions::FuncKind;
+use crate::ir::module::module_globals::{GlobalKind, LocalGlobal};
+use crate::ir::module::ReIndexable;
+use crate::ir::types::{BlockType, ElementItems, InitExpr, Instructions, Value};
+use crate::module_builder::AddLocal;
+use crate::transform::wrap::redirect_calls;
+use crate::{DataType, Module, Opcode};
+use std::collections::{HashMap, HashSet};
+use wasmparser::{MemArg, MemoryType, Operator, TypeRef};
+
+/// Whether the calls are recorded or replayed.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ReplayMode {
+    /// The imports are called, and their calls logged to the sink.
+    #[default]
+    Record,
+    /// The results of the imports are read from the log.
+    Replay,
+}
+
+/// The length of a region of memory written by the host.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WriteLen {
+    /// The value of an `i32` parameter of the import.
+    Param(u32),
+    /// A constant number of bytes.
+    Bytes(u32),
+}
+
+/// A region of memory 0 written by the host when an import is called.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct HostWrite<'s> {
+    /// The module of the import.
+    pub module: &'s str,
+    /// The name of the import.
+    pub name: &'s str,
+    /// The `i32` parameter holding the address of the region.
+    pub ptr: u32,
+    /// The length of the region.
+    pub len: WriteLen,
+}
+
+/// The configuration of the record and replay pass.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReplayOptions<'s> {
+    /// Whether the calls are recorded or replayed.
+    pub mode: ReplayMode,
+    /// The imported sink the entries are passed to when recording: (module, name).
+    pub sink: (&'s str, &'s str),
+    /// The name of the exported log memory.
+    pub log: &'s str,
+    /// The regions of memory written by the imports.
+    pub writes: Vec<HostWrite<'s>>,
+}
+
+impl Default for ReplayOptions<'_> {
+    fn default() -> Self {
+        ReplayOptions {
+            mode: ReplayMode::Record,
+            sink: ("replay", "record"),
+            log: "replay_log",
+            writes: vec![],
+        }
+    }
+}
+
+/// What the record and replay pass added to the module.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Replay {
+    /// The log memory.
+    pub log: MemoryID,
+    /// The imported sink, when recording.
+    pub sink: Option<FunctionID>,
+    /// The global holding the address of the next entry of the log, when replaying.
+    pub cursor: Option<GlobalID>,
+    /// The imports that were wrapped, with their wrappers: (import, wrapper).
+    pub wrappers: Vec<(FunctionID, FunctionID)>,
+    /// The imports that were left alone because of their type.
+    pub skipped: Vec<FunctionID>,
+    /// The wrapped imports that are also referenced by an element segment or a `ref.func`: their
+    /// calls through a table or a function reference are neither recorded nor replayed.
+    pub indirect: Vec<FunctionID>,
+}
+
+/// An import to wrap.
+struct Wrapped<'s> {
+    func: FunctionID,
+    /// The index of the import, which identifies it in the log.
+    import_idx: u32,
+    params: Vec<DataType>,
+    results: Vec<DataType>,
+    writes: Vec<&'s HostWrite<'s>>,
+}
+
+impl Wrapped<'_> {
+    /// The size of the entry without the regions of memory.
+    fn fixed_size(&self) -> u32 {
+        8 + self
+            .params
+            .iter()
+            .chain(self.results.iter())
+            .map(size_of)
+            .sum::<u32>()
+    }
+
+    /// The offset of the `idx`th result in the entry.
+    fn result_offset(&self, idx: usize) -> u32 {
+        4 + self
+            .params
+            .iter()
+            .chain(self.results[..idx].iter())
+            .map(size_of)
+            .sum::<u32>()
+    }
+}
+
+fn size_of(ty: &DataType) -> u32 {
+    match ty {
+        DataType::I32 | DataType::F32 => 4,
+        _ => 8,
+    }
+}
+
+/// Wraps every imported function of `module` to record or replay its calls, as configured by
+/// `options`.
+///
+/// Fails if a [`HostWrite`] does not match an import with an `i32` parameter for the address and
+/// the length, or if regions are written and memory 0 does not exist or is a 64-bit memory.
+pub fn instrument<'a>(module: &mut Module<'a>, options: &ReplayOptions) -> Result<Replay, Error> {
+    let invalid = |reason: String| Error::InvalidPass {
+        pass: "record and replay".to_string(),
+        reason,
+    };
+    if !options.writes.is_empty() {
+        match module.memories.get_mem_by_id(MemoryID(0)) {
+            Some(mem) if !mem.ty.memory64 => {}
+            _ => {
+                return Err(invalid(
+                    "the host writes regions of memory 0, which must be a 32-bit memory"
+                        .to_string(),
+                ))
+            }
+        }
+    }
+
+    let mut import_funcs: HashMap<ImportsID, FunctionID> = HashMap::new();
+    for func_idx in 0..module.functions.len() as u32 {
+        if let FuncKind::Import(import) = module.functions.get_kind(FunctionID(func_idx)) {
+            import_funcs.insert(import.import_id, FunctionID(func_idx));
+        }
+    }
+    let mut wrapped = vec![];
+    let mut skipped = vec![];
+    for (idx, import) in module.imports.iter().enumerate() {
+        let TypeRef::Func(ty) = import.ty else {
+            continue;
+        };
+        let Some(func) = import_funcs.get(&ImportsID(idx as u32)) else {
+            continue;
+        };
+        if import.deleted || module.functions.is_deleted(*func) {
+            continue;
+        }
+        let Some(ty) = module.types.get(TypeID(ty)) else {
+            continue;
+        };
+        let (params, results) = (ty.params(), ty.results());
+        let numeric = |ty: &DataType| {
+            matches!(
+                ty,
+                DataType::I32 | DataType::I64 | DataType::F32 | DataType::F64
+            )
+        };
+        if !params.iter().chain(results.iter()).all(numeric) {
+            skipped.push(*func);
+            continue;
+        }
+        let writes: Vec<&HostWrite> = options
+            .writes
+            .iter()
+            .filter(|write| write.module == import.module && write.name == import.name)
+            .collect();
+        for write in writes.iter() {
+            let is_i32 = |param: u32| params.get(param as usize) == Some(&DataType::I32);
+            let len_ok = match write.len {
+                WriteLen::Param(param) => is_i32(param),
+                WriteLen::Bytes(_) => true,
+            };
+            if !is_i32(write.ptr) || !len_ok {
+                return Err(invalid(format!(
+                    "the region written by {}.{} is not given by i32 parameters",
+                    write.module, write.name
+                )));
+            }
+        }
+        wrapped.push(Wrapped {
+            func: *func,
+            import_idx: idx as u32,
+            params,
+            results,
+            writes,
+        });
+    }
+    for write in options.writes.iter() {
+        if !wrapped
+            .iter()
+            .any(|wrapped| wrapped.writes.contains(&write))
+        {
+            return Err(invalid(format!(
+                "there is no import {}.{} to write memory",
+                write.module, write.name
+            )));
+        }
+    }
+
+    let log = module.add_local_memory(MemoryType {
+        memory64: false,
+        shared: false,
+        initial: 1,
+        maximum: None,
+        page_size_log2: None,
+    });
+    module
+        .exports
+        .add_export_memory(options.log.to_string(), *log);
+    let log_arg = |offset: u32, align: u8| MemArg {
+        align,
+        max_align: align,
+        offset: offset as u64,
+        memory: *log,
+    };
+
+    let mut replay = Replay {
+        log,
+        sink: None,
+        cursor: None,
+        wrappers: vec![],
+        skipped,
+        indirect: vec![],
+    };
+    let mut generated = vec![];
+    match options.mode {
+        ReplayMode::Record => {
+            let sink_ty = module
+                .types
+                .add_func_type(&[DataType::I32, DataType::I32], &[]);
+            let (sink, _) = module.add_import_func(
+                options.sink.0.to_string(),
+                options.sink.1.to_string(),
+                sink_ty,
+            );
+            let reserve = add_reserve(module, log);
+            generated.push(reserve);
+            for import in wrapped.iter() {
+                let wrapper = record_wrapper(module, import, log, sink, reserve);
+                replay.wrappers.push((import.func, wrapper));
+            }
+            replay.sink = Some(sink);
+        }
+        ReplayMode::Replay => {
+            let cursor = module.add_global(
+                InitExpr::new(vec![Instructions::Value(Value::I32(0))]),
+                DataType::I32,
+                true,
+                false,
+            );
+            for import in wrapped.iter() {
+                let mut wrapper = FunctionBuilder::new(&import.params, &import.results);
+                let entry = wrapper.add_local(DataType::I32);
+                let count = wrapper.add_local(DataType::I32);
+                let len = wrapper.add_local(DataType::I32);
+
+                // the entry must be for this import
+                wrapper
+                    .global_get(cursor)
+                    .local_tee(entry)
+                    .i32_load(log_arg(0, 2))
+                    .i32_const(import.import_idx as i32)
+                    .i32_ne()
+                    .if_stmt(BlockType::Empty)
+                    .unreachable()
+                    .end();
+                for (idx, ty) in import.results.iter().enumerate() {
+                    wrapper.local_get(entry);
+                    load(&mut wrapper, ty, log_arg(import.result_offset(idx), 0));
+                }
+
+                // copy the regions written by the host back to memory 0
+                let fixed = import.fixed_size();
+                wrapper
+                    .local_get(entry)
+                    .i32_load(log_arg(fixed - 4, 0))
+                    .local_set(count)
+                    .local_get(entry)
+                    .i32_const(fixed as i32)
+                    .i32_add()
+                    .local_set(entry)
+                    .block(BlockType::Empty)
+                    .loop_stmt(BlockType::Empty)
+                    .local_get(count)
+                    .i32_eqz()
+                    .br_if(1)
+                    .local_get(entry)
+                    .i32_load(log_arg(0, 0))
+                    .local_get(entry)
+                    .i32_const(8)
+                    .i32_add()
+                    .local_get(entry)
+                    .i32_load(log_arg(4, 0))
+                    .local_tee(len)
+                    .memory_copy(0, *log)
+                    .local_get(entry)
+                    .i32_const(8)
+                    .i32_add()
+                    .local_get(len)
+                    .i32_add()
+                    .local_set(entry)
+                    .local_get(count)
+                    .i32_const(1)
+                    .i32_sub()
+                    .local_set(count)
+                    .br(0)
+                    .end()
+                    .end()
+                    .local_get(entry)
+                    .global_set(cursor);
+
+                wrapper.set_name(format!("replay_{}", *import.func));
+                let wrapper = wrapper.finish_module(module);
+                replay.wrappers.push((import.func, wrapper));
+            }
+            replay.cursor = Some(cursor);
+        }
+    }
+
+    generated.extend(replay.wrappers.iter().map(|(_, wrapper)| *wrapper));
+    for (import, wrapper) in replay.wrappers.iter() {
+        module.exports.redirect_func(*import, *wrapper);
+        redirect_calls(module, *import, *wrapper, &generated);
+    }
+    let referenced = referenced_funcs(module)?;
+    replay.indirect = replay
+        .wrappers
+        .iter()
+        .map(|(import, _)| *import)
+        .filter(|import| referenced.contains(import))
+        .collect();
+    Ok(replay)
+}
+
+/// The functions referenced by the element segments, the `ref.func`s of the local functions and
+/// the initializers of the globals of `module`.
+fn referenced_funcs(module: &Module) -> Result<HashSet<FunctionID>, Error> {
+    let mut referenced = HashSet::new();
+    for (_, items) in module.elements.iter() {
+        match items {
+            ElementItems::Functions(funcs) => referenced.extend(funcs.iter().copied()),
+            ElementItems::ConstExprs { exprs, .. } => {
+                for expr in exprs.iter() {
+                    let mut reader = expr.get_operators_reader();
+                    while !reader.eof() {
+                        if let Operator::RefFunc { function_index } = reader.read()? {
+                            referenced.insert(FunctionID(function_index));
+                        }
+                    }
+                }
+            }
+        }
+    }
+    for func in module.functions.iter() {
+        if let FuncKind::Local(func) = &func.kind {
+            for instr in func.body.instructions.iter() {
+                if let Operator::RefFunc { function_index } = instr.op {
+                    referenced.insert(FunctionID(function_index));
+                }
+            }
+        }
+    }
+    for global in module.globals.iter() {
+        if let GlobalKind::Local(LocalGlobal { init_expr, .. }) = &global.kind {
+            for instr in init_expr.instructions() {
+                if let Instructions::RefFunc(func) = instr {
+                    referenced.insert(*func);
+                }
+            }
+        }
+    }
+    Ok(referenced)
+}
+
+/// Adds a function of type `[i32] -> []` growing the log memory to hold at least as many bytes as
+/// its argument.
+fn add_reserve(module: &mut Module, log: MemoryID) -> FunctionID {
+    let bytes = LocalID(0);
+    let mut reserve = FunctionBuilder::new(&[DataType::I32], &[]);
+    let missing = reserve.add_local(DataType::I32);
+    reserve
+        .local_get(bytes)
+        .i32_const(0xFFFF)
+        .i32_add()
+        .i32_const(16)
+        .i32_shr_unsigned()
+        .memory_size(*log)
+        .i32_sub()
+        .local_tee(missing)
+        .i32_const(0)
+        .i32_gt_signed()
+        .if_stmt(BlockType::Empty)
+        .local_get(missing)
+        .memory_grow(*log)
+        .drop()
+        .end();
+    reserve.set_name("replay_reserve".to_string());
+    reserve.finish_module(module)
+}
+
+/// Adds the wrapper calling `import` and passing the entry of the call to `sink`.
+fn record_wrapper(
+    module: &mut Module,
+    import: &Wrapped,
+    log: MemoryID,
+    sink: FunctionID,
+    reserve: FunctionID,
+) -> FunctionID {
+    let log_arg = |offset: u32| MemArg {
+        align: 0,
+        max_align: 0,
+        offset: offset as u64,
+        memory: *log,
+    };
+    let param = |idx: u32| LocalID(idx);
+    let mut wrapper = FunctionBuilder::new(&import.params, &import.results);
+    let results: Vec<LocalID> = import
+        .results
+        .iter()
+        .map(|ty| wrapper.add_local(*ty))
+        .collect();
+    let len = wrapper.add_local(DataType::I32);
+    let entry_len = wrapper.add_local(DataType::I32);
+
+    for idx in 0..import.params.len() {
+        wrapper.local_get(param(idx as u32));
+    }
+    wrapper.call(import.func);
+    for result in results.iter().rev() {
+        wrapper.local_set(*result);
+    }
+
+    // make room for the entry
+    let fixed = import.fixed_size();
+    wrapper.i32_const(fixed as i32).local_set(entry_len);
+    for write in import.writes.iter() {
+        write_len(&mut wrapper, write.len);
+        wrapper
+            .i32_const(8)
+            .i32_add()
+            .local_get(entry_len)
+            .i32_add()
+            .local_set(entry_len);
+    }
+    wrapper.local_get(entry_len).call(reserve);
+
+    // the import, its arguments and its results
+    wrapper
+        .i32_const(0)
+        .i32_const(import.import_idx as i32)
+        .i32_store(log_arg(0));
+    let mut offset = 4;
+    let values = (0..import.params.len() as u32)
+        .map(param)
+        .chain(results.iter().copied());
+    for (ty, local) in import
+        .params
+        .iter()
+        .chain(import.results.iter())
+        .zip(values)
+    {
+        wrapper.i32_const(0).local_get(local);
+        store(&mut wrapper, ty, log_arg(offset));
+        offset += size_of(ty);
+    }
+    wrapper
+        .i32_const(0)
+        .i32_const(import.writes.len() as i32)
+        .i32_store(log_arg(offset));
+
+    // the regions written by the host
+    wrapper.i32_const(fixed as i32).local_set(entry_len);
+    for write in import.writes.iter() {
+        write_len(&mut wrapper, write.len);
+        wrapper
+            .local_set(len)
+            .local_get(entry_len)
+            .local_get(param(write.ptr))
+            .i32_store(log_arg(0))
+            .local_get(entry_len)
+            .local_get(len)
+            .i32_store(log_arg(4))
+            .local_get(entry_len)
+            .i32_const(8)
+            .i32_add()
+            .local_get(param(write.ptr))
+            .local_get(len)
+            .memory_copy(*log, 0)
+            .local_get(entry_len)
+            .i32_const(8)
+            .i32_add()
+            .local_get(len)
+            .i32_add()
+            .local_set(entry_len);
+    }
+    wrapper.i32_const(0).local_get(entry_len).call(sink);
+
+    for result in results.iter() {
+        wrapper.local_get(*result);
+    }
+    wrapper.set_name(format!("record_{}", *import.func));
+    wrapper.finish_module(module)
+}
+
+/// Pushes the length of a region written by the host.
+fn write_len<'a, T: Opcode<'a>>(injector: &mut T, len: WriteLen) {
+    match len {
+        WriteLen::Param(param) => injector.local_get(LocalID(param)),
+        WriteLen::Bytes(bytes) => injector.i32_const(bytes as i32),
+    };
+}
+
+fn store<'a, T: Opcode<'a>>(injector: &mut T, ty: &DataType, memarg: MemArg) {
+    match ty {
+        DataType::I32 => injector.i32_store(memarg),
+        DataType::I64 => injector.i64_store(memarg),
+        DataType::F32 => injector.f32_store(memarg),
+        _ => injector.f64_store(memarg),
+    };
+}
+
+fn load<'a, T: Opcode<'a>>(injector: &mut T, ty: &DataType, memarg: MemArg) {
+    match ty {
+        DataType::I32 => injector.i32_load(memarg),
+        DataType::I64 => injector.i64_load(memarg),
+        DataType::F32 => injector.f32_load(memarg),
+        _ => injector.f64_load(memarg),
+    };
+}
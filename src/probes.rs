@@ -0,0 +1,847 @@
+//! Ready-made instrumentation primitives.
+//!
+//! Each probe first adds the state it needs to the [`Module`] (a global, a memory region or an
+//! imported function), then injects its code through any injector implementing [`Opcode`] and
+//! [`MacroOpcode`]: the iterators, a [`FunctionBuilder`] or a [`FunctionModifier`]. The injected code
+//! leaves the operand stack as it found it, unless documented otherwise.
+//!
+//! The [`AccessTracer`] describes the memory accesses of the operators, and saves them into locals
+//! for the probes watching memory.
+//!
+//! [`FunctionBuilder`]: crate::ir::function::FunctionBuilder
+//! [`FunctionModifier`]: crate::ir::function::FunctionModifier
+
+use crate::ir::id::{FunctionID, GlobalID, LocalID, MemoryID, TypeID};
+use crate::ir::types::{BlockType, InitExpr, Instructions, InstrumentationMode, Value};
+use crate::module_builder::AddLocal;
+use crate::opcode::{Instrumenter, MacroOpcode};
+use crate::{DataType, Module, Opcode};
+use wasmparser::{Catch, MemArg, MemoryType, Operator};
+
+/// The size of a wasm page, in bytes.
+const PAGE_SIZE: u64 = 65536;
+
+/// A counter held in a mutable global of the module.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct GlobalCounter {
+    /// The global holding the counter.
+    pub global: GlobalID,
+    /// The type of the counter, `I32` or `I64`.
+    pub ty: DataType,
+}
+
+impl GlobalCounter {
+    /// Adds a new mutable global of type `ty` (`I32` or `I64`), initialized to 0, to hold the counter.
+    pub fn new(module: &mut Module, ty: DataType) -> Self {
+        let zero = match ty {
+            DataType::I32 => Value::I32(0),
+            DataType::I64 => Value::I64(0),
+            _ => panic!("A counter must be an i32 or an i64, got: {:?}", ty),
+        };
+        let global = module.add_global(
+            InitExpr::new(vec![Instructions::Value(zero)]),
+            ty,
+            true,
+            false,
+        );
+        GlobalCounter { global, ty }
+    }
+
+    /// Uses the existing mutable global `global` of type `ty` (`I32` or `I64`) as the counter.
+    pub fn from_global(global: GlobalID, ty: DataType) -> Self {
+        assert!(
+            matches!(ty, DataType::I32 | DataType::I64),
+            "A counter must be an i32 or an i64, got: {:?}",
+            ty
+        );
+        GlobalCounter { global, ty }
+    }
+
+    /// Injects code incrementing the counter by 1.
+    pub fn increment<'a, T: Opcode<'a> + ?Sized>(&self, injector: &mut T) {
+        self.add(injector, 1);
+    }
+
+    /// Injects code adding `value` to the counter.
+    pub fn add<'a, T: Opcode<'a> + ?Sized>(&self, injector: &mut T, value: i64) {
+        injector.global_get(self.global);
+        match self.ty {
+            DataType::I32 => injector.i32_const(value as i32).i32_add(),
+            _ => injector.i64_const(value).i64_add(),
+        };
+        injector.global_set(self.global);
+    }
+}
+
+/// An array of 64-bit counters stored in linear memory, one per site ID.
+///
+/// The counter of site `i` is stored at `base + 8 * i` of `memory`, in little endian.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CounterArray {
+    /// The memory holding the counters.
+    pub memory: MemoryID,
+    /// The offset of the first counter in the memory.
+    pub base: u64,
+    /// The number of counters.
+    pub len: u32,
+    /// Whether the memory is a 64-bit memory (addresses are `i64`).
+    memory64: bool,
+}
+
+impl CounterArray {
+    /// The size of a counter, in bytes.
+    pub const COUNTER_SIZE: u64 = 8;
+
+    /// Uses the `len` counters starting at `base` in the existing memory `memory`. The region must
+    /// not be used by the application, and is expected to be zeroed (e.g. by a data segment).
+    ///
+    /// Panics if `memory` does not exist.
+    pub fn new(module: &Module, memory: MemoryID, base: u64, len: u32) -> Self {
+        let memory64 = match module.memories.get_mem_by_id(memory) {
+            Some(mem) => mem.ty.memory64,
+            None => panic!("No memory with ID: {}", *memory),
+        };
+        CounterArray {
+            memory,
+            base,
+            len,
+            memory64,
+        }
+    }
+
+    /// Adds a new memory large enough to hold `len` counters, starting at offset 0.
+    ///
+    /// If the module already has a memory, the instrumented module requires the multi-memory
+    /// proposal.
+    pub fn with_new_memory(module: &mut Module, len: u32) -> Self {
+        let pages = (len as u64 * Self::COUNTER_SIZE).div_ceil(PAGE_SIZE).max(1);
+        let memory = module.add_local_memory(MemoryType {
+            memory64: false,
+            shared: false,
+            initial: pages,
+            maximum: Some(pages),
+            page_size_log2: None,
+        });
+        CounterArray {
+            memory,
+            base: 0,
+            len,
+            memory64: false,
+        }
+    }
+
+    /// The offset of the counter of `site` in the memory.
+    pub fn offset_of(&self, site: u32) -> u64 {
+        self.base + site as u64 * Self::COUNTER_SIZE
+    }
+
+    /// The number of bytes covered by the counters.
+    pub fn byte_len(&self) -> u64 {
+        self.len as u64 * Self::COUNTER_SIZE
+    }
+
+    /// Injects code incrementing the counter of `site`.
+    ///
+    /// Panics if `site` is out of the bounds of the array.
+    pub fn increment<'a, T: Opcode<'a> + ?Sized>(&self, injector: &mut T, site: u32) {
+        assert!(
+            site < self.len,
+            "Site {} is out of the bounds of the counter array (len: {})",
+            site,
+            self.len
+        );
+        // the offset of the counter is encoded in the memarg, the address is 0
+        let memarg = MemArg {
+            align: 3,
+            max_align: 3,
+            offset: self.offset_of(site),
+            memory: *self.memory,
+        };
+        self.address_zero(injector);
+        self.address_zero(injector);
+        injector
+            .i64_load(memarg)
+            .i64_const(1)
+            .i64_add()
+            .i64_store(memarg);
+    }
+
+    fn address_zero<'a, T: Opcode<'a> + ?Sized>(&self, injector: &mut T) {
+        if self.memory64 {
+            injector.i64_const(0);
+        } else {
+            injector.i32_const(0);
+        }
+    }
+}
+
+/// A call to an imported host function of type `[i32] -> []`, notified with the ID of the site that
+/// was hit.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct HitImport {
+    /// The imported function.
+    pub func: FunctionID,
+}
+
+impl HitImport {
+    /// Imports the host function `module_name`.`name`, of type `[i32] -> []`.
+    pub fn new(module: &mut Module, module_name: &str, name: &str) -> Self {
+        let ty = module.types.add_func_type(&[DataType::I32], &[]);
+        let (func, _) = module.add_import_func(module_name.to_string(), name.to_string(), ty);
+        HitImport { func }
+    }
+
+    /// Injects a call notifying the host that `site` was hit.
+    pub fn call<'a, T: Opcode<'a> + MacroOpcode<'a> + ?Sized>(&self, injector: &mut T, site: u32) {
+        injector.u32_const(site).call(self.func);
+    }
+}
+
+/// A clock imported from the host, as a function of type `[] -> [i64]`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Clock {
+    /// The imported function returning the current time.
+    pub func: FunctionID,
+}
+
+impl Clock {
+    /// Imports the host function `module_name`.`name`, of type `[] -> [i64]`.
+    pub fn new(module: &mut Module, module_name: &str, name: &str) -> Self {
+        let ty = module.types.add_func_type(&[], &[DataType::I64]);
+        let (func, _) = module.add_import_func(module_name.to_string(), name.to_string(), ty);
+        Clock { func }
+    }
+
+    /// Injects code pushing the current time (an `i64`) onto the stack.
+    pub fn timestamp<'a, T: Opcode<'a> + ?Sized>(&self, injector: &mut T) {
+        injector.call(self.func);
+    }
+
+    /// Injects code storing the current time into `local`, which must be an `i64` local.
+    pub fn timestamp_into<'a, T: Opcode<'a> + ?Sized>(&self, injector: &mut T, local: LocalID) {
+        injector.call(self.func).local_set(local);
+    }
+}
+
+/// A runtime condition guarding injected code, with its state held in a global of the module.
+///
+/// Guarded code is injected between [`Predicate::begin`] (or [`Predicate::begin_typed`]) and
+/// [`Predicate::end`]:
+///
+/// ```no_run
+/// # use orca_wasm::Module;
+/// # use orca_wasm::iterator::module_iterator::ModuleIterator;
+/// # use orca_wasm::iterator::iterator_trait::IteratingInstrumenter;
+/// # use orca_wasm::probes::{GlobalCounter, Predicate};
+/// # use orca_wasm::DataType;
+/// # let buff = vec![];
+/// let mut module = Module::parse(&buff, false).unwrap();
+/// let counter = GlobalCounter::new(&mut module, DataType::I64);
+/// // probes are off until the host sets the exported global
+/// let enabled = Predicate::flag(&mut module, false);
+/// module.exports.add_export_global("probes_enabled".to_string(), *enabled.global());
+///
+/// let mut mod_it = ModuleIterator::new(&mut module, &vec![]);
+/// mod_it.before();
+/// enabled.begin(&mut mod_it);
+/// counter.increment(&mut mod_it);
+/// enabled.end(&mut mod_it);
+/// ```
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Predicate {
+    /// Holds when the `i32` global is not 0.
+    Flag { global: GlobalID },
+    /// Holds once every `period` evaluations, starting with the first one. `countdown` is the
+    /// number of evaluations left before the next one that holds, shared by every guard using the
+    /// predicate.
+    Sample { countdown: GlobalID, period: u32 },
+}
+
+impl Predicate {
+    /// Adds a new mutable `i32` global holding the flag, set to `enabled`.
+    ///
+    /// The flag can be switched at runtime by exporting the global (see
+    /// [`ModuleExports::add_export_global`]) or with [`Predicate::set_flag`].
+    ///
+    /// [`ModuleExports::add_export_global`]: crate::ir::module::module_exports::ModuleExports::add_export_global
+    pub fn flag(module: &mut Module, enabled: bool) -> Self {
+        let global = module.add_global(
+            InitExpr::new(vec![Instructions::Value(Value::I32(enabled as i32))]),
+            DataType::I32,
+            true,
+            false,
+        );
+        Predicate::Flag { global }
+    }
+
+    /// Adds a new mutable `i32` global holding the sampling state of a predicate that holds 1 in
+    /// `period` times.
+    ///
+    /// Panics if `period` is 0.
+    pub fn sample(module: &mut Module, period: u32) -> Self {
+        assert!(period > 0, "The sampling period must be at least 1");
+        let countdown = module.add_global(
+            InitExpr::new(vec![Instructions::Value(Value::I32(0))]),
+            DataType::I32,
+            true,
+            false,
+        );
+        Predicate::Sample { countdown, period }
+    }
+
+    /// The global holding the state of the predicate.
+    pub fn global(&self) -> GlobalID {
+        match self {
+            Predicate::Flag { global } => *global,
+            Predicate::Sample { countdown, .. } => *countdown,
+        }
+    }
+
+    /// Injects code evaluating the predicate, pushing an `i32` that is not 0 if it holds.
+    /// Evaluating a sampling predicate advances its state.
+    pub fn condition<'a, T: Opcode<'a> + MacroOpcode<'a> + ?Sized>(&self, injector: &mut T) {
+        match self {
+            Predicate::Flag { global } => {
+                injector.global_get(*global);
+            }
+            Predicate::Sample { countdown, period } => {
+                // holds when the countdown reached 0, which restarts it
+                injector.global_get(*countdown).i32_eqz();
+                injector
+                    .u32_const(period - 1)
+                    .global_get(*countdown)
+                    .i32_const(1)
+                    .i32_sub()
+                    .global_get(*countdown)
+                    .i32_eqz()
+                    .select()
+                    .global_set(*countdown);
+            }
+        }
+    }
+
+    /// Injects the start of a guard for code that leaves the operand stack as it found it.
+    pub fn begin<'a, T: Opcode<'a> + MacroOpcode<'a> + ?Sized>(&self, injector: &mut T) {
+        self.condition(injector);
+        injector.if_stmt(BlockType::Empty);
+    }
+
+    /// Injects the start of a guard for code of type `ty`, e.g. a `semantic_after` body that consumes
+    /// the results of a block and pushes them back. Without an `else`, `ty` must have the same params
+    /// and results. To guard an `alternate` or `block_alt` body, `ty` is the type of the code it
+    /// replaces and the guard is ended with [`Predicate::end_with_fallback`].
+    pub fn begin_typed<'a, T: Opcode<'a> + MacroOpcode<'a> + ?Sized>(
+        &self,
+        injector: &mut T,
+        ty: TypeID,
+    ) {
+        self.condition(injector);
+        injector.if_stmt(BlockType::FuncType(ty));
+    }
+
+    /// Injects the end of a guard.
+    pub fn end<'a, T: Opcode<'a> + ?Sized>(&self, injector: &mut T) {
+        injector.end();
+    }
+
+    /// Injects the end of a guard, like [`Predicate::end`]. When the guard is injected in `alternate`
+    /// or `block_alt` mode, the `original` code the body replaces (the instruction, or the block from
+    /// its header to its `end`) runs instead of the body when the predicate does not hold. The
+    /// branches of `original` that leave it are adjusted to the label of the guard. `original` is
+    /// ignored in the other modes.
+    ///
+    /// Panics if a `br_table` of `original` leaves it, as its targets cannot be adjusted.
+    pub fn end_with_fallback<'a, T: Opcode<'a> + Instrumenter<'a> + ?Sized>(
+        &self,
+        injector: &mut T,
+        original: &[Operator<'a>],
+    ) {
+        if matches!(
+            injector.curr_instrument_mode(),
+            Some(InstrumentationMode::Alternate | InstrumentationMode::BlockAlt)
+        ) {
+            injector.else_stmt();
+            // the number of blocks opened inside `original`
+            let mut depth = 0;
+            for op in original {
+                if matches!(op, Operator::End | Operator::Delegate { .. }) {
+                    depth = u32::saturating_sub(depth, 1);
+                }
+                injector.inject(nest_in_guard(op, depth));
+                if matches!(
+                    op,
+                    Operator::Block { .. }
+                        | Operator::Loop { .. }
+                        | Operator::If { .. }
+                        | Operator::TryTable { .. }
+                        | Operator::Try { .. }
+                ) {
+                    depth += 1;
+                }
+            }
+        }
+        injector.end();
+    }
+
+    /// Injects code switching a flag on or off.
+    ///
+    /// Panics if the predicate is not a [`Predicate::Flag`].
+    pub fn set_flag<'a, T: Opcode<'a> + ?Sized>(&self, injector: &mut T, enabled: bool) {
+        match self {
+            Predicate::Flag { global } => {
+                injector.i32_const(enabled as i32).global_set(*global);
+            }
+            Predicate::Sample { .. } => panic!("Cannot switch a sampling predicate"),
+        }
+    }
+}
+
+/// Adjusts the labels of `op`, given the number of blocks opened before it in the code moved into a
+/// guard, to the label the guard adds.
+fn nest_in_guard<'a>(op: &Operator<'a>, depth: u32) -> Operator<'a> {
+    let nest = |label: u32| if label >= depth { label + 1 } else { label };
+    match op.clone() {
+        Operator::Br { relative_depth } => Operator::Br {
+            relative_depth: nest(relative_depth),
+        },
+        Operator::BrIf { relative_depth } => Operator::BrIf {
+            relative_depth: nest(relative_depth),
+        },
+        Operator::BrOnNull { relative_depth } => Operator::BrOnNull {
+            relative_depth: nest(relative_depth),
+        },
+        Operator::BrOnNonNull { relative_depth } => Operator::BrOnNonNull {
+            relative_depth: nest(relative_depth),
+        },
+        Operator::BrOnCast {
+            relative_depth,
+            from_ref_type,
+            to_ref_type,
+        } => Operator::BrOnCast {
+            relative_depth: nest(relative_depth),
+            from_ref_type,
+            to_ref_type,
+        },
+        Operator::BrOnCastFail {
+            relative_depth,
+            from_ref_type,
+            to_ref_type,
+        } => Operator::BrOnCastFail {
+            relative_depth: nest(relative_depth),
+            from_ref_type,
+            to_ref_type,
+        },
+        Operator::Rethrow { relative_depth } => Operator::Rethrow {
+            relative_depth: nest(relative_depth),
+        },
+        Operator::Delegate { relative_depth } => Operator::Delegate {
+            relative_depth: nest(relative_depth),
+        },
+        Operator::TryTable { mut try_table } => {
+            for catch in try_table.catches.iter_mut() {
+                match catch {
+                    Catch::One { label, .. }
+                    | Catch::OneRef { label, .. }
+                    | Catch::All { label }
+                    | Catch::AllRef { label } => *label = nest(*label),
+                }
+            }
+            Operator::TryTable { try_table }
+        }
+        Operator::BrTable { targets } => {
+            assert!(
+                targets
+                    .targets()
+                    .flatten()
+                    .chain([targets.default()])
+                    .all(|label| label < depth),
+                "Cannot move a br_table leaving the original code into a guard"
+            );
+            Operator::BrTable { targets }
+        }
+        op => op,
+    }
+}
+
+/// The kind of a memory access (see [`MemoryAccess`]).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AccessKind {
+    /// Reads memory: `*.load*`, the atomic loads and `v128.load*_lane`.
+    Load,
+    /// Writes a value to memory: `*.store*` and the atomic stores.
+    Store,
+    /// Atomically reads, modifies and writes memory with a value: `*.atomic.rmw*` (but `cmpxchg`).
+    ReadModifyWrite,
+    /// Atomically writes a value to memory if it holds an expected one: `*.atomic.rmw*.cmpxchg*`.
+    CompareExchange,
+    /// Waits on or notifies an address: `memory.atomic.wait*` and `memory.atomic.notify`.
+    WaitNotify,
+    /// Copies a region of memory: `memory.copy`.
+    Copy,
+    /// Fills a region of memory with a byte: `memory.fill`.
+    Fill,
+}
+
+/// The memory accesses of the operators of a module.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AccessTracer {
+    /// Whether each memory of the module is a 64-bit memory (addresses are `i64`).
+    memory64: Vec<bool>,
+}
+
+impl AccessTracer {
+    /// Records the address types of the memories of `module`.
+    pub fn new(module: &Module) -> Self {
+        let mut memory64 = vec![];
+        while let Some(mem) = module
+            .memories
+            .get_mem_by_id(MemoryID(memory64.len() as u32))
+        {
+            memory64.push(mem.ty.memory64);
+        }
+        AccessTracer { memory64 }
+    }
+
+    fn address_ty(&self, memory: u32) -> DataType {
+        match self.memory64.get(memory as usize) {
+            Some(true) => DataType::I64,
+            _ => DataType::I32,
+        }
+    }
+
+    /// The memory access performed by `op`, `None` if it does not access memory. Only the
+    /// operators that access the bytes of a memory at an address are handled, e.g. `memory.init` and
+    /// `memory.grow` are not.
+    pub fn access(&self, op: &Operator) -> Option<MemoryAccess> {
+        use AccessKind::*;
+        use DataType::{I32, I64, V128};
+        // (kind, memarg, width, the operands after the address, the index of the value operand)
+        let (kind, memarg, width, mut operands, value) = match op {
+            Operator::I32Load8S { memarg }
+            | Operator::I32Load8U { memarg }
+            | Operator::I64Load8S { memarg }
+            | Operator::I64Load8U { memarg }
+            | Operator::I32AtomicLoad8U { memarg }
+            | Operator::I64AtomicLoad8U { memarg }
+            | Operator::V128Load8Splat { memarg } => (Load, memarg, 1, vec![], None),
+            Operator::I32Load16S { memarg }
+            | Operator::I32Load16U { memarg }
+            | Operator::I64Load16S { memarg }
+            | Operator::I64Load16U { memarg }
+            | Operator::I32AtomicLoad16U { memarg }
+            | Operator::I64AtomicLoad16U { memarg }
+            | Operator::V128Load16Splat { memarg } => (Load, memarg, 2, vec![], None),
+            Operator::I32Load { memarg }
+            | Operator::F32Load { memarg }
+            | Operator::I64Load32S { memarg }
+            | Operator::I64Load32U { memarg }
+            | Operator::I32AtomicLoad { memarg }
+            | Operator::I64AtomicLoad32U { memarg }
+            | Operator::V128Load32Splat { memarg }
+            | Operator::V128Load32Zero { memarg } => (Load, memarg, 4, vec![], None),
+            Operator::I64Load { memarg }
+            | Operator::F64Load { memarg }
+            | Operator::I64AtomicLoad { memarg }
+            | Operator::V128Load8x8S { memarg }
+            | Operator::V128Load8x8U { memarg }
+            | Operator::V128Load16x4S { memarg }
+            | Operator::V128Load16x4U { memarg }
+            | Operator::V128Load32x2S { memarg }
+            | Operator::V128Load32x2U { memarg }
+            | Operator::V128Load64Splat { memarg }
+            | Operator::V128Load64Zero { memarg } => (Load, memarg, 8, vec![], None),
+            Operator::V128Load { memarg } => (Load, memarg, 16, vec![], None),
+            Operator::V128Load8Lane { memarg, .. } => (Load, memarg, 1, vec![V128], None),
+            Operator::V128Load16Lane { memarg, .. } => (Load, memarg, 2, vec![V128], None),
+            Operator::V128Load32Lane { memarg, .. } => (Load, memarg, 4, vec![V128], None),
+            Operator::V128Load64Lane { memarg, .. } => (Load, memarg, 8, vec![V128], None),
+
+            Operator::I32Store8 { memarg } | Operator::I32AtomicStore8 { memarg } => {
+                (Store, memarg, 1, vec![I32], Some(1))
+            }
+            Operator::I32Store16 { memarg } | Operator::I32AtomicStore16 { memarg } => {
+                (Store, memarg, 2, vec![I32], Some(1))
+            }
+            Operator::I32Store { memarg } | Operator::I32AtomicStore { memarg } => {
+                (Store, memarg, 4, vec![I32], Some(1))
+            }
+            Operator::I64Store8 { memarg } | Operator::I64AtomicStore8 { memarg } => {
+                (Store, memarg, 1, vec![I64], Some(1))
+            }
+            Operator::I64Store16 { memarg } | Operator::I64AtomicStore16 { memarg } => {
+                (Store, memarg, 2, vec![I64], Some(1))
+            }
+            Operator::I64Store32 { memarg } | Operator::I64AtomicStore32 { memarg } => {
+                (Store, memarg, 4, vec![I64], Some(1))
+            }
+            Operator::I64Store { memarg } | Operator::I64AtomicStore { memarg } => {
+                (Store, memarg, 8, vec![I64], Some(1))
+            }
+            Operator::F32Store { memarg } => (Store, memarg, 4, vec![DataType::F32], Some(1)),
+            Operator::F64Store { memarg } => (Store, memarg, 8, vec![DataType::F64], Some(1)),
+            Operator::V128Store { memarg } => (Store, memarg, 16, vec![V128], Some(1)),
+            Operator::V128Store8Lane { memarg, .. } => (Store, memarg, 1, vec![V128], Some(1)),
+            Operator::V128Store16Lane { memarg, .. } => (Store, memarg, 2, vec![V128], Some(1)),
+            Operator::V128Store32Lane { memarg, .. } => (Store, memarg, 4, vec![V128], Some(1)),
+            Operator::V128Store64Lane { memarg, .. } => (Store, memarg, 8, vec![V128], Some(1)),
+
+            Operator::I32AtomicRmw8AddU { memarg }
+            | Operator::I32AtomicRmw8SubU { memarg }
+            | Operator::I32AtomicRmw8AndU { memarg }
+            | Operator::I32AtomicRmw8OrU { memarg }
+            | Operator::I32AtomicRmw8XorU { memarg }
+            | Operator::I32AtomicRmw8XchgU { memarg } => {
+                (ReadModifyWrite, memarg, 1, vec![I32], Some(1))
+            }
+            Operator::I32AtomicRmw16AddU { memarg }
+            | Operator::I32AtomicRmw16SubU { memarg }
+            | Operator::I32AtomicRmw16AndU { memarg }
+            | Operator::I32AtomicRmw16OrU { memarg }
+            | Operator::I32AtomicRmw16XorU { memarg }
+            | Operator::I32AtomicRmw16XchgU { memarg } => {
+                (ReadModifyWrite, memarg, 2, vec![I32], Some(1))
+            }
+            Operator::I32AtomicRmwAdd { memarg }
+            | Operator::I32AtomicRmwSub { memarg }
+            | Operator::I32AtomicRmwAnd { memarg }
+            | Operator::I32AtomicRmwOr { memarg }
+            | Operator::I32AtomicRmwXor { memarg }
+            | Operator::I32AtomicRmwXchg { memarg } => {
+                (ReadModifyWrite, memarg, 4, vec![I32], Some(1))
+            }
+            Operator::I64AtomicRmw8AddU { memarg }
+            | Operator::I64AtomicRmw8SubU { memarg }
+            | Operator::I64AtomicRmw8AndU { memarg }
+            | Operator::I64AtomicRmw8OrU { memarg }
+            | Operator::I64AtomicRmw8XorU { memarg }
+            | Operator::I64AtomicRmw8XchgU { memarg } => {
+                (ReadModifyWrite, memarg, 1, vec![I64], Some(1))
+            }
+            Operator::I64AtomicRmw16AddU { memarg }
+            | Operator::I64AtomicRmw16SubU { memarg }
+            | Operator::I64AtomicRmw16AndU { memarg }
+            | Operator::I64AtomicRmw16OrU { memarg }
+            | Operator::I64AtomicRmw16XorU { memarg }
+            | Operator::I64AtomicRmw16XchgU { memarg } => {
+                (ReadModifyWrite, memarg, 2, vec![I64], Some(1))
+            }
+            Operator::I64AtomicRmw32AddU { memarg }
+            | Operator::I64AtomicRmw32SubU { memarg }
+            | Operator::I64AtomicRmw32AndU { memarg }
+            | Operator::I64AtomicRmw32OrU { memarg }
+            | Operator::I64AtomicRmw32XorU { memarg }
+            | Operator::I64AtomicRmw32XchgU { memarg } => {
+                (ReadModifyWrite, memarg, 4, vec![I64], Some(1))
+            }
+            Operator::I64AtomicRmwAdd { memarg }
+            | Operator::I64AtomicRmwSub { memarg }
+            | Operator::I64AtomicRmwAnd { memarg }
+            | Operator::I64AtomicRmwOr { memarg }
+            | Operator::I64AtomicRmwXor { memarg }
+            | Operator::I64AtomicRmwXchg { memarg } => {
+                (ReadModifyWrite, memarg, 8, vec![I64], Some(1))
+            }
+
+            Operator::I32AtomicRmw8CmpxchgU { memarg } => {
+                (CompareExchange, memarg, 1, vec![I32, I32], Some(2))
+            }
+            Operator::I32AtomicRmw16CmpxchgU { memarg } => {
+                (CompareExchange, memarg, 2, vec![I32, I32], Some(2))
+            }
+            Operator::I32AtomicRmwCmpxchg { memarg } => {
+                (CompareExchange, memarg, 4, vec![I32, I32], Some(2))
+            }
+            Operator::I64AtomicRmw8CmpxchgU { memarg } => {
+                (CompareExchange, memarg, 1, vec![I64, I64], Some(2))
+            }
+            Operator::I64AtomicRmw16CmpxchgU { memarg } => {
+                (CompareExchange, memarg, 2, vec![I64, I64], Some(2))
+            }
+            Operator::I64AtomicRmw32CmpxchgU { memarg } => {
+                (CompareExchange, memarg, 4, vec![I64, I64], Some(2))
+            }
+            Operator::I64AtomicRmwCmpxchg { memarg } => {
+                (CompareExchange, memarg, 8, vec![I64, I64], Some(2))
+            }
+
+            Operator::MemoryAtomicNotify { memarg } => (WaitNotify, memarg, 4, vec![I32], None),
+            Operator::MemoryAtomicWait32 { memarg } => {
+                (WaitNotify, memarg, 4, vec![I32, I64], None)
+            }
+            Operator::MemoryAtomicWait64 { memarg } => {
+                (WaitNotify, memarg, 8, vec![I64, I64], None)
+            }
+
+            Operator::MemoryCopy { dst_mem, src_mem } => {
+                let dst = self.address_ty(*dst_mem);
+                let src = self.address_ty(*src_mem);
+                // the length is an i64 only if both memories are 64-bit memories
+                let len = if dst == I64 && src == I64 { I64 } else { I32 };
+                return Some(MemoryAccess {
+                    kind: Copy,
+                    memory: *dst_mem,
+                    source_memory: Some(*src_mem),
+                    offset: 0,
+                    width: None,
+                    operands: vec![dst, src, len],
+                    value: None,
+                });
+            }
+            Operator::MemoryFill { mem } => {
+                let address = self.address_ty(*mem);
+                return Some(MemoryAccess {
+                    kind: Fill,
+                    memory: *mem,
+                    source_memory: None,
+                    offset: 0,
+                    width: None,
+                    operands: vec![address, I32, address],
+                    value: Some(1),
+                });
+            }
+            _ => return None,
+        };
+        operands.insert(0, self.address_ty(memarg.memory));
+        Some(MemoryAccess {
+            kind,
+            memory: memarg.memory,
+            source_memory: None,
+            offset: memarg.offset,
+            width: Some(width),
+            operands,
+            value,
+        })
+    }
+}
+
+/// A memory access performed by an operator (see [`AccessTracer::access`]).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MemoryAccess {
+    /// The kind of the access.
+    pub kind: AccessKind,
+    /// The memory accessed (written to, for `memory.copy`).
+    pub memory: u32,
+    /// The memory read from by `memory.copy`.
+    pub source_memory: Option<u32>,
+    /// The static offset added to the address operand.
+    pub offset: u64,
+    /// The number of bytes accessed, `None` if it is given by the length operand (`memory.copy` and
+    /// `memory.fill`).
+    pub width: Option<u32>,
+    /// The types of the operands of the operator, the address first.
+    pub operands: Vec<DataType>,
+    /// The index of the operand holding the value written to memory.
+    value: Option<usize>,
+}
+
+impl MemoryAccess {
+    /// Injects code saving the access into locals, before the operator: the operands are spilled
+    /// into locals and pushed back once the effective address and the width are computed, so that
+    /// the operator still finds them. Returns the locals, which the probes injected next can read.
+    /// The injector must be in the `before` mode.
+    ///
+    /// The locals are taken from `temps`, which is shared by the saves of a function: they hold the
+    /// access until the operator runs, the next access saved overwrites them.
+    ///
+    /// The effective address (the address operand plus the static offset) is computed as an `i64`,
+    /// so that it does not wrap around for 32-bit memories.
+    pub fn save<'a, T: Opcode<'a> + AddLocal + ?Sized>(
+        &self,
+        injector: &mut T,
+        temps: &mut AccessTemps,
+    ) -> AccessLocals {
+        let mut types = self.operands.clone();
+        // the address, the width and the source of `memory.copy`
+        types.extend([DataType::I64, DataType::I64]);
+        if self.kind == AccessKind::Copy {
+            types.push(DataType::I64);
+        }
+        let mut locals = temps.take(injector, &types).into_iter();
+        let saved: Vec<LocalID> = locals.by_ref().take(self.operands.len()).collect();
+        for local in saved.iter().rev() {
+            injector.local_set(*local);
+        }
+
+        let address = locals.next().unwrap();
+        self.as_i64(injector, 0, &saved);
+        if self.offset != 0 {
+            injector.i64_const(self.offset as i64).i64_add();
+        }
+        injector.local_set(address);
+
+        let width = locals.next().unwrap();
+        match self.width {
+            Some(width) => {
+                injector.i64_const(width as i64);
+            }
+            None => self.as_i64(injector, saved.len() - 1, &saved),
+        }
+        injector.local_set(width);
+
+        let source = locals.next();
+        if let Some(source) = source {
+            self.as_i64(injector, 1, &saved);
+            injector.local_set(source);
+        }
+
+        for local in saved.iter() {
+            injector.local_get(*local);
+        }
+        AccessLocals {
+            address,
+            width,
+            value: self.value.map(|idx| saved[idx]),
+            source,
+        }
+    }
+
+    /// Pushes the `idx`th operand, saved in `saved`, as an `i64`.
+    fn as_i64<'a, T: Opcode<'a> + ?Sized>(&self, injector: &mut T, idx: usize, saved: &[LocalID]) {
+        injector.local_get(saved[idx]);
+        if self.operands[idx] == DataType::I32 {
+            injector.i64_extend_i32u();
+        }
+    }
+}
+
+/// The locals of a function that the code saving its memory accesses reuses (see
+/// [`MemoryAccess::save`]).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AccessTemps {
+    locals: Vec<(DataType, LocalID)>,
+}
+
+impl AccessTemps {
+    /// Gets distinct locals of the given types, adding the ones that are missing.
+    fn take<T: AddLocal + ?Sized>(&mut self, injector: &mut T, types: &[DataType]) -> Vec<LocalID> {
+        let mut taken: Vec<LocalID> = vec![];
+        for ty in types.iter() {
+            let reusable = self
+                .locals
+                .iter()
+                .find(|(local_ty, local)| local_ty == ty && !taken.contains(local))
+                .map(|(_, local)| *local);
+            let local = reusable.unwrap_or_else(|| {
+                let local = injector.add_local(*ty);
+                self.locals.push((*ty, local));
+                local
+            });
+            taken.push(local);
+        }
+        taken
+    }
+}
+
+/// The locals holding a memory access (see [`MemoryAccess::save`]).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AccessLocals {
+    /// The effective address accessed, an `i64` (the destination of `memory.copy`).
+    pub address: LocalID,
+    /// The number of bytes accessed, an `i64`.
+    pub width: LocalID,
+    /// The value written to memory, of the type of the operand: the stored value, the operand of a
+    /// read-modify-write, the replacement of a compare-exchange or the byte of `memory.fill`.
+    pub value: Option<LocalID>,
+    /// The address read from by `memory.copy`, an `i64`.
+    pub source: Option<LocalID>,
+}
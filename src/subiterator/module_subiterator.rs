@@ -91,6 +91,11 @@ impl ModuleSubIterator {
         }
     }
 
+    /// Checks if the SubIterator has no function to visit, all of them being skipped
+    pub(crate) fn end(&self) -> bool {
+        self.curr_idx >= self.metadata.len()
+    }
+
     /// Checks if there are functions left to visit
     pub fn has_next_function(&self) -> bool {
         self.curr_idx + 1 < self.metadata.len()
@@ -0,0 +1,172 @@
+//! Merges locals of the same type whose live ranges do not overlap.
+//!
+//! Every temporary that instrumentation asks for is a new local, so heavily instrumented functions
+//! can end up with thousands of locals. Two locals can share a slot if neither is written while the
+//! other one holds a value that is still needed. This pass builds that interference relation from the
+//! [`Liveness`] of the function, greedily assigns the declared locals to slots and rewrites every
+//! `local.*` instruction (injected ones included) to use the new indices.
+//!
+//! Parameters are never merged or renumbered.
+
+use crate::analysis::bitset::BitSet;
+use crate::analysis::dataflow::straight_line_ops;
+use crate::analysis::liveness::Liveness;
+use crate::analysis::{all_ops, declared_local_types, local_access, LocalAccess};
+use crate::ir::id::LocalID;
+use crate::ir::module::module_functions::LocalFunction;
+use crate::ir::types::DataType;
+use wasmparser::Operator;
+
+/// Merges the locals of `func` that are never live at the same time and have the same type.
+/// Returns the mapping from old to new local indices, or `None` if no locals could be merged.
+pub fn compact_locals(func: &mut LocalFunction) -> Option<Vec<u32>> {
+    let num_params = func.args.len();
+    let local_types = declared_local_types(&func.body);
+    if local_types.len() < 2 {
+        return None;
+    }
+    let num_locals = num_params + local_types.len();
+    let interference = interference(func, num_locals);
+
+    // greedily place every local in the first compatible slot
+    // (type, interference of the locals in the slot)
+    let mut slots: Vec<(DataType, BitSet)> = vec![];
+    let mut mapping: Vec<u32> = (0..num_params as u32).collect();
+    for (offset, ty) in local_types.iter().enumerate() {
+        let local = num_params + offset;
+        let slot = slots
+            .iter()
+            .position(|(slot_ty, interferes)| slot_ty == ty && !interferes.contains(local));
+        let slot = match slot {
+            Some(slot) => {
+                slots[slot].1.union_with(&interference[local]);
+                slot
+            }
+            None => {
+                slots.push((*ty, interference[local].clone()));
+                slots.len() - 1
+            }
+        };
+        mapping.push((num_params + slot) as u32);
+    }
+    if slots.len() == local_types.len() {
+        return None;
+    }
+
+    // re-declare the locals
+    let mut locals: Vec<(u32, DataType)> = vec![];
+    for (ty, _) in slots.iter() {
+        match locals.last_mut() {
+            Some((count, last_ty)) if last_ty == ty => *count += 1,
+            _ => locals.push((1, *ty)),
+        }
+    }
+    func.body.locals = locals;
+    func.body.num_locals = slots.len() as u32;
+
+    // rewrite the local indices
+    let remap = |op: &mut Operator| match op {
+        Operator::LocalGet { local_index }
+        | Operator::LocalSet { local_index }
+        | Operator::LocalTee { local_index } => {
+            if let Some(new) = mapping.get(*local_index as usize) {
+                *local_index = *new;
+            }
+        }
+        _ => {}
+    };
+    for instr in func.body.instructions.iter_mut() {
+        remap(&mut instr.op);
+        let flag = &mut instr.instr_flag;
+        flag.before
+            .iter_mut()
+            .chain(flag.after.iter_mut())
+            .chain(flag.alternate.iter_mut().flatten())
+            .chain(flag.semantic_after.iter_mut())
+            .chain(flag.block_entry.iter_mut())
+            .chain(flag.block_exit.iter_mut())
+            .chain(flag.block_alt.iter_mut().flatten())
+            .for_each(remap);
+    }
+    func.instr_flag
+        .entry
+        .iter_mut()
+        .chain(func.instr_flag.exit.iter_mut())
+        .for_each(remap);
+
+    // a slot keeps the name of the first named local placed in it
+    let names = std::mem::take(&mut func.body.local_names);
+    for (local, name) in names {
+        if let Some(new) = mapping.get(*local as usize) {
+            func.body.local_names.entry(LocalID(*new)).or_insert(name);
+        }
+    }
+
+    Some(mapping)
+}
+
+/// For every local, the set of locals it may not share a slot with.
+fn interference(func: &LocalFunction, num_locals: usize) -> Vec<BitSet> {
+    let body = &func.body;
+    let liveness = Liveness::for_function(func);
+    let mut interference = vec![BitSet::with_capacity(num_locals); num_locals];
+    let mut interfere = |a: usize, b: usize| {
+        if a != b && a < num_locals && b < num_locals {
+            interference[a].insert(b);
+            interference[b].insert(a);
+        }
+    };
+
+    // locals that are read before being written hold their default value from the function entry
+    if !body.instructions.is_empty() {
+        let live_in: Vec<usize> = liveness.live_before(0).iter().collect();
+        for a in live_in.iter() {
+            for b in live_in.iter() {
+                interfere(*a, *b);
+            }
+        }
+    }
+
+    for (idx, instr) in body.instructions.iter().enumerate() {
+        match straight_line_ops(instr) {
+            Some(ops) => {
+                // a write interferes with every local that is live right after it
+                let mut live = liveness.live_after(idx);
+                for op in ops.iter().rev() {
+                    match local_access(op) {
+                        Some((local, LocalAccess::Set | LocalAccess::Tee)) => {
+                            for other in live.iter() {
+                                interfere(local as usize, other);
+                            }
+                            live.remove(local as usize);
+                        }
+                        Some((local, LocalAccess::Get)) => {
+                            live.insert(local as usize);
+                        }
+                        None => {}
+                    }
+                }
+            }
+            None => {
+                // injected control flow: the writes interfere with everything around the instruction
+                let mut around = liveness.live_before(idx);
+                around.union_with(&liveness.live_after(idx));
+                let mut written = vec![];
+                for op in all_ops(instr) {
+                    if let Some((local, access)) = local_access(op) {
+                        around.insert(local as usize);
+                        if access != LocalAccess::Get {
+                            written.push(local as usize);
+                        }
+                    }
+                }
+                for local in written {
+                    for other in around.iter() {
+                        interfere(local, other);
+                    }
+                }
+            }
+        }
+    }
+    interference
+}
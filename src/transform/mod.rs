@@ -0,0 +1,131 @@
+//! Transformations over the functions of a module.
+//!
+//! These run on the instrumented functions when a module is encoded (if enabled on the [`Module`]),
+//! but can also be applied to a single function directly.
+//!
+//! [`Module`]: crate::Module
+
+use crate::analysis::special_ops;
+use crate::error::Error;
+use crate::ir::types::{Body, Instruction, InstrumentationFlag, InstrumentationMode, Origin};
+use wasmparser::Operator;
+
+pub mod local_compaction;
+pub mod outline;
+pub mod peephole;
+pub mod wrap;
+
+/// Turns the `before`, `after` and `alternate` instrumentation of every instruction into plain
+/// instructions of the body, exactly in the order they would be encoded. Instrumentation in the
+/// special modes (semantic after, block entry/exit/alt) stays attached to the original instruction.
+/// The flattened instructions injected under a tag record it in their [`Origin`].
+///
+/// Fails, leaving the body unchanged, if an instruction replaced by an `alternate` body also has
+/// instrumentation in a special mode: that instrumentation is resolved against the original
+/// instruction, which would be gone.
+pub fn flatten_instrumentation(body: &mut Body) -> Result<(), Error> {
+    // instrumentation after the `end` of the function is dropped anyway
+    let replaced = &body.instructions[..body.instructions.len().saturating_sub(1)];
+    if let Some(instr_idx) = replaced.iter().position(|instr| {
+        instr.instr_flag.alternate.is_some() && special_ops(instr).next().is_some()
+    }) {
+        return Err(Error::InvalidFlatten {
+            instr_idx,
+            reason: "the instruction is replaced by an alternate body and has instrumentation \
+                     in a special mode"
+                .to_string(),
+        });
+    }
+    flatten_injected(body);
+    Ok(())
+}
+
+/// Flattens the instrumentation of `body` (see [`flatten_instrumentation`]) and returns whether each
+/// instruction of the flattened body is injected code: flattened instrumentation, or code recorded
+/// with an [`Origin`] when the module was parsed.
+///
+/// The instructions replaced by an `alternate` body must not have instrumentation in a special
+/// mode, it would be dropped along with them.
+pub(crate) fn flatten_injected<'a>(body: &mut Body<'a>) -> Vec<bool> {
+    let len = body.instructions.len();
+    let mut flat = Vec::with_capacity(len);
+    let mut injected = Vec::with_capacity(len);
+    for (idx, instr) in body.instructions.drain(..).enumerate() {
+        debug_assert!(
+            idx + 1 >= len
+                || instr.instr_flag.alternate.is_none()
+                || special_ops(&instr).next().is_none()
+        );
+        let InstrumentationFlag {
+            current_mode: _,
+            before,
+            after,
+            alternate,
+            semantic_after,
+            block_entry,
+            block_exit,
+            block_alt,
+            tags,
+            origin,
+        } = instr.instr_flag;
+        // instrumentation after the `end` of the function is dropped on encode
+        let at_end = idx + 1 >= len;
+
+        let tags_of = |mode| tags.iter().find(|(body_mode, _)| *body_mode == mode);
+        let flatten = |ops: Vec<Operator<'a>>, mode| {
+            let body_tags = tags_of(mode).map(|(_, body_tags)| body_tags);
+            ops.into_iter().enumerate().map(move |(idx, op)| {
+                let mut instr = Instruction::new(op);
+                instr.instr_flag.origin = body_tags
+                    .and_then(|body_tags| body_tags.tag_of(idx))
+                    .map(|tag| Origin {
+                        tag,
+                        replaces_original: mode == InstrumentationMode::Alternate,
+                    });
+                instr
+            })
+        };
+
+        flat.extend(flatten(before, InstrumentationMode::Before));
+        injected.resize(flat.len(), true);
+        match alternate {
+            Some(alt) if !at_end => flat.extend(flatten(alt, InstrumentationMode::Alternate)),
+            _ => {
+                injected.push(origin.is_some());
+                flat.push(Instruction {
+                    op: instr.op,
+                    instr_flag: InstrumentationFlag {
+                        semantic_after,
+                        block_entry,
+                        block_exit,
+                        block_alt,
+                        tags: tags
+                            .iter()
+                            .filter(|(mode, _)| {
+                                !matches!(
+                                    mode,
+                                    InstrumentationMode::Before
+                                        | InstrumentationMode::After
+                                        | InstrumentationMode::Alternate
+                                )
+                            })
+                            .cloned()
+                            .collect(),
+                        origin,
+                        ..Default::default()
+                    },
+                    offset: instr.offset,
+                    label: instr.label,
+                });
+            }
+        }
+        injected.resize(flat.len(), true);
+        if !at_end {
+            flat.extend(flatten(after, InstrumentationMode::After));
+            injected.resize(flat.len(), true);
+        }
+    }
+    body.instructions = flat;
+    body.num_instructions = body.instructions.len();
+    injected
+}
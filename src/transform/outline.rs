@@ -0,0 +1,308 @@
+//! Moves a range of instructions out of a function into a new function.
+//!
+//! The range must be single-entry single-exit: it is structurally balanced (every block it opens is
+//! closed inside of it, it does not contain the `else`/`catch`/`end` of an enclosing block) and it
+//! does not branch or return out of itself. Locals whose value flows into the range become parameters
+//! of the new function, locals written in the range that are still needed afterward become its results.
+//! The range is replaced by a `call` to the new function, surrounded by the `local.get`s of the
+//! arguments and the `local.set`s of the results.
+
+use crate::analysis::liveness::Liveness;
+use crate::analysis::{all_ops, declared_local_types, local_access, LocalAccess};
+use crate::error::Error;
+use crate::ir::id::FunctionID;
+use crate::ir::module::module_functions::FuncKind;
+use crate::ir::module::Module;
+use crate::ir::types::{Body, DataType, Instruction};
+use std::ops::Range;
+use wasmparser::{Catch, Operator};
+
+/// Outlines the instructions in `range` of the local function `func_id` into a new function.
+/// Returns the ID of the new function.
+///
+/// The range (including the instrumentation injected on its instructions) must leave the operand
+/// stack as it found it: it may not consume values pushed before it, nor leave values behind.
+pub fn outline_range(
+    module: &mut Module,
+    func_id: FunctionID,
+    range: Range<usize>,
+    name: Option<String>,
+) -> Result<FunctionID, Error> {
+    let invalid = |reason: &str| Error::InvalidOutlineRange {
+        range: range.clone(),
+        reason: reason.to_string(),
+    };
+    let ty_id = match module.functions.get_kind(func_id) {
+        FuncKind::Local(func) => func.ty_id,
+        FuncKind::Import(_) => {
+            return Err(invalid("cannot outline code from an imported function"))
+        }
+    };
+    let func_params = match module.types.get(ty_id) {
+        Some(ty) => ty.params(),
+        None => return Err(invalid("the function has no type")),
+    };
+
+    let func = module.functions.unwrap_local(func_id);
+    let num_instrs = func.body.instructions.len();
+    if range.is_empty() || range.end >= num_instrs {
+        // the `end` of the function can never be part of the range
+        return Err(invalid("the range is empty or out of bounds"));
+    }
+    check_single_entry_single_exit(&func.body.instructions[range.clone()]).map_err(invalid)?;
+
+    // ==== find the locals flowing in and out of the range ====
+    let mut local_types = func_params.clone();
+    local_types.extend(declared_local_types(&func.body));
+    let liveness = Liveness::for_function(func);
+    let live_in = liveness.live_before(range.start);
+    let live_out = liveness.live_before(range.end);
+    let mut referenced = vec![];
+    let mut written = vec![];
+    for instr in func.body.instructions[range.clone()].iter() {
+        for op in all_ops(instr) {
+            if let Some((local, access)) = local_access(op) {
+                if !referenced.contains(&local) {
+                    referenced.push(local);
+                }
+                if access != LocalAccess::Get && !written.contains(&local) {
+                    written.push(local);
+                }
+            }
+        }
+    }
+    referenced.sort_unstable();
+    written.sort_unstable();
+    let always_written = always_written(&func.body.instructions[range.clone()]);
+    // a local written on some paths only still needs its incoming value on the others
+    let params: Vec<u32> = referenced
+        .iter()
+        .copied()
+        .filter(|local| {
+            live_in.contains(*local as usize)
+                || (live_out.contains(*local as usize) && !always_written.contains(local))
+        })
+        .collect();
+    let results: Vec<u32> = written
+        .iter()
+        .copied()
+        .filter(|local| live_out.contains(*local as usize))
+        .collect();
+
+    // ==== build the new function ====
+    let type_of = |local: &u32| local_types[*local as usize];
+    let param_types: Vec<DataType> = params.iter().map(type_of).collect();
+    let result_types: Vec<DataType> = results.iter().map(type_of).collect();
+
+    // the parameters keep their order, the other referenced locals follow
+    let mut mapping: Vec<(u32, u32)> = params
+        .iter()
+        .enumerate()
+        .map(|(new, old)| (*old, new as u32))
+        .collect();
+    let mut body = Body::default();
+    for local in referenced.iter().filter(|local| !params.contains(local)) {
+        mapping.push((*local, (params.len() + body.locals.len()) as u32));
+        body.locals.push((1, type_of(local)));
+    }
+    body.num_locals = body.locals.len() as u32;
+
+    let remap = |op: &mut Operator| {
+        if let Operator::LocalGet { local_index }
+        | Operator::LocalSet { local_index }
+        | Operator::LocalTee { local_index } = op
+        {
+            if let Some((_, new)) = mapping.iter().find(|(old, _)| old == local_index) {
+                *local_index = *new;
+            }
+        }
+    };
+    let insert_at = range.start;
+    let mut outlined: Vec<Instruction> = func.body.instructions.drain(range.clone()).collect();
+    for instr in outlined.iter_mut() {
+        remap(&mut instr.op);
+        let flag = &mut instr.instr_flag;
+        flag.before
+            .iter_mut()
+            .chain(flag.after.iter_mut())
+            .chain(flag.alternate.iter_mut().flatten())
+            .chain(flag.semantic_after.iter_mut())
+            .chain(flag.block_entry.iter_mut())
+            .chain(flag.block_exit.iter_mut())
+            .chain(flag.block_alt.iter_mut().flatten())
+            .for_each(remap);
+    }
+    body.instructions = outlined;
+    for local in results.iter() {
+        let (_, new) = mapping.iter().find(|(old, _)| old == local).unwrap();
+        body.push_op(Operator::LocalGet { local_index: *new });
+    }
+    body.push_op(Operator::End);
+    body.num_instructions = body.instructions.len();
+
+    let has_special_instr = body.instructions.iter().any(|instr| {
+        let flag = &instr.instr_flag;
+        !flag.semantic_after.is_empty()
+            || !flag.block_entry.is_empty()
+            || !flag.block_exit.is_empty()
+            || flag.block_alt.is_some()
+    });
+    let new_id = module.add_local_func(name, &param_types, &result_types, body);
+    module
+        .functions
+        .unwrap_local(new_id)
+        .instr_flag
+        .has_special_instr = has_special_instr;
+
+    // ==== call the new function from the original one ====
+    let mut call = vec![];
+    for local in params.iter() {
+        call.push(Instruction::new(Operator::LocalGet {
+            local_index: *local,
+        }));
+    }
+    call.push(Instruction::new(Operator::Call {
+        function_index: *new_id,
+    }));
+    for local in results.iter().rev() {
+        call.push(Instruction::new(Operator::LocalSet {
+            local_index: *local,
+        }));
+    }
+    let func = module.functions.unwrap_local(func_id);
+    func.body.instructions.splice(insert_at..insert_at, call);
+    func.body.num_instructions = func.body.instructions.len();
+
+    Ok(new_id)
+}
+
+/// The locals written on every path through the range: the writes that are not nested in a block of
+/// the range (since the range cannot branch out of itself, these are always executed).
+fn always_written(instrs: &[Instruction]) -> Vec<u32> {
+    let mut written = vec![];
+    let mut depth: u32 = 0;
+    for instr in instrs.iter() {
+        if depth == 0 && instr.instr_flag.alternate.is_none() {
+            if let Some((local, LocalAccess::Set | LocalAccess::Tee)) = local_access(&instr.op) {
+                written.push(local);
+            }
+        }
+        if is_block_opener(&instr.op) {
+            depth += 1;
+        } else if matches!(instr.op, Operator::End | Operator::Delegate { .. }) {
+            depth = depth.saturating_sub(1);
+        }
+    }
+    written
+}
+
+/// Checks that the instructions are structurally balanced and do not branch out of themselves.
+fn check_single_entry_single_exit(instrs: &[Instruction]) -> Result<(), &'static str> {
+    // number of blocks opened in the range that are still open
+    let mut depth: u32 = 0;
+    for instr in instrs.iter() {
+        let depth_before = depth;
+        let op = &instr.op;
+        match op {
+            Operator::Block { .. }
+            | Operator::Loop { .. }
+            | Operator::If { .. }
+            | Operator::TryTable { .. }
+            | Operator::Try { .. } => depth += 1,
+            Operator::Else | Operator::Catch { .. } | Operator::CatchAll if depth == 0 => {
+                return Err("the range splits an enclosing block");
+            }
+            Operator::End | Operator::Delegate { .. } => {
+                if depth == 0 {
+                    return Err("the range splits an enclosing block");
+                }
+                depth -= 1;
+            }
+            _ => {}
+        }
+        // labels are resolved outside of a `try_table` and a `delegate`
+        check_branches(op, depth_before.min(depth))?;
+
+        // injected code sits next to the instruction, with its own nesting
+        let flag = &instr.instr_flag;
+        let injected = [
+            &flag.before,
+            &flag.after,
+            &flag.semantic_after,
+            &flag.block_entry,
+            &flag.block_exit,
+        ]
+        .into_iter()
+        .chain(flag.alternate.iter())
+        .chain(flag.block_alt.iter());
+        for ops in injected {
+            let mut inner = depth_before.min(depth);
+            for op in ops.iter() {
+                check_branches(op, inner)?;
+                if is_block_opener(op) {
+                    inner += 1;
+                } else if matches!(op, Operator::End | Operator::Delegate { .. }) {
+                    inner = inner.saturating_sub(1);
+                }
+            }
+        }
+    }
+    if depth != 0 {
+        return Err("the range does not close all the blocks it opens");
+    }
+    Ok(())
+}
+
+fn is_block_opener(op: &Operator) -> bool {
+    matches!(
+        op,
+        Operator::Block { .. }
+            | Operator::Loop { .. }
+            | Operator::If { .. }
+            | Operator::TryTable { .. }
+            | Operator::Try { .. }
+    )
+}
+
+/// Checks that `op` does not leave the range, given the number of blocks opened inside the range.
+fn check_branches(op: &Operator, depth: u32) -> Result<(), &'static str> {
+    let branches_out = |relative_depth: u32| relative_depth >= depth;
+    match op {
+        Operator::Return
+        | Operator::ReturnCall { .. }
+        | Operator::ReturnCallIndirect { .. }
+        | Operator::ReturnCallRef { .. } => Err("the range returns from the function"),
+        Operator::Br { relative_depth }
+        | Operator::BrIf { relative_depth }
+        | Operator::BrOnNull { relative_depth }
+        | Operator::BrOnNonNull { relative_depth }
+        | Operator::BrOnCast { relative_depth, .. }
+        | Operator::BrOnCastFail { relative_depth, .. }
+        | Operator::Rethrow { relative_depth }
+        | Operator::Delegate { relative_depth }
+            if branches_out(*relative_depth) =>
+        {
+            Err("the range branches out of itself")
+        }
+        Operator::BrTable { targets }
+            if targets
+                .targets()
+                .flatten()
+                .chain([targets.default()])
+                .any(branches_out) =>
+        {
+            Err("the range branches out of itself")
+        }
+        Operator::TryTable { try_table }
+            if try_table.catches.iter().any(|catch| match catch {
+                Catch::One { label, .. }
+                | Catch::OneRef { label, .. }
+                | Catch::All { label }
+                | Catch::AllRef { label } => branches_out(*label),
+            }) =>
+        {
+            Err("the range branches out of itself")
+        }
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,539 @@
+//! Peephole and constant-folding optimizations for instrumented code.
+//!
+//! Injected code is usually generic: a probe pushes a value only to drop it, the flags created to
+//! resolve `semantic_after` instrumentation are set to a constant right before they are tested, etc.
+//! Once the instrumentation is resolved into plain instructions, the following rewrites are applied
+//! to the injected code until nothing changes anymore:
+//! - `local.get`s of a local that was set to a constant earlier in the same straight-line code are
+//!   replaced by that constant,
+//! - constant operands of integer operators are folded,
+//! - an `if` on a constant condition becomes a `block` with only the arm that is taken,
+//! - unreachable code after `br`, `return`, `unreachable`, etc. is removed,
+//! - writes to locals that are never read are removed,
+//! - `local.set x; local.get x` becomes `local.tee x` and `local.tee x; drop` becomes `local.set x`,
+//! - pushing a value that is then immediately dropped is removed.
+//!
+//! A rewrite only applies when all the instructions it changes or removes are injected, so the
+//! original code of the function is left as it is.
+
+use crate::analysis::cfg::is_control_op;
+use crate::analysis::special_ops;
+use crate::ir::types::{Body, Instruction};
+use crate::transform::flatten_injected;
+use std::ops::Range;
+use wasmparser::{BlockType, Operator};
+
+/// Optimizes the code injected into a function body.
+/// The body is flattened first (see [`flatten_instrumentation`]), so all instrumentation ends up as
+/// plain instructions. The original instructions, i.e. the ones that are neither flattened
+/// instrumentation nor recorded with an [`Origin`] when the module was parsed, are not rewritten,
+/// although the constants they store in locals can be propagated into the injected code. Bodies
+/// with unresolved special instrumentation modes (semantic after, block entry/exit/alt) are left
+/// untouched, since it is not known yet where that code will end up.
+///
+/// The instructions are rewritten in place: the ones that are kept (a folded constant takes the
+/// place of its operator) keep their offset, label and origin.
+///
+/// Returns whether the body changed.
+///
+/// [`flatten_instrumentation`]: crate::transform::flatten_instrumentation
+/// [`Origin`]: crate::ir::types::Origin
+pub fn optimize(body: &mut Body) -> bool {
+    if body
+        .instructions
+        .iter()
+        .any(|instr| special_ops(instr).next().is_some())
+    {
+        return false;
+    }
+    let injected = flatten_injected(body);
+
+    let mut code = Code {
+        instrs: &mut body.instructions,
+        injected,
+    };
+    let mut changed_any = false;
+    loop {
+        let mut changed = false;
+        changed |= propagate_local_constants(&mut code);
+        changed |= fold_constants(&mut code);
+        changed |= fold_constant_ifs(&mut code);
+        changed |= remove_unreachable_code(&mut code);
+        changed |= remove_dead_stores(&mut code);
+        changed |= simplify_local_ops(&mut code);
+        changed |= remove_dropped_pushes(&mut code);
+        if !changed {
+            break;
+        }
+        changed_any = true;
+    }
+
+    body.num_instructions = body.instructions.len();
+    changed_any
+}
+
+/// The instructions of a flattened body, with whether each of them is injected.
+struct Code<'a, 'b> {
+    instrs: &'b mut Vec<Instruction<'a>>,
+    injected: Vec<bool>,
+}
+
+impl<'a> Code<'a, '_> {
+    fn len(&self) -> usize {
+        self.instrs.len()
+    }
+
+    fn op(&self, idx: usize) -> &Operator<'a> {
+        &self.instrs[idx].op
+    }
+
+    /// Replaces the operator at `idx`, the instruction keeps its metadata.
+    fn set(&mut self, idx: usize, op: Operator<'a>) {
+        self.instrs[idx].op = op;
+    }
+
+    /// Whether all the instructions in `range` are injected.
+    fn injected(&self, range: Range<usize>) -> bool {
+        self.injected[range].iter().all(|injected| *injected)
+    }
+
+    fn remove(&mut self, range: Range<usize>) {
+        self.instrs.drain(range.clone());
+        self.injected.drain(range);
+    }
+}
+
+fn is_const(op: &Operator) -> bool {
+    matches!(
+        op,
+        Operator::I32Const { .. }
+            | Operator::I64Const { .. }
+            | Operator::F32Const { .. }
+            | Operator::F64Const { .. }
+            | Operator::V128Const { .. }
+    )
+}
+
+/// Operators that only push a value, without any other effect.
+fn is_pure_push(op: &Operator) -> bool {
+    is_const(op)
+        || matches!(
+            op,
+            Operator::LocalGet { .. }
+                | Operator::GlobalGet { .. }
+                | Operator::RefNull { .. }
+                | Operator::RefFunc { .. }
+        )
+}
+
+fn opens_block(op: &Operator) -> bool {
+    matches!(
+        op,
+        Operator::Block { .. }
+            | Operator::Loop { .. }
+            | Operator::If { .. }
+            | Operator::TryTable { .. }
+            | Operator::Try { .. }
+    )
+}
+
+fn closes_block(op: &Operator) -> bool {
+    matches!(op, Operator::End | Operator::Delegate { .. })
+}
+
+/// Replaces injected reads of locals that hold a known constant within straight-line code.
+fn propagate_local_constants(code: &mut Code) -> bool {
+    let mut changed = false;
+    // (local, constant) pairs known at the current point
+    let mut known: Vec<(u32, Operator)> = vec![];
+    for idx in 0..code.len() {
+        match *code.op(idx) {
+            Operator::LocalSet { local_index } | Operator::LocalTee { local_index } => {
+                known.retain(|(local, _)| *local != local_index);
+                if idx > 0 && is_const(code.op(idx - 1)) {
+                    known.push((local_index, code.op(idx - 1).clone()));
+                }
+            }
+            Operator::LocalGet { local_index } => {
+                if let Some((_, constant)) = known.iter().find(|(local, _)| *local == local_index) {
+                    if code.injected(idx..idx + 1) {
+                        code.set(idx, constant.clone());
+                        changed = true;
+                    }
+                }
+            }
+            _ => {
+                if is_control_op(code.op(idx)) {
+                    // control can arrive from elsewhere
+                    known.clear();
+                }
+            }
+        }
+    }
+    changed
+}
+
+/// Folds integer operators with constant operands. The folded constant takes the place of the
+/// operator.
+fn fold_constants(code: &mut Code) -> bool {
+    let mut changed = false;
+    let mut idx = 0;
+    while idx < code.len() {
+        if idx >= 2 && code.injected(idx - 2..idx + 1) {
+            let folded = match (code.op(idx - 2), code.op(idx - 1)) {
+                (Operator::I32Const { value: a }, Operator::I32Const { value: b }) => {
+                    fold_i32_binary(code.op(idx), *a, *b)
+                }
+                (Operator::I64Const { value: a }, Operator::I64Const { value: b }) => {
+                    fold_i64_binary(code.op(idx), *a, *b)
+                }
+                _ => None,
+            };
+            if let Some(folded) = folded {
+                code.remove(idx - 2..idx);
+                code.set(idx - 2, folded);
+                changed = true;
+                idx -= 1;
+                continue;
+            }
+        }
+        if idx >= 1 && code.injected(idx - 1..idx + 1) {
+            let folded = match code.op(idx - 1) {
+                Operator::I32Const { value } => fold_i32_unary(code.op(idx), *value),
+                Operator::I64Const { value } => fold_i64_unary(code.op(idx), *value),
+                _ => None,
+            };
+            if let Some(folded) = folded {
+                code.remove(idx - 1..idx);
+                code.set(idx - 1, folded);
+                changed = true;
+                continue;
+            }
+        }
+        idx += 1;
+    }
+    changed
+}
+
+fn fold_i32_unary<'a>(op: &Operator, a: i32) -> Option<Operator<'a>> {
+    Some(match op {
+        Operator::I32Eqz => Operator::I32Const {
+            value: (a == 0) as i32,
+        },
+        Operator::I32Clz => Operator::I32Const {
+            value: a.leading_zeros() as i32,
+        },
+        Operator::I32Ctz => Operator::I32Const {
+            value: a.trailing_zeros() as i32,
+        },
+        Operator::I32Popcnt => Operator::I32Const {
+            value: a.count_ones() as i32,
+        },
+        Operator::I64ExtendI32S => Operator::I64Const { value: a as i64 },
+        Operator::I64ExtendI32U => Operator::I64Const {
+            value: a as u32 as i64,
+        },
+        _ => return None,
+    })
+}
+
+fn fold_i64_unary<'a>(op: &Operator, a: i64) -> Option<Operator<'a>> {
+    Some(match op {
+        Operator::I64Eqz => Operator::I32Const {
+            value: (a == 0) as i32,
+        },
+        Operator::I64Clz => Operator::I64Const {
+            value: a.leading_zeros() as i64,
+        },
+        Operator::I64Ctz => Operator::I64Const {
+            value: a.trailing_zeros() as i64,
+        },
+        Operator::I64Popcnt => Operator::I64Const {
+            value: a.count_ones() as i64,
+        },
+        Operator::I32WrapI64 => Operator::I32Const { value: a as i32 },
+        _ => return None,
+    })
+}
+
+fn fold_i32_binary<'a>(op: &Operator, a: i32, b: i32) -> Option<Operator<'a>> {
+    let (ua, ub) = (a as u32, b as u32);
+    let value = match op {
+        Operator::I32Add => a.wrapping_add(b),
+        Operator::I32Sub => a.wrapping_sub(b),
+        Operator::I32Mul => a.wrapping_mul(b),
+        // division by zero and overflow trap, leave those alone
+        Operator::I32DivS => a.checked_div(b)?,
+        Operator::I32DivU => ua.checked_div(ub)? as i32,
+        Operator::I32RemS => {
+            if b == 0 {
+                return None;
+            }
+            a.wrapping_rem(b)
+        }
+        Operator::I32RemU => ua.checked_rem(ub)? as i32,
+        Operator::I32And => a & b,
+        Operator::I32Or => a | b,
+        Operator::I32Xor => a ^ b,
+        Operator::I32Shl => a.wrapping_shl(ub),
+        Operator::I32ShrS => a.wrapping_shr(ub),
+        Operator::I32ShrU => ua.wrapping_shr(ub) as i32,
+        Operator::I32Rotl => ua.rotate_left(ub % 32) as i32,
+        Operator::I32Rotr => ua.rotate_right(ub % 32) as i32,
+        Operator::I32Eq => (a == b) as i32,
+        Operator::I32Ne => (a != b) as i32,
+        Operator::I32LtS => (a < b) as i32,
+        Operator::I32LtU => (ua < ub) as i32,
+        Operator::I32GtS => (a > b) as i32,
+        Operator::I32GtU => (ua > ub) as i32,
+        Operator::I32LeS => (a <= b) as i32,
+        Operator::I32LeU => (ua <= ub) as i32,
+        Operator::I32GeS => (a >= b) as i32,
+        Operator::I32GeU => (ua >= ub) as i32,
+        _ => return None,
+    };
+    Some(Operator::I32Const { value })
+}
+
+fn fold_i64_binary<'a>(op: &Operator, a: i64, b: i64) -> Option<Operator<'a>> {
+    let (ua, ub) = (a as u64, b as u64);
+    let value = match op {
+        Operator::I64Add => a.wrapping_add(b),
+        Operator::I64Sub => a.wrapping_sub(b),
+        Operator::I64Mul => a.wrapping_mul(b),
+        // division by zero and overflow trap, leave those alone
+        Operator::I64DivS => a.checked_div(b)?,
+        Operator::I64DivU => ua.checked_div(ub)? as i64,
+        Operator::I64RemS => {
+            if b == 0 {
+                return None;
+            }
+            a.wrapping_rem(b)
+        }
+        Operator::I64RemU => ua.checked_rem(ub)? as i64,
+        Operator::I64And => a & b,
+        Operator::I64Or => a | b,
+        Operator::I64Xor => a ^ b,
+        Operator::I64Shl => a.wrapping_shl(ub as u32),
+        Operator::I64ShrS => a.wrapping_shr(ub as u32),
+        Operator::I64ShrU => ua.wrapping_shr(ub as u32) as i64,
+        Operator::I64Rotl => ua.rotate_left((ub % 64) as u32) as i64,
+        Operator::I64Rotr => ua.rotate_right((ub % 64) as u32) as i64,
+        _ => {
+            let cmp = match op {
+                Operator::I64Eq => a == b,
+                Operator::I64Ne => a != b,
+                Operator::I64LtS => a < b,
+                Operator::I64LtU => ua < ub,
+                Operator::I64GtS => a > b,
+                Operator::I64GtU => ua > ub,
+                Operator::I64LeS => a <= b,
+                Operator::I64LeU => ua <= ub,
+                Operator::I64GeS => a >= b,
+                Operator::I64GeU => ua >= ub,
+                _ => return None,
+            };
+            return Some(Operator::I32Const { value: cmp as i32 });
+        }
+    };
+    Some(Operator::I64Const { value })
+}
+
+/// Finds the `else` (if any) and the `end` that belong to the block opened at `start`.
+fn matching_else_end(code: &Code, start: usize) -> Option<(Option<usize>, usize)> {
+    let mut depth = 0;
+    let mut else_idx = None;
+    for (idx, Instruction { op, .. }) in code.instrs.iter().enumerate().skip(start + 1) {
+        if opens_block(op) {
+            depth += 1;
+        } else if closes_block(op) {
+            if depth == 0 {
+                return Some((else_idx, idx));
+            }
+            depth -= 1;
+        } else if depth == 0 && matches!(op, Operator::Else) {
+            else_idx = Some(idx);
+        }
+    }
+    None
+}
+
+/// Turns an injected `if` on a constant condition into a `block` that only contains the arm that is
+/// taken, when the arm that is not taken is injected as well.
+fn fold_constant_ifs(code: &mut Code) -> bool {
+    let mut changed = false;
+    let mut idx = 1;
+    while idx < code.len() {
+        let (Operator::I32Const { value }, Operator::If { blockty }) =
+            (code.op(idx - 1), code.op(idx))
+        else {
+            idx += 1;
+            continue;
+        };
+        let (value, blockty) = (*value, *blockty);
+        let cond = idx - 1;
+        let Some((else_idx, end_idx)) = matching_else_end(code, idx) else {
+            idx += 1;
+            continue;
+        };
+        // the instructions dropped along with the arm that is not taken
+        let dropped = match (value != 0, else_idx) {
+            // the else arm
+            (true, Some(else_idx)) => else_idx..end_idx,
+            (true, None) => end_idx..end_idx,
+            // the then arm, including the `else`
+            (false, Some(else_idx)) => idx + 1..else_idx + 1,
+            // an `if` without an `else` passes its parameters through
+            (false, None) => idx + 1..end_idx,
+        };
+        if !code.injected(cond..idx + 1) || !code.injected(dropped.clone()) {
+            idx += 1;
+            continue;
+        }
+        if value == 0
+            && else_idx.is_none()
+            && blockty == BlockType::Empty
+            && code.injected(end_idx..end_idx + 1)
+        {
+            code.remove(cond..end_idx + 1);
+        } else {
+            code.remove(dropped);
+            code.set(idx, Operator::Block { blockty });
+            code.remove(cond..idx);
+        }
+        changed = true;
+        idx = cond.max(1);
+    }
+    changed
+}
+
+/// Removes the injected code following an instruction that never falls through, up to the end of
+/// the block.
+fn remove_unreachable_code(code: &mut Code) -> bool {
+    let mut changed = false;
+    let mut idx = 0;
+    while idx < code.len() {
+        let diverges = matches!(
+            code.op(idx),
+            Operator::Br { .. }
+                | Operator::BrTable { .. }
+                | Operator::Return
+                | Operator::ReturnCall { .. }
+                | Operator::ReturnCallIndirect { .. }
+                | Operator::ReturnCallRef { .. }
+                | Operator::Unreachable
+                | Operator::Throw { .. }
+                | Operator::Rethrow { .. }
+                | Operator::ThrowRef
+        );
+        if diverges {
+            let mut depth = 0;
+            let mut last = idx + 1;
+            while last < code.len() {
+                let op = code.op(last);
+                if opens_block(op) {
+                    depth += 1;
+                } else if closes_block(op) {
+                    if depth == 0 {
+                        break;
+                    }
+                    depth -= 1;
+                } else if depth == 0
+                    && matches!(
+                        op,
+                        Operator::Else | Operator::Catch { .. } | Operator::CatchAll
+                    )
+                {
+                    break;
+                }
+                last += 1;
+            }
+            // removing only part of the unreachable code could leave values the block does not expect
+            if last > idx + 1 && code.injected(idx + 1..last) {
+                code.remove(idx + 1..last);
+                changed = true;
+            }
+        }
+        idx += 1;
+    }
+    changed
+}
+
+/// Removes injected writes to locals that are never read.
+fn remove_dead_stores(code: &mut Code) -> bool {
+    let read: Vec<u32> = code
+        .instrs
+        .iter()
+        .filter_map(|instr| match instr.op {
+            Operator::LocalGet { local_index } => Some(local_index),
+            _ => None,
+        })
+        .collect();
+    let mut changed = false;
+    let mut idx = 0;
+    while idx < code.len() {
+        if code.injected(idx..idx + 1) {
+            match *code.op(idx) {
+                Operator::LocalSet { local_index } if !read.contains(&local_index) => {
+                    code.set(idx, Operator::Drop);
+                    changed = true;
+                }
+                Operator::LocalTee { local_index } if !read.contains(&local_index) => {
+                    code.remove(idx..idx + 1);
+                    changed = true;
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        idx += 1;
+    }
+    changed
+}
+
+/// `local.set x; local.get x` => `local.tee x` and `local.tee x; drop` => `local.set x`
+fn simplify_local_ops(code: &mut Code) -> bool {
+    let mut changed = false;
+    let mut idx = 1;
+    while idx < code.len() {
+        if !code.injected(idx - 1..idx + 1) {
+            idx += 1;
+            continue;
+        }
+        match *code.op(idx - 1) {
+            Operator::LocalSet { local_index }
+                if *code.op(idx) == (Operator::LocalGet { local_index }) =>
+            {
+                code.set(idx - 1, Operator::LocalTee { local_index });
+                code.remove(idx..idx + 1);
+                changed = true;
+            }
+            Operator::LocalTee { local_index } if *code.op(idx) == Operator::Drop => {
+                code.set(idx - 1, Operator::LocalSet { local_index });
+                code.remove(idx..idx + 1);
+                changed = true;
+            }
+            _ => idx += 1,
+        }
+    }
+    changed
+}
+
+/// Removes injected values that are pushed only to be dropped right away.
+fn remove_dropped_pushes(code: &mut Code) -> bool {
+    let mut changed = false;
+    let mut idx = 1;
+    while idx < code.len() {
+        if matches!(code.op(idx), Operator::Drop)
+            && is_pure_push(code.op(idx - 1))
+            && code.injected(idx - 1..idx + 1)
+        {
+            code.remove(idx - 1..idx + 1);
+            changed = true;
+            idx = (idx - 1).max(1);
+        } else {
+            idx += 1;
+        }
+    }
+    changed
+}
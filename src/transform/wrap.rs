@@ -0,0 +1,83 @@
+//! Redirects the calls to an imported function through a generated wrapper function.
+//!
+//! The wrapper has the type of the import: it passes its parameters to the import and returns its
+//! results. The hooks of the wrapper are its function-level instrumentation: the `entry` body runs
+//! before the import is called, with the arguments in the parameters of the wrapper, and the `exit`
+//! body runs after it returns (see [`Module::func_exit_with_results`] to observe the results). The
+//! call to the import can also be replaced with `alternate` instrumentation, e.g. to mock the host
+//! function.
+
+use crate::error::Error;
+use crate::ir::function::FunctionBuilder;
+use crate::ir::id::{FunctionID, LocalID};
+use crate::ir::module::module_functions::FuncKind;
+use crate::ir::module::{Module, ReIndexable};
+use crate::Opcode;
+use wasmparser::Operator;
+
+/// Adds a wrapper function for the imported function `import` and redirects the `call`s and
+/// `return_call`s to the import in the instructions of every local function to it. Returns the ID
+/// of the wrapper.
+///
+/// The calls injected as instrumentation still call the import, as do the indirect calls through a
+/// table (or a `ref.func`) and the callers outside of the module when the import is re-exported.
+pub fn wrap_import<'a>(
+    module: &mut Module<'a>,
+    import: FunctionID,
+    name: Option<String>,
+) -> Result<FunctionID, Error> {
+    let invalid = |reason: &str| Error::InvalidImportWrap {
+        func_id: *import,
+        reason: reason.to_string(),
+    };
+    if *import as usize >= module.functions.len() || module.functions.is_deleted(import) {
+        return Err(invalid("the function does not exist"));
+    }
+    if let FuncKind::Local(_) = module.functions.get_kind(import) {
+        return Err(invalid("the function is not imported"));
+    }
+    let (params, results) = match module.types.get(module.functions.get_type_id(import)) {
+        Some(ty) => (ty.params(), ty.results()),
+        None => return Err(invalid("the function has no type")),
+    };
+
+    let mut wrapper = FunctionBuilder::new(&params, &results);
+    for param in 0..params.len() {
+        wrapper.local_get(LocalID(param as u32));
+    }
+    wrapper.call(import);
+    if let Some(name) = name {
+        wrapper.set_name(name);
+    }
+    let wrapper_id = wrapper.finish_module(module);
+
+    redirect_calls(module, import, wrapper_id, &[wrapper_id]);
+    Ok(wrapper_id)
+}
+
+/// Redirects the `call`s and `return_call`s to `from` in the instructions of every local function
+/// but the ones in `skip` to `to`.
+pub(crate) fn redirect_calls(
+    module: &mut Module,
+    from: FunctionID,
+    to: FunctionID,
+    skip: &[FunctionID],
+) {
+    for func_idx in 0..module.functions.len() as u32 {
+        if skip.contains(&FunctionID(func_idx)) {
+            continue;
+        }
+        if let FuncKind::Local(func) = &mut module.functions.get_mut(FunctionID(func_idx)).kind {
+            for instr in func.body.instructions.iter_mut() {
+                match &mut instr.op {
+                    Operator::Call { function_index } | Operator::ReturnCall { function_index }
+                        if *function_index == *from =>
+                    {
+                        *function_index = *to;
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,576 @@
+use orca_wasm::analysis::cfg::ControlFlowGraph;
+use orca_wasm::analysis::counter_placement::CounterPlacement;
+use orca_wasm::analysis::dominators::DominatorTree;
+use orca_wasm::analysis::liveness::Liveness;
+use orca_wasm::analysis::loops::LoopNest;
+use orca_wasm::analysis::reaching_definitions::{DefinitionSite, ReachingDefinitions};
+use orca_wasm::analysis::usage::ModuleUsage;
+use orca_wasm::ir::id::{FunctionID, GlobalID, LocalID, MemoryID};
+use orca_wasm::ir::module::Module;
+use orca_wasm::opcode::Instrumenter;
+use orca_wasm::{DataType, Opcode};
+
+const IF_ELSE: &str = r#"
+(module
+    (func (param i32) (result i32) (local i32 i32)
+        local.get 0
+        if (result i32)
+            i32.const 1
+            local.set 1
+            local.get 1
+        else
+            i32.const 2
+            local.set 1
+            local.get 1
+        end
+        local.set 2
+        local.get 2
+    )
+)
+"#;
+
+const LOOP: &str = r#"
+(module
+    (func (param i32) (local i32)
+        loop
+            local.get 1
+            i32.const 1
+            i32.add
+            local.set 1
+            local.get 0
+            br_if 0
+        end
+    )
+)
+"#;
+
+const NESTED_LOOPS: &str = r#"
+(module
+    (func (param i32) (result i32)
+        block
+            loop
+                local.get 0
+                br_if 1
+                loop
+                    local.get 0
+                    br_if 1
+                    local.get 0
+                    br_table 0 2 1
+                end
+                br 0
+            end
+        end
+        local.get 0
+    )
+)
+"#;
+
+fn parse(wat: &str) -> Vec<u8> {
+    wat::parse_str(wat).expect("couldn't convert the input wat to Wasm")
+}
+
+fn validate(module: &mut Module) {
+    let result = module.encode();
+    if let Err(e) = wasmparser::validate(&result) {
+        panic!(
+            "Instrumented module is invalid: {}\n{}",
+            e,
+            wasmprinter::print_bytes(&result).unwrap()
+        );
+    }
+}
+
+#[test]
+fn test_cfg_if_else() {
+    let buff = parse(IF_ELSE);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let body = &module.functions.unwrap_local(FunctionID(0)).body;
+    let cfg = ControlFlowGraph::new(body);
+
+    // [local.get, if], [then], [else], [end, local.set, local.get], [end], exit
+    assert_eq!(cfg.len(), 6);
+    let (cond, then, els, join) = (
+        cfg.block_of(0),
+        cfg.block_of(2),
+        cfg.block_of(6),
+        cfg.block_of(9),
+    );
+    assert_eq!(cfg.block(cond).succs, vec![then, els]);
+    assert_eq!(cfg.block(then).succs, vec![join]);
+    assert_eq!(cfg.block(els).succs, vec![join]);
+    assert_eq!(cfg.block(join).preds, vec![then, els]);
+    assert_eq!(cfg.block(cfg.block_of(12)).succs, vec![cfg.exit()]);
+}
+
+#[test]
+fn test_cfg_loop() {
+    let buff = parse(LOOP);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let body = &module.functions.unwrap_local(FunctionID(0)).body;
+    let cfg = ControlFlowGraph::new(body);
+
+    let header = cfg.block_of(0);
+    assert_eq!(cfg.block_of(6), header);
+    // the back-edge of the `br_if` and the fallthrough to the end of the loop
+    assert_eq!(cfg.block(header).succs, vec![header, cfg.block_of(7)]);
+}
+
+#[test]
+fn test_liveness() {
+    let buff = parse(IF_ELSE);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let body = &module.functions.unwrap_local(FunctionID(0)).body;
+    let liveness = Liveness::new(body, 1);
+
+    assert_eq!(liveness.live_before(0).iter().collect::<Vec<_>>(), vec![0]);
+    assert!(!liveness.is_live_before(2, LocalID(1)));
+    assert!(liveness.is_live_before(4, LocalID(1)));
+    assert!(!liveness.is_live_after(4, LocalID(1)));
+    assert_eq!(liveness.live_after(10).iter().collect::<Vec<_>>(), vec![2]);
+
+    assert_eq!(
+        liveness.dead_locals_at(6, DataType::I32, body),
+        vec![LocalID(1), LocalID(2)]
+    );
+    // the instruction itself refers to local 1
+    assert_eq!(
+        liveness.dead_locals_at(4, DataType::I32, body),
+        vec![LocalID(2)]
+    );
+    assert!(liveness.dead_locals_at(6, DataType::I64, body).is_empty());
+}
+
+#[test]
+fn test_liveness_loop() {
+    let buff = parse(LOOP);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let body = &module.functions.unwrap_local(FunctionID(0)).body;
+    let liveness = Liveness::new(body, 1);
+
+    // the value of local 1 is carried around the loop
+    assert!(liveness.is_live_after(4, LocalID(1)));
+    assert!(liveness.is_live_before(0, LocalID(1)));
+    assert!(!liveness.is_live_after(7, LocalID(1)));
+}
+
+#[test]
+fn test_liveness_with_instrumentation() {
+    let buff = parse(IF_ELSE);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let mut func = module.functions.get_fn_modifier(FunctionID(0)).unwrap();
+    // straight-line probe: writes local 2 before reading it
+    func.before_at(orca_wasm::Location::Module {
+        func_idx: FunctionID(0),
+        instr_idx: 0,
+    });
+    func.i32_const(7)
+        .local_set(LocalID(2))
+        .local_get(LocalID(2))
+        .drop();
+    // probe that reads local 1 once the `if` is done
+    func.before_at(orca_wasm::Location::Module {
+        func_idx: FunctionID(0),
+        instr_idx: 9,
+    });
+    func.local_get(LocalID(1)).drop();
+
+    let body = &module.functions.unwrap_local(FunctionID(0)).body;
+    let liveness = Liveness::new(body, 1);
+    assert!(!liveness.is_live_before(0, LocalID(2)));
+    assert!(liveness.is_live_after(8, LocalID(1)));
+    assert!(!liveness.is_live_after(9, LocalID(1)));
+}
+
+#[test]
+fn test_reaching_definitions() {
+    let buff = parse(IF_ELSE);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let body = &module.functions.unwrap_local(FunctionID(0)).body;
+    let reaching = ReachingDefinitions::new(body, 1);
+
+    let sites = |idx, local| {
+        reaching
+            .reaching_before(body, idx, LocalID(local))
+            .into_iter()
+            .map(|def| def.site)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(sites(2, 1), vec![DefinitionSite::Entry]);
+    assert_eq!(
+        sites(9, 1),
+        vec![DefinitionSite::Instr(3), DefinitionSite::Instr(7)]
+    );
+    assert_eq!(
+        reaching
+            .unique_reaching_before(body, 11, LocalID(2))
+            .map(|def| def.site),
+        Some(DefinitionSite::Instr(10))
+    );
+    assert_eq!(reaching.unique_reaching_before(body, 9, LocalID(1)), None);
+}
+
+#[test]
+fn test_reaching_definitions_loop() {
+    let buff = parse(LOOP);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let body = &module.functions.unwrap_local(FunctionID(0)).body;
+    let reaching = ReachingDefinitions::new(body, 1);
+
+    let sites = reaching
+        .reaching_before(body, 1, LocalID(1))
+        .into_iter()
+        .map(|def| def.site)
+        .collect::<Vec<_>>();
+    assert_eq!(sites, vec![DefinitionSite::Entry, DefinitionSite::Instr(4)]);
+}
+
+#[test]
+fn test_reaching_definitions_injected() {
+    let buff = parse(IF_ELSE);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let mut func = module.functions.get_fn_modifier(FunctionID(0)).unwrap();
+    func.after_at(orca_wasm::Location::Module {
+        func_idx: FunctionID(0),
+        instr_idx: 3,
+    });
+    func.i32_const(5).local_set(LocalID(1));
+
+    let body = &module.functions.unwrap_local(FunctionID(0)).body;
+    let reaching = ReachingDefinitions::new(body, 1);
+    // the probe overwrites the value the original code stored
+    assert_eq!(
+        reaching
+            .unique_reaching_before(body, 4, LocalID(1))
+            .map(|def| def.site),
+        Some(DefinitionSite::Injected(3))
+    );
+}
+
+#[test]
+fn test_add_temp_local_reuses_dead_locals() {
+    let buff = parse(IF_ELSE);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let mut func = module.functions.get_fn_modifier(FunctionID(0)).unwrap();
+
+    let first = func.add_temp_local(DataType::I32, 6);
+    assert_eq!(first, LocalID(1));
+    func.before_at(orca_wasm::Location::Module {
+        func_idx: FunctionID(0),
+        instr_idx: 6,
+    });
+    func.i32_const(42).local_set(first);
+
+    // the first temporary is now used by the probe
+    let second = func.add_temp_local(DataType::I32, 6);
+    assert_eq!(second, LocalID(2));
+    func.i32_const(43).local_set(second);
+
+    // no more dead i32 locals: a new local is added
+    let temps = func.add_temp_locals(&[DataType::I32, DataType::I64], 6);
+    assert_eq!(temps, vec![LocalID(3), LocalID(4)]);
+
+    validate(&mut module);
+}
+
+#[test]
+fn test_loop_nest() {
+    let buff = parse(NESTED_LOOPS);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let body = &module.functions.unwrap_local(FunctionID(0)).body;
+    let nest = LoopNest::new(body);
+
+    assert_eq!(nest.len(), 2);
+    assert_eq!(nest.roots().collect::<Vec<_>>(), vec![0]);
+    let (outer, inner) = (nest.get(0), nest.get(1));
+    assert_eq!((outer.header, outer.end), (1, 11));
+    assert_eq!((inner.header, inner.end), (4, 9));
+    assert_eq!(inner.parent, Some(0));
+    assert_eq!(outer.children, vec![1]);
+    assert_eq!((outer.depth, inner.depth), (1, 2));
+
+    // `br_if 1` in the inner loop and the `br_table` go back to the outer header
+    assert_eq!(outer.back_edges, vec![6, 8, 10]);
+    assert_eq!(inner.back_edges, vec![8]);
+    // `br_if 1` leaves the outer loop for the block, the `br_table` can leave both
+    assert_eq!(outer.exits, vec![3, 8, 11]);
+    assert_eq!(inner.exits, vec![6, 8, 9]);
+
+    assert_eq!(nest.depth(0), 0);
+    assert_eq!(nest.depth(2), 1);
+    assert_eq!(nest.depth(7), 2);
+    assert_eq!(nest.loop_with_header(4), Some(1));
+    assert_eq!(nest.loop_with_header(5), None);
+    assert!(nest.is_back_edge(10));
+    assert!(!nest.is_exit(10));
+    assert_eq!(nest.enclosing_loops(7).collect::<Vec<_>>(), vec![1, 0]);
+}
+
+#[test]
+fn test_dominators() {
+    let buff = parse(IF_ELSE);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let body = &module.functions.unwrap_local(FunctionID(0)).body;
+    let cfg = ControlFlowGraph::new(body);
+    let (cond, then, els, join) = (
+        cfg.block_of(0),
+        cfg.block_of(2),
+        cfg.block_of(6),
+        cfg.block_of(9),
+    );
+
+    let dom = DominatorTree::dominators(&cfg);
+    assert_eq!(dom.root(), cfg.entry());
+    assert_eq!(dom.idom(then), Some(cond));
+    assert_eq!(dom.idom(join), Some(cond));
+    assert_eq!(dom.children(cond), &[then, els, join]);
+    assert!(dom.dominates(cond, join));
+    assert!(!dom.dominates(then, join));
+    assert!(!dom.strictly_dominates(join, join));
+
+    let post_dom = DominatorTree::post_dominators(&cfg);
+    assert_eq!(post_dom.root(), cfg.exit());
+    assert_eq!(post_dom.idom(then), Some(join));
+    assert_eq!(post_dom.idom(cond), Some(join));
+    assert!(post_dom.dominates(join, cond));
+    assert!(!post_dom.dominates(then, cond));
+}
+
+#[test]
+fn test_dominators_loop() {
+    let buff = parse(LOOP);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let body = &module.functions.unwrap_local(FunctionID(0)).body;
+    let cfg = ControlFlowGraph::new(body);
+    let (header, after) = (cfg.block_of(0), cfg.block_of(7));
+
+    let dom = DominatorTree::dominators(&cfg);
+    assert!(dom.dominates(header, after));
+    let post_dom = DominatorTree::post_dominators(&cfg);
+    assert!(post_dom.dominates(after, header));
+}
+
+#[test]
+fn test_counter_placement() {
+    let buff = parse(IF_ELSE);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let body = &module.functions.unwrap_local(FunctionID(0)).body;
+    let cfg = ControlFlowGraph::new(body);
+    let (cond, then, els, join, ret) = (
+        cfg.block_of(0),
+        cfg.block_of(2),
+        cfg.block_of(6),
+        cfg.block_of(9),
+        cfg.block_of(12),
+    );
+
+    // the condition, the then arm and the code after the `if` are solved from the else arm and
+    // the number of invocations
+    let placement = CounterPlacement::with_cfg(body, &cfg);
+    assert_eq!(placement.probes, vec![els, ret]);
+    assert_eq!(placement.count_of(then), &[(0, -1), (1, 1)]);
+
+    let counts = placement.infer_counts(&[2, 5]);
+    assert_eq!(counts[cond], 5);
+    assert_eq!(counts[then], 3);
+    assert_eq!(counts[els], 2);
+    assert_eq!(counts[join], 5);
+    assert_eq!(counts[cfg.exit()], 5);
+
+    let covered = placement.infer_coverage(&[0, 1]);
+    assert!(covered.contains(then));
+    assert!(covered.contains(cond));
+    assert!(covered.contains(join));
+    assert!(!covered.contains(els));
+}
+
+/// The number of executions of the blocks starting at `starts` in the first function of `wat`,
+/// from the values of its probes given by `probe_value` (from the first instruction of the blocks).
+fn placement_counts<F: Fn(usize) -> u64>(wat: &str, starts: &[usize], probe_value: F) -> Vec<u64> {
+    let buff = parse(wat);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let body = &module.functions.unwrap_local(FunctionID(0)).body;
+    let cfg = ControlFlowGraph::new(body);
+    let placement = CounterPlacement::with_cfg(body, &cfg);
+    let probes: Vec<u64> = placement
+        .probes
+        .iter()
+        .map(|probe| probe_value(cfg.block(*probe).start))
+        .collect();
+    let counts = placement.infer_counts(&probes);
+    starts
+        .iter()
+        .map(|start| counts[cfg.block_of(*start)])
+        .collect()
+}
+
+#[test]
+fn test_counter_placement_if() {
+    let wat = r#"
+    (module
+        (func (param i32) (result i32)
+            local.get 0
+            if
+                i32.const 5
+                local.set 0
+            end
+            local.get 0
+        )
+    )
+    "#;
+    // [local.get, if], [i32.const, local.set], [end, local.get], [end]
+    let starts = [0, 2, 4, 6];
+    // a single call that does not take the branch
+    let not_taken = |start| [1, 0, 1, 1][starts.iter().position(|s| *s == start).unwrap()];
+    assert_eq!(placement_counts(wat, &starts, not_taken), vec![1, 0, 1, 1]);
+    // two calls, one taking it
+    let taken = |start| [2, 1, 2, 2][starts.iter().position(|s| *s == start).unwrap()];
+    assert_eq!(placement_counts(wat, &starts, taken), vec![2, 1, 2, 2]);
+}
+
+#[test]
+fn test_counter_placement_br_if() {
+    let wat = r#"
+    (module
+        (func (param i32) (result i32)
+            block
+                local.get 0
+                br_if 0
+                i32.const 5
+                local.set 0
+            end
+            local.get 0
+            br_if 0
+            nop
+        )
+    )
+    "#;
+    // [block, local.get, br_if], [i32.const, local.set], [end, local.get, br_if], [nop], [end]
+    let starts = [0, 3, 5, 8, 9];
+    let values =
+        |counts: [u64; 5]| move |start| counts[starts.iter().position(|s| *s == start).unwrap()];
+    // a single call that skips the inner code and leaves the function through its label: the
+    // final `end` is not reached
+    let run = [1, 0, 1, 0, 0];
+    assert_eq!(placement_counts(wat, &starts, values(run)), run.to_vec());
+    // a single call that takes none of the branches
+    let run = [1, 1, 1, 1, 1];
+    assert_eq!(placement_counts(wat, &starts, values(run)), run.to_vec());
+    // three calls, the last one taking both branches
+    let run = [3, 2, 3, 2, 2];
+    assert_eq!(placement_counts(wat, &starts, values(run)), run.to_vec());
+}
+
+#[test]
+fn test_counter_placement_loop() {
+    // [loop .. br_if], [end], [end]
+    let starts = [0, 7, 8];
+    let values =
+        |counts: [u64; 3]| move |start| counts[starts.iter().position(|s| *s == start).unwrap()];
+    // a single iteration, not branching back
+    let run = [1, 1, 1];
+    assert_eq!(placement_counts(LOOP, &starts, values(run)), run.to_vec());
+    // two calls iterating 4 and 1 times
+    let run = [5, 2, 2];
+    assert_eq!(placement_counts(LOOP, &starts, values(run)), run.to_vec());
+
+    // [block], [loop, local.get, br_if], [loop, local.get, br_if], [local.get, br_table],
+    // [end(block), local.get], [end]
+    let starts = [0, 1, 4, 7, 12, 14];
+    let values =
+        |counts: [u64; 6]| move |start| counts[starts.iter().position(|s| *s == start).unwrap()];
+    // leaving the outer loop right away
+    let run = [1, 1, 0, 0, 1, 1];
+    assert_eq!(
+        placement_counts(NESTED_LOOPS, &starts, values(run)),
+        run.to_vec()
+    );
+    // branching back to the outer loop from the inner one
+    let run = [1, 2, 1, 0, 1, 1];
+    assert_eq!(
+        placement_counts(NESTED_LOOPS, &starts, values(run)),
+        run.to_vec()
+    );
+    // through the `br_table`, to the inner loop then to the outer one
+    let run = [1, 2, 2, 2, 1, 1];
+    assert_eq!(
+        placement_counts(NESTED_LOOPS, &starts, values(run)),
+        run.to_vec()
+    );
+}
+
+#[test]
+fn test_usage() {
+    let buff = parse(
+        r#"
+        (module
+            (import "env" "log" (func $log (param i32)))
+            (memory 1)
+            (global $a (mut i32) (i32.const 0))
+            (global $b (mut i32) (i32.const 0))
+            (func $pure (param i32) (result i32)
+                local.get 0
+                i32.load
+            )
+            (func $impure (param i32)
+                local.get 0
+                call $pure
+                global.set $b
+                i32.const 1
+                memory.grow
+                call $log
+            )
+            (func $counter
+                global.get $a
+                i32.const 1
+                i32.add
+                global.set $a
+            )
+        )
+        "#,
+    );
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    // a probe that stores to memory
+    let mut func = module.functions.get_fn_modifier(FunctionID(3)).unwrap();
+    func.before_at(orca_wasm::Location::Module {
+        func_idx: FunctionID(3),
+        instr_idx: 0,
+    });
+    func.i32_const(0)
+        .i32_const(1)
+        .i32_store(wasmparser::MemArg {
+            align: 2,
+            max_align: 2,
+            offset: 0,
+            memory: 0,
+        });
+
+    let usage = ModuleUsage::new(&module);
+    assert!(usage.get(FunctionID(0)).is_none());
+
+    let pure = usage.get(FunctionID(1)).unwrap();
+    assert_eq!(
+        pure.original.memories_loaded.iter().collect::<Vec<_>>(),
+        vec![&MemoryID(0)]
+    );
+    assert!(!pure.original.has_side_effects());
+
+    let impure = usage.get(FunctionID(2)).unwrap();
+    assert_eq!(
+        impure.original.globals_written.iter().collect::<Vec<_>>(),
+        vec![&GlobalID(1)]
+    );
+    assert!(impure.original.memories_grown.contains(&MemoryID(0)));
+    assert!(impure.original.calls_imports);
+    assert!(impure.original.memories_loaded.is_empty());
+    // the load of the callee
+    assert!(usage
+        .transitive(FunctionID(2))
+        .memories_loaded
+        .contains(&MemoryID(0)));
+
+    let counter = usage.get(FunctionID(3)).unwrap();
+    assert!(counter.original.globals_read.contains(&GlobalID(0)));
+    assert!(counter.original.memories_stored.is_empty());
+    assert!(counter.injected.memories_stored.contains(&MemoryID(0)));
+    assert!(counter.injected.has_side_effects());
+}
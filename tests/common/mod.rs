@@ -26,11 +26,22 @@ pub fn check_instrumentation_encoding(orca_wat: &String, file: &str) -> Result<(
     Ok(())
 }
 
+/// Checks the `annotated` text printed for a module or component with pending instrumentation
+/// against the text of its encoding.
+pub fn check_pending_instrumentation(
+    orca_wat: &String,
+    annotated: &str,
+) -> Result<(), std::io::Error> {
+    let wat_with_instr = get_wat_with_inline_instrumentation(&mut annotated.as_bytes())?;
+    assert_eq!(*orca_wat, wat_with_instr);
+    Ok(())
+}
+
 const INSERT_PREFIX_PATTERN: &str = ";; << ";
 const REPLACE_PREFIX_PATTERN: &str = ";; < ";
 const REMOVE_PREFIX_PATTERN: &str = ";; rm";
 fn get_wat_with_inline_instrumentation(
-    reader: &mut BufReader<File>,
+    reader: &mut impl BufRead,
 ) -> Result<String, std::io::Error> {
     let mut wat_with_instr = String::new();
 
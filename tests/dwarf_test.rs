@@ -0,0 +1,526 @@
+use gimli::write::{
+    Address, AttributeValue, DwarfUnit, EndianVec, Expression, LineProgram, LineString,
+    LocationList, Range, RangeList, Sections,
+};
+use orca_wasm::ir::dwarf::TOMBSTONE;
+use orca_wasm::ir::id::{FunctionID, LocalID};
+use orca_wasm::ir::source_map::{Mapping, SourceMap, SOURCE_MAPPING_URL};
+use orca_wasm::opcode::Instrumenter;
+use orca_wasm::{Location, Module, Opcode};
+
+const FUNCS: &str = r#"
+(module
+    (func $add (export "add") (param i32 i32) (result i32)
+        local.get 0
+        local.get 1
+        i32.add
+    )
+    (func $double (export "double") (param i32) (result i32)
+        local.get 0
+        local.get 0
+        call $add
+    )
+    (func $unused (param i32) (result i32)
+        local.get 0
+        i32.eqz
+    )
+)
+"#;
+
+/// The code of a binary, as offsets in its code section.
+struct Code {
+    /// The offset of the code section in the binary.
+    start: u64,
+    /// The range of each function body, without its size field.
+    bodies: Vec<std::ops::Range<u64>>,
+    /// The offsets of the instructions of each function.
+    instrs: Vec<Vec<u64>>,
+}
+
+fn code_of(wasm: &[u8]) -> Code {
+    let mut code = Code {
+        start: 0,
+        bodies: vec![],
+        instrs: vec![],
+    };
+    let mut start = 0;
+    for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+        match payload.unwrap() {
+            wasmparser::Payload::CodeSectionStart { range, .. } => {
+                start = range.start;
+                code.start = start as u64;
+            }
+            wasmparser::Payload::CodeSectionEntry(body) => {
+                let range = body.range();
+                code.bodies
+                    .push((range.start - start) as u64..(range.end - start) as u64);
+                code.instrs.push(
+                    body.get_operators_reader()
+                        .unwrap()
+                        .into_iter_with_offsets()
+                        .map(|op| (op.unwrap().1 - start) as u64)
+                        .collect(),
+                );
+            }
+            _ => {}
+        }
+    }
+    code
+}
+
+/// Appends a custom section to the binary `wasm`.
+fn add_custom_section(wasm: &mut Vec<u8>, name: &str, data: &[u8]) {
+    fn leb(mut value: usize, out: &mut Vec<u8>) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(byte);
+                return;
+            }
+            out.push(byte | 0x80);
+        }
+    }
+    let mut content = vec![];
+    leb(name.len(), &mut content);
+    content.extend_from_slice(name.as_bytes());
+    content.extend_from_slice(data);
+    wasm.push(0);
+    leb(content.len(), wasm);
+    wasm.extend(content);
+}
+
+/// Adds DWARF describing the functions of `wasm`: a compilation unit covering them, a subprogram
+/// per function, the line `10 * func + instr + 1` for each instruction, and a variable of the first
+/// function located in the code of its second and third instructions.
+fn add_dwarf(wasm: &mut Vec<u8>) {
+    let code = code_of(wasm);
+    let encoding = gimli::Encoding {
+        format: gimli::Format::Dwarf32,
+        version: 4,
+        address_size: 4,
+    };
+    let mut dwarf = DwarfUnit::new(encoding);
+    let mut program = LineProgram::new(
+        encoding,
+        gimli::LineEncoding::default(),
+        LineString::String(b"/src".to_vec()),
+        LineString::String(b"funcs.c".to_vec()),
+        None,
+    );
+    let dir = program.default_directory();
+    let file = program.add_file(LineString::String(b"funcs.c".to_vec()), dir, None);
+    for (func, (body, instrs)) in code.bodies.iter().zip(code.instrs.iter()).enumerate() {
+        program.begin_sequence(Some(Address::Constant(body.start)));
+        for (idx, instr) in instrs.iter().enumerate() {
+            program.row().address_offset = instr - body.start;
+            program.row().file = file;
+            program.row().line = 10 * func as u64 + idx as u64 + 1;
+            program.generate_row();
+        }
+        program.end_sequence(body.end - body.start);
+    }
+    dwarf.unit.line_program = program;
+
+    let ranges = RangeList(
+        code.bodies
+            .iter()
+            .map(|body| Range::StartEnd {
+                begin: Address::Constant(body.start),
+                end: Address::Constant(body.end),
+            })
+            .collect(),
+    );
+    let ranges = dwarf.unit.ranges.add(ranges);
+    let root = dwarf.unit.root();
+    let cu = dwarf.unit.get_mut(root);
+    cu.set(
+        gimli::DW_AT_low_pc,
+        AttributeValue::Address(Address::Constant(0)),
+    );
+    cu.set(gimli::DW_AT_ranges, AttributeValue::RangeListRef(ranges));
+    cu.set(
+        gimli::DW_AT_comp_dir,
+        AttributeValue::String(b"/src".to_vec()),
+    );
+    cu.set(gimli::DW_AT_stmt_list, AttributeValue::LineProgramRef);
+    for body in code.bodies.iter() {
+        let func = dwarf.unit.add(root, gimli::DW_TAG_subprogram);
+        let func = dwarf.unit.get_mut(func);
+        func.set(
+            gimli::DW_AT_low_pc,
+            AttributeValue::Address(Address::Constant(body.start)),
+        );
+        func.set(
+            gimli::DW_AT_high_pc,
+            AttributeValue::Udata(body.end - body.start),
+        );
+    }
+
+    let location = LocationList(vec![gimli::write::Location::StartEnd {
+        begin: Address::Constant(code.instrs[0][1]),
+        end: Address::Constant(code.instrs[0][3]),
+        data: Expression::raw(vec![gimli::DW_OP_fbreg.0, 0]),
+    }]);
+    let location = dwarf.unit.locations.add(location);
+    let first = dwarf.unit.get(root).children().next().copied().unwrap();
+    let var = dwarf.unit.add(first, gimli::DW_TAG_variable);
+    dwarf.unit.get_mut(var).set(
+        gimli::DW_AT_location,
+        AttributeValue::LocationListRef(location),
+    );
+
+    let mut sections = Sections::new(EndianVec::new(gimli::LittleEndian));
+    dwarf.write(&mut sections).unwrap();
+    sections
+        .for_each(|id, data| {
+            if !data.slice().is_empty() {
+                add_custom_section(wasm, id.name(), data.slice());
+            }
+            Ok::<(), gimli::write::Error>(())
+        })
+        .unwrap();
+}
+
+#[test]
+fn test_parse_debug_info() {
+    let mut buff = wat::parse_str(FUNCS).unwrap();
+    add_dwarf(&mut buff);
+    let module = Module::parse(&buff, false).expect("Unable to parse");
+    assert!(!module.debug.is_empty());
+    assert!(module
+        .custom_sections
+        .iter()
+        .all(|section| !section.name.starts_with(".debug_")));
+    let mut units = module.debug.dwarf.units();
+    assert!(units.next().unwrap().is_some());
+
+    // without instrumentation, the debug information is the same
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let result = module.encode();
+    assert_eq!(code_of(&result).bodies, code_of(&buff).bodies);
+    let encoded = Module::parse(&result, false).expect("Unable to parse");
+    let encoded_sections: Vec<&str> = encoded
+        .debug
+        .sections()
+        .iter()
+        .map(|(name, _)| *name)
+        .collect();
+    assert!(encoded_sections.contains(&".debug_info"));
+    assert!(encoded_sections.contains(&".debug_line"));
+}
+
+#[test]
+fn test_rewrite_debug_info() {
+    let mut buff = wat::parse_str(FUNCS).unwrap();
+    add_dwarf(&mut buff);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let mut func = module.functions.get_fn_modifier(FunctionID(0)).unwrap();
+    for instr_idx in 0..3 {
+        func.before_at(Location::Module {
+            func_idx: FunctionID(0),
+            instr_idx,
+        });
+        func.i32_const(1000).drop();
+    }
+    module.delete_func(FunctionID(2));
+    let result = module.encode();
+    wasmparser::validate(&result).unwrap();
+
+    // each original instruction of `add` follows the injected `i32.const` and `drop`
+    let code = code_of(&result);
+    let add: Vec<u64> = [2, 5, 8, 9]
+        .iter()
+        .map(|idx| code.instrs[0][*idx])
+        .collect();
+    let double = code.instrs[1].clone();
+
+    let module = Module::parse(&result, false).expect("Unable to parse");
+    let dwarf = &module.debug.dwarf;
+    let header = dwarf.units().next().unwrap().unwrap();
+    let unit = dwarf.unit(header).unwrap();
+
+    // the rows of the removed function are gone
+    let mut rows = unit.line_program.clone().unwrap().rows();
+    let mut lines = vec![];
+    while let Some((_, row)) = rows.next_row().unwrap() {
+        let line = row.line().map(|line| line.get());
+        lines.push((row.address(), if row.end_sequence() { None } else { line }));
+    }
+    let mut expected: Vec<(u64, Option<u64>)> = add
+        .iter()
+        .enumerate()
+        .map(|(idx, address)| (*address, Some(idx as u64 + 1)))
+        .collect();
+    expected.push((code.bodies[0].end, None));
+    expected.extend(
+        double
+            .iter()
+            .enumerate()
+            .map(|(idx, address)| (*address, Some(11 + idx as u64))),
+    );
+    expected.push((code.bodies[1].end, None));
+    assert_eq!(lines, expected);
+
+    // the compilation unit covers the encoded functions
+    let mut ranges = dwarf.unit_ranges(&unit).unwrap();
+    let mut covered = vec![];
+    while let Some(range) = ranges.next().unwrap() {
+        covered.push(range.begin..range.end);
+    }
+    assert_eq!(covered, code.bodies);
+
+    let mut entries = unit.entries();
+    let mut funcs = vec![];
+    let mut locations = vec![];
+    while let Some((_, entry)) = entries.next_dfs().unwrap() {
+        if entry.tag() == gimli::DW_TAG_subprogram {
+            let low = entry.attr_value(gimli::DW_AT_low_pc).unwrap().unwrap();
+            let low = dwarf.attr_address(&unit, low).unwrap().unwrap();
+            let len = entry
+                .attr_value(gimli::DW_AT_high_pc)
+                .unwrap()
+                .unwrap()
+                .udata_value()
+                .unwrap();
+            funcs.push((low, len));
+        }
+        if entry.tag() == gimli::DW_TAG_variable {
+            let value = entry.attr_value(gimli::DW_AT_location).unwrap().unwrap();
+            let offset = dwarf.attr_locations_offset(&unit, value).unwrap().unwrap();
+            let mut list = dwarf.locations(&unit, offset).unwrap();
+            while let Some(location) = list.next().unwrap() {
+                locations.push((
+                    location.range.begin,
+                    location.range.end,
+                    location.data.0.to_vec(),
+                ));
+            }
+        }
+    }
+    let len = |body: &std::ops::Range<u64>| body.end - body.start;
+    assert_eq!(
+        funcs,
+        vec![
+            (code.bodies[0].start, len(&code.bodies[0])),
+            (code.bodies[1].start, len(&code.bodies[1])),
+            (TOMBSTONE, 0),
+        ]
+    );
+    assert_eq!(
+        locations,
+        vec![(add[1], add[3], vec![gimli::DW_OP_fbreg.0, 0])]
+    );
+}
+
+#[test]
+fn test_source_location() {
+    let mut buff = wat::parse_str(FUNCS).unwrap();
+    add_dwarf(&mut buff);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let at = |func_idx, instr_idx| Location::Module {
+        func_idx: FunctionID(func_idx),
+        instr_idx,
+    };
+    assert_eq!(
+        module.source_location(at(0, 2)),
+        Some(("/src/funcs.c", 3, 0))
+    );
+    assert_eq!(
+        module.source_location(at(1, 0)),
+        Some(("/src/funcs.c", 11, 0))
+    );
+    assert_eq!(module.source_location(at(1, 10)), None);
+    assert_eq!(module.source_location(at(5, 0)), None);
+
+    assert_eq!(module.locations_of("funcs.c", 12), vec![at(1, 1)]);
+    assert_eq!(module.locations_of("/src/funcs.c", 22), vec![at(2, 1)]);
+    assert!(module.locations_of("unc.c", 12).is_empty());
+    assert!(module.locations_of("funcs.c", 50).is_empty());
+
+    // injected instructions have no source location, and removed functions no longer match
+    let mut func = module.functions.get_fn_modifier(FunctionID(0)).unwrap();
+    func.before_at(at(0, 0));
+    func.i32_const(1000).drop();
+    module.delete_func(FunctionID(2));
+    assert!(module.locations_of("funcs.c", 22).is_empty());
+    let result = module.encode();
+    let module = Module::parse(&result, false).expect("Unable to parse");
+    assert_eq!(module.source_location(at(0, 0)), None);
+    assert_eq!(
+        module.source_location(at(0, 2)),
+        Some(("/src/funcs.c", 1, 0))
+    );
+    assert_eq!(module.locations_of("funcs.c", 2), vec![at(0, 3)]);
+}
+
+/// Decodes the `mappings` of a source map.
+fn decode_mappings(mappings: &str) -> Vec<Mapping> {
+    const BASE64: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut decoded = vec![];
+    let mut prev = [0i64; 4];
+    for segment in mappings.split(',') {
+        let mut fields = vec![];
+        let (mut value, mut shift) = (0i64, 0);
+        for c in segment.chars() {
+            let digit = BASE64.find(c).unwrap() as i64;
+            value |= (digit & 0x1f) << shift;
+            shift += 5;
+            if digit & 0x20 == 0 {
+                fields.push(if value & 1 == 1 {
+                    -(value >> 1)
+                } else {
+                    value >> 1
+                });
+                (value, shift) = (0, 0);
+            }
+        }
+        for (field, delta) in fields.iter().enumerate() {
+            prev[field] += delta;
+        }
+        let original =
+            (fields.len() == 4).then(|| (prev[1] as u32, prev[2] as u32, prev[3] as u32));
+        decoded.push(Mapping {
+            offset: prev[0] as u32,
+            original,
+        });
+    }
+    decoded
+}
+
+/// Checks that the JSON of `map` describes its mappings.
+fn check_json(map: &SourceMap) {
+    let json: serde_json::Value = serde_json::from_str(&map.to_json()).unwrap();
+    assert_eq!(json["version"], 3);
+    assert_eq!(json["sources"], serde_json::json!(map.sources));
+    assert_eq!(
+        decode_mappings(json["mappings"].as_str().unwrap()),
+        map.mappings
+    );
+}
+
+#[test]
+fn test_source_map_to_binary() {
+    let buff = wat::parse_str(FUNCS).unwrap();
+    let input = code_of(&buff);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let mut func = module.functions.get_fn_modifier(FunctionID(1)).unwrap();
+    func.before_at(Location::Module {
+        func_idx: FunctionID(1),
+        instr_idx: 2,
+    });
+    func.i32_const(1000).drop();
+    let (result, map) = module.encode_with_source_map("funcs.wasm.map", "funcs.wasm");
+    wasmparser::validate(&result).unwrap();
+
+    let module = Module::parse(&result, false).expect("Unable to parse");
+    let id = module
+        .custom_sections
+        .get_id(SOURCE_MAPPING_URL.to_string())
+        .unwrap();
+    assert_eq!(
+        module.custom_sections.get_by_id(id).data,
+        b"\x0efuncs.wasm.map"
+    );
+    assert_eq!(map.sources, vec!["funcs.wasm".to_string()]);
+
+    // each instruction maps to its offset in the parsed binary, and the injected code to nothing
+    let output = code_of(&result);
+    let at =
+        |code: &Code, func: usize, instr: usize| (code.start + code.instrs[func][instr]) as u32;
+    for func in 0..3 {
+        let encoded: Vec<usize> = match func {
+            1 => vec![0, 1, 4, 5],
+            _ => (0..input.instrs[func].len()).collect(),
+        };
+        for (instr, encoded) in encoded.into_iter().enumerate() {
+            assert_eq!(
+                map.lookup(at(&output, func, encoded)),
+                Some(("funcs.wasm", 0, at(&input, func, instr)))
+            );
+        }
+    }
+    assert_eq!(map.lookup(at(&output, 1, 2)), None);
+    assert_eq!(map.lookup(at(&output, 1, 3)), None);
+    check_json(&map);
+
+    // the section of the parsed binary is replaced
+    let mut module = Module::parse(&result, false).expect("Unable to parse");
+    let (result, _) = module.encode_with_source_map("other.map", "funcs.wasm");
+    let module = Module::parse(&result, false).expect("Unable to parse");
+    let urls: Vec<&[u8]> = module
+        .custom_sections
+        .iter()
+        .filter(|section| section.name == SOURCE_MAPPING_URL)
+        .map(|section| section.data)
+        .collect();
+    assert_eq!(urls, vec![b"\x09other.map"]);
+}
+
+#[test]
+fn test_source_map_optimized() {
+    let buff = wat::parse_str(FUNCS).unwrap();
+    let input = code_of(&buff);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let mut func = module.functions.get_fn_modifier(FunctionID(1)).unwrap();
+    func.before_at(Location::Module {
+        func_idx: FunctionID(1),
+        instr_idx: 2,
+    });
+    func.i32_const(1)
+        .i32_const(2)
+        .i32_add()
+        .local_set(LocalID(0));
+    module.set_optimize_instrumentation(true);
+    let (result, map) = module.encode_with_source_map("funcs.wasm.map", "funcs.wasm");
+    wasmparser::validate(&result).unwrap();
+
+    // the injected code is folded to `i32.const 3; local.set 0`, the original code is still mapped
+    let output = code_of(&result);
+    let at =
+        |code: &Code, func: usize, instr: usize| (code.start + code.instrs[func][instr]) as u32;
+    for (instr, encoded) in [0, 1, 4, 5].into_iter().enumerate() {
+        assert_eq!(
+            map.lookup(at(&output, 1, encoded)),
+            Some(("funcs.wasm", 0, at(&input, 1, instr)))
+        );
+    }
+    assert_eq!(map.lookup(at(&output, 1, 2)), None);
+    assert_eq!(map.lookup(at(&output, 1, 3)), None);
+    check_json(&map);
+}
+
+#[test]
+fn test_source_map_to_source() {
+    let mut buff = wat::parse_str(FUNCS).unwrap();
+    add_dwarf(&mut buff);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let mut func = module.functions.get_fn_modifier(FunctionID(0)).unwrap();
+    func.before_at(Location::Module {
+        func_idx: FunctionID(0),
+        instr_idx: 0,
+    });
+    func.i32_const(1000).drop();
+    module.delete_func(FunctionID(2));
+    let (result, map) = module.encode_with_source_map("funcs.wasm.map", "funcs.wasm");
+    wasmparser::validate(&result).unwrap();
+    assert_eq!(map.sources, vec!["/src/funcs.c".to_string()]);
+
+    let output = code_of(&result);
+    let at = |func: usize, instr: usize| (output.start + output.instrs[func][instr]) as u32;
+    assert_eq!(map.lookup(at(0, 0)), None);
+    assert_eq!(map.lookup(at(0, 1)), None);
+    for instr in 0..4 {
+        assert_eq!(
+            map.lookup(at(0, instr + 2)),
+            Some(("/src/funcs.c", instr as u32, 0))
+        );
+    }
+    for instr in 0..4 {
+        assert_eq!(
+            map.lookup(at(1, instr)),
+            Some(("/src/funcs.c", 10 + instr as u32, 0))
+        );
+    }
+    check_json(&map);
+}
@@ -1,6 +1,10 @@
+#![allow(clippy::vec_init_then_push)]
+
 use log::{error, trace};
-use orca_wasm::ir::id::{FunctionID, TypeID};
-use orca_wasm::ir::types::InstrumentationMode;
+use orca_wasm::ir::arena::WatArena;
+use orca_wasm::ir::function::FunctionBuilder;
+use orca_wasm::ir::id::{FunctionID, GlobalID, LocalID, TypeID};
+use orca_wasm::ir::types::{InstrumentationMode, INJECTED_SECTION};
 use orca_wasm::iterator::component_iterator::ComponentIterator;
 use orca_wasm::iterator::iterator_trait::{IteratingInstrumenter, Iterator};
 use orca_wasm::iterator::module_iterator::ModuleIterator;
@@ -12,7 +16,7 @@ use std::mem::discriminant;
 use wasmparser::Operator;
 
 mod common;
-use crate::common::check_instrumentation_encoding;
+use crate::common::{check_instrumentation_encoding, check_pending_instrumentation};
 
 #[test]
 fn no_injection() {
@@ -1201,6 +1205,55 @@ fn test_fn_exit_two_funcs() {
     }
 }
 
+#[test]
+fn test_fn_exit_observe_results() {
+    let file = "tests/test_inputs/instr_testing/modules/fn_exit/observe_results.wat";
+    let buff = wat::parse_file(file).expect("couldn't convert the input wat to Wasm");
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let mut mod_it = ModuleIterator::new(&mut module, &vec![]);
+
+    // record the results of the function in globals
+    let results = mod_it.func_exit_with_results();
+    assert_eq!(results, vec![LocalID(1), LocalID(2)]);
+    mod_it
+        .local_get(results[0])
+        .global_set(GlobalID(0))
+        .local_get(results[1])
+        .global_set(GlobalID(1));
+    // asking again reuses the same locals
+    assert_eq!(mod_it.func_exit_with_results(), results);
+    mod_it.finish_instr();
+
+    let result = module.encode();
+    if let Err(e) = wasmparser::validate(&result) {
+        panic!("Instrumented module is invalid: {}", e);
+    }
+    let out = wasmprinter::print_bytes(result).expect("couldn't translate wasm to wat");
+    check_instrumentation_encoding(&out, file).expect("couldn't read the expected encoding");
+}
+
+#[test]
+fn test_fn_exit_catch_exceptions() {
+    let file = "tests/test_inputs/instr_testing/modules/fn_exit/catch_exceptions.wat";
+    let buff = wat::parse_file(file).expect("couldn't convert the input wat to Wasm");
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    module.set_exit_on_exception(true);
+    let mut mod_it = ModuleIterator::new(&mut module, &vec![]);
+
+    let mut fn_exit_body = vec![];
+    fn_exit_body.push(Operator::I32Const { value: 1 });
+    fn_exit_body.push(Operator::Drop);
+
+    inject_function_exit(&mut mod_it, fn_exit_body);
+
+    let result = module.encode();
+    if let Err(e) = wasmparser::validate(&result) {
+        panic!("Instrumented module is invalid: {}", e);
+    }
+    let out = wasmprinter::print_bytes(result).expect("couldn't translate wasm to wat");
+    check_instrumentation_encoding(&out, file).expect("couldn't read the expected encoding");
+}
+
 // ==== SEMANTIC AFTER ====
 #[test]
 fn test_semantic_after_complex_mult_nested_diff_opcodes() {
@@ -1390,6 +1443,46 @@ fn test_semantic_after_medium_1br_table() {
     }
 }
 
+#[test]
+fn test_semantic_after_typed_results() {
+    let file = "tests/test_inputs/instr_testing/modules/semantic_after/typed_results.wat";
+    let buff = wat::parse_file(file).expect("couldn't convert the input wat to Wasm");
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let mut mod_it = ModuleIterator::new(&mut module, &vec![]);
+
+    // the bodies consume the result of the block and push it back
+    let mut block_body = vec![];
+    block_body.push(Operator::I32Const { value: 1 });
+    block_body.push(Operator::I32Add);
+
+    let mut br_body = vec![];
+    br_body.push(Operator::I32Const { value: 2 });
+    br_body.push(Operator::I32Mul);
+
+    let ops_of_interest = vec![
+        (
+            SupportedOperators::Block,
+            (InstrumentationMode::SemanticAfter, block_body),
+        ),
+        (
+            SupportedOperators::BrIf,
+            (InstrumentationMode::SemanticAfter, br_body.clone()),
+        ),
+        (
+            SupportedOperators::BrTable,
+            (InstrumentationMode::SemanticAfter, br_body),
+        ),
+    ];
+    run_block_injection(&mut mod_it, &ops_of_interest);
+
+    let result = module.encode();
+    if let Err(e) = wasmparser::validate(&result) {
+        panic!("Instrumented module is invalid: {}", e);
+    }
+    let out = wasmprinter::print_bytes(result).expect("couldn't translate wasm to wat");
+    check_instrumentation_encoding(&out, file).expect("couldn't read the expected encoding");
+}
+
 #[test]
 fn test_semantic_after_medium_2br() {
     let file = "tests/test_inputs/instr_testing/modules/semantic_after/medium_2br.wat";
@@ -1693,7 +1786,6 @@ fn test_semantic_after_medium_multiple() {
 fn test_semantic_after_medium_other_operators() {
     let _file = "tests/test_inputs/instr_testing/modules/semantic_after/medium_other_operators.wat";
     // todo -- test the other operators (when I know how to write wat using them)
-    assert!(true);
 }
 
 #[test]
@@ -1914,6 +2006,430 @@ fn add_imports_when_has_start_func() {
     }
 }
 
+// ==== TAGGED INSTRUMENTATION ====
+const TAGGED: &str = r#"
+(module
+    (func $f (param i32) (result i32)
+        local.get 0
+        i32.const 1
+        i32.add
+    )
+)
+"#;
+
+/// Injects `i32.const <value>; drop` before every instruction matching `at`
+fn inject_tagged(module: &mut Module, at: Operator, value: i32) {
+    let mut mod_it = ModuleIterator::new(module, &vec![]);
+    loop {
+        if *mod_it.curr_op().unwrap() == at {
+            mod_it.before().i32_const(value).drop();
+        }
+        if mod_it.next().is_none() {
+            break;
+        };
+    }
+}
+
+fn ops_of(wasm: &[u8]) -> Vec<Operator<'_>> {
+    let module = Module::parse(wasm, false).expect("Unable to parse");
+    let body = &module.functions.get(FunctionID(0)).unwrap_local().body;
+    body.instructions
+        .iter()
+        .map(|instr| instr.op.clone())
+        .collect()
+}
+
+#[test]
+fn test_strip_instrumentation() {
+    let buff = wat::parse_str(TAGGED).expect("couldn't convert the input wat to Wasm");
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    module.set_instrumentation_tag(Some("a"));
+    inject_tagged(&mut module, Operator::I32Add, 10);
+    module.set_instrumentation_tag(Some("b"));
+    inject_tagged(&mut module, Operator::LocalGet { local_index: 0 }, 20);
+    module.set_instrumentation_tag(None);
+    inject_tagged(&mut module, Operator::I32Const { value: 1 }, 30);
+
+    let result = module.encode();
+    if let Err(e) = wasmparser::validate(&result) {
+        panic!("Instrumented module is invalid: {}", e);
+    }
+    let has_section = wasmparser::Parser::new(0)
+        .parse_all(&result)
+        .any(|payload| match payload.unwrap() {
+            wasmparser::Payload::CustomSection(reader) => reader.name() == INJECTED_SECTION,
+            _ => false,
+        });
+    assert!(has_section);
+
+    // the code of pass `a` is gone, the untagged code is kept
+    let mut module = Module::parse(&result, false).expect("Unable to parse");
+    assert_eq!(module.strip_instrumentation("a").unwrap(), 2);
+    let stripped = module.encode();
+    if let Err(e) = wasmparser::validate(&stripped) {
+        panic!("Instrumented module is invalid: {}", e);
+    }
+    assert_eq!(
+        ops_of(&stripped),
+        vec![
+            Operator::I32Const { value: 20 },
+            Operator::Drop,
+            Operator::LocalGet { local_index: 0 },
+            Operator::I32Const { value: 30 },
+            Operator::Drop,
+            Operator::I32Const { value: 1 },
+            Operator::I32Add,
+            Operator::End,
+        ]
+    );
+
+    // the pending code of a pass is stripped as well
+    let mut module = Module::parse(&stripped, false).expect("Unable to parse");
+    module.set_instrumentation_tag(Some("c"));
+    inject_tagged(&mut module, Operator::I32Add, 40);
+    assert_eq!(module.strip_instrumentation("b").unwrap(), 2);
+    assert_eq!(module.strip_instrumentation("c").unwrap(), 2);
+    assert_eq!(
+        ops_of(&module.encode()),
+        vec![
+            Operator::LocalGet { local_index: 0 },
+            Operator::I32Const { value: 30 },
+            Operator::Drop,
+            Operator::I32Const { value: 1 },
+            Operator::I32Add,
+            Operator::End,
+        ]
+    );
+
+    // the original `i32.add` cannot be restored
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    module.set_instrumentation_tag(Some("d"));
+    let mut mod_it = ModuleIterator::new(&mut module, &vec![]);
+    loop {
+        if *mod_it.curr_op().unwrap() == Operator::I32Add {
+            mod_it.alternate().i32_sub();
+        }
+        if mod_it.next().is_none() {
+            break;
+        };
+    }
+    let result = module.encode();
+    let mut module = Module::parse(&result, false).expect("Unable to parse");
+    let err = module.strip_instrumentation("d").unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("replaced the instruction 2 of function 0"));
+}
+
+#[test]
+fn test_layer_order() {
+    let buff = wat::parse_str(TAGGED).expect("couldn't convert the input wat to Wasm");
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    // the passes run innermost first, the layer order puts them back in place
+    for (layer, value) in [(Some("tracer"), 10), (Some("profiler"), 20), (None, 30)] {
+        module.set_instrumentation_tag(layer);
+        let mut mod_it = ModuleIterator::new(&mut module, &vec![]);
+        loop {
+            if *mod_it.curr_op().unwrap() == Operator::I32Add {
+                mod_it.before().i32_const(value).drop();
+                mod_it.after().i32_const(value + 1).drop();
+            }
+            if mod_it.next().is_none() {
+                break;
+            };
+        }
+    }
+    module.set_layer_order(&["profiler", "tracer"]);
+
+    let result = module.encode();
+    if let Err(e) = wasmparser::validate(&result) {
+        panic!("Instrumented module is invalid: {}", e);
+    }
+    let ops = ops_of(&result);
+    let values: Vec<i32> = ops
+        .iter()
+        .filter_map(|op| match op {
+            Operator::I32Const { value } if *value >= 10 => Some(*value),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(values, vec![20, 10, 30, 31, 11, 21]);
+    assert_eq!(ops[8], Operator::I32Add);
+}
+
+#[test]
+fn test_to_wat_pending_instrumentation() {
+    let file = "tests/test_inputs/instr_testing/modules/fn_exit/one_func.wat";
+    let buff = wat::parse_file(file).expect("couldn't convert the input wat to Wasm");
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let mut func = module.functions.get_fn_modifier(FunctionID(0)).unwrap();
+    func.func_entry();
+    func.i32_const(7).drop();
+    func.finish_instr();
+    func.func_exit();
+    func.i32_const(8).drop();
+    func.finish_instr();
+    func.before_at(Location::Module {
+        func_idx: FunctionID(0),
+        instr_idx: 2,
+    })
+    .nop();
+    func.alternate_at(Location::Module {
+        func_idx: FunctionID(0),
+        instr_idx: 3,
+    })
+    .i32_const(5);
+
+    let expected = "(module
+  (type (;0;) (func))
+  (memory (;0;) 1)
+  (func (;0;) (type 0)
+    ;; << i32.const 7 ;; func_entry
+    ;; << drop ;; func_entry
+    block $hi
+      block ;; label = @2
+        ;; << nop ;; before
+        br $hi
+        i32.const 1 ;; rm
+        ;; << i32.const 5 ;; alternate
+        i32.const 2
+        i32.add
+        drop
+      end
+    end
+    ;; << i32.const 8 ;; func_exit
+    ;; << drop ;; func_exit
+  )
+)
+";
+    assert_eq!(module.to_wat().unwrap(), expected);
+    assert_eq!(module.to_string(), expected);
+    // printing leaves the instrumentation pending
+    let out = wasmprinter::print_bytes(module.encode()).expect("couldn't translate wasm to wat");
+    assert!(out.contains("i32.const 8"));
+}
+
+#[test]
+fn test_to_wat_component_inject_all_variations() {
+    let file = "tests/test_inputs/instr_testing/components/add-inject_all_variations.wat";
+    let buff = wat::parse_file(file).expect("couldn't convert the input wat to Wasm");
+    let mut component = Component::parse(&buff, false).expect("Unable to parse");
+    let mut comp_it = ComponentIterator::new(&mut component, HashMap::new());
+    loop {
+        match comp_it.curr_op().unwrap() {
+            Operator::Drop => {
+                comp_it.before().call(FunctionID(0));
+            }
+            Operator::Call { function_index: 1 } => {
+                comp_it.after().i32_const(0);
+            }
+            Operator::I32Const { value: 2 } => {
+                comp_it.alternate().i32_const(3);
+            }
+            _ => {}
+        }
+        if comp_it.next().is_none() {
+            break;
+        };
+    }
+
+    let annotated = component.to_wat().unwrap();
+    assert!(annotated.contains(";; << i32.const 3 ;; alternate"));
+    let out = wasmprinter::print_bytes(component.encode()).expect("couldn't translate wasm to wat");
+    check_pending_instrumentation(&out, &annotated).unwrap();
+}
+
+#[test]
+fn test_inject_wat() {
+    let mut module = Module::from_wat(
+        r#"
+        (module
+          (import "env" "log" (func $log (param i32)))
+          (global $count (mut i32) (i32.const 0))
+          (func $main (param $x i32)
+            local.get $x
+            drop
+          )
+        )"#,
+    )
+    .expect("Unable to parse");
+    let result = module.with_module(|module, arena| {
+        let mut mod_it = ModuleIterator::new(module, &vec![]);
+        loop {
+            if *mod_it.curr_op().unwrap() == Operator::Drop {
+                mod_it.before();
+                mod_it
+                    .inject_wat(
+                        "block $out local.get $x br_if $out global.get $count call $log end",
+                        arena,
+                    )
+                    .unwrap();
+                assert!(mod_it.inject_wat("call $missing", arena).is_err());
+            }
+            if mod_it.next().is_none() {
+                break;
+            };
+        }
+        module.encode()
+    });
+
+    if let Err(e) = wasmparser::validate(&result) {
+        panic!("Instrumented module is invalid: {}", e);
+    }
+    let encoded = Module::parse(&result, false).expect("Unable to parse");
+    let body = &encoded.functions.get(FunctionID(1)).unwrap_local().body;
+    let ops: Vec<Operator> = body
+        .instructions
+        .iter()
+        .map(|instr| instr.op.clone())
+        .collect();
+    assert_eq!(
+        ops,
+        vec![
+            Operator::LocalGet { local_index: 0 },
+            Operator::Block {
+                blockty: wasmparser::BlockType::Empty
+            },
+            Operator::LocalGet { local_index: 0 },
+            Operator::BrIf { relative_depth: 0 },
+            Operator::GlobalGet { global_index: 0 },
+            Operator::Call { function_index: 0 },
+            Operator::End,
+            Operator::Drop,
+            Operator::End,
+        ]
+    );
+}
+
+#[test]
+fn test_parse_wat_instrs() {
+    let wasm = wat::parse_str(
+        r#"
+        (module
+          (type $sig (func (param i32)))
+          (import "env" "log" (func $log (param i32)))
+          (import "env" "table" (table $imported_table 1 funcref))
+          (import "env" "error" (tag $imported_tag (param i32)))
+          (table $t 2 funcref)
+          (tag $e (param i32))
+          (memory 1)
+          (data $d "abc")
+          (elem $seg func $log)
+          (func $main (param $x i32)
+            local.get $x
+            drop
+          )
+        )"#,
+    )
+    .unwrap();
+    let mut module = Module::parse(&wasm, false).expect("Unable to parse");
+    let arena = WatArena::new();
+    let main = FunctionID(1);
+    let ops = module
+        .parse_wat_instrs(
+            main,
+            "local.get $x i32.const 0 call_indirect $t (type $sig) \
+             local.get $x i32.const 0 call_indirect $imported_table (type $sig) \
+             local.get $x throw $e local.get $x throw $imported_tag \
+             data.drop $d elem.drop $seg",
+            &arena,
+        )
+        .unwrap();
+    assert_eq!(
+        ops[2],
+        Operator::CallIndirect {
+            type_index: 0,
+            table_index: 1
+        }
+    );
+    assert_eq!(
+        ops[5],
+        Operator::CallIndirect {
+            type_index: 0,
+            table_index: 0
+        }
+    );
+    assert_eq!(ops[7], Operator::Throw { tag_index: 1 });
+    assert_eq!(ops[9], Operator::Throw { tag_index: 0 });
+    assert_eq!(ops[10], Operator::DataDrop { data_index: 0 });
+    assert_eq!(ops[11], Operator::ElemDrop { elem_index: 0 });
+    // the labels are resolved, and the targets of a `br_table` are kept by the arena
+    let ops = module
+        .parse_wat_instrs(
+            main,
+            "block $a block $b i32.const 0 br_table $a $b end end",
+            &arena,
+        )
+        .unwrap();
+    let Operator::BrTable { targets } = &ops[3] else {
+        panic!("expected a br_table, got {:?}", ops[3]);
+    };
+    assert_eq!(
+        targets.targets().collect::<Result<Vec<_>, _>>().unwrap(),
+        vec![1]
+    );
+    assert_eq!(targets.default(), 0);
+
+    // the declarations follow the changes to the module
+    assert!(module
+        .parse_wat_instrs(main, "call $added", &arena)
+        .is_err());
+    let mut added = FunctionBuilder::new(&[], &[]);
+    added.set_name("added".to_string());
+    let added = added.finish_module(&mut module);
+    let ops = module
+        .parse_wat_instrs(main, "call $added", &arena)
+        .unwrap();
+    assert_eq!(
+        ops,
+        vec![Operator::Call {
+            function_index: *added
+        }]
+    );
+}
+
+#[test]
+fn test_parse_wat_instrs_indices() {
+    let wasm = wat::parse_str(
+        r#"
+        (module
+          (import "env" "a" (func $a))
+          (import "env" "b" (func $b))
+          (import "env" "c" (func $c))
+          (global $g0 (mut i32) (i32.const 0))
+          (global $g1 (mut i32) (i32.const 1))
+          (func $main
+            nop
+          )
+        )"#,
+    )
+    .unwrap();
+    let module = Module::parse(&wasm, false).expect("Unable to parse");
+    let arena = WatArena::new();
+    // only the named functions and globals are declared: the named ones and the ones written as
+    // indices are resolved to the indices of the module
+    let ops = module
+        .parse_wat_instrs(
+            FunctionID(3),
+            "call $c call 0 call $main call 1 global.get $g1 global.set 0 ref.func $b drop",
+            &arena,
+        )
+        .unwrap();
+    assert_eq!(
+        ops,
+        vec![
+            Operator::Call { function_index: 2 },
+            Operator::Call { function_index: 0 },
+            Operator::Call { function_index: 3 },
+            Operator::Call { function_index: 1 },
+            Operator::GlobalGet { global_index: 1 },
+            Operator::GlobalSet { global_index: 0 },
+            Operator::RefFunc { function_index: 1 },
+            Operator::Drop,
+        ]
+    );
+}
+
 // =================
 // ==== HELPERS ====
 // =================
@@ -1978,7 +2494,7 @@ fn run_block_injection<'a, 'b, 'c>(
                     }
                 };
                 if matches {
-                    if body.len() > 0 {
+                    if !body.is_empty() {
                         // has body
                         mod_it.set_instrument_mode(*mode);
                         mod_it.inject_all(body);
@@ -63,7 +63,7 @@ fn test_it_instr_at() {
         func_idx: FunctionID(1),
         instr_idx: 1,
     };
-    mod_it.before_at(loc.clone());
+    mod_it.before_at(loc);
     mod_it.add_instr_at(loc, Operator::Unreachable);
     loop {
         let op = mod_it.curr_op();
@@ -225,6 +225,78 @@ fn test_fn_name() {
 // ==== HELPERS ====
 // =================
 
+#[test]
+fn test_loop_nest() {
+    let buff = wat::parse_str(
+        r#"
+        (module
+            (func (param i32)
+                loop
+                    loop
+                        local.get 0
+                        br_if 1
+                    end
+                end
+            )
+        )
+        "#,
+    )
+    .expect("couldn't convert the input wat to Wasm");
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let mut mod_it = ModuleIterator::new(&mut module, &vec![]);
+
+    let mut depths = vec![];
+    let mut headers = vec![];
+    loop {
+        depths.push(mod_it.curr_loop_depth());
+        if let Some(Operator::Loop { .. }) = mod_it.curr_op() {
+            // count the iterations of every loop at its header
+            let back_edges = mod_it.curr_loop().unwrap().back_edges.clone();
+            headers.push(back_edges);
+        }
+        if mod_it.next().is_none() {
+            break;
+        }
+    }
+    assert_eq!(depths, vec![1, 2, 2, 2, 2, 1, 0]);
+    assert_eq!(headers, vec![vec![3], vec![]]);
+}
+
+#[test]
+fn test_loop_nest_edited() {
+    let buff = wat::parse_str(
+        r#"
+        (module
+            (func (param i32)
+                nop
+                loop
+                    local.get 0
+                    br_if 0
+                end
+            )
+        )
+        "#,
+    )
+    .expect("couldn't convert the input wat to Wasm");
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let mut mod_it = ModuleIterator::new(&mut module, &vec![]);
+    assert_eq!(mod_it.curr_loop_depth(), 0);
+    assert_eq!(mod_it.curr_loop_nest().unwrap().loops.len(), 1);
+
+    // the loop is outlined: the nest follows the body
+    mod_it
+        .module
+        .outline(FunctionID(0), 1..5, None)
+        .expect("the loop can be outlined");
+    assert!(mod_it.curr_loop_nest().unwrap().loops.is_empty());
+    mod_it.next();
+    assert_eq!(mod_it.curr_loop_depth(), 0);
+
+    // every function is skipped
+    let mut mod_it = ModuleIterator::new(&mut module, &vec![FunctionID(0), FunctionID(1)]);
+    assert!(mod_it.curr_loop_nest().is_none());
+}
+
 fn iterate_component_and_count(comp_it: &mut ComponentIterator, exp_count: u32) {
     let mut count = 0;
     loop {
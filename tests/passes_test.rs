@@ -0,0 +1,888 @@
+use orca_wasm::ir::id::FunctionID;
+use orca_wasm::ir::module::Module;
+use orca_wasm::passes::asan::{self, AsanOptions, ReportKind};
+use orca_wasm::passes::coverage::{self, CounterStorage, CoverageMap, CoverageOptions};
+use orca_wasm::passes::replay::{self, HostWrite, ReplayMode, ReplayOptions, WriteLen};
+use wasmparser::{ExternalKind, Operator};
+
+const HEAP: &str = r#"
+(module
+    (memory 1)
+    (global $top (mut i32) (i32.const 1024))
+    (global $freed (mut i32) (i32.const 0))
+    (func $malloc (export "malloc") (param $size i32) (result i32)
+        (local $block i32)
+        global.get $top
+        local.set $block
+        global.get $top
+        local.get $size
+        i32.const 7
+        i32.add
+        i32.const -8
+        i32.and
+        i32.add
+        global.set $top
+        local.get $block
+    )
+    (func $free (export "free") (param $block i32)
+        ;; links the block into a free list
+        local.get $block
+        global.get $freed
+        i32.store
+        local.get $block
+        global.set $freed
+    )
+    (func $poke (export "poke") (param $at i32) (result i32)
+        (local $block i32)
+        i32.const 12
+        call $malloc
+        local.tee $block
+        local.get $at
+        i32.add
+        i32.const 1
+        i32.store8
+        local.get $block
+        call $free
+        local.get $block
+        i32.load8_u
+    )
+)
+"#;
+
+fn parse(wat: &str) -> Vec<u8> {
+    wat::parse_str(wat).expect("couldn't convert the input wat to Wasm")
+}
+
+fn validate(result: &[u8]) {
+    let mut validator = wasmparser::Validator::new_with_features(
+        wasmparser::WasmFeatures::default() | wasmparser::WasmFeatures::MULTI_MEMORY,
+    );
+    if let Err(e) = validator.validate_all(result) {
+        panic!(
+            "Instrumented module is invalid: {}\n{}",
+            e,
+            wasmprinter::print_bytes(result).unwrap()
+        );
+    }
+}
+
+fn exports_of(wasm: &[u8]) -> Vec<(String, ExternalKind, u32)> {
+    wasmparser::Parser::new(0)
+        .parse_all(wasm)
+        .filter_map(|payload| match payload.unwrap() {
+            wasmparser::Payload::ExportSection(reader) => Some(reader),
+            _ => None,
+        })
+        .flat_map(|reader| reader.into_iter().map(|export| export.unwrap()))
+        .map(|export| (export.name.to_string(), export.kind, export.index))
+        .collect()
+}
+
+fn calls_of(wasm: &[u8], func: u32) -> Vec<u32> {
+    let module = Module::parse(wasm, true).expect("Unable to parse");
+    let body = &module
+        .functions
+        .get(FunctionID(func))
+        .unwrap_local()
+        .body
+        .instructions;
+    body.iter()
+        .filter_map(|instr| match instr.op {
+            Operator::Call { function_index } => Some(function_index),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn test_asan() {
+    let buff = parse(HEAP);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let asan = asan::instrument(&mut module, &AsanOptions::default()).unwrap();
+    assert_eq!(asan.wrappers.len(), 2);
+    assert!(asan.unchecked.contains(&asan.wrappers[0].0));
+    assert!(asan.unchecked.contains(&asan.wrappers[1].0));
+
+    let result = module.encode();
+    validate(&result);
+
+    // the handler is imported first: the local functions are malloc (1), free (2), poke (3), the
+    // check (4), the runtime (5-7) and the wrappers of malloc (8) and free (9)
+    assert_eq!(
+        exports_of(&result),
+        vec![
+            ("malloc".to_string(), ExternalKind::Func, 8),
+            ("free".to_string(), ExternalKind::Func, 9),
+            ("poke".to_string(), ExternalKind::Func, 3),
+        ]
+    );
+    // the calls go through the wrappers, and both accesses are checked
+    assert_eq!(calls_of(&result, 3), vec![8, 4, 9, 4]);
+    // the allocator is not checked
+    assert!(calls_of(&result, 2).is_empty());
+    // the wrappers call the allocator
+    assert_eq!(calls_of(&result, 8), vec![1, 5]);
+    assert_eq!(calls_of(&result, 9), vec![6, 2]);
+}
+
+#[test]
+fn test_asan_invalid() {
+    let options = AsanOptions::default();
+
+    let buff = parse(r#"(module (func (export "malloc") (param i32) (result i32) i32.const 0))"#);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let err = asan::instrument(&mut module, &options).unwrap_err();
+    assert!(err.to_string().contains("the module has no memory"));
+
+    let buff = parse(r#"(module (memory 1) (func (export "free") (param i64)))"#);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let err = asan::instrument(&mut module, &options).unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("the exported `free` is not of type"));
+
+    let buff = parse(HEAP);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let options = AsanOptions {
+        redzone: 12,
+        ..AsanOptions::default()
+    };
+    assert!(asan::instrument(&mut module, &options).is_err());
+}
+
+const BUMP: &str = r#"
+(module
+    (memory (export "memory") 1)
+    (global $top (export "top") (mut i32) (i32.const 1024))
+    (func $malloc (export "malloc") (param $size i32) (result i32)
+        (local $block i32)
+        global.get $top
+        local.set $block
+        global.get $top
+        local.get $size
+        i32.const 7
+        i32.add
+        i32.const -8
+        i32.and
+        i32.add
+        global.set $top
+        local.get $block
+    )
+    (func $free (export "free") (param $block i32))
+    (func (export "realloc") (param $block i32) (param $size i32) (result i32)
+        (local $new i32)
+        local.get $size
+        call $malloc
+        local.tee $new
+        local.get $block
+        local.get $size
+        memory.copy
+        local.get $new
+    )
+    ;; allocates without the wrappers of the sanitizer
+    (type $alloc (func (param i32) (result i32)))
+    (table 1 funcref)
+    (elem (i32.const 0) $malloc)
+    (func (export "foreign_malloc") (param $size i32) (result i32)
+        local.get $size
+        i32.const 0
+        call_indirect (type $alloc)
+    )
+    (func (export "poke") (param $at i32) (result i32)
+        (local $block i32)
+        i32.const 12
+        call $malloc
+        local.tee $block
+        local.get $at
+        i32.add
+        i32.const 1
+        i32.store8
+        local.get $block
+        call $free
+        local.get $block
+    )
+    (func (export "peek") (param $at i32) (result i32)
+        local.get $at
+        i32.load8_u
+    )
+)
+"#;
+
+#[test]
+fn test_asan_run() {
+    use wasmtime::{Caller, Config, Engine, Linker, Store, TypedFunc, Val};
+
+    let buff = parse(BUMP);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    asan::instrument(&mut module, &AsanOptions::default()).unwrap();
+    let result = module.encode();
+    validate(&result);
+
+    let engine = Engine::new(Config::new().wasm_multi_memory(true)).unwrap();
+    let module = wasmtime::Module::new(&engine, &result).unwrap();
+    // the reports of the handler: (address, length, kind)
+    let mut store: Store<Vec<(i64, i64, i32)>> = Store::new(&engine, vec![]);
+    let mut linker = Linker::new(&engine);
+    linker
+        .func_wrap(
+            "asan",
+            "report",
+            |mut caller: Caller<'_, Vec<(i64, i64, i32)>>, address: i64, len: i64, kind: i32| {
+                caller.data_mut().push((address, len, kind))
+            },
+        )
+        .unwrap();
+    let instance = linker.instantiate(&mut store, &module).unwrap();
+    let poke: TypedFunc<i32, i32> = instance.get_typed_func(&mut store, "poke").unwrap();
+    let peek: TypedFunc<i32, i32> = instance.get_typed_func(&mut store, "peek").unwrap();
+    let (read, write) = (ReportKind::Read as i32, ReportKind::Write as i32);
+
+    // the last byte of the block
+    poke.call(&mut store, 11).unwrap();
+    assert!(store.data().is_empty());
+    // the first byte of the redzone
+    let block = poke.call(&mut store, 12).unwrap();
+    assert_eq!(
+        std::mem::take(store.data_mut()),
+        vec![(block as i64 + 12, 1, write)]
+    );
+    // the block was freed
+    peek.call(&mut store, block).unwrap();
+    assert_eq!(
+        std::mem::take(store.data_mut()),
+        vec![(block as i64, 1, read)]
+    );
+    // the memory that was never allocated is addressable
+    peek.call(&mut store, 0).unwrap();
+    assert!(store.data().is_empty());
+
+    // the host grows memory 0 past what the shadow covers
+    let memory = instance.get_memory(&mut store, "memory").unwrap();
+    memory.grow(&mut store, 8).unwrap();
+    let top = instance.get_global(&mut store, "top").unwrap();
+    top.set(&mut store, Val::I32(8 << 16)).unwrap();
+    peek.call(&mut store, (9 << 16) - 1).unwrap();
+    assert!(store.data().is_empty());
+    let block = poke.call(&mut store, 12).unwrap();
+    // after its header
+    assert_eq!(block, (8 << 16) + 8);
+    assert_eq!(
+        std::mem::take(store.data_mut()),
+        vec![(block as i64 + 12, 1, write)]
+    );
+}
+
+#[test]
+fn test_asan_run_free() {
+    use wasmtime::{Caller, Config, Engine, Linker, Store, TypedFunc};
+
+    let buff = parse(BUMP);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    asan::instrument(&mut module, &AsanOptions::default()).unwrap();
+    let result = module.encode();
+    validate(&result);
+
+    let engine = Engine::new(Config::new().wasm_multi_memory(true)).unwrap();
+    let module = wasmtime::Module::new(&engine, &result).unwrap();
+    let mut store: Store<Vec<(i64, i64, i32)>> = Store::new(&engine, vec![]);
+    let mut linker = Linker::new(&engine);
+    linker
+        .func_wrap(
+            "asan",
+            "report",
+            |mut caller: Caller<'_, Vec<(i64, i64, i32)>>, address: i64, len: i64, kind: i32| {
+                caller.data_mut().push((address, len, kind))
+            },
+        )
+        .unwrap();
+    let instance = linker.instantiate(&mut store, &module).unwrap();
+    let malloc: TypedFunc<i32, i32> = instance.get_typed_func(&mut store, "malloc").unwrap();
+    let foreign_malloc: TypedFunc<i32, i32> = instance
+        .get_typed_func(&mut store, "foreign_malloc")
+        .unwrap();
+    let free: TypedFunc<i32, ()> = instance.get_typed_func(&mut store, "free").unwrap();
+    let peek: TypedFunc<i32, i32> = instance.get_typed_func(&mut store, "peek").unwrap();
+
+    // a block of size 0 is not addressable, and freeing it twice is reported
+    let empty = malloc.call(&mut store, 0).unwrap();
+    peek.call(&mut store, empty).unwrap();
+    assert_eq!(
+        std::mem::take(store.data_mut()),
+        vec![(empty as i64, 1, ReportKind::Read as i32)]
+    );
+    free.call(&mut store, empty).unwrap();
+    assert!(store.data().is_empty());
+    free.call(&mut store, empty).unwrap();
+    assert_eq!(
+        std::mem::take(store.data_mut()),
+        vec![(empty as i64, 0, ReportKind::InvalidFree as i32)]
+    );
+
+    // freeing a block the wrappers did not allocate leaves the blocks after it addressable
+    let foreign = foreign_malloc.call(&mut store, 16).unwrap();
+    let block = malloc.call(&mut store, 16).unwrap();
+    assert!(block > foreign);
+    free.call(&mut store, foreign).unwrap();
+    for at in [foreign, foreign + 15, block, block + 15] {
+        peek.call(&mut store, at).unwrap();
+    }
+    assert!(store.data().is_empty());
+    // only the bytes of a freed block are poisoned
+    free.call(&mut store, block).unwrap();
+    peek.call(&mut store, block + 15).unwrap();
+    peek.call(&mut store, block + 16).unwrap();
+    peek.call(&mut store, foreign + 15).unwrap();
+    let reports: Vec<i64> = std::mem::take(store.data_mut())
+        .into_iter()
+        .map(|(address, _, _)| address)
+        .collect();
+    assert_eq!(reports, vec![block as i64 + 15, block as i64 + 16]);
+
+    // a reallocated block keeps its content, with or without a header
+    let realloc: TypedFunc<(i32, i32), i32> =
+        instance.get_typed_func(&mut store, "realloc").unwrap();
+    let memory = instance.get_memory(&mut store, "memory").unwrap();
+    for block in [
+        malloc.call(&mut store, 4).unwrap(),
+        foreign_malloc.call(&mut store, 4).unwrap(),
+    ] {
+        memory
+            .write(&mut store, block as usize, &[1, 2, 3, 4])
+            .unwrap();
+        let new = realloc.call(&mut store, (block, 12)).unwrap();
+        let mut content = [0; 4];
+        memory.read(&store, new as usize, &mut content).unwrap();
+        assert_eq!(content, [1, 2, 3, 4]);
+        peek.call(&mut store, new + 11).unwrap();
+        assert!(store.data().is_empty());
+    }
+}
+
+const ABS: &str = r#"
+(module
+    (func $abs (export "abs") (param i32) (result i32)
+        local.get 0
+        i32.const 0
+        i32.lt_s
+        if (result i32)
+            i32.const 0
+            local.get 0
+            i32.sub
+        else
+            local.get 0
+        end
+    )
+)
+"#;
+
+/// Appends a custom section to the binary `wasm`.
+fn add_custom_section(wasm: &mut Vec<u8>, name: &str, data: &[u8]) {
+    fn leb(mut value: usize, out: &mut Vec<u8>) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(byte);
+                return;
+            }
+            out.push(byte | 0x80);
+        }
+    }
+    let mut content = vec![];
+    leb(name.len(), &mut content);
+    content.extend_from_slice(name.as_bytes());
+    content.extend_from_slice(data);
+    wasm.push(0);
+    leb(content.len(), wasm);
+    wasm.extend(content);
+}
+
+/// Adds DWARF line information to `wasm`, mapping the instructions of its first function to the
+/// lines of `/src/abs.c`: `lines[i]` is the line of the `i`th instruction.
+fn add_lines(wasm: &mut Vec<u8>, lines: &[u64]) {
+    use gimli::write::{
+        Address, AttributeValue, DwarfUnit, EndianVec, LineProgram, LineString, Sections,
+    };
+    let offsets: Vec<usize> = Module::parse(wasm, false)
+        .expect("Unable to parse")
+        .functions
+        .get(FunctionID(0))
+        .unwrap_local()
+        .body
+        .instructions
+        .iter()
+        .map(|instr| instr.offset.unwrap())
+        .collect();
+
+    let encoding = gimli::Encoding {
+        format: gimli::Format::Dwarf32,
+        version: 4,
+        address_size: 4,
+    };
+    let mut dwarf = DwarfUnit::new(encoding);
+    let root = dwarf.unit.root();
+    dwarf.unit.get_mut(root).set(
+        gimli::DW_AT_comp_dir,
+        AttributeValue::String(b"/src".to_vec()),
+    );
+    let mut program = LineProgram::new(
+        encoding,
+        gimli::LineEncoding::default(),
+        LineString::String(b"/src".to_vec()),
+        LineString::String(b"abs.c".to_vec()),
+        None,
+    );
+    let dir = program.default_directory();
+    let file = program.add_file(LineString::String(b"abs.c".to_vec()), dir, None);
+    program.begin_sequence(Some(Address::Constant(0)));
+    for (offset, line) in offsets.iter().zip(lines) {
+        program.row().address_offset = *offset as u64;
+        program.row().file = file;
+        program.row().line = *line;
+        program.generate_row();
+    }
+    program.end_sequence(*offsets.last().unwrap() as u64 + 1);
+    dwarf.unit.line_program = program;
+
+    let mut sections = Sections::new(EndianVec::new(gimli::LittleEndian));
+    dwarf.write(&mut sections).unwrap();
+    sections
+        .for_each(|id, data| {
+            if !data.slice().is_empty() {
+                add_custom_section(wasm, id.name(), data.slice());
+            }
+            Ok::<(), gimli::write::Error>(())
+        })
+        .unwrap();
+}
+
+#[test]
+fn test_coverage() {
+    let buff = parse(ABS);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let options = CoverageOptions {
+        storage: CounterStorage::Globals,
+        ..CoverageOptions::default()
+    };
+    let coverage = coverage::instrument(&mut module, &options).unwrap();
+    // the else arm and the final `end` solve the other blocks
+    assert_eq!(coverage.map.num_counters, 2);
+    let blocks = &coverage.map.functions[0].blocks;
+    let starts: Vec<u32> = blocks.iter().map(|block| block.instrs.start).collect();
+    assert_eq!(starts, vec![0, 4, 7, 9, 10]);
+    assert_eq!(blocks[2].counter, Some(0));
+    assert_eq!(blocks[4].counter, Some(1));
+    assert_eq!(blocks[0].terms, vec![(1, 1)]);
+    assert_eq!(blocks[1].terms, vec![(0, -1), (1, 1)]);
+
+    let result = module.encode();
+    validate(&result);
+    assert_eq!(
+        CoverageMap::from_wasm(&result).unwrap(),
+        Some(coverage.map.clone())
+    );
+    assert_eq!(
+        exports_of(&result),
+        vec![
+            ("abs".to_string(), ExternalKind::Func, 1),
+            ("coverage_dump".to_string(), ExternalKind::Func, 4),
+        ]
+    );
+    let ops = {
+        let module = Module::parse(&result, false).expect("Unable to parse");
+        let ops: Vec<Operator> = module
+            .functions
+            .get(FunctionID(1))
+            .unwrap_local()
+            .body
+            .instructions
+            .iter()
+            .map(|instr| instr.op.clone())
+            .collect();
+        ops
+    };
+    // the else arm counts after the `else`, the final `end` before it
+    assert_eq!(ops[7], Operator::Else);
+    assert_eq!(ops[8], Operator::GlobalGet { global_index: 0 });
+    assert_eq!(ops[14], Operator::GlobalGet { global_index: 1 });
+    assert_eq!(ops[18], Operator::End);
+
+    // without DWARF, the lines are the instructions
+    assert_eq!(
+        coverage.map.to_lcov(&[0, 3]),
+        "TN:\nSF:wasm-function[0]\nFN:1,abs\nFNDA:3,abs\nFNF:1\nFNH:1\n\
+         DA:1,3\nDA:5,3\nDA:8,0\nDA:10,3\nDA:11,3\nLF:5\nLH:4\nend_of_record\n"
+    );
+}
+
+/// Runs the instrumented module `wasm`, calling its export `func` with each of `args`, and returns
+/// the counters dumped afterwards.
+fn run_coverage(wasm: &[u8], func: &str, args: &[i32]) -> Vec<u64> {
+    use wasmtime::{Caller, Config, Engine, Linker, Store, TypedFunc};
+
+    let engine = Engine::new(Config::new().wasm_multi_memory(true)).unwrap();
+    let module = wasmtime::Module::new(&engine, wasm).unwrap();
+    // the dumped counters: (index, value)
+    let mut store: Store<Vec<(i32, i64)>> = Store::new(&engine, vec![]);
+    let mut linker = Linker::new(&engine);
+    linker
+        .func_wrap(
+            "coverage",
+            "dump",
+            |mut caller: Caller<'_, Vec<(i32, i64)>>, counter: i32, value: i64| {
+                caller.data_mut().push((counter, value))
+            },
+        )
+        .unwrap();
+    let instance = linker.instantiate(&mut store, &module).unwrap();
+    let func: TypedFunc<i32, i32> = instance.get_typed_func(&mut store, func).unwrap();
+    let dump: TypedFunc<(), ()> = instance
+        .get_typed_func(&mut store, "coverage_dump")
+        .unwrap();
+    for arg in args {
+        func.call(&mut store, *arg).unwrap();
+    }
+    dump.call(&mut store, ()).unwrap();
+    store
+        .data()
+        .iter()
+        .enumerate()
+        .map(|(idx, (counter, value))| {
+            assert_eq!(idx as i32, *counter);
+            *value as u64
+        })
+        .collect()
+}
+
+#[test]
+fn test_coverage_dump() {
+    for storage in [CounterStorage::Memory, CounterStorage::Globals] {
+        let buff = parse(ABS);
+        let mut module = Module::parse(&buff, false).expect("Unable to parse");
+        let options = CoverageOptions {
+            storage,
+            ..CoverageOptions::default()
+        };
+        coverage::instrument(&mut module, &options).unwrap();
+        let result = module.encode();
+        validate(&result);
+
+        // the else arm ran twice, the function three times
+        let counts = run_coverage(&result, "abs", &[-3, 1, 2]);
+        assert_eq!(counts, vec![2, 3], "{:?}", storage);
+    }
+}
+
+#[test]
+fn test_coverage_lcov() {
+    let buff = parse(
+        r#"
+        (module
+            (func $f (export "f") (param i32) (result i32)
+                local.get 0
+                if
+                    i32.const 5
+                    local.set 0
+                end
+                local.get 0
+            )
+        )
+        "#,
+    );
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let coverage = coverage::instrument(&mut module, &CoverageOptions::default()).unwrap();
+    let result = module.encode();
+    validate(&result);
+
+    // the branch is not taken: everything but its body ran
+    let counts = run_coverage(&result, "f", &[0]);
+    assert_eq!(
+        coverage.map.to_lcov(&counts),
+        "TN:\nSF:wasm-function[0]\nFN:1,f\nFNDA:1,f\nFNF:1\nFNH:1\n\
+         DA:1,1\nDA:3,0\nDA:5,1\nDA:7,1\nLF:4\nLH:3\nend_of_record\n"
+    );
+    // then taken once out of three calls
+    let counts = run_coverage(&result, "f", &[0, 1, 0]);
+    assert_eq!(
+        coverage.map.to_lcov(&counts),
+        "TN:\nSF:wasm-function[0]\nFN:1,f\nFNDA:3,f\nFNF:1\nFNH:1\n\
+         DA:1,3\nDA:3,1\nDA:5,3\nDA:7,3\nLF:4\nLH:4\nend_of_record\n"
+    );
+}
+
+#[test]
+fn test_coverage_lines() {
+    let mut buff = parse(ABS);
+    add_lines(&mut buff, &[2, 2, 2, 2, 3, 3, 3, 4, 5, 6, 6]);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let coverage = coverage::instrument(&mut module, &CoverageOptions::default()).unwrap();
+    assert_eq!(coverage.map.files, vec!["/src/abs.c".to_string()]);
+    let lines: Vec<Vec<(u32, u32)>> = coverage.map.functions[0]
+        .blocks
+        .iter()
+        .map(|block| block.lines.clone())
+        .collect();
+    assert_eq!(
+        lines,
+        vec![
+            vec![(0, 2)],
+            vec![(0, 3)],
+            vec![(0, 4), (0, 5)],
+            vec![(0, 6)],
+            vec![(0, 6)]
+        ]
+    );
+    validate(&module.encode());
+
+    assert_eq!(
+        coverage.map.to_lcov(&[2, 2]),
+        "TN:\nSF:/src/abs.c\nFN:2,abs\nFNDA:2,abs\nFNF:1\nFNH:1\n\
+         DA:2,2\nDA:3,0\nDA:4,2\nDA:5,2\nDA:6,2\nLF:5\nLH:4\nend_of_record\n"
+    );
+}
+
+const HOST: &str = r#"
+(module
+    (import "env" "rand" (func $rand (result i32)))
+    (import "env" "read" (func $read (param $buf i32) (param $len i32) (result i32)))
+    (import "env" "drop" (func $drop (param externref)))
+    (memory (export "memory") 1)
+    (func (export "run") (result i32)
+        call $rand
+        i32.const 64
+        i32.const 8
+        call $read
+        i32.add
+        i32.const 64
+        i32.load
+        i32.add
+    )
+)
+"#;
+
+fn host_writes() -> Vec<HostWrite<'static>> {
+    vec![HostWrite {
+        module: "env",
+        name: "read",
+        ptr: 0,
+        len: WriteLen::Param(1),
+    }]
+}
+
+#[test]
+fn test_record() {
+    let buff = parse(HOST);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let options = ReplayOptions {
+        writes: host_writes(),
+        ..ReplayOptions::default()
+    };
+    let record = replay::instrument(&mut module, &options).unwrap();
+    assert_eq!(record.skipped, vec![FunctionID(2)]);
+    assert!(record.indirect.is_empty());
+    assert!(record.cursor.is_none());
+
+    let result = module.encode();
+    validate(&result);
+    // the sink is imported last: the local functions are run (4), the reserve helper (5) and the
+    // wrappers of rand (6) and read (7)
+    assert_eq!(
+        exports_of(&result),
+        vec![
+            ("memory".to_string(), ExternalKind::Memory, 0),
+            ("run".to_string(), ExternalKind::Func, 4),
+            ("replay_log".to_string(), ExternalKind::Memory, 1),
+        ]
+    );
+    assert_eq!(calls_of(&result, 4), vec![6, 7]);
+    // the wrappers call the import, then the sink
+    assert_eq!(calls_of(&result, 6), vec![0, 5, 3]);
+    assert_eq!(calls_of(&result, 7), vec![1, 5, 3]);
+}
+
+#[test]
+fn test_replay() {
+    let buff = parse(HOST);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let options = ReplayOptions {
+        mode: ReplayMode::Replay,
+        writes: host_writes(),
+        ..ReplayOptions::default()
+    };
+    let replay = replay::instrument(&mut module, &options).unwrap();
+    assert!(replay.sink.is_none());
+    assert!(replay.cursor.is_some());
+    assert_eq!(
+        replay.wrappers,
+        vec![
+            (FunctionID(0), FunctionID(4)),
+            (FunctionID(1), FunctionID(5))
+        ]
+    );
+
+    let result = module.encode();
+    validate(&result);
+    // the imports are not called anymore
+    assert_eq!(calls_of(&result, 3), vec![4, 5]);
+    assert!(calls_of(&result, 4).is_empty());
+    assert!(calls_of(&result, 5).is_empty());
+}
+
+#[test]
+fn test_record_replay_run() {
+    use std::sync::{Arc, Mutex};
+    use wasmtime::{Caller, Config, Engine, Extern, Linker, Store};
+
+    // the externref import needs the gc support of the runtime
+    let host = HOST.replace(
+        r#"(import "env" "drop" (func $drop (param externref)))"#,
+        "",
+    );
+    let engine = Engine::new(Config::new().wasm_multi_memory(true)).unwrap();
+    let instrument = |mode: ReplayMode| {
+        let buff = parse(&host);
+        let mut module = Module::parse(&buff, false).expect("Unable to parse");
+        let options = ReplayOptions {
+            mode,
+            writes: host_writes(),
+            ..ReplayOptions::default()
+        };
+        replay::instrument(&mut module, &options).unwrap();
+        let result = module.encode();
+        validate(&result);
+        wasmtime::Module::new(&engine, &result).unwrap()
+    };
+
+    // record: the host returns 7 from rand, and writes 8 bytes from read
+    let log = Arc::new(Mutex::new(vec![]));
+    let mut store = Store::new(&engine, ());
+    let mut linker = Linker::new(&engine);
+    linker.func_wrap("env", "rand", || 7).unwrap();
+    linker
+        .func_wrap(
+            "env",
+            "read",
+            |mut caller: Caller<'_, ()>, buf: i32, len: i32| {
+                let Some(Extern::Memory(memory)) = caller.get_export("memory") else {
+                    panic!("the memory is exported");
+                };
+                let bytes: Vec<u8> = (1..=len as u8).collect();
+                memory.write(&mut caller, buf as usize, &bytes).unwrap();
+                len
+            },
+        )
+        .unwrap();
+    let sink = log.clone();
+    linker
+        .func_wrap(
+            "replay",
+            "record",
+            move |mut caller: Caller<'_, ()>, entry: i32, len: i32| {
+                let Some(Extern::Memory(memory)) = caller.get_export("replay_log") else {
+                    panic!("the log is exported");
+                };
+                let entry = entry as usize..(entry + len) as usize;
+                let bytes = memory.data(&caller)[entry].to_vec();
+                sink.lock().unwrap().extend(bytes);
+            },
+        )
+        .unwrap();
+    let instance = linker
+        .instantiate(&mut store, &instrument(ReplayMode::Record))
+        .unwrap();
+    let run = instance
+        .get_typed_func::<(), i32>(&mut store, "run")
+        .unwrap();
+    // 7 + 8 + the first 4 bytes written by read
+    let recorded = run.call(&mut store, ()).unwrap();
+    assert_eq!(recorded, 15 + i32::from_le_bytes([1, 2, 3, 4]));
+    let log = log.lock().unwrap().clone();
+    // the entry of rand starts with the index of its import
+    assert_eq!(log[..8], [0, 0, 0, 0, 7, 0, 0, 0]);
+
+    // replay: the imports are not called, the results and the memory come from the log
+    let mut store = Store::new(&engine, ());
+    let mut linker = Linker::new(&engine);
+    linker
+        .func_wrap("env", "rand", || -> i32 { panic!("rand is replayed") })
+        .unwrap();
+    linker
+        .func_wrap("env", "read", |_: i32, _: i32| -> i32 {
+            panic!("read is replayed")
+        })
+        .unwrap();
+    let instance = linker
+        .instantiate(&mut store, &instrument(ReplayMode::Replay))
+        .unwrap();
+    let memory = instance.get_memory(&mut store, "replay_log").unwrap();
+    memory.write(&mut store, 0, &log).unwrap();
+    let run = instance
+        .get_typed_func::<(), i32>(&mut store, "run")
+        .unwrap();
+    assert_eq!(run.call(&mut store, ()).unwrap(), recorded);
+}
+
+#[test]
+fn test_replay_indirect() {
+    let buff = parse(
+        r#"
+        (module
+            (import "env" "a" (func $a))
+            (import "env" "b" (func $b))
+            (import "env" "c" (func $c))
+            (table 1 funcref)
+            (elem (i32.const 0) $a)
+            (elem declare func $b)
+            (func (export "run")
+                ref.func $b
+                drop
+                call $c
+            )
+        )
+        "#,
+    );
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let record = replay::instrument(&mut module, &ReplayOptions::default()).unwrap();
+    // only the calls to c are recorded
+    assert_eq!(record.wrappers.len(), 3);
+    assert_eq!(record.indirect, vec![FunctionID(0), FunctionID(1)]);
+    validate(&module.encode());
+}
+
+#[test]
+fn test_replay_invalid() {
+    let buff = parse(HOST);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let options = ReplayOptions {
+        writes: vec![HostWrite {
+            module: "env",
+            name: "write",
+            ptr: 0,
+            len: WriteLen::Bytes(4),
+        }],
+        ..ReplayOptions::default()
+    };
+    let err = replay::instrument(&mut module, &options).unwrap_err();
+    assert!(err.to_string().contains("there is no import env.write"));
+
+    let options = ReplayOptions {
+        writes: vec![HostWrite {
+            len: WriteLen::Param(2),
+            ..host_writes()[0]
+        }],
+        ..ReplayOptions::default()
+    };
+    assert!(replay::instrument(&mut module, &options).is_err());
+
+    let buff = parse(r#"(module (import "env" "rand" (func (result i32))))"#);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let options = ReplayOptions {
+        writes: vec![HostWrite {
+            name: "rand",
+            ..host_writes()[0]
+        }],
+        ..ReplayOptions::default()
+    };
+    let err = replay::instrument(&mut module, &options).unwrap_err();
+    assert!(err.to_string().contains("must be a 32-bit memory"));
+}
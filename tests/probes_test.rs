@@ -0,0 +1,405 @@
+use orca_wasm::ir::id::{FunctionID, GlobalID};
+use orca_wasm::ir::module::Module;
+use orca_wasm::iterator::iterator_trait::{IteratingInstrumenter, Iterator};
+use orca_wasm::iterator::module_iterator::ModuleIterator;
+use orca_wasm::module_builder::AddLocal;
+use orca_wasm::probes::{
+    AccessKind, AccessTemps, AccessTracer, Clock, CounterArray, GlobalCounter, HitImport, Predicate,
+};
+use orca_wasm::{DataType, Location, Opcode};
+use wasmparser::{ExternalKind, Operator};
+
+const TWO_FUNCS: &str = r#"
+(module
+    (memory 1)
+    (func $a (param i32) (result i32)
+        local.get 0
+        i32.const 1
+        i32.add
+    )
+    (func $b (result i32)
+        i32.const 41
+        call $a
+    )
+)
+"#;
+
+fn parse(wat: &str) -> Vec<u8> {
+    wat::parse_str(wat).expect("couldn't convert the input wat to Wasm")
+}
+
+fn validate(result: &[u8]) {
+    let mut validator = wasmparser::Validator::new_with_features(
+        wasmparser::WasmFeatures::default() | wasmparser::WasmFeatures::MULTI_MEMORY,
+    );
+    if let Err(e) = validator.validate_all(result) {
+        panic!(
+            "Instrumented module is invalid: {}\n{}",
+            e,
+            wasmprinter::print_bytes(result).unwrap()
+        );
+    }
+}
+
+fn ops_of(wasm: &[u8], func: u32) -> Vec<Operator<'_>> {
+    let module = Module::parse(wasm, false).expect("Unable to parse");
+    let body = &module
+        .functions
+        .get(FunctionID(func))
+        .unwrap_local()
+        .body
+        .instructions;
+    body.iter().map(|instr| instr.op.clone()).collect()
+}
+
+#[test]
+fn test_probes() {
+    let buff = parse(TWO_FUNCS);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+
+    let calls = GlobalCounter::new(&mut module, DataType::I64);
+    let sites = CounterArray::with_new_memory(&mut module, 2);
+    let hit = HitImport::new(&mut module, "probes", "hit");
+    let clock = Clock::new(&mut module, "probes", "now");
+    module
+        .exports
+        .add_export_global("calls".to_string(), *calls.global);
+    module
+        .exports
+        .add_export_memory("counters".to_string(), *sites.memory);
+    assert_eq!(sites.offset_of(1), 8);
+
+    let mut mod_it = ModuleIterator::new(&mut module, &vec![]);
+    let mut site = 0;
+    loop {
+        if let (Location::Module { instr_idx: 0, .. }, ..) = mod_it.curr_loc() {
+            let start = mod_it.add_local(DataType::I64);
+            mod_it.before();
+            calls.increment(&mut mod_it);
+            sites.increment(&mut mod_it, site);
+            hit.call(&mut mod_it, site);
+            clock.timestamp_into(&mut mod_it, start);
+            site += 1;
+        }
+        if mod_it.next().is_none() {
+            break;
+        }
+    }
+
+    let result = module.encode();
+    validate(&result);
+    let exports: Vec<(String, ExternalKind, u32)> = wasmparser::Parser::new(0)
+        .parse_all(&result)
+        .filter_map(|payload| match payload.unwrap() {
+            wasmparser::Payload::ExportSection(reader) => Some(reader),
+            _ => None,
+        })
+        .flat_map(|reader| reader.into_iter().map(|export| export.unwrap()))
+        .map(|export| (export.name.to_string(), export.kind, export.index))
+        .collect();
+    assert_eq!(
+        exports,
+        vec![
+            ("calls".to_string(), ExternalKind::Global, 0),
+            ("counters".to_string(), ExternalKind::Memory, 1)
+        ]
+    );
+
+    // the second function: its counter is at offset 8 of the new memory
+    let ops = ops_of(&result, 3);
+    let memarg = wasmparser::MemArg {
+        align: 3,
+        max_align: 3,
+        offset: 8,
+        memory: 1,
+    };
+    assert_eq!(
+        ops[..15],
+        [
+            Operator::GlobalGet { global_index: 0 },
+            Operator::I64Const { value: 1 },
+            Operator::I64Add,
+            Operator::GlobalSet { global_index: 0 },
+            Operator::I32Const { value: 0 },
+            Operator::I32Const { value: 0 },
+            Operator::I64Load { memarg },
+            Operator::I64Const { value: 1 },
+            Operator::I64Add,
+            Operator::I64Store { memarg },
+            Operator::I32Const { value: 1 },
+            Operator::Call { function_index: 0 },
+            Operator::Call { function_index: 1 },
+            Operator::LocalSet { local_index: 0 },
+            Operator::I32Const { value: 41 },
+        ]
+    );
+}
+
+#[test]
+fn test_predicates() {
+    let buff = parse(TWO_FUNCS);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+
+    let counter = GlobalCounter::new(&mut module, DataType::I32);
+    let enabled = Predicate::flag(&mut module, false);
+    let sampled = Predicate::sample(&mut module, 4);
+    let same = module
+        .types
+        .add_func_type(&[DataType::I32], &[DataType::I32]);
+
+    let mut mod_it = ModuleIterator::new(&mut module, &vec![]);
+    loop {
+        if let (
+            Location::Module {
+                func_idx,
+                instr_idx,
+            },
+            ..,
+        ) = mod_it.curr_loc()
+        {
+            let predicate = if *func_idx == 0 { enabled } else { sampled };
+            if instr_idx == 0 {
+                mod_it.before();
+                predicate.begin(&mut mod_it);
+                counter.increment(&mut mod_it);
+                predicate.end(&mut mod_it);
+            }
+            if *mod_it.curr_op().unwrap() == Operator::I32Add {
+                // consumes the result of the `i32.add` and pushes it back
+                mod_it.after();
+                predicate.begin_typed(&mut mod_it, same);
+                mod_it.i32_const(2).i32_mul();
+                predicate.end(&mut mod_it);
+            }
+        }
+        if mod_it.next().is_none() {
+            break;
+        }
+    }
+
+    let result = module.encode();
+    validate(&result);
+
+    let ops = ops_of(&result, 1);
+    assert_eq!(
+        ops[..12],
+        [
+            Operator::GlobalGet { global_index: 2 },
+            Operator::I32Eqz,
+            Operator::I32Const { value: 3 },
+            Operator::GlobalGet { global_index: 2 },
+            Operator::I32Const { value: 1 },
+            Operator::I32Sub,
+            Operator::GlobalGet { global_index: 2 },
+            Operator::I32Eqz,
+            Operator::Select,
+            Operator::GlobalSet { global_index: 2 },
+            Operator::If {
+                blockty: wasmparser::BlockType::Empty
+            },
+            Operator::GlobalGet { global_index: 0 },
+        ]
+    );
+    let ops = ops_of(&result, 0);
+    assert_eq!(ops[0], Operator::GlobalGet { global_index: 1 });
+    assert!(ops.contains(&Operator::If {
+        blockty: wasmparser::BlockType::FuncType(*same)
+    }));
+}
+
+#[test]
+fn test_predicate_fallback() {
+    let buff = parse(
+        r#"
+        (module
+            (func $f (param i32) (result i32)
+                block $out
+                    block $in
+                        local.get 0
+                        br_if $out
+                    end
+                end
+                local.get 0
+                i32.const 1
+                i32.add
+            )
+        )
+        "#,
+    );
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let enabled = Predicate::flag(&mut module, false);
+    let add = module
+        .types
+        .add_func_type(&[DataType::I32, DataType::I32], &[DataType::I32]);
+    let original = ops_of(&buff, 0);
+
+    let mut mod_it = ModuleIterator::new(&mut module, &vec![]);
+    loop {
+        let instr_idx = match mod_it.curr_loc().0 {
+            Location::Module { instr_idx, .. } => instr_idx,
+            _ => unreachable!(),
+        };
+        if instr_idx == 1 {
+            // the inner block runs when the flag is off
+            mod_it.block_alt();
+            enabled.begin(&mut mod_it);
+            mod_it.nop();
+            enabled.end_with_fallback(&mut mod_it, &original[1..=4]);
+        }
+        if *mod_it.curr_op().unwrap() == Operator::I32Add {
+            mod_it.alternate();
+            enabled.begin_typed(&mut mod_it, add);
+            mod_it.i32_sub();
+            enabled.end_with_fallback(&mut mod_it, &[Operator::I32Add]);
+        }
+        if mod_it.next().is_none() {
+            break;
+        }
+    }
+
+    let result = module.encode();
+    validate(&result);
+    let ops = ops_of(&result, 0);
+    assert_eq!(
+        ops[1..10],
+        [
+            Operator::GlobalGet { global_index: 0 },
+            Operator::If {
+                blockty: wasmparser::BlockType::Empty
+            },
+            Operator::Nop,
+            Operator::Else,
+            Operator::Block {
+                blockty: wasmparser::BlockType::Empty
+            },
+            Operator::LocalGet { local_index: 0 },
+            // the branch to `$out` crosses the `if` of the guard
+            Operator::BrIf { relative_depth: 2 },
+            Operator::End,
+            Operator::End,
+        ]
+    );
+    assert_eq!(
+        ops[13..19],
+        [
+            Operator::GlobalGet { global_index: 0 },
+            Operator::If {
+                blockty: wasmparser::BlockType::FuncType(*add)
+            },
+            Operator::I32Sub,
+            Operator::Else,
+            Operator::I32Add,
+            Operator::End,
+        ]
+    );
+}
+
+const ACCESSES: &str = r#"
+(module
+    (memory 1)
+    (memory i64 1)
+    (global $address (mut i64) (i64.const 0))
+    (global $width (mut i64) (i64.const 0))
+    (func $f (param i32)
+        local.get 0
+        i32.const 7
+        i32.store offset=4
+        i64.const 16
+        i64.load8_u 1 offset=2
+        drop
+        local.get 0
+        i32.const 0
+        i32.const 1
+        i32.atomic.rmw.cmpxchg
+        drop
+        local.get 0
+        i32.const 255
+        i32.const 8
+        memory.fill
+        i64.const 0
+        local.get 0
+        i32.const 8
+        memory.copy 1 0
+    )
+)
+"#;
+
+#[test]
+fn test_memory_accesses() {
+    let buff = parse(ACCESSES);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let tracer = AccessTracer::new(&module);
+
+    let mut accesses = vec![];
+    let mut temps = AccessTemps::default();
+    let mut mod_it = ModuleIterator::new(&mut module, &vec![]);
+    loop {
+        if let Some(access) = tracer.access(mod_it.curr_op().unwrap()) {
+            mod_it.before();
+            let locals = access.save(&mut mod_it, &mut temps);
+            mod_it
+                .local_get(locals.address)
+                .global_set(GlobalID(0))
+                .local_get(locals.width)
+                .global_set(GlobalID(1));
+            accesses.push((access, locals));
+        }
+        if mod_it.next().is_none() {
+            break;
+        }
+    }
+
+    let kinds: Vec<(AccessKind, u32, Option<u32>)> = accesses
+        .iter()
+        .map(|(access, _)| (access.kind, access.memory, access.width))
+        .collect();
+    assert_eq!(
+        kinds,
+        vec![
+            (AccessKind::Store, 0, Some(4)),
+            (AccessKind::Load, 1, Some(1)),
+            (AccessKind::CompareExchange, 0, Some(4)),
+            (AccessKind::Fill, 0, None),
+            (AccessKind::Copy, 1, None),
+        ]
+    );
+    assert_eq!(accesses[1].0.operands, vec![DataType::I64]);
+    assert_eq!(
+        accesses[4].0.operands,
+        vec![DataType::I64, DataType::I32, DataType::I32]
+    );
+    assert!(accesses[0].1.value.is_some());
+    assert!(accesses[1].1.value.is_none());
+    assert!(accesses[4].1.source.is_some());
+    // the accesses share their locals
+    assert_eq!(accesses[0].1.address, accesses[2].1.address);
+
+    let result = module.encode();
+    validate(&result);
+
+    // the store: the operands are spilled, the address computed and the operands pushed back
+    let ops = ops_of(&result, 0);
+    assert_eq!(
+        ops[..17],
+        [
+            Operator::LocalGet { local_index: 0 },
+            Operator::I32Const { value: 7 },
+            Operator::LocalSet { local_index: 2 },
+            Operator::LocalSet { local_index: 1 },
+            Operator::LocalGet { local_index: 1 },
+            Operator::I64ExtendI32U,
+            Operator::I64Const { value: 4 },
+            Operator::I64Add,
+            Operator::LocalSet { local_index: 3 },
+            Operator::I64Const { value: 4 },
+            Operator::LocalSet { local_index: 4 },
+            Operator::LocalGet { local_index: 1 },
+            Operator::LocalGet { local_index: 2 },
+            Operator::LocalGet { local_index: 3 },
+            Operator::GlobalSet { global_index: 0 },
+            Operator::LocalGet { local_index: 4 },
+            Operator::GlobalSet { global_index: 1 },
+        ]
+    );
+    assert!(matches!(ops[17], Operator::I32Store { .. }));
+}
@@ -5,7 +5,7 @@ use orca_wasm::ir::component::Component;
 use std::fs::File;
 use std::io::Write;
 
-fn write_to_file(bytes: &Vec<u8>, path: String) {
+fn write_to_file(bytes: &[u8], path: String) {
     let mut file = match File::create(path) {
         Ok(file) => file,
         Err(e) => {
@@ -42,10 +42,10 @@ fn round_trip_component(testname: &str, folder: &str) {
     if out != original {
         println!("Test: {:?} failed! Writing to file to check", testname);
         write_to_file(
-            &original.as_bytes().to_vec(),
+            original.as_bytes(),
             format!("{}_test_original.wat", testname),
         );
-        write_to_file(&out.as_bytes().to_vec(), format!("{}_test.wat", testname));
+        write_to_file(out.as_bytes(), format!("{}_test.wat", testname));
     }
     assert_eq!(out, original);
 }
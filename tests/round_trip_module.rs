@@ -2,12 +2,16 @@ mod common;
 
 use crate::common::{try_path, WASM_OUTPUT_DIR, WAT_OUTPUT_DIR};
 use log::{debug, error, trace};
-use orca_wasm::ir::id::FunctionID;
+use orca_wasm::ir::id::{FunctionID, GlobalID, LocalID, MemoryID, TypeID};
+use orca_wasm::ir::module::module_functions::FuncKind;
 use orca_wasm::ir::module::Module;
+use orca_wasm::ir::types::BlockType;
+use orca_wasm::opcode::Instrumenter;
+use orca_wasm::{DataType, Location, Opcode};
 use std::fs::File;
 use std::io::Write;
 
-fn write_to_file(bytes: &Vec<u8>, path: String) {
+fn write_to_file(bytes: &[u8], path: String) {
     try_path(&path);
     let mut file = match File::create(path) {
         Ok(file) => file,
@@ -41,7 +45,7 @@ fn round_trip_module(testname: &str, folder: &str) {
     if out != original {
         debug!("Test: {:?} failed! Writing to file to check", testname);
         write_to_file(
-            &out.as_bytes().to_vec(),
+            out.as_bytes(),
             format!("{WAT_OUTPUT_DIR}/module_{}.wat", testname),
         );
     }
@@ -94,3 +98,155 @@ fn set_name() {
     let out = wasmprinter::print_bytes(result).expect("couldn't translated Wasm to wat");
     debug!("{}", out);
 }
+
+const NAMED: &str = r#"
+(module
+    (type $point (struct (field $x i32) (field $y i32)))
+    (type $binop (func (param i32 i32) (result i32)))
+    (import "env" "g0" (global $imported i32))
+    (memory $first 1)
+    (memory $second 1)
+    (global $a (mut i32) (i32.const 0))
+    (global $b (mut i32) (i32.const 1))
+    (func $f (type $binop) (param $lhs i32) (param $rhs i32) (result i32) (local $tmp i32)
+        block $outer
+            loop $inner
+                local.get $lhs
+                br_if $outer
+            end
+        end
+        global.get $b
+        local.get $rhs
+        i32.add
+    )
+)
+"#;
+
+fn labels_of(module: &Module, func: FunctionID) -> Vec<Option<String>> {
+    match module.functions.get_kind(func) {
+        FuncKind::Local(func) => func
+            .body
+            .instructions
+            .iter()
+            .map(|instr| instr.label.clone())
+            .collect(),
+        FuncKind::Import(_) => vec![],
+    }
+}
+
+#[test]
+fn parse_names() {
+    let buff = wat::parse_str(NAMED).unwrap();
+    let module = Module::parse(&buff, false).unwrap();
+    assert_eq!(module.types.get_name(TypeID(0)), Some("point"));
+    assert_eq!(module.types.get_name(TypeID(1)), Some("binop"));
+    assert_eq!(module.types.get_field_name(TypeID(0), 1), Some("y"));
+    assert_eq!(module.types.get_field_name(TypeID(1), 0), None);
+    let globals: Vec<Option<String>> = (0..3)
+        .map(|idx| module.globals.get_name(GlobalID(idx)).clone())
+        .collect();
+    assert_eq!(
+        globals,
+        vec![
+            Some("imported".to_string()),
+            Some("a".to_string()),
+            Some("b".to_string())
+        ]
+    );
+    assert_eq!(
+        module.memories.get_name(MemoryID(1)),
+        &Some("second".to_string())
+    );
+
+    let func = module.functions.get_kind(FunctionID(0));
+    let FuncKind::Local(func) = func else {
+        panic!("not a local function");
+    };
+    let locals: Vec<(LocalID, &str)> = func
+        .body
+        .local_names
+        .iter()
+        .map(|(local, name)| (*local, name.as_str()))
+        .collect();
+    assert_eq!(
+        locals,
+        vec![
+            (LocalID(0), "lhs"),
+            (LocalID(1), "rhs"),
+            (LocalID(2), "tmp")
+        ]
+    );
+    let labels = labels_of(&module, FunctionID(0));
+    assert_eq!(labels[0].as_deref(), Some("outer"));
+    assert_eq!(labels[1].as_deref(), Some("inner"));
+    assert!(labels[2..].iter().all(Option::is_none));
+}
+
+#[test]
+fn names_survive_reindexing() {
+    let buff = wat::parse_str(NAMED).unwrap();
+    let mut module = Module::parse(&buff, false).unwrap();
+    module.delete_global(GlobalID(1));
+    let (added, _) = module.add_imported_global(
+        "env".to_string(),
+        "g1".to_string(),
+        DataType::I32,
+        false,
+        false,
+    );
+    module.globals.set_name(added, "added".to_string());
+    module.delete_memory(MemoryID(0));
+    module.types.set_name(TypeID(1), "renamed".to_string());
+    module
+        .types
+        .set_field_name(TypeID(0), 0, "first".to_string());
+    module
+        .functions
+        .get_mut(FunctionID(0))
+        .unwrap_local_mut()
+        .body
+        .local_names
+        .insert(LocalID(2), "scratch".to_string());
+    let mut func = module.functions.get_fn_modifier(FunctionID(0)).unwrap();
+    func.before_at(Location::Module {
+        func_idx: FunctionID(0),
+        instr_idx: 1,
+    });
+    func.block(BlockType::Empty).end();
+    let result = module.encode();
+
+    let module = Module::parse(&result, false).unwrap();
+    let globals: Vec<Option<String>> = (0..3)
+        .map(|idx| module.globals.get_name(GlobalID(idx)).clone())
+        .collect();
+    assert_eq!(
+        globals,
+        vec![
+            Some("imported".to_string()),
+            Some("added".to_string()),
+            Some("b".to_string())
+        ]
+    );
+    assert_eq!(
+        module.memories.get_name(MemoryID(0)),
+        &Some("second".to_string())
+    );
+    assert_eq!(module.types.get_name(TypeID(1)), Some("renamed"));
+    assert_eq!(module.types.get_field_name(TypeID(0), 0), Some("first"));
+    assert_eq!(module.types.get_field_name(TypeID(0), 1), Some("y"));
+    let FuncKind::Local(func) = module.functions.get_kind(FunctionID(0)) else {
+        panic!("not a local function");
+    };
+    assert_eq!(
+        func.body.local_names.get(&LocalID(2)).map(String::as_str),
+        Some("scratch")
+    );
+    // the injected block is not named, the loop keeps its name
+    let labels = labels_of(&module, FunctionID(0));
+    assert_eq!(labels[0].as_deref(), Some("outer"));
+    assert_eq!(labels[1], None);
+    assert_eq!(labels[3].as_deref(), Some("inner"));
+
+    let out = wasmprinter::print_bytes(result).unwrap();
+    assert!(out.contains("global.get $b"));
+}
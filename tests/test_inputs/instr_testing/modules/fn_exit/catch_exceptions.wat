@@ -0,0 +1,31 @@
+(module
+  (type (;0;) (func))
+  (type (;1;) (func (param i32) (result i32)))
+  ;; << (type (;2;) (func (result i32)))
+  (import "env" "may_throw" (func (;0;) (type 0)))
+  (tag (;0;) (type 0))
+  (func (;1;) (type 1) (param i32) (result i32)
+    ;; << block (type 2) (result i32) ;; label = @1
+    ;; << block (result exnref) ;; label = @2
+    ;; << try_table (type 2) (result i32) (catch_all_ref 0 (;@2;)) ;; label = @3
+    call 0
+    local.get 0
+    if ;; label = @1
+      throw 0
+    end
+    i32.const 0
+    local.get 0
+    br_if 0
+    drop
+    i32.const 1
+    ;; << end
+    ;; << br 1 (;@1;)
+    ;; << end
+    ;; << i32.const 1
+    ;; << drop
+    ;; << throw_ref
+    ;; << end
+    ;; << i32.const 1
+    ;; << drop
+  )
+)
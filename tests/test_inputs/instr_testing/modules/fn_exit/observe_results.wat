@@ -0,0 +1,35 @@
+(module
+  (type (;0;) (func (param i32) (result i32 i64)))
+  ;; << (type (;1;) (func (result i32 i64)))
+  (global (;0;) (mut i32) i32.const 0)
+  (global (;1;) (mut i64) i64.const 0)
+  (func (;0;) (type 0) (param i32) (result i32 i64)
+    ;; << (local i32 i64)
+    ;; << block (type 1) (result i32 i64) ;; label = @1
+    local.get 0
+    if ;; label = @1
+      i32.const 1
+      i64.const 2
+      ;; << local.set 2
+      ;; << local.set 1
+      ;; << local.get 1
+      ;; << global.set 0
+      ;; << local.get 2
+      ;; << global.set 1
+      ;; << local.get 1
+      ;; << local.get 2
+      return
+    end
+    i32.const 3
+    i64.const 4
+    ;; << end
+    ;; << local.set 2
+    ;; << local.set 1
+    ;; << local.get 1
+    ;; << global.set 0
+    ;; << local.get 2
+    ;; << global.set 1
+    ;; << local.get 1
+    ;; << local.get 2
+  )
+)
@@ -0,0 +1,43 @@
+(module
+  (type (;0;) (func (param i32) (result i32)))
+  (func (;0;) (type 0) (param i32) (result i32)
+    ;; << (local i32 i32)
+    block (result i32) ;; label = @1
+      i32.const 5
+      local.get 0
+      ;; << i32.const 1
+      ;; << local.set 1
+      br_if 0 (;@1;)
+      ;; << i32.const 0
+      ;; << local.set 1
+      ;; << i32.const 2
+      ;; << i32.mul
+      i32.const 7
+      local.get 0
+      ;; << i32.const 1
+      ;; << local.set 2
+      br_table 0 (;@1;) 0 (;@1;)
+      ;; << i32.const 0
+      ;; << local.set 2
+    end
+    ;; << local.get 1
+    ;; << if (type 0) (param i32) (result i32) ;; label = @1
+    ;; <<   i32.const 2
+    ;; <<   i32.mul
+    ;; << else
+    ;; <<   local.get 2
+    ;; <<   if (type 0) (param i32) (result i32) ;; label = @2
+    ;; <<     i32.const 2
+    ;; <<     i32.mul
+    ;; <<   else
+    ;; <<     local.get 2
+    ;; <<     if (type 0) (param i32) (result i32) ;; label = @3
+    ;; <<       i32.const 2
+    ;; <<       i32.mul
+    ;; <<     end
+    ;; <<   end
+    ;; << end
+    ;; << i32.const 1
+    ;; << i32.add
+  )
+)
@@ -0,0 +1,514 @@
+use orca_wasm::ir::id::{FunctionID, GlobalID, LocalID};
+use orca_wasm::ir::module::Module;
+use orca_wasm::module_builder::AddLocal;
+use orca_wasm::opcode::Instrumenter;
+use orca_wasm::transform::flatten_instrumentation;
+use orca_wasm::transform::local_compaction::compact_locals;
+use orca_wasm::transform::peephole::optimize;
+use orca_wasm::{DataType, Location, Opcode};
+use wasmparser::{BlockType, Operator};
+
+const ADD: &str = r#"
+(module
+    (func $add (param i32 i32) (result i32)
+        local.get 0
+        local.get 1
+        i32.add
+        i32.const 1
+        i32.add
+    )
+)
+"#;
+
+fn parse(wat: &str) -> Vec<u8> {
+    wat::parse_str(wat).expect("couldn't convert the input wat to Wasm")
+}
+
+fn validate(result: &[u8]) {
+    if let Err(e) = wasmparser::validate(result) {
+        panic!(
+            "Instrumented module is invalid: {}\n{}",
+            e,
+            wasmprinter::print_bytes(result).unwrap()
+        );
+    }
+}
+
+fn num_locals(wasm: &[u8], func: u32) -> u32 {
+    let mut module = Module::parse(wasm, false).expect("Unable to parse");
+    module
+        .functions
+        .unwrap_local(FunctionID(func))
+        .body
+        .num_locals
+}
+
+/// Saves the value on top of the stack into a fresh local before every instruction that consumes it
+fn instrument_with_temps(module: &mut Module) {
+    let mut func = module.functions.get_fn_modifier(FunctionID(0)).unwrap();
+    for instr_idx in [2, 4] {
+        let tmp = func.add_local(DataType::I32);
+        func.before_at(Location::Module {
+            func_idx: FunctionID(0),
+            instr_idx,
+        });
+        func.local_tee(tmp).local_get(tmp).drop();
+    }
+    let flag = func.add_local(DataType::I64);
+    func.before_at(Location::Module {
+        func_idx: FunctionID(0),
+        instr_idx: 0,
+    });
+    func.i64_const(1).local_set(flag);
+}
+
+#[test]
+fn test_compact_locals_on_encode() {
+    let buff = parse(ADD);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    instrument_with_temps(&mut module);
+    let result = module.encode();
+    validate(&result);
+    assert_eq!(num_locals(&result, 0), 3);
+
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    instrument_with_temps(&mut module);
+    module.set_compact_locals(true);
+    let result = module.encode();
+    validate(&result);
+    // both i32 temporaries share a slot, the i64 keeps its own
+    assert_eq!(num_locals(&result, 0), 2);
+}
+
+#[test]
+fn test_compact_locals_keeps_overlapping() {
+    let buff = parse(
+        r#"
+        (module
+            (func (param i32) (result i32) (local i32 i32 i32)
+                local.get 0
+                local.set 1
+                local.get 0
+                local.set 2
+                local.get 1
+                local.get 2
+                i32.add
+                local.set 3
+                local.get 3
+            )
+        )
+        "#,
+    );
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let func = module.functions.unwrap_local(FunctionID(0));
+    let mapping = compact_locals(func).expect("local 3 can reuse a slot");
+    // 1 and 2 are live at the same time, 3 is only written once both are dead
+    assert_ne!(mapping[1], mapping[2]);
+    assert_eq!(mapping[3], mapping[1]);
+    assert_eq!(func.body.num_locals, 2);
+
+    let result = module.encode();
+    validate(&result);
+}
+
+#[test]
+fn test_compact_locals_default_values() {
+    // locals read before they are written hold zero, they may not be merged
+    let buff = parse(
+        r#"
+        (module
+            (func (result i32) (local i32 i32)
+                local.get 0
+                local.get 1
+                i32.add
+            )
+        )
+        "#,
+    );
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let func = module.functions.unwrap_local(FunctionID(0));
+    assert!(compact_locals(func).is_none());
+    assert_eq!(
+        func.body.instructions[1].op,
+        wasmparser::Operator::LocalGet {
+            local_index: *LocalID(1)
+        }
+    );
+}
+
+const BR: &str = r#"
+(module
+    (global $g (mut i32) (i32.const 0))
+    (func (param i32) (result i32)
+        block
+            i32.const 2
+            global.set $g
+            br 0
+        end
+        i32.const 7
+    )
+)
+"#;
+
+fn instrument_semantic_after(module: &mut Module) {
+    let mut func = module.functions.get_fn_modifier(FunctionID(0)).unwrap();
+    func.semantic_after_at(Location::Module {
+        func_idx: FunctionID(0),
+        instr_idx: 3,
+    });
+    func.i32_const(1).global_set(GlobalID(0));
+}
+
+fn ops(wasm: &[u8]) -> Vec<String> {
+    let mut module = Module::parse(wasm, false).expect("Unable to parse");
+    module
+        .functions
+        .unwrap_local(FunctionID(0))
+        .body
+        .instructions
+        .iter()
+        .map(|instr| format!("{:?}", instr.op))
+        .collect()
+}
+
+#[test]
+fn test_optimize_instrumentation_on_encode() {
+    let buff = parse(BR);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    instrument_semantic_after(&mut module);
+    let unoptimized = module.encode();
+    validate(&unoptimized);
+
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    instrument_semantic_after(&mut module);
+    module.set_optimize_instrumentation(true);
+    let optimized = module.encode();
+    validate(&optimized);
+    assert!(ops(&optimized).len() < ops(&unoptimized).len());
+}
+
+#[test]
+fn test_optimize() {
+    let buff = parse(
+        r#"
+        (module
+            (global $g (mut i32) (i32.const 0))
+            (func (param i32) (local i32 i32)
+                i32.const 1
+                local.set 1
+                local.get 1
+                drop
+            )
+            (func (param i32) (local i32 i32))
+        )
+        "#,
+    );
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    // the original code is left as it is
+    let body = &mut module.functions.unwrap_local(FunctionID(0)).body;
+    assert!(!optimize(body));
+    assert_eq!(body.instructions.len(), 5);
+
+    let mut func = module.functions.get_fn_modifier(FunctionID(1)).unwrap();
+    func.before_at(Location::Module {
+        func_idx: FunctionID(1),
+        instr_idx: 0,
+    });
+    func.i32_const(1)
+        .local_set(LocalID(1))
+        .local_get(LocalID(1))
+        .if_stmt(orca_wasm::ir::types::BlockType::Empty)
+        .i32_const(2)
+        .i32_const(3)
+        .i32_add()
+        .global_set(GlobalID(0))
+        .else_stmt()
+        .i32_const(4)
+        .global_set(GlobalID(0))
+        .end()
+        .local_get(LocalID(0))
+        .drop()
+        .local_get(LocalID(0))
+        .local_set(LocalID(2))
+        .local_get(LocalID(2))
+        .global_set(GlobalID(0))
+        .return_stmt()
+        .i32_const(5)
+        .global_set(GlobalID(0));
+    let body = &mut module.functions.unwrap_local(FunctionID(1)).body;
+    assert!(optimize(body));
+    let ops: Vec<Operator> = body
+        .instructions
+        .iter()
+        .map(|instr| instr.op.clone())
+        .collect();
+    assert_eq!(
+        ops,
+        vec![
+            Operator::Block {
+                blockty: BlockType::Empty
+            },
+            Operator::I32Const { value: 5 },
+            Operator::GlobalSet { global_index: 0 },
+            Operator::End,
+            Operator::LocalGet { local_index: 0 },
+            Operator::GlobalSet { global_index: 0 },
+            Operator::Return,
+            Operator::End,
+        ]
+    );
+    validate(&module.encode());
+}
+
+#[test]
+fn test_optimize_keeps_metadata() {
+    let buff = parse(
+        r#"
+        (module
+            (global $g (mut i32) (i32.const 0))
+            (func (param i32)
+                block $exit
+                    local.get 0
+                    br_if $exit
+                end
+            )
+        )
+        "#,
+    );
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    module.set_instrumentation_tag(Some("a"));
+    let mut func = module.functions.get_fn_modifier(FunctionID(0)).unwrap();
+    func.before_at(Location::Module {
+        func_idx: FunctionID(0),
+        instr_idx: 1,
+    });
+    func.i32_const(1)
+        .i32_const(2)
+        .i32_add()
+        .global_set(GlobalID(0));
+    module.set_optimize_instrumentation(true);
+    let result = module.encode();
+    validate(&result);
+    assert_eq!(
+        ops(&result)[..3],
+        [
+            "Block { blockty: Empty }",
+            "I32Const { value: 3 }",
+            "GlobalSet { global_index: 0 }"
+        ]
+    );
+
+    // the folded code is still recorded under its tag, the original code keeps its label
+    let mut module = Module::parse(&result, false).expect("Unable to parse");
+    assert_eq!(
+        module
+            .functions
+            .unwrap_local(FunctionID(0))
+            .body
+            .instructions[0]
+            .label
+            .as_deref(),
+        Some("exit")
+    );
+    assert_eq!(module.strip_instrumentation("a").unwrap(), 2);
+    validate(&module.encode());
+}
+
+#[test]
+fn test_flatten_instrumentation() {
+    let buff = parse(BR);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let mut func = module.functions.get_fn_modifier(FunctionID(0)).unwrap();
+    func.alternate_at(Location::Module {
+        func_idx: FunctionID(0),
+        instr_idx: 1,
+    });
+    func.i32_const(3);
+    let body = &mut module.functions.unwrap_local(FunctionID(0)).body;
+    flatten_instrumentation(body).unwrap();
+    assert_eq!(body.instructions[1].op, Operator::I32Const { value: 3 });
+    assert!(!body.instructions[1].instr_flag.has_instr());
+
+    // the `br` replaced by an alternate body also has semantic after instrumentation
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    instrument_semantic_after(&mut module);
+    let mut func = module.functions.get_fn_modifier(FunctionID(0)).unwrap();
+    func.alternate_at(Location::Module {
+        func_idx: FunctionID(0),
+        instr_idx: 3,
+    });
+    func.br(0);
+    let body = &mut module.functions.unwrap_local(FunctionID(0)).body;
+    assert!(flatten_instrumentation(body).is_err());
+    // nothing was flattened
+    assert_eq!(body.instructions.len(), 7);
+    assert!(!body.instructions[3].instr_flag.semantic_after.is_empty());
+    validate(&module.encode());
+}
+
+const OUTLINE: &str = r#"
+(module
+    (global $g (mut i32) (i32.const 0))
+    (func (param i32) (result i32) (local i32 i32)
+        block
+            local.get 0
+            i32.const 1
+            i32.add
+            local.set 1
+            local.get 1
+            i32.const 2
+            i32.mul
+            local.set 2
+            local.get 2
+            global.set $g
+            local.get 0
+            br_if 0
+        end
+        local.get 1
+    )
+)
+"#;
+
+#[test]
+fn test_outline() {
+    let buff = parse(OUTLINE);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    // probe on the outlined code moves along
+    let mut func = module.functions.get_fn_modifier(FunctionID(0)).unwrap();
+    func.before_at(Location::Module {
+        func_idx: FunctionID(0),
+        instr_idx: 9,
+    });
+    func.local_get(LocalID(2)).global_set(GlobalID(0));
+
+    let new_id = module
+        .outline(FunctionID(0), 1..11, Some("outlined".to_string()))
+        .expect("the range can be outlined");
+    assert_eq!(*new_id, 1);
+
+    let outlined = module.functions.unwrap_local(new_id);
+    let ty = module.types.get(outlined.ty_id).unwrap();
+    // local 0 flows in, local 1 flows out, local 2 is only used inside
+    assert_eq!(ty.params(), vec![DataType::I32]);
+    assert_eq!(ty.results(), vec![DataType::I32]);
+    assert_eq!(outlined.body.num_locals, 2);
+
+    let ops: Vec<Operator> = module
+        .functions
+        .unwrap_local(FunctionID(0))
+        .body
+        .instructions
+        .iter()
+        .map(|instr| instr.op.clone())
+        .collect();
+    assert_eq!(
+        ops[..4],
+        [
+            Operator::Block {
+                blockty: BlockType::Empty
+            },
+            Operator::LocalGet { local_index: 0 },
+            Operator::Call { function_index: 1 },
+            Operator::LocalSet { local_index: 1 },
+        ]
+    );
+
+    let result = module.encode();
+    validate(&result);
+}
+
+#[test]
+fn test_outline_invalid_range() {
+    let buff = parse(OUTLINE);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    // branches to the enclosing block
+    assert!(module.outline(FunctionID(0), 11..13, None).is_err());
+    // splits the block
+    assert!(module.outline(FunctionID(0), 0..5, None).is_err());
+    // contains the end of the function
+    assert!(module.outline(FunctionID(0), 14..16, None).is_err());
+    assert_eq!(module.functions.iter().count(), 1);
+}
+
+const HOST_CALLS: &str = r#"
+(module
+    (import "wasi" "clock" (func $clock (param i32) (result i64)))
+    (global $last (mut i64) (i64.const 0))
+    (func $f (result i64)
+        i32.const 0
+        call $clock
+        drop
+        i32.const 1
+        return_call $clock
+    )
+)
+"#;
+
+#[test]
+fn test_wrap_import() {
+    let buff = parse(HOST_CALLS);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    let wrapper = module
+        .wrap_import(FunctionID(0), Some("clock_wrapper".to_string()))
+        .expect("the import can be wrapped");
+    // the hooks call a new import, the calls are remapped on encode
+    let log_ty = module.types.add_func_type(&[DataType::I32], &[]);
+    let (log, _) = module.add_import_func("env".to_string(), "log".to_string(), log_ty);
+
+    let results = module.func_exit_with_results(wrapper).unwrap();
+    let mut func = module.functions.get_fn_modifier(wrapper).unwrap();
+    func.func_entry();
+    func.local_get(LocalID(0)).call(log);
+    func.func_exit();
+    func.local_get(results[0]).global_set(GlobalID(0));
+
+    let result = module.encode();
+    validate(&result);
+
+    let module = Module::parse(&result, false).expect("Unable to parse");
+    let ops = |func: u32| -> Vec<Operator> {
+        module
+            .functions
+            .get(FunctionID(func))
+            .unwrap_local()
+            .body
+            .instructions
+            .iter()
+            .map(|instr| instr.op.clone())
+            .collect()
+    };
+    // imports: clock, log, then $f and the wrapper
+    assert_eq!(
+        ops(2),
+        vec![
+            Operator::I32Const { value: 0 },
+            Operator::Call { function_index: 3 },
+            Operator::Drop,
+            Operator::I32Const { value: 1 },
+            Operator::ReturnCall { function_index: 3 },
+            Operator::End,
+        ]
+    );
+    let wrapper_ops = ops(3);
+    assert_eq!(
+        wrapper_ops[..3],
+        [
+            Operator::LocalGet { local_index: 0 },
+            Operator::Call { function_index: 1 },
+            Operator::Block {
+                blockty: BlockType::FuncType(1)
+            },
+        ]
+    );
+    assert!(wrapper_ops.contains(&Operator::Call { function_index: 0 }));
+    assert!(wrapper_ops.contains(&Operator::GlobalSet { global_index: 0 }));
+}
+
+#[test]
+fn test_wrap_import_invalid() {
+    let buff = parse(HOST_CALLS);
+    let mut module = Module::parse(&buff, false).expect("Unable to parse");
+    // $f is a local function
+    assert!(module.wrap_import(FunctionID(1), None).is_err());
+    assert!(module.wrap_import(FunctionID(5), None).is_err());
+}